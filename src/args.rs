@@ -1,6 +1,28 @@
 use clap::{Arg, Command};
 
+use crate::{
+    client,
+    dump_descriptor::DumpFormat,
+    fetch,
+    pipeline::{OnInvalid, OutputFormat},
+    valid_proto::{EnumCase, FieldNameMode, FieldRange, TimestampFormat, DEFAULT_MAX_DEPTH},
+};
+
+/// Documents written together by --preserve-revs when --bulk-size is not given.
+const PRESERVE_REVS_BULK_SIZE: usize = 100;
+
+/// What the invocation should do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Morph,         // Fetch, validate, transform and update documents in CouchDB
+    LintTransform, // Check a Lua transform against a synthetic document, no database involved
+    Try,           // Run a single document through validate -> transform -> validate
+    DumpDescriptor, // Print the parsed FileDescriptorSet, no database or script involved
+    CheckSchema,   // Report schema constructs validation skips silently, no database or script involved
+}
+
 pub struct Args {
+    pub mode: Mode,         // Selected subcommand (Morph when none is given)
     pub db_url: String,     // URL of the CouchDB database
     pub table_name: String, // Name of the table (or document type)
     pub ignore_list: String, // Comma-separated list of fields to ignore
@@ -9,7 +31,87 @@ pub struct Args {
     pub limit: usize,  // Maximum number of documents to fetch per iteration
     pub proto_path: String, // Path to the .proto file
     pub proto_dir: String,  // Path containing .proto file
+    pub descriptor_set: Option<String>, // Binary FileDescriptorSet path or http(s) URL, replaces proto_path
+    pub proto_root: Option<String>, // Extra include path for imports relative to a repository root
     pub script_dir: String, // Path to script that transform JSON document
+    pub assert_output_shape: bool, // Refuse transform outputs that no longer resemble the input
+    pub min_key_overlap: f64, // Minimum share of input keys the transform output must keep
+    pub input_dump: Option<String>, // JSONL dump to process offline instead of the database
+    pub output_path: Option<String>, // File receiving transformed documents in offline mode
+    pub on_invalid: OnInvalid, // Policy for documents still invalid after the transform
+    pub format: OutputFormat,  // How validation errors are printed (text or json)
+    pub seen_file: Option<String>, // File of id+rev hashes that already validated
+    pub max_docs: Option<usize>, // Stop after processing this many documents
+    pub timeout: Option<u64>,    // Stop fetching after this many seconds
+    pub strict_strings: bool,    // Flag string values containing NUL characters
+    pub auto_coerce: bool,       // Fix simple type mismatches before calling Lua
+    pub summary_json: Option<String>, // File receiving the final run counts as JSON
+    pub only_fields: String,          // Comma-separated list of field paths to validate, others are ignored
+    pub unknown_enum_ok: bool,        // Accept undeclared enum numbers with a warning
+    pub dump_invalid: Option<String>, // JSONL file receiving invalid documents instead of transforming them
+    pub headers: Vec<String>,         // Extra "Name: Value" headers sent with every CouchDB request
+    pub preserve_revs: bool,          // Write with new_edits=false, keeping the revision history
+    pub reset_lua_per_doc: bool,      // Fresh Lua state for every document
+    pub consistent_arrays: bool,      // Flag repeated message fields with differently shaped elements
+    pub fetch_rate: Option<f64>,      // Maximum number of pages fetched per second
+    pub shadow_table: Option<String>, // Rehearsal: write transform outputs to this table instead
+    pub skip_ids_file: Option<String>, // File of document ids to skip entirely
+    pub transform_valid_if_changed: bool, // Also transform valid documents, writing only changed outputs
+    pub max_doc_bytes: Option<usize>, // Largest serialized document that may be written
+    pub user_agent: Option<String>,   // Overrides the default bulkmorph/<version> user agent
+    pub ranges: Vec<FieldRange>,      // Business-rule bounds for numeric fields
+    pub strict_resolution: bool,      // Report field types missing from the schema
+    pub sample_rate: Option<f64>,     // Share of the invalid documents transformed this run
+    pub seed: Option<u64>,            // Seed of the --sample-rate selection
+    pub enum_case: EnumCase,          // How JSON enum names are normalized before matching
+    pub wrong_type_ratio: Option<f64>, // Unknown to matching fields ratio flagging another message type
+    pub verify_updates: bool,         // Read updated documents back and validate them again
+    pub unflatten: bool,              // Rewrite dotted top-level keys into nested objects
+    pub field_mask: Option<String>,   // Only validate the subtree under this nested message path
+    pub run_id_field: Option<String>, // Field stamped with the run id on every updated document
+    pub run_id: Option<String>,       // Run id to stamp, generated when not given
+    pub doc: Option<String>,          // Document given to the try subcommand, read from stdin when absent
+    pub exec_stats: bool,             // Log CouchDB's _find execution statistics
+    pub abort_if_invalid_rate: Option<f64>, // Invalid rate in percent aborting the run after the warmup
+    pub abort_warmup: usize,          // Documents processed before the invalid rate is checked
+    pub timestamp_format: TimestampFormat, // Expected JSON encoding of Timestamp fields
+    pub lua_workers: Option<usize>,   // Worker threads, each with its own Lua state, running the transform
+    pub require_nonempty_messages: bool, // Report required message fields given as {}
+    pub dump_format: DumpFormat,      // Output of dump-descriptor, tree or JSON
+    pub dump_output: Option<String>,  // File written by dump-descriptor instead of stdout
+    pub defer_conflicts: bool,        // Retry conflicting updates once, after the main scan
+    pub strict_integer_format: bool,  // Report integer fields stored as floats such as 1e9
+    pub lua_libs: Vec<String>,        // Shared Lua helper folders loaded before the script includes
+    pub max_depth: usize,             // Deepest message nesting validated
+    pub csv_out: Option<String>,      // CSV file receiving the error counts by field path and type
+    pub auto_message: bool,           // Fall back to the sole message of the proto when the table name matches none
+    pub id_range_start: Option<String>, // First id scanned, inclusive
+    pub id_range_end: Option<String>,   // Id where the scan stops, exclusive
+    pub bulk_size: Option<usize>,     // Updates written together through _bulk_docs
+    pub max_concurrency: usize,       // Updates of a page sent to CouchDB at the same time
+    pub conflict_retries: usize,      // Immediate retries of a conflicting update with the current revision
+    pub fill_defaults: bool,          // Set missing scalar fields to their proto default before calling Lua
+    pub report_fixes: bool,           // Print the fields changed by each repair with a confidence label
+    pub username: Option<String>,     // CouchDB user, sent with HTTP basic auth or used to open a session
+    pub password: Option<String>,     // Password of the CouchDB user
+    pub session_auth: bool,           // Log in once through _session and send the AuthSession cookie
+    pub max_retries: usize,           // Retries of a metadata or page read failing with a network error or 5xx
+    pub retry_base_ms: u64,           // Wait before the first retry, doubled on every attempt
+    pub output_message: Option<String>, // Message the transform output is validated against, the table message if None
+    pub selector: Option<serde_json::Value>, // Mango selector limiting the fetched documents
+    pub checkpoint: Option<String>,   // File saving the bookmark of the next page after each page
+    pub resume: bool,                 // Start from the bookmark saved in the checkpoint file
+    pub shards: Option<usize>,        // Number of id ranges fetched concurrently
+    pub report: Option<String>,       // JSON file receiving the errors and outcome of every document
+    pub tables: Vec<String>,          // Every --table value, processed one after the other; table_name is the first
+    pub sandbox: bool,                // Run the Lua scripts without io, os, package and file loading
+    pub string_transform: bool,       // Hand documents to the hooks as JSON text, for older scripts
+    pub no_progress: bool,            // Print a line per page instead of the progress bar
+    pub timeouts: client::Timeouts,   // Connect and whole-request limits of every CouchDB request
+    pub strict_required: bool,        // Require every non-repeated field whatever the syntax of its file
+    pub field_name_mode: FieldNameMode, // Whether JSON keys may use the json_name, the proto field name or both
+    pub show_diff: bool,              // Print the field changes of each update previewed by --dry-run
+    pub dead_letter: Option<String>,  // JSONL file receiving the documents still invalid after the transform
 }
 
 /// Parse command-line arguments using `clap`
@@ -29,39 +131,9 @@ pub fn parse_args() -> Result<Args, String> {
                 .long("url")
                 .value_name("URL")
                 .help("URL of the CouchDB database (Example: http://localhost:5984)")
-                .required(true),
-        )
-        .arg(
-            Arg::new("table_name")
-                .short('t')
-                .long("table")
-                .value_name("TABLE")
-                .help("Name of the table (or document type)")
-                .required(true),
-        )
-        .arg(
-            Arg::new("ignore")
-                .short('g')
-                .long("ignore")
-                .value_name("IGNORE")
-                .help("Comma-separated list of fields to ignore"),
-        )
-        .arg(
-            Arg::new("proto")
-                .short('p')
-                .long("proto")
-                .value_name("FILE")
-                .help("Path to the .proto file")
-                .required(true),
-        )
-        .arg(
-            Arg::new("include")
-                .short('i')
-                .long("include")
-                .value_name("DIRECTORY")
-                .help("Path containing .proto file")
-                .required(true),
+                .required_unless_present("input"),
         )
+        .args(schema_args())
         .arg(
             Arg::new("dry_run")
                 .long("dry-run") // Use --dry-run to enable dry-run mode
@@ -72,7 +144,7 @@ pub fn parse_args() -> Result<Args, String> {
         .arg(
             Arg::new("stat") // Print list of document id without their error information.
                 .long("stat")
-                .help("Print list of document id without their error information.")
+                .help("Survey mode: print the id of each invalid document without its errors, transform and write nothing, then count the documents by error type")
                 .action(clap::ArgAction::SetTrue)
                 .default_value("false"),
         )
@@ -86,30 +158,770 @@ pub fn parse_args() -> Result<Args, String> {
                 .help("Maximum number of documents to fetch per iteration"),
         )
         .arg(
-            Arg::new("luascript")
-                .short('s')
-                .long("script")
-                .help("Path to script that transform JSON document"),
+            Arg::new("assert_output_shape")
+                .long("assert-output-shape")
+                .help("Refuse transform outputs that are not an object with _id and _rev or that share too few keys with the input")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("min_key_overlap")
+                .long("min-key-overlap")
+                .value_name("RATIO")
+                .default_value("0.5")
+                .value_parser(clap::value_parser!(f64))
+                .help("Minimum share (0.0 - 1.0) of input keys kept by the transform output when --assert-output-shape is set"),
+        )
+        .arg(
+            Arg::new("input")
+                .long("input")
+                .num_args(2)
+                .value_names(["KIND", "FILE"])
+                .help("Process documents from a file instead of CouchDB, skipping ids seen twice; every id is kept in memory, about 100 bytes each (Example: --input couchdump export.jsonl)"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("FILE")
+                .help("File receiving transformed documents as JSON lines when processing offline")
+                .requires("input"),
+        )
+        .arg(
+            Arg::new("on_invalid")
+                .long("on-invalid")
+                .value_name("POLICY")
+                .value_parser(["skip", "report", "delete"])
+                .default_value("skip")
+                .help("What to do with documents still invalid after transform: skip (print errors), report (errors to stderr only) or delete"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("How validation errors are printed: text, or json for one object per document"),
+        )
+        .arg(
+            Arg::new("seen_file")
+                .long("seen-file")
+                .value_name("FILE")
+                .help("File recording documents that validated; unchanged revisions are skipped on later runs"),
+        )
+        .arg(
+            Arg::new("max_docs")
+                .long("max-docs")
+                .visible_alias("limit-total")
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(usize))
+                .help("Stop after processing this many documents, even in the middle of a batch"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Stop processing after this many seconds, even in the middle of a batch"),
+        )
+        .arg(
+            Arg::new("strict_strings")
+                .long("strict-strings")
+                .help("Report string values containing NUL characters as InvalidStringContent")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("auto_coerce")
+                .long("auto-coerce")
+                .help("Coerce number/string and bool/number mismatches to the proto field type before calling the Lua transform")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("summary_json")
+                .long("summary-json")
+                .value_name("FILE")
+                .help("Write the final run counts as a single JSON object to this file"),
+        )
+        .arg(
+            Arg::new("only_fields")
+                .long("only-fields")
+                .value_name("FIELDS")
+                .help("Comma-separated list of field paths to validate (Example: amount,items.price); all other fields are ignored"),
+        )
+        .arg(
+            Arg::new("unknown_enum_ok")
+                .long("unknown-enum-ok")
+                .help("Accept enum numbers outside the declared values with a warning (proto3 open enums); unknown names still fail")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("dump_invalid")
+                .long("dump-invalid")
+                .value_name("FILE")
+                .help("Export every invalid document with its errors as JSON lines, without transforming or updating anything"),
+        )
+        .arg(
+            Arg::new("dead_letter")
+                .long("dead-letter")
+                .value_name("FILE")
+                .help("Append every document still invalid after the transform, with its remaining errors, to this file as JSON lines")
+                .conflicts_with("dump_invalid"),
+        )
+        .arg(
+            Arg::new("header")
+                .long("header")
+                .value_name("NAME: VALUE")
+                .action(clap::ArgAction::Append)
+                .help("Extra HTTP header sent with every CouchDB request, can be repeated (Example: --header \"X-Api-Key: secret\")"),
+        )
+        .arg(
+            Arg::new("preserve_revs")
+                .long("preserve-revs")
+                .help("Write updates through _bulk_docs with new_edits=false, as a child revision of each document's _rev that extends its _revisions history (replication scenarios)")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("reset_lua_per_doc")
+                .long("reset-lua-per-doc")
+                .help("Load the Lua scripts into a fresh state for every document, so documents cannot contaminate each other through globals (slower)")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("consistent_arrays")
+                .long("consistent-arrays")
+                .help("Report repeated message fields whose elements have differing key sets as InconsistentArrayShape")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("fetch_rate")
+                .long("fetch-rate")
+                .value_name("PAGES_PER_SEC")
+                .value_parser(clap::value_parser!(f64))
+                .help("Fetch at most this many pages per second (Example: 0.5 for one page every 2 seconds)"),
+        )
+        .arg(
+            Arg::new("shadow_table")
+                .long("shadow-table")
+                .value_name("TABLE")
+                .help("Rehearsal mode: write transformed documents, without _rev, to this table instead of updating them in place (Example: orders_shadow)")
+                .conflicts_with_all(["dry_run", "input", "preserve_revs"]),
+        )
+        .arg(
+            Arg::new("skip_ids_file")
+                .long("skip-ids-file")
+                .value_name("FILE")
+                .help("File listing document ids (one per line) to skip before validation, e.g. the ids updated by an interrupted run"),
+        )
+        .arg(
+            Arg::new("transform_valid_if_changed")
+                .long("transform-on-valid-if-changed")
+                .help("Normalization mode: also run the transform on valid documents and update them only when the output changed and still validates")
+                .conflicts_with("dump_invalid")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("max_doc_bytes")
+                .long("max-doc-bytes")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .help("Do not write repaired documents larger than this many bytes (match CouchDB's max_document_size)"),
+        )
+        .arg(
+            Arg::new("user_agent")
+                .long("user-agent")
+                .value_name("USER_AGENT")
+                .help("User-Agent sent with every CouchDB request (default: bulkmorph/<version>)"),
+        )
+        .arg(
+            Arg::new("range")
+                .long("range")
+                .value_name("PATH=MIN:MAX")
+                .action(clap::ArgAction::Append)
+                .help("Allowed range of a numeric field, can be repeated; either bound may be left open (Example: --range age=0:150 --range amount=0:)"),
+        )
+        .arg(
+            Arg::new("strict_resolution")
+                .long("strict-resolution")
+                .action(clap::ArgAction::SetTrue)
+                .help("Report fields whose message or enum type is missing from the schema as UnresolvedType instead of skipping them"),
+        )
+        .arg(
+            Arg::new("sample_rate")
+                .long("sample-rate")
+                .value_name("RATE")
+                .value_parser(clap::value_parser!(f64))
+                .help("Canary mode: transform and update only this random fraction (0.0-1.0) of the documents that need it, the rest are counted as deferred (Example: 0.1)"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .value_parser(clap::value_parser!(u64))
+                .requires("sample_rate")
+                .help("Seed of the --sample-rate selection, to pick the same documents again"),
+        )
+        .arg(
+            Arg::new("enum_case")
+                .long("enum-case")
+                .value_name("CASE")
+                .value_parser(["screaming_snake", "camel", "as_is"])
+                .help("Convert JSON enum names to this convention before matching the declared values (Example: screaming_snake to accept activeStatus for ACTIVE_STATUS)")
+                .default_value("as_is"),
+        )
+        .arg(
+            Arg::new("wrong_type_ratio")
+                .long("wrong-type-ratio")
+                .value_name("RATIO")
+                .value_parser(clap::value_parser!(f64))
+                .help("Multi-type tables: leave a document untransformed as likely of another type when its unknown top-level fields outnumber RATIO times its matching fields (Example: 1.0)"),
+        )
+        .arg(
+            Arg::new("verify_updates")
+                .long("verify-updates")
+                .help("Read every updated document back and validate it again, reporting a verification failure when it does not match the schema")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("unflatten")
+                .long("unflatten")
+                .help("Rewrite dotted top-level keys into nested objects before validation (Example: {\"address.city\": \"X\"} becomes {\"address\": {\"city\": \"X\"}})")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("field_mask")
+                .long("field-mask")
+                .value_name("PATH")
+                .help("Only validate the subtree under this nested message field, the rest of the document is ignored (Example: payment.card)"),
+        )
+        .arg(
+            Arg::new("run_id_field")
+                .long("run-id-field")
+                .value_name("FIELD")
+                .help("Stamp every updated document with the run id in this field, and skip documents already stamped with it (Example: morph_run_id)"),
+        )
+        .arg(
+            Arg::new("run_id")
+                .long("run-id")
+                .value_name("ID")
+                .requires("run_id_field")
+                .help("Run id stamped by --run-id-field, pass the id printed by an interrupted run to retry it (Default: a generated UUID)"),
+        )
+        .arg(
+            Arg::new("exec_stats")
+                .long("exec-stats")
+                .help("Ask CouchDB for the _find execution statistics (documents examined, execution time) and log them per page and for the whole run")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("abort_if_invalid_rate")
+                .long("abort-if-invalid-rate")
+                .value_name("PERCENT")
+                .value_parser(clap::value_parser!(f64))
+                .help("Safety valve: abort the run when more than this percentage of the documents are invalid, checked once the warmup is over (Example: 90)"),
+        )
+        .arg(
+            Arg::new("abort_warmup")
+                .long("abort-warmup")
+                .value_name("DOCS")
+                .value_parser(clap::value_parser!(usize))
+                .requires("abort_if_invalid_rate")
+                .help("Number of documents processed before --abort-if-invalid-rate is checked")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("timestamp_format")
+                .long("timestamp-format")
+                .value_name("FORMAT")
+                .value_parser(["rfc3339", "epoch_millis", "epoch_secs"])
+                .help("Encoding accepted for google.protobuf.Timestamp fields: RFC3339 strings or integer epoch milliseconds/seconds")
+                .default_value("rfc3339"),
+        )
+        .arg(
+            Arg::new("lua_workers")
+                .long("lua-workers")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with("reset_lua_per_doc")
+                .help("Run the transform on N worker threads, each owning its own Lua state (globals are not shared between workers)"),
+        )
+        .arg(
+            Arg::new("require_nonempty_messages")
+                .long("require-nonempty-messages")
+                .help("Report a required message field given as an empty object as EmptyRequiredMessage, instead of each of its missing fields")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("defer_conflicts")
+                .long("defer-conflicts")
+                .help("Collect updates refused with 409 Conflict and retry each once after the main scan, on a freshly fetched revision")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("strict_integer_format")
+                .long("strict-integer-format")
+                .help("Report integer fields stored as floats or in exponential form, e.g. 1e9, as FloatInteger so a transform can rewrite them as plain integers")
+                .action(clap::ArgAction::SetTrue)
+                .default_value("false"),
+        )
+        .arg(
+            Arg::new("max_depth")
+                .long("max-depth")
+                .value_name("DEPTH")
+                .value_parser(clap::value_parser!(usize))
+                .help("Deepest message nesting validated; deeper messages, e.g. of a self-referencing tree, are reported as MaxDepthExceeded")
+                .default_value("64"),
+        )
+        .arg(
+            Arg::new("csv_out")
+                .long("csv-out")
+                .value_name("FILE")
+                .help("Write the validation errors of the run, counted by field path and error type, as CSV to this file"),
+        )
+        .arg(
+            Arg::new("id_range_start")
+                .long("id-range-start")
+                .value_name("ID")
+                .help("Only scan documents whose _id is greater than or equal to this id, to split a table across runs"),
+        )
+        .arg(
+            Arg::new("id_range_end")
+                .long("id-range-end")
+                .value_name("ID")
+                .help("Only scan documents whose _id is strictly lower than this id, to split a table across runs"),
+        )
+        .arg(
+            Arg::new("bulk_size")
+                .long("bulk-size")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["shadow_table", "output"])
+                .help("Buffer updates and write them to CouchDB N at a time through _bulk_docs instead of one PUT per document"),
+        )
+        .arg(
+            Arg::new("max_concurrency")
+                .long("max-concurrency")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Send at most N updates of a page to CouchDB at the same time, the others wait for a free slot")
+                .default_value("16"),
+        )
+        .arg(
+            Arg::new("conflict_retries")
+                .long("conflict-retries")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("On a 409 conflict, fetch the current revision of the document and run it through the pipeline again, up to N times")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("fill_defaults")
+                .long("fill-defaults")
+                .help("Before calling the Lua transform, set missing single scalar fields to their proto default (0, \"\" or false)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("report_fixes")
+                .long("report-fixes")
+                .help("Print the fields changed in each repaired document with a confidence label: high for defaults, medium for coercions, low for the transform")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("username")
+                .long("username")
+                .value_name("USER")
+                .requires("password")
+                .help("CouchDB user, sent with HTTP basic auth on every request"),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .requires("username")
+                .help("Password of the --username user"),
+        )
+        .arg(
+            Arg::new("session_auth")
+                .long("session-auth")
+                .requires("username")
+                .action(clap::ArgAction::SetTrue)
+                .help("Log in once through CouchDB's _session endpoint and send the AuthSession cookie instead of the credentials"),
+        )
+        .arg(
+            Arg::new("max_retries")
+                .long("max-retries")
+                .value_name("COUNT")
+                .default_value("3")
+                .value_parser(clap::value_parser!(usize))
+                .help("Retries of a table metadata or page read failing with a network error, a timeout or a 5xx status; 4xx statuses are not retried"),
+        )
+        .arg(
+            Arg::new("retry_base_ms")
+                .long("retry-base-ms")
+                .value_name("MILLISECONDS")
+                .default_value("500")
+                .value_parser(clap::value_parser!(u64))
+                .help("Wait before the first retry of a failed read, doubled on every attempt, plus a random jitter of up to the same amount"),
+        )
+        .arg(
+            Arg::new("output_message")
+                .long("output-message")
+                .value_name("MESSAGE")
+                .help("Validate the transform output against this message instead of the table message, for transforms migrating documents to a new message"),
+        )
+        .arg(
+            Arg::new("selector")
+                .long("selector")
+                .value_name("JSON")
+                .conflicts_with("selector_file")
+                .help("Mango selector fetching only the matching documents instead of the whole table (Example: --selector '{\"status\": \"pending\"}')"),
+        )
+        .arg(
+            Arg::new("selector_file")
+                .long("selector-file")
+                .value_name("PATH")
+                .help("File containing the Mango selector, as an alternative to --selector"),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .conflicts_with("input")
+                .help("Save the bookmark of the next page and the document counts to this file after each page"),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .requires("checkpoint")
+                .action(clap::ArgAction::SetTrue)
+                .help("Continue an interrupted run from the bookmark saved in the --checkpoint file"),
+        )
+        .arg(
+            Arg::new("shards")
+                .long("shards")
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["id_range_start", "id_range_end", "checkpoint", "max_docs", "input"])
+                .help("Split the ids into this many ranges (2 to 36) by their first character and fetch them concurrently into the same pipeline"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("FILE")
+                .help("Write a JSON array with the errors before and after the transform and the outcome of every document"),
+        )
+        .arg(
+            Arg::new("connect_timeout")
+                .long("connect-timeout")
+                .value_name("DURATION")
+                .help("Give up connecting to CouchDB after this long, in seconds (30, 30s) or milliseconds (500ms) (Default: 10s)"),
+        )
+        .arg(
+            Arg::new("request_timeout")
+                .long("request-timeout")
+                .value_name("DURATION")
+                .help("Give up on a CouchDB request without a complete response after this long, in seconds or milliseconds (Default: 120s)"),
+        )
+        .arg(
+            Arg::new("no_progress")
+                .long("no-progress")
+                .help("Print a line per fetched page instead of the progress bar shown when stdout is a terminal")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict_required")
+                .long("strict-required")
+                .help("Report every absent non-repeated field as MissingField, also in proto3 and editions files")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("field_name_mode")
+                .long("field-name-mode")
+                .value_name("MODE")
+                .value_parser(["json", "proto", "both"])
+                .help("Accept JSON keys written as the json_name (orderId), the proto field name (order_id) or both")
+                .default_value("both"),
+        )
+        .arg(
+            Arg::new("show_diff")
+                .long("show-diff")
+                .help("With --dry-run, print the fields each update would add (+), remove (-) or change (~)")
+                .action(clap::ArgAction::SetTrue)
+                .requires("dry_run"),
+        )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("lint-transform")
+                .about("Run the Lua transform on a synthetic worst-case document and validate the output, without a database")
+                .args(schema_args())
+                .mut_arg("luascript", |arg| arg.required(true)),
+        )
+        .subcommand(
+            Command::new("try")
+                .about("Validate, transform and validate again a single document, then print the verdict, without a database")
+                .args(schema_args())
+                .mut_arg("luascript", |arg| arg.required(true))
+                .arg(
+                    Arg::new("doc")
+                        .long("doc")
+                        .value_name("JSON")
+                        .help("Document to try, read from stdin when not given (Example: --doc '{\"_id\":\"order-1\",\"quantity\":\"10\"}')"),
+                ),
+        )
+        .subcommand(
+            Command::new("dump-descriptor")
+                .about("Print the FileDescriptorSet parsed from the schema: messages, fields, enums and nested types")
+                .args(schema_args())
+                // Only the schema location matters, the table and script are not used
+                .mut_arg("table_name", |arg| arg.required(false).hide(true))
+                .mut_arg("ignore", |arg| arg.hide(true))
+                .mut_arg("luascript", |arg| arg.hide(true))
+                .mut_arg("lua_lib", |arg| arg.hide(true))
+                .mut_arg("sandbox", |arg| arg.hide(true))
+                .mut_arg("string_transform", |arg| arg.hide(true))
+                .arg(
+                    Arg::new("dump_format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["tree", "json"])
+                        .help("Readable tree or JSON document")
+                        .default_value("tree"),
+                )
+                .arg(
+                    Arg::new("dump_output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Write the dump to this file instead of stdout"),
+                ),
+        )
+        .subcommand(
+            Command::new("check-schema")
+                .about("Report schema pitfalls that let documents pass validation unchecked: missing JSON names, unresolved types, unused messages")
+                .args(schema_args())
+                // The table is optional, it enables the unused message check
+                .mut_arg("table_name", |arg| arg.required(false))
+                .mut_arg("ignore", |arg| arg.hide(true))
+                .mut_arg("luascript", |arg| arg.hide(true))
+                .mut_arg("lua_lib", |arg| arg.hide(true))
+                .mut_arg("sandbox", |arg| arg.hide(true))
+                .mut_arg("string_transform", |arg| arg.hide(true)),
         )
         .get_matches();
 
+    // Subcommands carry their own copy of the schema arguments
+    let (mode, schema_matches) = match matches.subcommand() {
+        Some(("lint-transform", sub_matches)) => (Mode::LintTransform, sub_matches),
+        Some(("try", sub_matches)) => (Mode::Try, sub_matches),
+        Some(("dump-descriptor", sub_matches)) => (Mode::DumpDescriptor, sub_matches),
+        Some(("check-schema", sub_matches)) => (Mode::CheckSchema, sub_matches),
+        _ => (Mode::Morph, &matches),
+    };
+
     // Extract arguments from matches
-    let db_url = matches.get_one::<String>("db_prefix").unwrap().clone();
-    let table_name = matches.get_one::<String>("table_name").unwrap().clone();
-    let ignore_list = matches.get_one::<String>("ignore").unwrap_or(&"".to_string()).clone();
+    let db_url = match mode {
+        Mode::Morph => matches
+            .get_one::<String>("db_prefix")
+            .cloned()
+            .unwrap_or_default(),
+        Mode::LintTransform | Mode::Try | Mode::DumpDescriptor | Mode::CheckSchema => String::new(),
+    };
+    let tables: Vec<String> = schema_matches
+        .get_many::<String>("table_name")
+        .map(|tables| tables.filter(|table| !table.is_empty()).cloned().collect())
+        .unwrap_or_default();
+    let table_name = tables.first().cloned().unwrap_or_default();
+    let dump_matches = matches.subcommand_matches("dump-descriptor");
+    let ignore_list = schema_matches.get_one::<String>("ignore").unwrap_or(&"".to_string()).clone();
     let dry_run = *matches.get_one::<bool>("dry_run").unwrap_or(&false);
     let stat = *matches.get_one::<bool>("stat").unwrap_or(&false);
     let limit = *matches.get_one::<usize>("limit").unwrap_or(&1000);
     // Read the .proto file
-    let proto_path = matches.get_one::<String>("proto").unwrap().clone();
-    let proto_dir = matches.get_one::<String>("include").unwrap().clone();
+    let proto_path = schema_matches.get_one::<String>("proto").cloned().unwrap_or_default();
+    let proto_dir = schema_matches.get_one::<String>("include").cloned().unwrap_or_default();
+    let descriptor_set = schema_matches.get_one::<String>("descriptor_set").cloned();
+    let proto_root = schema_matches.get_one::<String>("proto_root").cloned();
 
-    let script_dir = matches
+    let script_dir = schema_matches
         .get_one::<String>("luascript")
         .unwrap_or(&"".to_string())
         .clone();
 
+    let assert_output_shape = *matches.get_one::<bool>("assert_output_shape").unwrap_or(&false);
+    let min_key_overlap = *matches.get_one::<f64>("min_key_overlap").unwrap_or(&0.5);
+    if !(0.0..=1.0).contains(&min_key_overlap) {
+        return Err(format!(
+            "--min-key-overlap must be between 0.0 and 1.0, got {}",
+            min_key_overlap
+        ));
+    }
+
+    // Offline input is given as a kind followed by the file path
+    let input_dump = match matches.get_many::<String>("input") {
+        Some(values) => {
+            let values: Vec<&String> = values.collect();
+            if values[0] != "couchdump" {
+                return Err(format!(
+                    "Unsupported input kind {:?}, expected couchdump",
+                    values[0]
+                ));
+            }
+            Some(values[1].clone())
+        }
+        None => None,
+    };
+    let output_path = matches.get_one::<String>("output").cloned();
+    if input_dump.is_some() && output_path.is_none() {
+        return Err("--output is required when processing a couchdump input".to_string());
+    }
+
+    let fetch_rate = matches.get_one::<f64>("fetch_rate").copied();
+    if fetch_rate.is_some_and(|rate| rate <= 0.0 || !rate.is_finite()) {
+        return Err(format!(
+            "--fetch-rate must be a positive number of pages per second, got {}",
+            fetch_rate.unwrap_or_default()
+        ));
+    }
+
+    let selector = match (
+        matches.get_one::<String>("selector"),
+        matches.get_one::<String>("selector_file"),
+    ) {
+        (Some(selector), _) => Some(fetch::parse_selector(selector)?),
+        (None, Some(path)) => {
+            let selector = std::fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read --selector-file {} - {}", path, e))?;
+            Some(fetch::parse_selector(&selector)?)
+        }
+        (None, None) => None,
+    };
+
+    let shards = matches.get_one::<usize>("shards").copied();
+    if shards.is_some_and(|shards| !(2..=36).contains(&shards)) {
+        return Err(format!("--shards must be between 2 and 36, got {}", shards.unwrap_or_default()));
+    }
+
+    let sample_rate = matches.get_one::<f64>("sample_rate").copied();
+    if sample_rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+        return Err(format!(
+            "--sample-rate must be between 0.0 and 1.0, got {}",
+            sample_rate.unwrap_or_default()
+        ));
+    }
+
+    let wrong_type_ratio = matches.get_one::<f64>("wrong_type_ratio").copied();
+    if wrong_type_ratio.is_some_and(|ratio| ratio < 0.0 || !ratio.is_finite()) {
+        return Err(format!(
+            "--wrong-type-ratio must be a positive number, got {}",
+            wrong_type_ratio.unwrap_or_default()
+        ));
+    }
+
+    let lua_workers = matches.get_one::<usize>("lua_workers").copied();
+    if lua_workers == Some(0) {
+        return Err("--lua-workers must be at least 1, got 0".to_string());
+    }
+
+    let mut bulk_size = matches.get_one::<usize>("bulk_size").copied();
+    if bulk_size == Some(0) {
+        return Err("--bulk-size must be at least 1, got 0".to_string());
+    }
+    // --preserve-revs always writes through the _bulk_docs buffer
+    let preserve_revs = matches.get_flag("preserve_revs");
+    if preserve_revs && bulk_size.is_none() {
+        bulk_size = Some(PRESERVE_REVS_BULK_SIZE);
+    }
+
+    let max_concurrency = *matches.get_one::<usize>("max_concurrency").unwrap_or(&16);
+    if max_concurrency == 0 {
+        return Err("--max-concurrency must be at least 1, got 0".to_string());
+    }
+
+    let abort_if_invalid_rate = matches.get_one::<f64>("abort_if_invalid_rate").copied();
+    if abort_if_invalid_rate.is_some_and(|rate| !(0.0..=100.0).contains(&rate)) {
+        return Err(format!(
+            "--abort-if-invalid-rate must be a percentage between 0 and 100, got {}",
+            abort_if_invalid_rate.unwrap_or_default()
+        ));
+    }
+
+    let on_invalid = matches
+        .get_one::<String>("on_invalid")
+        .map(|policy| policy.parse())
+        .unwrap_or(Ok(OnInvalid::Skip))?;
+    let enum_case = matches
+        .get_one::<String>("enum_case")
+        .map(|case| case.parse())
+        .unwrap_or(Ok(EnumCase::AsIs))?;
+    let field_name_mode = matches
+        .get_one::<String>("field_name_mode")
+        .map(|mode| mode.parse())
+        .unwrap_or(Ok(FieldNameMode::Both))?;
+    let timestamp_format = matches
+        .get_one::<String>("timestamp_format")
+        .map(|format| format.parse())
+        .unwrap_or(Ok(TimestampFormat::Rfc3339))?;
+    let dump_format = dump_matches
+        .and_then(|dump_matches| dump_matches.get_one::<String>("dump_format"))
+        .map(|format| format.parse())
+        .unwrap_or(Ok(DumpFormat::Tree))?;
+    let ranges = matches
+        .get_many::<String>("range")
+        .map(|ranges| ranges.map(|range| range.parse()).collect())
+        .unwrap_or(Ok(Vec::new()))?;
+
+    let shadow_table = matches.get_one::<String>("shadow_table").cloned();
+    if shadow_table.is_some() && on_invalid == OnInvalid::Delete {
+        return Err("--on-invalid delete cannot be rehearsed with --shadow-table".to_string());
+    }
+
+    // Several tables are processed in turn, options naming a single output would be overwritten
+    if tables.len() > 1 {
+        if mode != Mode::Morph {
+            return Err("Only one --table can be given to a subcommand".to_string());
+        }
+        let single_table_options = [
+            ("--input", input_dump.is_some()),
+            ("--output", output_path.is_some()),
+            ("--shadow-table", shadow_table.is_some()),
+            ("--checkpoint", matches.contains_id("checkpoint")),
+            ("--summary-json", matches.contains_id("summary_json")),
+            ("--csv-out", matches.contains_id("csv_out")),
+            ("--report", matches.contains_id("report")),
+            ("--dump-invalid", matches.contains_id("dump_invalid")),
+            ("--dead-letter", matches.contains_id("dead_letter")),
+        ];
+        if let Some((option, _)) = single_table_options.iter().find(|(_, given)| *given) {
+            return Err(format!("{} cannot be used with several tables", option));
+        }
+    }
+
+    let mut timeouts = client::Timeouts::default();
+    if let Some(connect_timeout) = matches.get_one::<String>("connect_timeout") {
+        timeouts.connect = client::parse_timeout(connect_timeout).map_err(|e| format!("--connect-timeout: {}", e))?;
+    }
+    if let Some(request_timeout) = matches.get_one::<String>("request_timeout") {
+        timeouts.request = client::parse_timeout(request_timeout).map_err(|e| format!("--request-timeout: {}", e))?;
+    }
+
+    let format = matches
+        .get_one::<String>("format")
+        .map(|format| format.parse())
+        .unwrap_or(Ok(OutputFormat::Text))?;
+
     Ok(Args {
+        mode,
         db_url,
         table_name,
         ignore_list,
@@ -118,6 +930,159 @@ pub fn parse_args() -> Result<Args, String> {
         limit,
         proto_path,
         proto_dir,
-        script_dir: script_dir,
+        descriptor_set,
+        proto_root,
+        script_dir,
+        assert_output_shape,
+        min_key_overlap,
+        input_dump,
+        output_path,
+        on_invalid,
+        format,
+        seen_file: matches.get_one::<String>("seen_file").cloned(),
+        max_docs: matches.get_one::<usize>("max_docs").copied(),
+        timeout: matches.get_one::<u64>("timeout").copied(),
+        strict_strings: *matches.get_one::<bool>("strict_strings").unwrap_or(&false),
+        auto_coerce: *matches.get_one::<bool>("auto_coerce").unwrap_or(&false),
+        summary_json: matches.get_one::<String>("summary_json").cloned(),
+        only_fields: matches.get_one::<String>("only_fields").cloned().unwrap_or_default(),
+        unknown_enum_ok: *matches.get_one::<bool>("unknown_enum_ok").unwrap_or(&false),
+        dump_invalid: matches.get_one::<String>("dump_invalid").cloned(),
+        headers: matches
+            .get_many::<String>("header")
+            .map(|headers| headers.cloned().collect())
+            .unwrap_or_default(),
+        preserve_revs,
+        reset_lua_per_doc: *matches.get_one::<bool>("reset_lua_per_doc").unwrap_or(&false),
+        consistent_arrays: *matches.get_one::<bool>("consistent_arrays").unwrap_or(&false),
+        fetch_rate,
+        shadow_table,
+        skip_ids_file: matches.get_one::<String>("skip_ids_file").cloned(),
+        transform_valid_if_changed: *matches
+            .get_one::<bool>("transform_valid_if_changed")
+            .unwrap_or(&false),
+        max_doc_bytes: matches.get_one::<usize>("max_doc_bytes").copied(),
+        user_agent: matches.get_one::<String>("user_agent").cloned(),
+        ranges,
+        strict_resolution: matches.get_flag("strict_resolution"),
+        sample_rate,
+        seed: matches.get_one::<u64>("seed").copied(),
+        enum_case,
+        wrong_type_ratio,
+        verify_updates: matches.get_flag("verify_updates"),
+        unflatten: matches.get_flag("unflatten"),
+        field_mask: matches.get_one::<String>("field_mask").cloned(),
+        run_id_field: matches.get_one::<String>("run_id_field").cloned(),
+        run_id: matches.get_one::<String>("run_id").cloned(),
+        doc: matches
+            .subcommand_matches("try")
+            .and_then(|try_matches| try_matches.get_one::<String>("doc").cloned()),
+        exec_stats: matches.get_flag("exec_stats"),
+        abort_if_invalid_rate,
+        abort_warmup: *matches.get_one::<usize>("abort_warmup").unwrap_or(&100),
+        timestamp_format,
+        lua_workers,
+        require_nonempty_messages: matches.get_flag("require_nonempty_messages"),
+        dump_format,
+        dump_output: dump_matches.and_then(|dump_matches| dump_matches.get_one::<String>("dump_output").cloned()),
+        defer_conflicts: matches.get_flag("defer_conflicts"),
+        strict_integer_format: matches.get_flag("strict_integer_format"),
+        lua_libs: schema_matches
+            .get_many::<String>("lua_lib")
+            .map(|lua_libs| lua_libs.cloned().collect())
+            .unwrap_or_default(),
+        max_depth: *matches.get_one::<usize>("max_depth").unwrap_or(&DEFAULT_MAX_DEPTH),
+        csv_out: matches.get_one::<String>("csv_out").cloned(),
+        auto_message: schema_matches.get_flag("auto_message"),
+        id_range_start: matches.get_one::<String>("id_range_start").cloned(),
+        id_range_end: matches.get_one::<String>("id_range_end").cloned(),
+        bulk_size,
+        max_concurrency,
+        conflict_retries: *matches.get_one::<usize>("conflict_retries").unwrap_or(&0),
+        fill_defaults: matches.get_flag("fill_defaults"),
+        report_fixes: matches.get_flag("report_fixes"),
+        username: matches.get_one::<String>("username").cloned(),
+        password: matches.get_one::<String>("password").cloned(),
+        session_auth: matches.get_flag("session_auth"),
+        max_retries: *matches.get_one::<usize>("max_retries").unwrap_or(&3),
+        retry_base_ms: *matches.get_one::<u64>("retry_base_ms").unwrap_or(&500),
+        output_message: matches.get_one::<String>("output_message").cloned(),
+        selector,
+        checkpoint: matches.get_one::<String>("checkpoint").cloned(),
+        resume: matches.get_flag("resume"),
+        shards,
+        report: matches.get_one::<String>("report").cloned(),
+        tables,
+        sandbox: schema_matches.get_flag("sandbox"),
+        string_transform: schema_matches.get_flag("string_transform"),
+        no_progress: matches.get_flag("no_progress"),
+        timeouts,
+        strict_required: matches.get_flag("strict_required"),
+        field_name_mode,
+        show_diff: matches.get_flag("show_diff"),
+        dead_letter: matches.get_one::<String>("dead_letter").cloned(),
     })
 }
+
+/// Arguments describing the schema and the transform script.
+/// Shared by the main run and the subcommands.
+fn schema_args() -> [Arg; 11] {
+    [
+        Arg::new("table_name")
+            .short('t')
+            .long("table")
+            .value_name("TABLE")
+            .help("Name of the table (or document type), repeated or comma-separated to process several tables in turn")
+            .action(clap::ArgAction::Append)
+            .value_delimiter(',')
+            .required(true),
+        Arg::new("ignore")
+            .short('g')
+            .long("ignore")
+            .value_name("IGNORE")
+            .help("Comma-separated list of fields to ignore"),
+        Arg::new("proto")
+            .short('p')
+            .long("proto")
+            .value_name("FILE")
+            .help("Path to the .proto file")
+            .required_unless_present("descriptor_set"),
+        Arg::new("include")
+            .short('i')
+            .long("include")
+            .value_name("DIRECTORY")
+            .help("Path containing .proto file")
+            .required_unless_present("descriptor_set"),
+        Arg::new("descriptor_set")
+            .long("descriptor-set")
+            .value_name("PATH|URL")
+            .help("Binary FileDescriptorSet to validate against instead of --proto, from a local path or an http(s) URL")
+            .conflicts_with_all(["proto", "include"]),
+        Arg::new("proto_root")
+            .long("proto-root")
+            .value_name("DIRECTORY")
+            .help("Additional include path resolving imports written relative to a repository root (Example: import \"org/schema/common.proto\")")
+            .requires("proto"),
+        Arg::new("luascript")
+            .short('s')
+            .long("script")
+            .help("Path to script that transform JSON document"),
+        Arg::new("lua_lib")
+            .long("lua-lib")
+            .value_name("DIRECTORY")
+            .action(clap::ArgAction::Append)
+            .help("Folder of shared Lua helpers loaded before the script's include folder, can be repeated"),
+        Arg::new("auto_message")
+            .long("auto-message")
+            .help("When the table name matches no message but the proto defines a single message, validate against that message")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("sandbox")
+            .long("sandbox")
+            .help("Run untrusted Lua scripts without the io, package and debug libraries, file loading and the os functions beyond the clock")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("string_transform")
+            .long("string-transform")
+            .help("Hand documents to transform, enrich and approve as JSON text and expect JSON text back, for scripts written before the table contract")
+            .action(clap::ArgAction::SetTrue),
+    ]
+}
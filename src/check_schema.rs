@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use protobuf::descriptor::{
+    field_descriptor_proto::Type, DescriptorProto, EnumDescriptorProto, FileDescriptorSet,
+};
+
+use crate::valid_proto::{self, DURATION_TYPE, TIMESTAMP_TYPE};
+
+/// A schema construct the validation silently skips or cannot check as intended.
+#[derive(Debug, PartialEq)]
+pub enum Pitfall {
+    UnknownMessage(String),               // Table name matching no message, every document fails
+    MissingJsonName(String),              // Field without a JSON name, never validated
+    UnresolvedType(String, String),       // Field and referenced type validation cannot find
+    UnreachableMessage(String),           // Message not used by the table message
+    UnrecognizedMapEntry(String, String), // Map field whose entry message is not handled as a map
+}
+
+impl std::fmt::Display for Pitfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Pitfall::UnknownMessage(table_name) => {
+                write!(f, "no message named {:?}, every document will be reported as invalid", table_name)
+            }
+            Pitfall::MissingJsonName(field) => {
+                write!(f, "{} has no json_name, the field is never validated", field)
+            }
+            Pitfall::UnresolvedType(field, type_name) => write!(
+                f,
+                "{} refers to {}, which is not resolved: its content is not validated (UnresolvedType with --strict-resolution)",
+                field, type_name
+            ),
+            Pitfall::UnreachableMessage(message) => {
+                write!(f, "message {} is not used by the table message, it is never validated", message)
+            }
+            Pitfall::UnrecognizedMapEntry(field, type_name) => write!(
+                f,
+                "{} is a map of {}, which is not found as a map entry: JSON objects will be reported as WrongDataType",
+                field, type_name
+            ),
+        }
+    }
+}
+
+/// Lists the pitfalls of the schema, checking the messages validation can look up. Without
+/// a table name, reachability from the table message is not checked.
+pub fn check_schema(file_descriptor_set: &FileDescriptorSet, table_name: Option<&str>) -> Vec<Pitfall> {
+    let message_types = valid_proto::build_message_types(file_descriptor_set);
+    let enum_types = valid_proto::build_enum_types(file_descriptor_set);
+    let mut pitfalls = Vec::new();
+
+    // Messages in declaration order, for a stable report
+    let messages: Vec<&DescriptorProto> = file_descriptor_set
+        .file
+        .iter()
+        .flat_map(|file| &file.message_type)
+        .collect();
+
+    for message in &messages {
+        check_fields(message, message.name(), &message_types, &enum_types, &mut pitfalls);
+    }
+
+    if let Some(table_name) = table_name {
+        let Some(table_message) = message_types.get(&table_name.to_lowercase()) else {
+            pitfalls.insert(0, Pitfall::UnknownMessage(table_name.to_string()));
+            return pitfalls;
+        };
+
+        // Messages reached from the table message through its message fields
+        let mut reached = HashSet::from([valid_proto::table_key(file_descriptor_set, table_name)]);
+        let mut queue = VecDeque::from([table_message]);
+        while let Some(message) = queue.pop_front() {
+            for field in &message.field {
+                let type_name = field.type_name().trim_start_matches('.').to_lowercase();
+                if let Some(nested) = message_types.get(&type_name) {
+                    // Map entries are looked up by their dotted name
+                    if reached.insert(type_name) {
+                        queue.push_back(nested);
+                    }
+                }
+            }
+        }
+        // Well-known types imported along the schema are not expected to be used
+        let own_messages = file_descriptor_set
+            .file
+            .iter()
+            .filter(|file| file.package() != "google.protobuf")
+            .flat_map(|file| file.message_type.iter().map(|message| (file.package(), message)));
+        for (package, message) in own_messages {
+            if !reached.contains(&valid_proto::qualified_key(package, message.name())) {
+                pitfalls.push(Pitfall::UnreachableMessage(message.name().to_string()));
+            }
+        }
+    }
+    pitfalls
+}
+
+/// Checks the fields of a message and of the map entries it declares.
+fn check_fields(
+    message: &DescriptorProto,
+    path: &str, // Dotted name of the message, e.g. `Inventory.StockEntry`
+    message_types: &HashMap<String, DescriptorProto>,
+    enum_types: &HashMap<String, EnumDescriptorProto>,
+    pitfalls: &mut Vec<Pitfall>,
+) {
+    for field in &message.field {
+        let field_path = format!("{}.{}", path, field.name());
+        if valid_proto::field_json_name(field).is_none() {
+            pitfalls.push(Pitfall::MissingJsonName(field_path.clone()));
+        }
+
+        let type_name = field.type_name();
+        let key = type_name.trim_start_matches('.').to_lowercase();
+        let resolved = match field.type_() {
+            Type::TYPE_MESSAGE => {
+                type_name == TIMESTAMP_TYPE || type_name == DURATION_TYPE || message_types.contains_key(&key)
+            }
+            Type::TYPE_ENUM => enum_types.contains_key(&key),
+            _ => true,
+        };
+        if resolved {
+            continue;
+        }
+
+        // A nested map entry the lookup does not know is still declared in the schema
+        let map_entry = message
+            .nested_type
+            .iter()
+            .any(|nested| nested.options.map_entry() && type_name.ends_with(&format!(".{}", nested.name())));
+        if map_entry {
+            pitfalls.push(Pitfall::UnrecognizedMapEntry(field_path, type_name.to_string()));
+        } else {
+            pitfalls.push(Pitfall::UnresolvedType(field_path, type_name.to_string()));
+        }
+    }
+
+    for nested in &message.nested_type {
+        if nested.options.map_entry() {
+            check_fields(nested, &format!("{}.{}", path, nested.name()), message_types, enum_types, pitfalls);
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{descriptor::FieldDescriptorProto, EnumOrUnknown};
+
+    fn field(name: &str, type_: Type, type_name: Option<&str>) -> FieldDescriptorProto {
+        let mut field = FieldDescriptorProto::new();
+        field.name = Some(name.to_string());
+        field.json_name = Some(name.to_string());
+        field.type_ = Some(EnumOrUnknown::new(type_));
+        field.type_name = type_name.map(str::to_string);
+        field
+    }
+
+    #[test]
+    fn test_pitfalls_are_reported() {
+        // Order { Money total; Item item; string note (no json_name); }  Item {}  Audit {}
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        order.field.push(field("total", Type::TYPE_MESSAGE, Some(".billing.Money")));
+        order.field.push(field("item", Type::TYPE_MESSAGE, Some(".Item")));
+        let mut note = field("note", Type::TYPE_STRING, None);
+        note.json_name = None;
+        order.field.push(note);
+        let mut item = DescriptorProto::new();
+        item.name = Some("Item".to_string());
+        let mut audit = DescriptorProto::new();
+        audit.name = Some("Audit".to_string());
+
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.message_type.push(order);
+        file.message_type.push(item);
+        file.message_type.push(audit);
+        file_set.file.push(file);
+
+        assert_eq!(
+            check_schema(&file_set, Some("Order")),
+            vec![
+                Pitfall::UnresolvedType("Order.total".to_string(), ".billing.Money".to_string()),
+                Pitfall::MissingJsonName("Order.note".to_string()),
+                Pitfall::UnreachableMessage("Audit".to_string()),
+            ]
+        );
+        assert_eq!(
+            check_schema(&file_set, Some("Invoice"))[0],
+            Pitfall::UnknownMessage("Invoice".to_string())
+        );
+        assert_eq!(check_schema(&file_set, None).len(), 2);
+        assert_eq!(
+            Pitfall::UnresolvedType("Order.total".to_string(), ".billing.Money".to_string()).to_string(),
+            "Order.total refers to .billing.Money, which is not resolved: its content is not validated (UnresolvedType with --strict-resolution)"
+        );
+    }
+}
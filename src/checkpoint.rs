@@ -0,0 +1,64 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+/// Where an interrupted scan resumes: the bookmark of the next page to fetch, with the
+/// counts of the pages already processed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub bookmark: Option<String>, // Bookmark of the next page, None before the first page
+    pub processed: usize,         // Documents passed to the pipeline so far
+    pub updated: usize,           // Repaired documents written so far
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint saved by an earlier run.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read checkpoint {:?} - {}", path, e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Invalid checkpoint {:?} - {}", path, e))
+    }
+
+    /// Writes the checkpoint to a temporary file renamed over the previous one, so a crash
+    /// leaves either the old or the new checkpoint, never a truncated one.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", path);
+        let content = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(&tmp_path, content)
+            .map_err(|e| format!("Unable to write checkpoint {:?} - {}", tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| format!("Unable to write checkpoint {:?} - {}", path, e))
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let path = std::env::temp_dir().join(format!("bulkmorph-checkpoint-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let checkpoint = Checkpoint {
+            bookmark: Some("g1AAAABweJzLYWBgYMpgSmHgKy5JLCrJTq2MT8lPzkzJBYqzG5gYWliaGIDkOWDyOYlAJUD1AHSPDb8".to_string()),
+            processed: 2000,
+            updated: 37,
+        };
+        checkpoint.save(path).unwrap();
+        assert_eq!(Checkpoint::load(path).unwrap(), checkpoint);
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+
+        // A later page replaces the previous checkpoint
+        let next = Checkpoint {
+            bookmark: Some("page-3".to_string()),
+            processed: 3000,
+            updated: 41,
+        };
+        next.save(path).unwrap();
+        assert_eq!(Checkpoint::load(path).unwrap(), next);
+
+        fs::remove_file(path).unwrap();
+        assert!(Checkpoint::load(path).is_err());
+    }
+}
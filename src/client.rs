@@ -0,0 +1,306 @@
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER, SET_COOKIE},
+    Client, RequestBuilder, Response, StatusCode,
+};
+use serde_json::json;
+
+/// Identifies bulkmorph traffic in CouchDB access logs unless overridden.
+pub const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Attempts made for a request throttled with 429 Too Many Requests before giving up.
+const MAX_THROTTLED_ATTEMPTS: usize = 5;
+
+/// Wait before retrying a throttled request that has no usable Retry-After header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Limits of every CouchDB request, so a hung connection fails instead of stalling the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub connect: Duration, // Establishing the connection
+    pub request: Duration, // Whole request, from sending it to the end of the response body
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            request: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Parses a timeout given in seconds (`30` or `30s`) or in milliseconds (`500ms`).
+pub fn parse_timeout(text: &str) -> Result<Duration, String> {
+    let (number, millis) = match text.trim() {
+        text if text.ends_with("ms") => (&text[..text.len() - 2], true),
+        text => (text.trim_end_matches('s'), false),
+    };
+    let value: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid timeout {:?}, expected seconds (30, 30s) or milliseconds (500ms)", text))?;
+    if value == 0 {
+        return Err(format!("invalid timeout {:?}, it must be greater than zero", text));
+    }
+    Ok(if millis { Duration::from_millis(value) } else { Duration::from_secs(value) })
+}
+
+/// Builds the HTTP client shared by every CouchDB request of the run.
+/// Each header is given as `Name: Value` and sent with every request.
+pub fn build_client(headers: &[String], user_agent: Option<&str>, timeouts: Timeouts) -> Result<Client, String> {
+    let mut default_headers = HeaderMap::new();
+    for header in headers {
+        let (name, value) = parse_header(header)?;
+        default_headers.append(name, value);
+    }
+
+    Client::builder()
+        .user_agent(user_agent.unwrap_or(DEFAULT_USER_AGENT))
+        .default_headers(default_headers)
+        .connect_timeout(timeouts.connect)
+        .timeout(timeouts.request)
+        .build()
+        .map_err(|e| format!("Unable to create HTTP client - {}", e))
+}
+
+/// Builds the client fetching `--descriptor-set` URLs. A schema registry is another host than
+/// CouchDB: it gets neither the `--header` values nor the credentials of the CouchDB client.
+pub fn build_schema_client(user_agent: Option<&str>, timeouts: Timeouts) -> Result<Client, String> {
+    build_client(&[], user_agent, timeouts)
+}
+
+/// `Authorization` header sending the credentials with HTTP basic auth.
+pub fn basic_auth_header(username: &str, password: &str) -> String {
+    let encoded = STANDARD.encode(format!("{}:{}", username, password));
+    format!("Authorization: Basic {}", encoded)
+}
+
+/// Logs in through CouchDB's `_session` endpoint and returns the `Cookie` header carrying
+/// the `AuthSession` cookie, so the credentials are sent only once.
+pub async fn session_cookie_header(
+    client: &Client,
+    db_host: &str,
+    username: &str,
+    password: &str,
+) -> Result<String, String> {
+    let url = format!("{}/_session", db_host);
+    let response = send_with_retry(client.post(&url).json(&json!({"name": username, "password": password})))
+        .await
+        .map_err(|e| format!("Unable to open a CouchDB session - {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Unable to open a CouchDB session - {}", response.status()));
+    }
+    response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find_map(auth_session_cookie)
+        .map(|cookie| format!("Cookie: {}", cookie))
+        .ok_or_else(|| "Unable to open a CouchDB session - no AuthSession cookie in the response".to_string())
+}
+
+/// `AuthSession=<token>` from a `Set-Cookie` value, without its attributes.
+fn auth_session_cookie(set_cookie: &str) -> Option<&str> {
+    let cookie = set_cookie.split(';').next()?.trim();
+    cookie.starts_with("AuthSession=").then_some(cookie)
+}
+
+/// Sends a CouchDB request, sending it again while the server answers 429 Too Many Requests
+/// after waiting as long as its Retry-After header asks. The last throttled response is
+/// returned once the attempts are exhausted.
+pub async fn send_with_retry(request: RequestBuilder) -> Result<Response, String> {
+    let mut request = request;
+    let mut attempt = 1;
+    loop {
+        // JSON and string bodies can be cloned, streamed bodies are sent once
+        let retry = request.try_clone();
+        let response = request.send().await.map_err(describe_error)?;
+        match retry {
+            Some(retry) if response.status() == StatusCode::TOO_MANY_REQUESTS && attempt < MAX_THROTTLED_ATTEMPTS => {
+                tokio::time::sleep(retry_after(&response, Utc::now())).await;
+                request = retry;
+                attempt += 1;
+            }
+            _ => return Ok(response),
+        }
+    }
+}
+
+/// Message of a request that got no response, naming the options to raise when it timed out.
+fn describe_error(e: reqwest::Error) -> String {
+    if e.is_timeout() {
+        format!("{} (timed out, see --connect-timeout and --request-timeout)", e)
+    } else {
+        e.to_string()
+    }
+}
+
+/// Wait asked by the Retry-After header, given either in seconds or as an HTTP date.
+fn retry_after(response: &Response, now: DateTime<Utc>) -> Duration {
+    let Some(value) = response.headers().get(RETRY_AFTER).and_then(|value| value.to_str().ok()) else {
+        return DEFAULT_RETRY_AFTER;
+    };
+    parse_retry_after(value, now).unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    // A date already passed means the request can be sent again right away
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Parses a `Name: Value` header.
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header {:?}, expected \"Name: Value\"", header))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("Invalid header name in {:?} - {}", header, e))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|e| format!("Invalid header value in {:?} - {}", header, e))?;
+    Ok((name, value))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{any, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[test]
+    fn test_header_syntax() {
+        let (name, value) = parse_header("X-Api-Key:  secret ").unwrap();
+        assert_eq!(name, "x-api-key");
+        assert_eq!(value, "secret");
+
+        assert!(parse_header("X-Api-Key secret").is_err());
+        assert!(parse_header("Bad Name: value").is_err());
+        assert!(parse_header("X-Tenant: line\nbreak").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_custom_headers_on_outgoing_requests() {
+        let server = MockServer::start().await;
+        Mock::given(path("/orders"))
+            .and(header("x-api-key", "secret"))
+            .and(header("x-tenant-id", "acme"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let headers = ["X-Api-Key: secret".to_string(), "X-Tenant-Id: acme".to_string()];
+        let client = build_client(&headers, None, Timeouts::default()).unwrap();
+        let response = client.get(format!("{}/orders", server.uri())).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_credentials_headers() {
+        assert_eq!(
+            basic_auth_header("admin", "secret"),
+            "Authorization: Basic YWRtaW46c2VjcmV0"
+        );
+        assert_eq!(
+            auth_session_cookie("AuthSession=YWRtaW46NjdBQjE; Version=1; Path=/; HttpOnly"),
+            Some("AuthSession=YWRtaW46NjdBQjE")
+        );
+        assert_eq!(auth_session_cookie("Other=1; Path=/"), None);
+    }
+
+    #[test]
+    fn test_timeout_syntax() {
+        assert_eq!(parse_timeout("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_timeout("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_timeout("500ms"), Ok(Duration::from_millis(500)));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("1m").is_err());
+        assert!(parse_timeout("fast").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slow_response_times_out() {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&server)
+            .await;
+
+        let timeouts = Timeouts {
+            request: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let client = build_client(&[], None, timeouts).unwrap();
+        let err = send_with_retry(client.get(format!("{}/orders", server.uri()))).await.unwrap_err();
+
+        assert!(err.contains("timed out, see --connect-timeout and --request-timeout"), "{}", err);
+    }
+
+    #[test]
+    fn test_retry_after_syntax() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2026 07:28:00 GMT").unwrap().with_timezone(&Utc);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2026 07:28:30 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2026 07:27:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_request_is_retried_after_the_wait() {
+        // 429 with Retry-After: 1 on the first request, then success
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let client = build_client(&[], None, Timeouts::default()).unwrap();
+        let started = std::time::Instant::now();
+        let response = send_with_retry(client.post(format!("{}/orders/_find", server.uri())).body("{}"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].body, b"{}");
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_default_and_override() {
+        let server = MockServer::start().await;
+        Mock::given(header("user-agent", concat!("bulkmorph/", env!("CARGO_PKG_VERSION"))))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(header("user-agent", "orders-migration/2"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = build_client(&[], None, Timeouts::default()).unwrap();
+        client.get(server.uri()).send().await.unwrap();
+        let client = build_client(&[], Some("orders-migration/2"), Timeouts::default()).unwrap();
+        client.get(server.uri()).send().await.unwrap();
+    }
+}
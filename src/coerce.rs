@@ -0,0 +1,416 @@
+use protobuf::descriptor::{
+    field_descriptor_proto::{Label, Type},
+    FileDescriptorSet,
+};
+use serde_json::{Map, Value};
+
+use crate::valid_proto::{self, ErrorType, ValidationError};
+
+/// How an automatic fix changed a field, from the most to the least predictable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixKind {
+    DefaultFill, // Missing scalar field set to its proto default (--fill-defaults)
+    Coercion,    // Value converted to the field type (--auto-coerce)
+    Transform,   // Changed by the Lua transform
+}
+
+impl FixKind {
+    /// Confidence that the fix is what the data meant, to focus the review on the others.
+    pub fn confidence(&self) -> &'static str {
+        match self {
+            FixKind::DefaultFill => "high",
+            FixKind::Coercion => "medium",
+            FixKind::Transform => "low",
+        }
+    }
+}
+
+/// A field changed by an automatic fix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    pub field: String, // Error path, or top-level key for the transform
+    pub kind: FixKind,
+}
+
+impl std::fmt::Display for Fix {
+    /// `quantity (coercion, medium confidence)`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self.kind {
+            FixKind::DefaultFill => "default",
+            FixKind::Coercion => "coercion",
+            FixKind::Transform => "transform",
+        };
+        write!(f, "{} ({}, {} confidence)", self.field, kind, self.kind.confidence())
+    }
+}
+
+/// Attempts safe type coercions for WrongDataType errors on single (non-repeated) fields:
+/// - number -> string for string fields
+/// - numeric string or bool -> number for numeric fields
+///
+/// Returns the coerced document and the coerced fields, or None when no error could be coerced.
+pub fn auto_coerce(
+    file_descriptor_set: &FileDescriptorSet,
+    table_name: &str,
+    doc: &Value,
+    errors: &[ValidationError],
+) -> Option<(Value, Vec<Fix>)> {
+    let mut coerced = doc.clone();
+    let mut fixes = Vec::new();
+
+    for error in errors
+        .iter()
+        .filter(|e| e.error_type == ErrorType::WrongDataType)
+    {
+        let Some(field) = valid_proto::resolve_field(file_descriptor_set, table_name, &error.field)
+        else {
+            continue;
+        };
+        if field.label() == Label::LABEL_REPEATED {
+            continue;
+        }
+
+        if let Some(value) = coerced.pointer_mut(&json_pointer(&error.field)) {
+            if let Some(new_value) = coerce_value(field.type_(), value) {
+                *value = new_value;
+                fixes.push(Fix {
+                    field: error.field.clone(),
+                    kind: FixKind::Coercion,
+                });
+            }
+        }
+    }
+
+    (!fixes.is_empty()).then_some((coerced, fixes))
+}
+
+/// Sets MissingField errors on single scalar fields to the proto default value: 0, "" or
+/// false. Returns the filled document and the filled fields, or None when nothing was filled.
+pub fn fill_defaults(
+    file_descriptor_set: &FileDescriptorSet,
+    table_name: &str,
+    doc: &Value,
+    errors: &[ValidationError],
+) -> Option<(Value, Vec<Fix>)> {
+    let mut filled = doc.clone();
+    let mut fixes = Vec::new();
+
+    for error in errors.iter().filter(|e| e.error_type == ErrorType::MissingField) {
+        let Some(field) = valid_proto::resolve_field(file_descriptor_set, table_name, &error.field)
+        else {
+            continue;
+        };
+        if field.label() == Label::LABEL_REPEATED {
+            continue;
+        }
+        let Some(default) = default_value(field.type_()) else {
+            continue;
+        };
+
+        // The parent object is present, only the key is missing
+        let (parent, key) = match error.field.rsplit_once('.') {
+            Some((parent, key)) => (json_pointer(parent), key),
+            None => (String::new(), error.field.as_str()),
+        };
+        if let Some(Value::Object(parent)) = filled.pointer_mut(&parent) {
+            parent.insert(key.to_string(), default);
+            fixes.push(Fix {
+                field: error.field.clone(),
+                kind: FixKind::DefaultFill,
+            });
+        }
+    }
+
+    (!fixes.is_empty()).then_some((filled, fixes))
+}
+
+/// Proto3 default of a scalar field type, None for messages, enums and bytes.
+fn default_value(field_type: Type) -> Option<Value> {
+    match field_type {
+        Type::TYPE_STRING => Some(Value::String(String::new())),
+        Type::TYPE_BOOL => Some(Value::Bool(false)),
+        Type::TYPE_INT32
+        | Type::TYPE_INT64
+        | Type::TYPE_UINT32
+        | Type::TYPE_UINT64
+        | Type::TYPE_SINT32
+        | Type::TYPE_SINT64
+        | Type::TYPE_FIXED32
+        | Type::TYPE_FIXED64
+        | Type::TYPE_SFIXED32
+        | Type::TYPE_SFIXED64 => Some(Value::from(0)),
+        Type::TYPE_FLOAT | Type::TYPE_DOUBLE => Some(Value::from(0.0)),
+        _ => None,
+    }
+}
+
+/// Top-level keys the transform added, changed or removed, except those already fixed
+/// before the transform.
+pub fn transform_fixes(doc: &Value, transformed_doc: &Value, earlier_fixes: &[Fix]) -> Vec<Fix> {
+    let empty = Map::new();
+    let before = doc.as_object().unwrap_or(&empty);
+    let after = transformed_doc.as_object().unwrap_or(&empty);
+
+    let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|key| before.get(*key) != after.get(*key))
+        .filter(|key| !earlier_fixes.iter().any(|fix| fix.field == **key))
+        .map(|key| Fix {
+            field: key.clone(),
+            kind: FixKind::Transform,
+        })
+        .collect()
+}
+
+/// Converts a value to the JSON type expected by a proto field, when it is lossless and
+/// the result fits the width of the field.
+fn coerce_value(field_type: Type, value: &Value) -> Option<Value> {
+    match (field_type, value) {
+        (Type::TYPE_STRING, Value::Number(n)) => Some(Value::String(n.to_string())),
+        (Type::TYPE_FLOAT | Type::TYPE_DOUBLE, Value::String(s)) => s
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|f| f.is_finite() && (field_type == Type::TYPE_DOUBLE || f.abs() <= f32::MAX as f64))
+            .map(Value::from),
+        (Type::TYPE_FLOAT | Type::TYPE_DOUBLE, Value::Bool(b)) => Some(Value::from(*b as i64)),
+        (_, Value::String(s)) => {
+            let (min, max) = integer_range(field_type)?;
+            let n = s.trim().parse::<i128>().ok().filter(|n| (min..=max).contains(n))?;
+            i64::try_from(n)
+                .map(Value::from)
+                .or_else(|_| u64::try_from(n).map(Value::from))
+                .ok()
+        }
+        (_, Value::Bool(b)) if integer_range(field_type).is_some() => Some(Value::from(*b as i64)),
+        _ => None,
+    }
+}
+
+/// Smallest and largest value of an integer field type, None for the other types.
+fn integer_range(field_type: Type) -> Option<(i128, i128)> {
+    match field_type {
+        Type::TYPE_INT32 | Type::TYPE_SINT32 | Type::TYPE_SFIXED32 => Some((i32::MIN.into(), i32::MAX.into())),
+        Type::TYPE_UINT32 | Type::TYPE_FIXED32 => Some((0, u32::MAX.into())),
+        Type::TYPE_INT64 | Type::TYPE_SINT64 | Type::TYPE_SFIXED64 => Some((i64::MIN.into(), i64::MAX.into())),
+        Type::TYPE_UINT64 | Type::TYPE_FIXED64 => Some((0, u64::MAX.into())),
+        _ => None,
+    }
+}
+
+/// Turns an error path such as `items[1].id` into the JSON pointer `/items/1/id`.
+fn json_pointer(path: &str) -> String {
+    let mut pointer = String::new();
+    for segment in path.split('.') {
+        let mut parts = segment.split('[');
+        if let Some(name) = parts.next() {
+            pointer.push('/');
+            pointer.push_str(&name.replace('~', "~0").replace('/', "~1"));
+        }
+        // Array indexes, or map keys such as `["key"]`
+        for index in parts {
+            pointer.push('/');
+            let index = index.trim_end_matches(']').trim_matches('"');
+            pointer.push_str(&index.replace('~', "~0").replace('/', "~1"));
+        }
+    }
+    pointer
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{
+        descriptor::{DescriptorProto, FieldDescriptorProto},
+        EnumOrUnknown,
+    };
+    use serde_json::json;
+
+    fn create_test_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        for (name, type_) in [
+            ("reference", Type::TYPE_STRING),
+            ("quantity", Type::TYPE_INT32),
+            ("price", Type::TYPE_FLOAT),
+            ("express", Type::TYPE_BOOL),
+        ] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            order.field.push(field);
+        }
+
+        file.message_type.push(order);
+        file_set.file.push(file);
+        file_set
+    }
+
+    fn coerce(doc: &Value) -> Option<Value> {
+        let file_set = create_test_descriptor();
+        let errors = valid_proto::validate_json(&file_set, "Order", doc, vec![]);
+        auto_coerce(&file_set, "Order", doc, &errors).map(|(coerced, _)| coerced)
+    }
+
+    #[test]
+    fn test_number_to_string_coercion() {
+        let doc = json!({"reference": 1042, "quantity": 1, "price": 2.5, "express": false});
+        let coerced = coerce(&doc).unwrap();
+
+        assert_eq!(coerced["reference"], json!("1042"));
+        let file_set = create_test_descriptor();
+        assert!(valid_proto::validate_json(&file_set, "Order", &coerced, vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_string_to_number_coercion() {
+        let doc = json!({"reference": "R-1", "quantity": " 3 ", "price": "2.5", "express": true});
+        let coerced = coerce(&doc).unwrap();
+
+        assert_eq!(coerced["quantity"], json!(3));
+        assert_eq!(coerced["price"], json!(2.5));
+
+        let doc = json!({"reference": "R-1", "quantity": true, "price": 1, "express": true});
+        assert_eq!(coerce(&doc).unwrap()["quantity"], json!(1));
+    }
+
+    /// Message with a field of every numeric type, named after its type.
+    fn create_numeric_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+
+        let mut numbers = DescriptorProto::new();
+        numbers.name = Some("Numbers".to_string());
+        for (name, type_) in NUMERIC_FIELDS {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            numbers.field.push(field);
+        }
+
+        file.message_type.push(numbers);
+        file_set.file.push(file);
+        file_set
+    }
+
+    const NUMERIC_FIELDS: [(&str, Type); 12] = [
+        ("int32", Type::TYPE_INT32),
+        ("int64", Type::TYPE_INT64),
+        ("uint32", Type::TYPE_UINT32),
+        ("uint64", Type::TYPE_UINT64),
+        ("sint32", Type::TYPE_SINT32),
+        ("sint64", Type::TYPE_SINT64),
+        ("fixed32", Type::TYPE_FIXED32),
+        ("fixed64", Type::TYPE_FIXED64),
+        ("sfixed32", Type::TYPE_SFIXED32),
+        ("sfixed64", Type::TYPE_SFIXED64),
+        ("float", Type::TYPE_FLOAT),
+        ("double", Type::TYPE_DOUBLE),
+    ];
+
+    /// Coerces a document of valid zeros where `field` is set to `value`.
+    fn coerce_number(field: &str, value: Value) -> Option<Value> {
+        let file_set = create_numeric_descriptor();
+        let mut doc: Value = NUMERIC_FIELDS.iter().map(|(name, _)| (name.to_string(), json!(0))).collect();
+        doc[field] = value;
+        let errors = valid_proto::validate_json(&file_set, "Numbers", &doc, vec![]);
+        let coerced = auto_coerce(&file_set, "Numbers", &doc, &errors).map(|(coerced, _)| coerced)?;
+        assert!(valid_proto::validate_json(&file_set, "Numbers", &coerced, vec![]).is_empty());
+        Some(coerced[field].clone())
+    }
+
+    #[test]
+    fn test_every_numeric_type_is_coerced() {
+        for (field, value, expected) in [
+            ("int32", json!("-7"), json!(-7)),
+            ("int64", json!(" 9007199254740993 "), json!(9007199254740993_i64)),
+            ("uint32", json!("4294967295"), json!(4294967295_u32)),
+            ("uint64", json!("18446744073709551615 "), json!(u64::MAX)),
+            ("sint32", json!(true), json!(1)),
+            ("sint64", json!(" -7"), json!(-7)),
+            ("fixed32", json!(" 7 "), json!(7)),
+            ("fixed64", json!(false), json!(0)),
+            ("sfixed32", json!("-2147483648"), json!(i32::MIN)),
+            ("sfixed64", json!("-9223372036854775808 "), json!(i64::MIN)),
+            ("float", json!("2.5"), json!(2.5)),
+            ("double", json!("1e300"), json!(1e300)),
+        ] {
+            assert_eq!(coerce_number(field, value), Some(expected), "{}", field);
+        }
+    }
+
+    #[test]
+    fn test_values_outside_the_field_width_are_left_alone() {
+        for (field, value) in [
+            ("int32", json!("9999999999")),
+            ("uint32", json!("-1")),
+            ("uint32", json!("4294967296")),
+            ("sint32", json!("2147483648")),
+            ("fixed32", json!("-7")),
+            ("fixed64", json!(" -1")),
+            ("sfixed32", json!("-2147483649")),
+            ("int64", json!(" 9223372036854775808")),
+            ("uint64", json!("18446744073709551616 ")),
+            ("float", json!("1e39")),
+            ("double", json!("1e999")),
+        ] {
+            assert_eq!(coerce_number(field, value.clone()), None, "{} {}", field, value);
+        }
+    }
+
+    #[test]
+    fn test_uncoercible_values_are_left_alone() {
+        // "three" is not a number and bool fields are never coerced
+        let doc = json!({"reference": "R-1", "quantity": "three", "price": 1, "express": "yes"});
+        assert_eq!(coerce(&doc), None);
+    }
+
+    #[test]
+    fn test_fixes_carry_their_confidence() {
+        let file_set = create_test_descriptor();
+        let doc = json!({"reference": 1042, "price": 2.5, "express": false});
+        let errors = valid_proto::validate_json(&file_set, "Order", &doc, vec![]);
+
+        let (filled, default_fixes) = fill_defaults(&file_set, "Order", &doc, &errors).unwrap();
+        assert_eq!(filled["quantity"], json!(0));
+        let (coerced, coerce_fixes) = auto_coerce(&file_set, "Order", &filled, &errors).unwrap();
+        assert!(valid_proto::validate_json(&file_set, "Order", &coerced, vec![]).is_empty());
+
+        let labels: Vec<String> = default_fixes.iter().chain(&coerce_fixes).map(Fix::to_string).collect();
+        assert_eq!(
+            labels,
+            vec![
+                "quantity (default, high confidence)",
+                "reference (coercion, medium confidence)",
+            ]
+        );
+
+        // The transform is only credited with the fields it changed itself
+        let mut transformed = coerced.clone();
+        transformed["reference"] = json!("R-1042");
+        transformed["price"] = json!(3.0);
+        let earlier_fixes: Vec<Fix> = default_fixes.into_iter().chain(coerce_fixes).collect();
+        assert_eq!(
+            transform_fixes(&doc, &transformed, &earlier_fixes),
+            vec![Fix {
+                field: "price".to_string(),
+                kind: FixKind::Transform,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_json_pointer_from_error_path() {
+        assert_eq!(json_pointer("items[1].id"), "/items/1/id");
+        assert_eq!(json_pointer("a/b"), "/a~1b");
+        assert_eq!(json_pointer("stock[\"pear\"].count"), "/stock/pear/count");
+    }
+}
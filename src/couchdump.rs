@@ -0,0 +1,76 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use serde_json::Value;
+
+/// Reads a JSONL dump of a CouchDB database (one document per line) and applies the
+/// callback to each document. Design documents (`_design/...`) and blank lines are skipped.
+/// Records of --dead-letter and --dump-invalid, `{"doc": ..., "errors": [...]}`, are read
+/// as their document, so the failures of a run can be processed again.
+/// Returns the number of documents passed to the callback.
+pub fn read_dump(path: &str, callback: impl Fn(Value)) -> Result<usize, String> {
+    let file =
+        File::open(path).map_err(|e| format!("Unable to open dump file {:?} - {}", path, e))?;
+
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut doc: Value = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid JSON on line {} of {:?} - {}", index + 1, path, e))?;
+        if doc.get("_id").is_none() && doc["doc"].is_object() && doc["errors"].is_array() {
+            doc = doc["doc"].take();
+        }
+
+        // Design documents hold views and validation functions, not data
+        if doc["_id"]
+            .as_str()
+            .is_some_and(|id| id.starts_with("_design/"))
+        {
+            continue;
+        }
+
+        callback(doc);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_read_dump_skips_design_documents() {
+        let path = std::env::temp_dir().join(format!("bulkmorph-dump-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            concat!(
+                "{\"_id\":\"_design/orders\",\"_rev\":\"1-a\",\"views\":{}}\n",
+                "{\"_id\":\"order-1\",\"_rev\":\"1-b\",\"amount\":10}\n",
+                "\n",
+                "{\"_id\":\"order-2\",\"_rev\":\"2-c\",\"amount\":\"12\"}\n",
+                "{\"doc\":{\"_id\":\"order-3\",\"_rev\":\"1-d\"},\"errors\":[]}\n",
+            ),
+        )
+        .unwrap();
+
+        let seen = RefCell::new(Vec::new());
+        let count = read_dump(path.to_str().unwrap(), |doc| {
+            seen.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(seen.into_inner(), vec!["order-1", "order-2", "order-3"]);
+    }
+}
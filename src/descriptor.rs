@@ -0,0 +1,180 @@
+use std::{fs, path::Path};
+
+use protobuf::{descriptor::FileDescriptorSet, Message};
+use protobuf_parse::Parser;
+use reqwest::{Client, StatusCode};
+
+/// Parses a `.proto` file, resolving imports from the include directory and, when given,
+/// from the proto root, for imports written relative to a repository root.
+pub fn parse_proto(
+    proto_path: &str,
+    proto_dir: &str,
+    proto_root: Option<&str>,
+) -> Result<FileDescriptorSet, String> {
+    let mut includes = vec![proto_dir];
+    if let Some(proto_root) = proto_root {
+        if !Path::new(proto_root).is_dir() {
+            return Err(format!("Proto root {:?} is not a directory", proto_root));
+        }
+        includes.push(proto_root);
+    }
+
+    Parser::new()
+        .pure()
+        .inputs([proto_path])
+        .includes(includes)
+        .file_descriptor_set()
+        .map_err(|e| format!("Unable to parse proto {:?} - {}", proto_path, e))
+}
+
+/// Loads a binary FileDescriptorSet (as written by `protoc --descriptor_set_out`)
+/// from a local path or from an http/https URL, e.g. a schema registry.
+pub async fn load_descriptor_set(source: &str, client: &Client) -> Result<FileDescriptorSet, String> {
+    let bytes = if source.starts_with("http://") || source.starts_with("https://") {
+        let response = client
+            .get(source)
+            .send()
+            .await
+            .map_err(|e| format!("Unable to fetch descriptor set {:?} - {}", source, e))?;
+        if response.status() != StatusCode::OK {
+            return Err(format!(
+                "Failed to fetch descriptor set {:?}: Status code {}",
+                source,
+                response.status()
+            ));
+        }
+        response
+            .bytes()
+            .await
+            .map_err(|e| format!("Unable to read descriptor set {:?} - {}", source, e))?
+            .to_vec()
+    } else {
+        fs::read(source).map_err(|e| format!("Unable to read descriptor set {:?} - {}", source, e))?
+    };
+
+    FileDescriptorSet::parse_from_bytes(&bytes)
+        .map_err(|e| format!("Invalid descriptor set {:?} - {}", source, e))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client,
+        valid_proto::{self, ErrorType},
+    };
+    use protobuf::{
+        descriptor::{field_descriptor_proto::Type, DescriptorProto, FieldDescriptorProto},
+        EnumOrUnknown,
+    };
+    use serde_json::json;
+    use wiremock::{
+        matchers::{any, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn create_test_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.name = Some("Order.proto".to_string());
+
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        let mut field = FieldDescriptorProto::new();
+        field.name = Some("quantity".to_string());
+        field.json_name = Some("quantity".to_string());
+        field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        order.field.push(field);
+
+        file.message_type.push(order);
+        file_set.file.push(file);
+        file_set
+    }
+
+    #[test]
+    fn test_proto_root_resolves_prefixed_imports() {
+        let base = std::env::temp_dir().join(format!("bulkmorph-proto-root-{}", std::process::id()));
+        let root = base.join("repo");
+        let include = base.join("protos");
+        fs::create_dir_all(root.join("org/schema")).unwrap();
+        fs::create_dir_all(&include).unwrap();
+        fs::write(
+            root.join("org/schema/common.proto"),
+            "syntax = \"proto3\";\nmessage Money { int32 cents = 1; }\n",
+        )
+        .unwrap();
+        let order = include.join("order.proto");
+        fs::write(
+            &order,
+            "syntax = \"proto3\";\nimport \"org/schema/common.proto\";\nmessage Order { Money total = 1; }\n",
+        )
+        .unwrap();
+        let (order, include, root) = (order.to_str().unwrap(), include.to_str().unwrap(), root.to_str().unwrap());
+
+        // The import only resolves through the root
+        assert!(parse_proto(order, include, None).is_err());
+        let file_set = parse_proto(order, include, Some(root)).unwrap();
+        assert_eq!(file_set.file[0].dependency, vec!["org/schema/common.proto"]);
+        let total = &file_set.file[0].message_type[0].field[0];
+        assert_eq!(total.type_name(), ".Money");
+
+        assert!(parse_proto(order, include, Some(&format!("{}/missing", root))).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_descriptor_set_served_over_http() {
+        let bytes = create_test_descriptor().write_to_bytes().unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/descriptors/order.pb"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(bytes))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let url = format!("{}/descriptors/order.pb", server.uri());
+
+        let file_set = load_descriptor_set(&url, &Client::new()).await.unwrap();
+
+        let valid = json!({"quantity": 3});
+        assert!(valid_proto::validate_json(&file_set, "Order", &valid, vec![]).is_empty());
+
+        let invalid = json!({"quantity": "three"});
+        let errors = valid_proto::validate_json(&file_set, "Order", &invalid, vec![]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error_type, ErrorType::WrongDataType);
+    }
+
+    #[tokio::test]
+    async fn test_descriptor_request_carries_no_credentials() {
+        let bytes = create_test_descriptor().write_to_bytes().unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(bytes))
+            .mount(&server)
+            .await;
+
+        let schema_client = client::build_schema_client(None, client::Timeouts::default()).unwrap();
+        load_descriptor_set(&server.uri(), &schema_client).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].headers.get("authorization").is_none());
+        assert!(requests[0].headers.get("cookie").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_descriptor_set_is_an_error() {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("not a descriptor"))
+            .mount(&server)
+            .await;
+        assert!(load_descriptor_set(&server.uri(), &Client::new()).await.is_err());
+
+        let server = MockServer::start().await;
+        assert!(load_descriptor_set(&server.uri(), &Client::new()).await.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::Value;
+
+/// One difference between the original and the repaired document, at a path such as
+/// `customer.address.city` or `lines[2].quantity`.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added(String, Value),          // Key or array element only in the repaired document
+    Removed(String, Value),        // Key or array element only in the original document
+    Changed(String, Value, Value), // Value replaced, from the original to the repaired one
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Change::Added(path, value) => write!(f, "+ {}: {}", path, value),
+            Change::Removed(path, value) => write!(f, "- {}: {}", path, value),
+            Change::Changed(path, before, after) => write!(f, "~ {}: {} -> {}", path, before, after),
+        }
+    }
+}
+
+/// Lists the field-level differences between two documents. Objects are compared key by
+/// key and arrays element by element; any other difference, including a change of JSON
+/// type, is reported as a whole value change.
+pub fn diff(before: &Value, after: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_at("", before, after, &mut changes);
+    changes
+}
+
+fn diff_at(path: &str, before: &Value, after: &Value, changes: &mut Vec<Change>) {
+    match (before, after) {
+        (Value::Object(before_obj), Value::Object(after_obj)) => {
+            let mut keys: Vec<&String> = before_obj.keys().chain(after_obj.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let key_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match (before_obj.get(key), after_obj.get(key)) {
+                    (Some(before), Some(after)) => diff_at(&key_path, before, after, changes),
+                    (Some(before), None) => changes.push(Change::Removed(key_path, before.clone())),
+                    (None, Some(after)) => changes.push(Change::Added(key_path, after.clone())),
+                    (None, None) => (),
+                }
+            }
+        }
+        (Value::Array(before_arr), Value::Array(after_arr)) => {
+            for index in 0..before_arr.len().max(after_arr.len()) {
+                let item_path = format!("{}[{}]", path, index);
+                match (before_arr.get(index), after_arr.get(index)) {
+                    (Some(before), Some(after)) => diff_at(&item_path, before, after, changes),
+                    (Some(before), None) => changes.push(Change::Removed(item_path, before.clone())),
+                    (None, Some(after)) => changes.push(Change::Added(item_path, after.clone())),
+                    (None, None) => (),
+                }
+            }
+        }
+        _ if before != after => changes.push(Change::Changed(path.to_string(), before.clone(), after.clone())),
+        _ => (),
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_changed_nested_field_is_reported() {
+        let before = json!({
+            "_id": "order-1",
+            "customer": {"address": {"city": "KL", "zip": "50000"}},
+            "lines": [{"sku": "A", "quantity": "2"}],
+            "legacy": true
+        });
+        let after = json!({
+            "_id": "order-1",
+            "customer": {"address": {"city": "Kuala Lumpur", "zip": "50000"}},
+            "lines": [{"sku": "A", "quantity": 2}, {"sku": "B", "quantity": 1}],
+            "status": "OPEN"
+        });
+
+        let changes = diff(&before, &after);
+        assert_eq!(
+            changes,
+            vec![
+                Change::Changed("customer.address.city".to_string(), json!("KL"), json!("Kuala Lumpur")),
+                Change::Removed("legacy".to_string(), json!(true)),
+                Change::Changed("lines[0].quantity".to_string(), json!("2"), json!(2)),
+                Change::Added("lines[1]".to_string(), json!({"sku": "B", "quantity": 1})),
+                Change::Added("status".to_string(), json!("OPEN")),
+            ]
+        );
+        assert_eq!(changes[0].to_string(), r#"~ customer.address.city: "KL" -> "Kuala Lumpur""#);
+
+        assert!(diff(&before, &before).is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt::Write;
+
+use protobuf::descriptor::{
+    field_descriptor_proto::Type, DescriptorProto, EnumDescriptorProto, FieldDescriptorProto,
+    FileDescriptorSet,
+};
+use serde_json::{json, Value};
+
+/// How the parsed descriptor set is printed by `dump-descriptor`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DumpFormat {
+    #[default]
+    Tree, // Indented listing, one line per message, field and enum value
+    Json, // The whole descriptor set as a JSON document
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tree" => Ok(DumpFormat::Tree),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err(format!("Unknown dump-descriptor --format {:?}", s)),
+        }
+    }
+}
+
+/// Renders every file of the descriptor set, with its messages, fields, enums and nested types.
+pub fn dump(file_descriptor_set: &FileDescriptorSet, format: DumpFormat) -> String {
+    match format {
+        DumpFormat::Tree => to_tree(file_descriptor_set),
+        DumpFormat::Json => {
+            serde_json::to_string_pretty(&to_json(file_descriptor_set)).unwrap_or_default() + "\n"
+        }
+    }
+}
+
+/// JSON form of the descriptor set: `{"files": [{"name", "package", "syntax", "messages", "enums"}]}`.
+pub fn to_json(file_descriptor_set: &FileDescriptorSet) -> Value {
+    let files: Vec<Value> = file_descriptor_set
+        .file
+        .iter()
+        .map(|file| {
+            json!({
+                "name": file.name(),
+                "package": file.package(),
+                "syntax": file.syntax.as_deref().unwrap_or("proto2"),
+                "dependencies": file.dependency,
+                "messages": file.message_type.iter().map(message_json).collect::<Vec<_>>(),
+                "enums": file.enum_type.iter().map(enum_json).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    json!({ "files": files })
+}
+
+fn message_json(message: &DescriptorProto) -> Value {
+    let fields: Vec<Value> = message
+        .field
+        .iter()
+        .map(|field| {
+            json!({
+                "name": field.name(),
+                "number": field.number(),
+                "json_name": field.json_name.as_deref(),
+                "label": label_name(field),
+                "type": type_name(field),
+                "oneof_index": field.oneof_index,
+            })
+        })
+        .collect();
+    json!({
+        "name": message.name(),
+        "fields": fields,
+        "oneofs": message.oneof_decl.iter().map(|oneof| oneof.name()).collect::<Vec<_>>(),
+        "nested_types": message.nested_type.iter().map(message_json).collect::<Vec<_>>(),
+        "enums": message.enum_type.iter().map(enum_json).collect::<Vec<_>>(),
+    })
+}
+
+fn enum_json(enum_type: &EnumDescriptorProto) -> Value {
+    let values: Vec<Value> = enum_type
+        .value
+        .iter()
+        .map(|value| json!({"name": value.name(), "number": value.number()}))
+        .collect();
+    json!({"name": enum_type.name(), "values": values})
+}
+
+/// Readable form of the descriptor set, e.g. `optional int32 quantity = 1 (json: quantity)`.
+pub fn to_tree(file_descriptor_set: &FileDescriptorSet) -> String {
+    let mut tree = String::new();
+    for file in &file_descriptor_set.file {
+        let _ = write!(tree, "file {}", file.name());
+        if !file.package().is_empty() {
+            let _ = write!(tree, " (package {})", file.package());
+        }
+        let _ = writeln!(tree, " {}", file.syntax.as_deref().unwrap_or("proto2"));
+        for message in &file.message_type {
+            message_tree(&mut tree, message, 1);
+        }
+        for enum_type in &file.enum_type {
+            enum_tree(&mut tree, enum_type, 1);
+        }
+    }
+    tree
+}
+
+fn message_tree(tree: &mut String, message: &DescriptorProto, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let _ = writeln!(tree, "{}message {}", indent, message.name());
+    for field in &message.field {
+        let _ = write!(
+            tree,
+            "{}  {} {} {} = {}",
+            indent,
+            label_name(field),
+            type_name(field),
+            field.name(),
+            field.number()
+        );
+        match &field.json_name {
+            Some(json_name) => {
+                let _ = writeln!(tree, " (json: {})", json_name);
+            }
+            None => tree.push('\n'),
+        }
+    }
+    for nested in &message.nested_type {
+        message_tree(tree, nested, depth + 1);
+    }
+    for enum_type in &message.enum_type {
+        enum_tree(tree, enum_type, depth + 1);
+    }
+}
+
+fn enum_tree(tree: &mut String, enum_type: &EnumDescriptorProto, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let _ = writeln!(tree, "{}enum {}", indent, enum_type.name());
+    for value in &enum_type.value {
+        let _ = writeln!(tree, "{}  {} = {}", indent, value.name(), value.number());
+    }
+}
+
+/// `optional`, `required` or `repeated`.
+fn label_name(field: &FieldDescriptorProto) -> String {
+    format!("{:?}", field.label())
+        .trim_start_matches("LABEL_")
+        .to_lowercase()
+}
+
+/// Scalar type name (e.g. `int32`), or the referenced type name for messages and enums.
+fn type_name(field: &FieldDescriptorProto) -> String {
+    match field.type_() {
+        Type::TYPE_MESSAGE | Type::TYPE_ENUM | Type::TYPE_GROUP if field.type_name.is_some() => {
+            field.type_name().to_string()
+        }
+        type_ => format!("{:?}", type_).trim_start_matches("TYPE_").to_lowercase(),
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{descriptor::field_descriptor_proto::Label, EnumOrUnknown};
+
+    fn field(name: &str, number: i32, label: Label, type_: Type, type_name: Option<&str>) -> FieldDescriptorProto {
+        let mut field = FieldDescriptorProto::new();
+        field.name = Some(name.to_string());
+        field.json_name = Some(name.to_string());
+        field.number = Some(number);
+        field.label = Some(EnumOrUnknown::new(label));
+        field.type_ = Some(EnumOrUnknown::new(type_));
+        field.type_name = type_name.map(str::to_string);
+        field
+    }
+
+    fn create_test_descriptor() -> FileDescriptorSet {
+        // TopLevel -> SubMessage -> SubDescriptorProto, as in the validation tests
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.name = Some("FileLevel.proto".to_string());
+
+        let mut top_level = DescriptorProto::new();
+        top_level.name = Some("TopLevel".to_string());
+        top_level.field.push(field("name", 1, Label::LABEL_OPTIONAL, Type::TYPE_STRING, None));
+        top_level.field.push(field("items", 2, Label::LABEL_REPEATED, Type::TYPE_MESSAGE, Some(".SubMessage")));
+
+        let mut sub_message = DescriptorProto::new();
+        sub_message.name = Some("SubMessage".to_string());
+        sub_message.field.push(field("id", 1, Label::LABEL_OPTIONAL, Type::TYPE_INT32, None));
+        sub_message.field.push(field("description", 2, Label::LABEL_OPTIONAL, Type::TYPE_STRING, None));
+        sub_message.field.push(field(
+            "details",
+            3,
+            Label::LABEL_REPEATED,
+            Type::TYPE_MESSAGE,
+            Some(".SubDescriptorProto"),
+        ));
+
+        let mut sub_descriptor = DescriptorProto::new();
+        sub_descriptor.name = Some("SubDescriptorProto".to_string());
+        sub_descriptor.field.push(field("value", 1, Label::LABEL_OPTIONAL, Type::TYPE_STRING, None));
+        let mut kind = EnumDescriptorProto::new();
+        kind.name = Some("Kind".to_string());
+        let mut plain = protobuf::descriptor::EnumValueDescriptorProto::new();
+        plain.name = Some("PLAIN".to_string());
+        plain.number = Some(0);
+        kind.value.push(plain);
+        sub_descriptor.enum_type.push(kind);
+
+        file.message_type.push(top_level);
+        file.message_type.push(sub_message);
+        file.message_type.push(sub_descriptor);
+        file_set.file.push(file);
+        file_set
+    }
+
+    #[test]
+    fn test_tree_lists_every_message() {
+        let tree = dump(&create_test_descriptor(), DumpFormat::Tree);
+        assert_eq!(
+            tree,
+            "file FileLevel.proto proto2
+  message TopLevel
+    optional string name = 1 (json: name)
+    repeated .SubMessage items = 2 (json: items)
+  message SubMessage
+    optional int32 id = 1 (json: id)
+    optional string description = 2 (json: description)
+    repeated .SubDescriptorProto details = 3 (json: details)
+  message SubDescriptorProto
+    optional string value = 1 (json: value)
+    enum Kind
+      PLAIN = 0
+"
+        );
+    }
+
+    #[test]
+    fn test_json_lists_every_message() {
+        let dumped: Value = serde_json::from_str(&dump(&create_test_descriptor(), DumpFormat::Json)).unwrap();
+        let messages: Vec<&str> = dumped["files"][0]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(messages, vec!["TopLevel", "SubMessage", "SubDescriptorProto"]);
+
+        let details = &dumped["files"][0]["messages"][1]["fields"][2];
+        assert_eq!(
+            details,
+            &json!({
+                "name": "details",
+                "number": 3,
+                "json_name": "details",
+                "label": "repeated",
+                "type": ".SubDescriptorProto",
+                "oneof_index": null
+            })
+        );
+        assert_eq!(dumped["files"][0]["messages"][2]["enums"][0]["values"][0]["name"], "PLAIN");
+        assert_eq!("yaml".parse::<DumpFormat>(), Err("Unknown dump-descriptor --format \"yaml\"".to_string()));
+    }
+}
@@ -1,13 +1,48 @@
-use reqwest::StatusCode;
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
+use reqwest::{Client, RequestBuilder};
+use std::time::Duration;
+
 use serde_json::{from_str, json, Value};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::client::send_with_retry;
+
+/// Receives the bookmark of the next page once a page was fully processed.
+type BookmarkCallback = Box<dyn Fn(&str)>;
 
 pub struct Fetch {
     dbprefix: String,
     dbtable: String,
-    callback: Box<dyn Fn(Value) -> ()>,
+    client: Client, // Shared HTTP client
+    callback: Box<dyn Fn(Value)>,
+    batch_callback: Option<Box<dyn Fn(Vec<String>)>>, // Called once per page with the processed ids
+    bookmark_callback: Option<BookmarkCallback>, // Called once per fully processed page with the next bookmark
     bookmark: Option<String>,
     limit: usize,
     doc_count: usize, // Total number of documents in the table
+    cancel: CancellationToken, // Stops the run between documents once cancelled
+    max_docs: Option<usize>,   // Stop after this many documents have been processed
+    processed: usize,          // Number of documents passed to the callback so far
+    throttle: Throttle,        // Spaces out page requests (--fetch-rate)
+    exec_stats: Option<ExecutionStats>, // Query statistics summed over the pages, when requested
+    id_range: (Option<String>, Option<String>), // Inclusive start and exclusive end of the scanned ids
+    backoff: Backoff,          // Retries of reads failing with a network error or a 5xx status
+    selector: Option<Value>,   // Mango selector replacing the scan of every id
+    progress: Option<ProgressBar>, // Bar replacing the per-page lines, may be shared by the shards
+}
+
+/// Progress bar of a scan, showing the share of the table processed, the rate and the
+/// estimated time remaining. Its length is set from the table document count.
+pub fn progress_bar() -> ProgressBar {
+    let progress = ProgressBar::new(0);
+    if let Ok(style) =
+        ProgressStyle::with_template("{wide_bar} {pos}/{len} ({percent}%) {per_sec:.0} ETA {eta}")
+    {
+        progress.set_style(style);
+    }
+    progress
 }
 
 impl Fetch {
@@ -15,44 +50,148 @@ impl Fetch {
         Fetch {
             dbprefix: dbprefix.to_string(),
             dbtable: dbtable.to_string(),
+            client: Client::new(),
             callback: Box::new(|_| ()),
+            batch_callback: None,
+            bookmark_callback: None,
             bookmark: None,
             limit,
             doc_count: 0,
+            cancel: CancellationToken::new(),
+            max_docs: None,
+            processed: 0,
+            throttle: Throttle::default(),
+            exec_stats: None,
+            id_range: (None, None),
+            backoff: Backoff::default(),
+            selector: None,
+            progress: None,
         }
     }
 
-    pub fn with_callback(mut self, callback: Box<dyn Fn(Value) -> ()>) -> Self {
+    /// Reuses the run's HTTP client instead of a private one.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_callback(mut self, callback: Box<dyn Fn(Value)>) -> Self {
         self.callback = callback;
         self
     }
 
+    /// Registers a callback invoked after each page with the ids of the documents processed.
+    pub fn with_batch_callback(mut self, batch_callback: Box<dyn Fn(Vec<String>)>) -> Self {
+        self.batch_callback = Some(batch_callback);
+        self
+    }
+
+    /// Registers a callback invoked after each fully processed page with the bookmark of the
+    /// next page, where a resumed run starts. Not called for a page cut short by cancellation.
+    pub fn with_bookmark_callback(mut self, bookmark_callback: BookmarkCallback) -> Self {
+        self.bookmark_callback = Some(bookmark_callback);
+        self
+    }
+
+    /// Starts the scan at a bookmark saved by an earlier run instead of the first page.
+    pub fn with_bookmark(mut self, bookmark: Option<String>) -> Self {
+        self.bookmark = bookmark;
+        self
+    }
+
+    /// Reports progress on the bar instead of printing a line per page.
+    pub fn with_progress(mut self, progress: Option<ProgressBar>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Uses an externally controlled token, e.g. one cancelled by a timeout.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Stops the run, mid-batch if needed, once `max_docs` documents were processed.
+    pub fn with_max_docs(mut self, max_docs: Option<usize>) -> Self {
+        self.max_docs = max_docs;
+        self
+    }
+
+    /// Requests at most `pages_per_sec` pages per second, to leave read capacity to other consumers.
+    pub fn with_fetch_rate(mut self, pages_per_sec: Option<f64>) -> Self {
+        self.throttle.interval = pages_per_sec.map(|rate| Duration::from_secs_f64(1.0 / rate));
+        self
+    }
+
+    /// Asks CouchDB for the `_find` execution statistics of every page, logged per page and
+    /// summed up at the end of the run, to spot scans missing an index.
+    pub fn with_execution_stats(mut self, enabled: bool) -> Self {
+        self.exec_stats = enabled.then(ExecutionStats::default);
+        self
+    }
+
+    /// Only scans ids from `start` (inclusive) up to `end` (exclusive), so disjoint ranges
+    /// can be processed by separate runs. A missing bound leaves that side open.
+    pub fn with_id_range(mut self, start: Option<String>, end: Option<String>) -> Self {
+        self.id_range = (start, end);
+        self
+    }
+
+    /// Only fetches the documents matching a Mango selector instead of the whole table.
+    /// Combined with an id range, documents must match both.
+    pub fn with_selector(mut self, selector: Option<Value>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Retries the metadata and page reads up to `max_retries` times on network errors and
+    /// 5xx statuses, waiting `base` doubled on every attempt plus a random jitter.
+    pub fn with_retry(mut self, max_retries: usize, base: Duration) -> Self {
+        self.backoff = Backoff { max_retries, base };
+        self
+    }
+
     /// Executes the document fetching process.
     /// - Fetches metadata about the table.
     /// - Fetches documents in batches and applies the callback to each document.
-    pub async fn execute(&mut self) {
+    ///
+    /// Stops at the first request failing once its retries are exhausted, returning the
+    /// error; the documents of the earlier pages were already handed to the callback.
+    pub async fn execute(&mut self) -> Result<(), String> {
         // Fetch metadata about the table (e.g., partitioned status, document count)
-        match self.get_metadata().await {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Failed to fetch table metadata: {}", e);
-                return;
-            }
+        self.get_metadata().await?;
+        if let Some(progress) = &self.progress {
+            progress.set_length(self.doc_count as u64);
         }
 
         let mut count = 1; // Counter for tracking the number of iterations
         let mut total_record = 0; // Total number of records fetched so far
 
         loop {
-            // Fetch a batch of documents and apply the callback
-            let num_of_record = self.fetch_and_apply().await.unwrap();
+            // Fetch a batch of documents and apply the callback, unless cancelled meanwhile
+            let cancel = self.cancel.clone();
+            let num_of_record = tokio::select! {
+                _ = cancel.cancelled() => 0,
+                result = async {
+                    self.throttle.wait().await;
+                    self.fetch_and_apply().await
+                } => result?,
+            };
             total_record += num_of_record;
 
-            // Log progress
-            println!(
-                "Fetched {}/{} transactions. Iteration: {}",
-                total_record, self.doc_count, count
-            );
+            // Log progress, the bar is updated document by document
+            if self.progress.is_none() {
+                println!(
+                    "Fetched {}/{} transactions. Iteration: {}",
+                    total_record, self.doc_count, count
+                );
+            }
+
+            // Stop requesting pages once a limit or timeout cancelled the run
+            if self.cancel.is_cancelled() {
+                self.log(format!("Stopped after {} documents", self.processed));
+                break;
+            }
 
             // Break the loop if fewer records than the limit are returned (end of data)
             if num_of_record < self.limit {
@@ -61,72 +200,103 @@ impl Fetch {
 
             count += 1; // Increment the iteration counter
         }
-    }
-
-    async fn fetch_and_apply(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
-        let url = format!("{}/{}/_find", self.dbprefix, self.dbtable);
 
-        let response = reqwest::Client::new()
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .body(self.selector())
-            .send()
-            .await
-            .map_err(|e| e.to_string())?;
+        if let Some(exec_stats) = &self.exec_stats {
+            self.log(format!("Execution stats for the run: {}", exec_stats));
+        }
+        Ok(())
+    }
 
-        if response.status() != StatusCode::OK {
-            return Err(format!(
-                "Failed to fetch documents: Status code {}",
-                response.status()
-            )
-            .into());
+    /// Prints a line above the progress bar, or as is without one.
+    fn log(&self, line: String) {
+        match &self.progress {
+            Some(progress) => progress.println(line),
+            None => println!("{}", line),
         }
+    }
+
+    async fn fetch_and_apply(&mut self) -> Result<usize, String> {
+        let url = format!("{}/{}/_find", self.dbprefix, self.dbtable);
 
-        let body = response.text().await.map_err(|e| e.to_string())?;
-        let json: Value = from_str(&body).map_err(|e| e.to_string())?;
+        // The same bookmark is sent again on every attempt, so no page is skipped
+        let selector = self.selector();
+        let json = self
+            .read_json("Failed to fetch documents", || {
+                self.client
+                    .post(&url)
+                    .header("Content-Type", "application/json")
+                    .body(selector.clone())
+            })
+            .await?;
 
         // Extract the bookmark for pagination
-        self.bookmark = json
-            .get("bookmark")
-            .map(|b| b.as_str().unwrap())
-            .map(|b| b.to_string());
+        self.bookmark = match json.get("bookmark") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(bookmark)) => Some(bookmark.clone()),
+            Some(bookmark) => return Err(format!("Failed to fetch documents: invalid bookmark {}", bookmark)),
+        };
+
+        // Per-page query statistics, requested with --exec-stats
+        if let Some(exec_stats) = &mut self.exec_stats {
+            let page = ExecutionStats::from_response(&json["execution_stats"]);
+            let line = format!("Execution stats: {}", page);
+            exec_stats.add(&page);
+            self.log(line);
+        }
 
         // Extract the "docs" array from the response
         let rows = json["docs"]
             .as_array()
-            .ok_or("No 'docs' field in response")?;
-
-        // Apply the callback to each document
-        let count = rows
-            .iter()
-            .map(|doc| (self.callback)(doc.clone())) // Call the callback for each document
-            .count(); // Count the number of documents processed
+            .ok_or("Failed to fetch documents: no 'docs' field in response")?;
 
+        let count = self.apply_batch(rows);
+        if let (Some(bookmark_callback), Some(bookmark)) = (&self.bookmark_callback, &self.bookmark) {
+            if count == rows.len() {
+                bookmark_callback(bookmark);
+            }
+        }
         Ok(count)
     }
 
-    /// Fetches metadata about the table, including whether it is partitioned and the total document count.
-    async fn get_metadata(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // Construct the URL for fetching table metadata
-        let url = format!("{}/{}", self.dbprefix, self.dbtable);
+    /// Applies the callback to each document of a batch, checking for cancellation between
+    /// documents. Returns the number of documents processed.
+    fn apply_batch(&mut self, rows: &[Value]) -> usize {
+        let mut ids = Vec::new();
+        for doc in rows {
+            if self.cancel.is_cancelled() {
+                break;
+            }
 
-        let client = reqwest::Client::new();
+            (self.callback)(doc.clone()); // Call the callback for each document
+            ids.push(doc["_id"].as_str().unwrap_or_default().to_string());
+            self.processed += 1;
+            if let Some(progress) = &self.progress {
+                progress.inc(1);
+            }
 
-        // Send a GET request to fetch metadata
-        let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
+            if self.max_docs.is_some_and(|max_docs| self.processed >= max_docs) {
+                self.cancel.cancel();
+            }
+        }
 
-        // Check if the response status is successful (HTTP 200)
-        if response.status() != StatusCode::OK {
-            return Err(format!(
-                "Failed to fetch table metadata: Status code {}",
-                response.status()
-            )
-            .into());
+        let count = ids.len();
+        if let Some(batch_callback) = &self.batch_callback {
+            if count > 0 {
+                batch_callback(ids);
+            }
         }
+        count
+    }
+
+    /// Fetches metadata about the table, including whether it is partitioned and the total document count.
+    async fn get_metadata(&mut self) -> Result<(), String> {
+        // Construct the URL for fetching table metadata
+        let url = format!("{}/{}", self.dbprefix, self.dbtable);
 
-        // Parse the response body as JSON
-        let body = response.text().await.map_err(|e| e.to_string())?;
-        let json: Value = from_str(&body).map_err(|e| e.to_string())?;
+        // Send a GET request to fetch metadata
+        let json = self
+            .read_json("Failed to fetch table metadata", || self.client.get(&url))
+            .await?;
 
         // Extract the total document count
         self.doc_count = json["doc_count"].as_u64().unwrap_or(0) as usize;
@@ -134,16 +304,46 @@ impl Fetch {
         Ok(())
     }
 
+    /// Sends a read and parses its JSON body, retrying transient failures with backoff.
+    async fn read_json(&self, context: &str, request: impl Fn() -> RequestBuilder) -> Result<Value, String> {
+        let mut attempt = 0;
+        loop {
+            match read_once(context, request()).await {
+                Ok(json) => return Ok(json),
+                Err(err) if err.transient && attempt < self.backoff.max_retries => {
+                    let delay = self.backoff.delay(attempt);
+                    eprintln!("{}, retrying in {} ms", err.message, delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.message),
+            }
+        }
+    }
+
     /// Generates the JSON selector for querying transactions.
     fn selector(&self) -> String {
+        // Every id sorts after null, the range bounds narrow the scan down
+        let mut id_condition = serde_json::Map::new();
+        match &self.id_range.0 {
+            Some(start) => id_condition.insert("$gte".to_string(), json!(start)),
+            None => id_condition.insert("$gt".to_string(), Value::Null),
+        };
+        if let Some(end) = &self.id_range.1 {
+            id_condition.insert("$lt".to_string(), json!(end));
+        }
+        let id_selector = json!({ "_id": id_condition });
+        let selector = match (&self.selector, &self.id_range) {
+            (None, _) => id_selector,
+            (Some(selector), (None, None)) => selector.clone(),
+            (Some(selector), _) => json!({ "$and": [selector, id_selector] }),
+        };
+
         let selector = SelectorContent {
-            selector: json!({
-                "_id": {
-                    "$gt": null  // Transactions after the start date
-                },
-            }),
+            selector,
             limit: self.limit as i32, // Limit the number of records per query
             bookmark: self.bookmark.clone(), // Use the bookmark for pagination
+            execution_stats: self.exec_stats.is_some(),
         };
 
         // Serialize the selector to a JSON string
@@ -151,6 +351,107 @@ impl Fetch {
     }
 }
 
+/// First characters the id space is split on by `--shards`, in CouchDB collation order.
+const SHARD_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Splits the ids into `shards` contiguous ranges, as `[start, end)` id bounds. The first
+/// and last ranges are open, so every id falls in exactly one range however the ids are
+/// distributed. At most `SHARD_ALPHABET.len()` shards are supported.
+pub fn shard_ranges(shards: usize) -> Vec<(Option<String>, Option<String>)> {
+    let shards = shards.clamp(1, SHARD_ALPHABET.len());
+    let bounds: Vec<Option<String>> = (1..shards)
+        .map(|shard| Some((SHARD_ALPHABET[shard * SHARD_ALPHABET.len() / shards] as char).to_string()))
+        .collect();
+    let starts = std::iter::once(None).chain(bounds.clone());
+    starts.zip(bounds.into_iter().chain(std::iter::once(None))).collect()
+}
+
+/// Parses a `--selector` value, which must be a JSON object.
+pub fn parse_selector(text: &str) -> Result<Value, String> {
+    let selector: Value = from_str(text).map_err(|e| format!("Invalid selector, not valid JSON - {}", e))?;
+    if !selector.is_object() {
+        return Err(format!("Invalid selector, expected a JSON object, got {}", selector));
+    }
+    Ok(selector)
+}
+
+/// A failed read, and whether sending it again may succeed.
+struct ReadError {
+    message: String,
+    transient: bool, // Network error or 5xx status, as opposed to a 4xx or an unreadable body
+}
+
+/// Sends a read once, accepting only a 200 response with a JSON body.
+async fn read_once(context: &str, request: RequestBuilder) -> Result<Value, ReadError> {
+    let transient = |message: String| ReadError {
+        message,
+        transient: true,
+    };
+    let response = send_with_retry(request)
+        .await
+        .map_err(|e| transient(format!("{}: {}", context, e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ReadError {
+            message: format!("{}: Status code {}", context, status),
+            transient: status.is_server_error(),
+        });
+    }
+
+    let body = response
+        .text()
+        .await
+        .map_err(|e| transient(format!("{}: {}", context, e)))?;
+    from_str(&body).map_err(|e| ReadError {
+        message: format!("{}: {}", context, e),
+        transient: false,
+    })
+}
+
+/// Exponential backoff between attempts of a failed read.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    max_retries: usize, // Attempts after the first one, 0 fails on the first error
+    base: Duration,     // Wait before the first retry, doubled on every attempt
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            max_retries: 3,
+            base: Duration::from_millis(500),
+        }
+    }
+}
+
+impl Backoff {
+    /// Wait before retry number `attempt` (from 0): `base * 2^attempt`, plus up to `base` of
+    /// jitter so that concurrent runs do not retry in lockstep.
+    fn delay(&self, attempt: usize) -> Duration {
+        let exponential = self.base.saturating_mul(1 << attempt.min(16));
+        let jitter = rand::thread_rng().gen_range(0..=self.base.as_millis() as u64);
+        exponential + Duration::from_millis(jitter)
+    }
+}
+
+/// Keeps a minimum interval between two page requests.
+#[derive(Default)]
+struct Throttle {
+    interval: Option<Duration>, // No throttling when None
+    last: Option<Instant>,      // When the previous page was requested
+}
+
+impl Throttle {
+    /// Waits until the interval since the previous request has elapsed.
+    async fn wait(&mut self) {
+        if let (Some(interval), Some(last)) = (self.interval, self.last) {
+            tokio::time::sleep_until(last + interval).await;
+        }
+        self.last = Some(Instant::now());
+    }
+}
+
 /// Represents the structure of the query selector used for fetching documents.
 #[derive(Debug, serde::Serialize)]
 struct SelectorContent {
@@ -158,4 +459,589 @@ struct SelectorContent {
     limit: i32,                  // Maximum number of records to fetch
     #[serde(skip_serializing_if = "Option::is_none")]
     bookmark: Option<String>, // Optional bookmark for pagination
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    execution_stats: bool, // Ask CouchDB for the query execution statistics
+}
+
+/// `_find` execution statistics reported by CouchDB.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExecutionStats {
+    pub pages: usize,             // Number of pages the statistics cover
+    pub total_keys_examined: u64, // Index keys read
+    pub total_docs_examined: u64, // Documents read, far above results_returned without a fitting index
+    pub results_returned: u64,    // Documents matching the selector
+    pub execution_time_ms: f64,   // Time spent by CouchDB on the query
+}
+
+impl ExecutionStats {
+    /// Reads the `execution_stats` object of a `_find` response, missing counters are 0.
+    fn from_response(stats: &Value) -> Self {
+        let count = |name: &str| stats[name].as_u64().unwrap_or(0);
+        ExecutionStats {
+            pages: 1,
+            total_keys_examined: count("total_keys_examined"),
+            total_docs_examined: count("total_docs_examined"),
+            results_returned: count("results_returned"),
+            execution_time_ms: stats["execution_time_ms"].as_f64().unwrap_or(0.0),
+        }
+    }
+
+    fn add(&mut self, other: &ExecutionStats) {
+        self.pages += other.pages;
+        self.total_keys_examined += other.total_keys_examined;
+        self.total_docs_examined += other.total_docs_examined;
+        self.results_returned += other.results_returned;
+        self.execution_time_ms += other.execution_time_ms;
+    }
+}
+
+impl std::fmt::Display for ExecutionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} docs examined, {} keys examined, {} results returned in {:.1}ms",
+            self.total_docs_examined,
+            self.total_keys_examined,
+            self.results_returned,
+            self.execution_time_ms
+        )
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::Checkpoint;
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path},
+        Mock, MockBuilder, MockServer, Request, ResponseTemplate,
+    };
+
+    /// Mock CouchDB answering the `orders` table metadata; each test mounts its own `_find` mocks.
+    async fn mock_table(doc_count: usize) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/orders"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"db_name": "orders", "doc_count": doc_count})))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn find() -> MockBuilder {
+        Mock::given(method("POST")).and(path("/orders/_find"))
+    }
+
+    /// `_find` answered with two pages linked by the "page-2" bookmark.
+    async fn mount_two_pages(server: &MockServer) {
+        find()
+            .and(body_partial_json(json!({"bookmark": "page-2"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"docs": [{"_id": "order-3"}], "bookmark": "end"})))
+            .mount(server)
+            .await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "docs": [{"_id": "order-1"}, {"_id": "order-2"}],
+                "bookmark": "page-2"
+            })))
+            .mount(server)
+            .await;
+    }
+
+    async fn received_bodies(server: &MockServer) -> Vec<Value> {
+        let requests = server.received_requests().await.unwrap();
+        requests
+            .iter()
+            .filter(|request| request.url.path() == "/orders/_find")
+            .map(|request| request.body_json().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_max_docs_stops_mid_batch() {
+        let calls = Rc::new(Cell::new(0));
+        let cancel = CancellationToken::new();
+        let mut fetch = Fetch::new("http://localhost:5984", "orders", 1000)
+            .with_callback(Box::new({
+                let calls = Rc::clone(&calls);
+                move |_| calls.set(calls.get() + 1)
+            }))
+            .with_cancellation(cancel.clone())
+            .with_max_docs(Some(2));
+
+        let page: Vec<Value> = (0..1000).map(|i| json!({"_id": format!("doc-{}", i)})).collect();
+        let count = fetch.apply_batch(&page);
+
+        assert_eq!(count, 2);
+        assert_eq!(calls.get(), 2);
+        assert!(cancel.is_cancelled());
+
+        // A cancelled run does not process any further page
+        assert_eq!(fetch.apply_batch(&page), 0);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_batch_callback_called_once_per_page() {
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        let mut fetch = Fetch::new("http://localhost:5984", "orders", 2).with_batch_callback(
+            Box::new({
+                let batches = Rc::clone(&batches);
+                move |ids| batches.borrow_mut().push(ids)
+            }),
+        );
+
+        fetch.apply_batch(&[json!({"_id": "a"}), json!({"_id": "b"})]);
+        fetch.apply_batch(&[json!({"_id": "c"})]);
+        fetch.apply_batch(&[]);
+
+        assert_eq!(
+            *batches.borrow(),
+            vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["c".to_string()]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rate_spaces_out_pages() {
+        let mut fetch = Fetch::new("http://localhost:5984", "orders", 2).with_fetch_rate(Some(20.0));
+
+        let start = Instant::now();
+        let mut requested = Vec::new();
+        for _ in 0..4 {
+            fetch.throttle.wait().await;
+            requested.push(start.elapsed());
+        }
+
+        // The first page is immediate, the following ones 50ms apart
+        assert!(requested[0] < Duration::from_millis(50));
+        for pair in requested.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(45));
+        }
+        assert!(requested[3] >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_paginates_with_bookmark() {
+        // Mock CouchDB: table metadata and two _find pages linked by a bookmark
+        let server = mock_table(3).await;
+        mount_two_pages(&server).await;
+
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        Fetch::new(&server.uri(), "orders", 2)
+            .with_callback(Box::new({
+                let ids = Rc::clone(&ids);
+                move |doc| ids.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+            }))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(*ids.borrow(), vec!["order-1", "order-2", "order-3"]);
+
+        let requests = server.received_requests().await.unwrap();
+        let calls: Vec<(&str, &str)> = requests
+            .iter()
+            .map(|request| (request.method.as_str(), request.url.path()))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![("GET", "/orders"), ("POST", "/orders/_find"), ("POST", "/orders/_find")]
+        );
+        let pages = received_bodies(&server).await;
+        assert_eq!(pages[0]["limit"], 2);
+        assert_eq!(pages[0].get("bookmark"), None);
+        assert_eq!(pages[1]["bookmark"], "page-2");
+    }
+
+    #[tokio::test]
+    async fn test_max_docs_caps_a_paginated_scan() {
+        // Mock CouchDB: 10 documents served 3 per page, the bookmark naming the next offset
+        let server = mock_table(10).await;
+        find()
+            .respond_with(|request: &Request| {
+                let query: Value = request.body_json().unwrap();
+                let offset: usize = query["bookmark"].as_str().map_or(0, |bookmark| bookmark.parse().unwrap());
+                let end = (offset + 3).min(10);
+                let docs: Vec<Value> = (offset..end).map(|i| json!({"_id": format!("order-{}", i)})).collect();
+                ResponseTemplate::new(200).set_body_json(json!({"docs": docs, "bookmark": end.to_string()}))
+            })
+            .mount(&server)
+            .await;
+
+        let calls = Rc::new(Cell::new(0));
+        Fetch::new(&server.uri(), "orders", 3)
+            .with_callback(Box::new({
+                let calls = Rc::clone(&calls);
+                move |_| calls.set(calls.get() + 1)
+            }))
+            .with_max_docs(Some(5))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(calls.get(), 5);
+        // Two full pages were requested, the second one cut short, and no third
+        let pages = received_bodies(&server).await;
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|page| page["limit"] == 3));
+        assert_eq!(pages[1]["bookmark"], "3");
+    }
+
+    #[tokio::test]
+    async fn test_progress_bar_follows_the_documents() {
+        let server = mock_table(3).await;
+        mount_two_pages(&server).await;
+
+        // A hidden bar keeps counting, as when stdout is not a terminal
+        let progress = ProgressBar::hidden();
+        Fetch::new(&server.uri(), "orders", 2)
+            .with_progress(Some(progress.clone()))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(progress.length(), Some(3));
+        assert_eq!(progress.position(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried() {
+        // The first two _find requests fail with 503, the third succeeds
+        let server = mock_table(1).await;
+        find()
+            .respond_with(ResponseTemplate::new(503).set_body_json(json!({"error": "unavailable"})))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"docs": [{"_id": "order-1"}], "bookmark": "end"})))
+            .mount(&server)
+            .await;
+
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        Fetch::new(&server.uri(), "orders", 10)
+            .with_retry(2, Duration::from_millis(1))
+            .with_callback(Box::new({
+                let ids = Rc::clone(&ids);
+                move |doc| ids.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+            }))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(*ids.borrow(), vec!["order-1"]);
+        let pages = received_bodies(&server).await;
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|page| *page == pages[0]));
+
+        let backoff = Backoff {
+            max_retries: 3,
+            base: Duration::from_millis(100),
+        };
+        let delay = backoff.delay(2);
+        assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        // Nothing is mounted, every request gets a 404
+        let server = MockServer::start().await;
+
+        let err = Fetch::new(&server.uri(), "orders", 10)
+            .with_retry(3, Duration::from_millis(1))
+            .execute()
+            .await
+            .unwrap_err();
+
+        assert!(err.starts_with("Failed to fetch table metadata"), "{}", err);
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_page_stops_the_scan() {
+        // The second page keeps failing, the first one was already processed
+        let server = mock_table(3).await;
+        find()
+            .and(body_partial_json(json!({"bookmark": "page-2"})))
+            .respond_with(ResponseTemplate::new(500).set_body_json(json!({"error": "unavailable"})))
+            .mount(&server)
+            .await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "docs": [{"_id": "order-1"}, {"_id": "order-2"}],
+                "bookmark": "page-2"
+            })))
+            .mount(&server)
+            .await;
+
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        let err = Fetch::new(&server.uri(), "orders", 2)
+            .with_retry(1, Duration::from_millis(1))
+            .with_callback(Box::new({
+                let ids = Rc::clone(&ids);
+                move |doc| ids.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+            }))
+            .execute()
+            .await
+            .unwrap_err();
+
+        assert_eq!(err, "Failed to fetch documents: Status code 500 Internal Server Error");
+        assert_eq!(*ids.borrow(), vec!["order-1", "order-2"]);
+
+        // A malformed response is an error as well
+        let server = mock_table(1).await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"docs": [{"_id": "order-1"}], "bookmark": 42})))
+            .mount(&server)
+            .await;
+        let err = Fetch::new(&server.uri(), "orders", 2).execute().await.unwrap_err();
+        assert_eq!(err, "Failed to fetch documents: invalid bookmark 42");
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_read_is_retried() {
+        // Only the first metadata request hangs past the timeout
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(300)))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"db_name": "orders", "doc_count": 1})))
+            .mount(&server)
+            .await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"docs": [{"_id": "order-1"}], "bookmark": "end"})))
+            .mount(&server)
+            .await;
+
+        let timeouts = crate::client::Timeouts {
+            request: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = crate::client::build_client(&[], None, timeouts).unwrap();
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        Fetch::new(&server.uri(), "orders", 10)
+            .with_client(client)
+            .with_retry(1, Duration::from_millis(1))
+            .with_callback(Box::new({
+                let ids = Rc::clone(&ids);
+                move |doc| ids.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+            }))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(*ids.borrow(), vec!["order-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_find_sends_basic_auth() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("authorization", "Basic YWRtaW46c2VjcmV0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"db_name": "orders", "doc_count": 1})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        find()
+            .and(header("authorization", "Basic YWRtaW46c2VjcmV0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"docs": [{"_id": "order-1"}], "bookmark": "end"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = crate::client::build_client(
+            &[crate::client::basic_auth_header("admin", "secret")],
+            None,
+            crate::client::Timeouts::default(),
+        ).unwrap();
+        Fetch::new(&server.uri(), "orders", 10).with_client(client).execute().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_execution_stats_requested_and_summed() {
+        let server = mock_table(1).await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "docs": [{"_id": "order-1"}],
+                "bookmark": "end",
+                "execution_stats": {
+                    "total_keys_examined": 0,
+                    "total_docs_examined": 250,
+                    "total_quorum_docs_examined": 0,
+                    "results_returned": 1,
+                    "execution_time_ms": 12.5
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let mut fetch = Fetch::new(&server.uri(), "orders", 2).with_execution_stats(true);
+        fetch.execute().await.unwrap();
+
+        assert_eq!(received_bodies(&server).await[0]["execution_stats"], true);
+        assert_eq!(
+            fetch.exec_stats,
+            Some(ExecutionStats {
+                pages: 1,
+                total_keys_examined: 0,
+                total_docs_examined: 250,
+                results_returned: 1,
+                execution_time_ms: 12.5,
+            })
+        );
+
+        // Not requested by default
+        let fetch = Fetch::new(&server.uri(), "orders", 2);
+        let selector: Value = serde_json::from_str(&fetch.selector()).unwrap();
+        assert_eq!(selector.get("execution_stats"), None);
+    }
+
+    #[test]
+    fn test_id_range_bounds_the_selector() {
+        let fetch = Fetch::new("http://localhost:5984", "orders", 2)
+            .with_id_range(Some("order-1000".to_string()), Some("order-2000".to_string()));
+        let selector: Value = serde_json::from_str(&fetch.selector()).unwrap();
+        assert_eq!(selector["selector"], json!({"_id": {"$gte": "order-1000", "$lt": "order-2000"}}));
+
+        let fetch = Fetch::new("http://localhost:5984", "orders", 2).with_id_range(None, Some("m".to_string()));
+        let selector: Value = serde_json::from_str(&fetch.selector()).unwrap();
+        assert_eq!(selector["selector"], json!({"_id": {"$gt": null, "$lt": "m"}}));
+
+        // Whole table by default
+        let fetch = Fetch::new("http://localhost:5984", "orders", 2);
+        let selector: Value = serde_json::from_str(&fetch.selector()).unwrap();
+        assert_eq!(selector["selector"], json!({"_id": {"$gt": null}}));
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_saved_bookmark() {
+        // Two pages linked by a bookmark, the run is resumed after the first one
+        let server = mock_table(3).await;
+        mount_two_pages(&server).await;
+        let path = std::env::temp_dir().join(format!("bulkmorph-resume-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        // The first run is interrupted after its first page
+        let cancel = CancellationToken::new();
+        Fetch::new(&server.uri(), "orders", 2)
+            .with_cancellation(cancel.clone())
+            .with_bookmark_callback(Box::new({
+                let path = path.clone();
+                move |bookmark| {
+                    let checkpoint = Checkpoint {
+                        bookmark: Some(bookmark.to_string()),
+                        processed: 2,
+                        updated: 0,
+                    };
+                    checkpoint.save(&path).unwrap();
+                    cancel.cancel();
+                }
+            }))
+            .execute()
+            .await
+            .unwrap();
+
+        // The resumed run only fetches the remaining page
+        let ids = Rc::new(RefCell::new(Vec::new()));
+        Fetch::new(&server.uri(), "orders", 2)
+            .with_bookmark(Checkpoint::load(&path).unwrap().bookmark)
+            .with_callback(Box::new({
+                let ids = Rc::clone(&ids);
+                move |doc| ids.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+            }))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(*ids.borrow(), vec!["order-3"]);
+        assert_eq!(received_bodies(&server).await[1]["bookmark"], "page-2");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shards_process_every_document_once() {
+        // Mock CouchDB answering _find with the ids within the requested range
+        let ids = ["0001", "7z", "alpha", "hotel", "india", "kilo", "zulu"];
+        let server = mock_table(7).await;
+        find()
+            .respond_with(move |request: &Request| {
+                let body: Value = request.body_json().unwrap();
+                let range = &body["selector"]["_id"];
+                let docs: Vec<Value> = ids
+                    .iter()
+                    .filter(|id| range["$gte"].as_str().is_none_or(|start| **id >= start))
+                    .filter(|id| range["$lt"].as_str().is_none_or(|end| **id < end))
+                    .map(|id| json!({"_id": id}))
+                    .collect();
+                ResponseTemplate::new(200).set_body_json(json!({"docs": docs, "bookmark": "end"}))
+            })
+            .mount(&server)
+            .await;
+
+        assert_eq!(
+            shard_ranges(2),
+            vec![(None, Some("i".to_string())), (Some("i".to_string()), None)]
+        );
+        assert_eq!(shard_ranges(4).len(), 4);
+        assert_eq!(shard_ranges(1), vec![(None, None)]);
+
+        let processed = Rc::new(RefCell::new(Vec::new()));
+        let mut fetchers: Vec<Fetch> = shard_ranges(2)
+            .into_iter()
+            .map(|(start, end)| {
+                Fetch::new(&server.uri(), "orders", 10)
+                    .with_id_range(start, end)
+                    .with_callback(Box::new({
+                        let processed = Rc::clone(&processed);
+                        move |doc| processed.borrow_mut().push(doc["_id"].as_str().unwrap().to_string())
+                    }))
+            })
+            .collect();
+        let results = futures_util::future::join_all(fetchers.iter_mut().map(|fetcher| fetcher.execute())).await;
+        assert!(results.iter().all(Result::is_ok));
+
+        // Uneven shards: 3 ids before "i", 4 from "i" on
+        let mut processed = processed.take();
+        processed.sort();
+        assert_eq!(processed, ids);
+    }
+
+    #[tokio::test]
+    async fn test_custom_selector_is_posted() {
+        let server = mock_table(1).await;
+        find()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"docs": [], "bookmark": "end"})))
+            .mount(&server)
+            .await;
+
+        let selector = parse_selector(r#"{"status": "pending"}"#).unwrap();
+        Fetch::new(&server.uri(), "orders", 50)
+            .with_selector(Some(selector))
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(received_bodies(&server).await, vec![json!({"selector": {"status": "pending"}, "limit": 50})]);
+
+        // An id range narrows the custom selector down
+        let fetch = Fetch::new("http://localhost:5984", "orders", 2)
+            .with_selector(Some(json!({"status": "pending"})))
+            .with_id_range(Some("a".to_string()), None);
+        let body: Value = serde_json::from_str(&fetch.selector()).unwrap();
+        assert_eq!(body["selector"], json!({"$and": [{"status": "pending"}, {"_id": {"$gte": "a"}}]}));
+
+        assert!(parse_selector("[1, 2]").unwrap_err().contains("expected a JSON object"));
+        assert!(parse_selector("{status").unwrap_err().contains("not valid JSON"));
+    }
 }
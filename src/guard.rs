@@ -0,0 +1,250 @@
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::valid_proto::{ErrorType, ValidationError};
+
+/// Checks that a transform output still looks like the document it was produced from.
+/// - The output must be a JSON object carrying both `_id` and `_rev`.
+/// - The output `_id` must be the `_id` of the input, when the input has one.
+/// - The share of input keys still present in the output must reach `min_overlap` (0.0 - 1.0).
+pub fn check_output_shape(input: &Value, output: &Value, min_overlap: f64) -> Result<(), String> {
+    let output_obj = output
+        .as_object()
+        .ok_or("transform output is not a JSON object")?;
+
+    for key in ["_id", "_rev"] {
+        if !output_obj.contains_key(key) {
+            return Err(format!("transform output is missing '{}'", key));
+        }
+    }
+    if !input["_id"].is_null() && output_obj["_id"] != input["_id"] {
+        return Err(format!(
+            "transform output changed '_id' from {} to {}",
+            input["_id"], output_obj["_id"]
+        ));
+    }
+
+    // Nothing to compare against when the input itself has no keys
+    let input_keys: HashSet<&String> = match input.as_object() {
+        Some(obj) if !obj.is_empty() => obj.keys().collect(),
+        _ => return Ok(()),
+    };
+
+    let shared = input_keys
+        .iter()
+        .filter(|key| output_obj.contains_key(key.as_str()))
+        .count();
+    let overlap = shared as f64 / input_keys.len() as f64;
+
+    if overlap < min_overlap {
+        return Err(format!(
+            "transform output shares {:.0}% of the input keys (minimum {:.0}%)",
+            overlap * 100.0,
+            min_overlap * 100.0
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that a repaired document still targets the document it was produced from: its
+/// `_id` must be unchanged and the `_rev` of the input, when it has one, kept as is.
+pub fn check_identity(input: &Value, output: &Value) -> Result<(), String> {
+    for key in ["_id", "_rev"] {
+        let expected = &input[key];
+        if expected.is_null() {
+            continue; // Offline dumps may come without revisions
+        }
+        match output.get(key) {
+            None => return Err(format!("the repaired document dropped '{}'", key)),
+            Some(found) if found != expected => {
+                return Err(format!("the repaired document changed '{}' from {} to {}", key, expected, found))
+            }
+            Some(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a document serializes to at most `max_bytes`, the size CouchDB accepts.
+pub fn check_size(doc: &Value, max_bytes: usize) -> Result<(), String> {
+    let size = doc.to_string().len();
+    if size > max_bytes {
+        return Err(format!(
+            "document is {} bytes, larger than the {} bytes limit",
+            size, max_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Detects a document that is most likely of another message type, as found in tables
+/// mixing several types. Such a document has more unknown top-level fields than `ratio`
+/// times the number of its top-level fields the message declares.
+pub fn check_message_type(
+    doc: &Value,
+    errors: &[ValidationError],
+    ignore_list: &[String],
+    ratio: f64,
+) -> Result<(), String> {
+    let Some(doc_obj) = doc.as_object() else {
+        return Ok(());
+    };
+
+    let additional: HashSet<&str> = errors
+        .iter()
+        .filter(|error| error.error_type == ErrorType::AdditionalField)
+        .map(|error| error.field.as_str())
+        .collect();
+    let (unknown, matching): (Vec<&String>, Vec<&String>) = doc_obj
+        .keys()
+        .filter(|key| !ignore_list.contains(key))
+        .partition(|key| additional.contains(key.as_str()));
+
+    if !unknown.is_empty() && unknown.len() as f64 > ratio * matching.len() as f64 {
+        return Err(format!(
+            "{} unknown fields for {} matching fields",
+            unknown.len(),
+            matching.len()
+        ));
+    }
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn input_doc() -> Value {
+        json!({
+            "_id": "tx-1",
+            "_rev": "1-abc",
+            "amount": "12",
+            "currency": "MYR",
+            "status": "paid"
+        })
+    }
+
+    #[test]
+    fn test_output_shape_accepts_repaired_document() {
+        let output = json!({
+            "_id": "tx-1",
+            "_rev": "1-abc",
+            "amount": 12,
+            "currency": "MYR",
+            "status": "paid",
+            "createdAt": "2025-01-01T00:00:00Z"
+        });
+
+        assert_eq!(check_output_shape(&input_doc(), &output, 0.5), Ok(()));
+    }
+
+    #[test]
+    fn test_output_shape_rejects_unrelated_object() {
+        // A transform bug returning some other record entirely
+        let output = json!({
+            "_id": "user-9",
+            "_rev": "3-def",
+            "username": "alice",
+            "email": "alice@example.com"
+        });
+
+        assert_eq!(
+            check_output_shape(&input_doc(), &output, 0.5),
+            Err("transform output changed '_id' from \"tx-1\" to \"user-9\"".to_string())
+        );
+
+        // Same record, but most of its fields replaced
+        let output = json!({
+            "_id": "tx-1",
+            "_rev": "3-def",
+            "username": "alice",
+            "email": "alice@example.com"
+        });
+        let result = check_output_shape(&input_doc(), &output, 0.5);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("40%"));
+    }
+
+    #[test]
+    fn test_output_shape_rejects_missing_metadata_and_non_objects() {
+        let output = json!({"amount": 12, "currency": "MYR", "status": "paid"});
+        assert_eq!(
+            check_output_shape(&input_doc(), &output, 0.5),
+            Err("transform output is missing '_id'".to_string())
+        );
+
+        let output = json!(["tx-1"]);
+        assert!(check_output_shape(&input_doc(), &output, 0.5).is_err());
+    }
+
+    #[test]
+    fn test_identity_rejects_dropped_id() {
+        let output = json!({"_rev": "1-abc", "amount": 12});
+        assert_eq!(
+            check_identity(&input_doc(), &output),
+            Err("the repaired document dropped '_id'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_identity_rejects_changed_id_and_rev() {
+        let output = json!({"_id": "tx-2", "_rev": "1-abc", "amount": 12});
+        assert_eq!(
+            check_identity(&input_doc(), &output),
+            Err("the repaired document changed '_id' from \"tx-1\" to \"tx-2\"".to_string())
+        );
+
+        let output = json!({"_id": "tx-1", "_rev": "2-def", "amount": 12});
+        assert!(check_identity(&input_doc(), &output).unwrap_err().contains("'_rev'"));
+
+        let output = json!({"_id": "tx-1", "amount": 12});
+        assert_eq!(
+            check_identity(&input_doc(), &output),
+            Err("the repaired document dropped '_rev'".to_string())
+        );
+
+        // Repaired documents keep both, documents without a revision only need the id
+        assert_eq!(check_identity(&input_doc(), &json!({"_id": "tx-1", "_rev": "1-abc"})), Ok(()));
+        assert_eq!(check_identity(&json!({"_id": "tx-1"}), &json!({"_id": "tx-1"})), Ok(()));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let doc = json!({"_id": "tx-1", "note": "x".repeat(100)});
+        let size = doc.to_string().len();
+
+        assert!(check_size(&doc, size).is_ok());
+        assert!(check_size(&doc, size - 1).is_err());
+    }
+
+    #[test]
+    fn test_document_of_another_message_type() {
+        let additional = |field: &str| ValidationError {
+            field: field.to_string(),
+            error_type: ErrorType::AdditionalField,
+            found: None,
+            expected: None,
+        };
+        let ignore_list = vec!["_id".to_string(), "_rev".to_string()];
+
+        // A user record fetched from the transactions table
+        let user = json!({"_id": "user-9", "_rev": "3-def", "username": "alice", "email": "a@b.c", "amount": "1"});
+        let errors = vec![additional("username"), additional("email")];
+        assert_eq!(
+            check_message_type(&user, &errors, &ignore_list, 1.0),
+            Err("2 unknown fields for 1 matching fields".to_string())
+        );
+        // A higher threshold tolerates more unknown fields
+        assert!(check_message_type(&user, &errors, &ignore_list, 2.0).is_ok());
+
+        // A transaction with a single legacy field is just invalid, nested errors do not count
+        let errors = vec![additional("legacyRef"), additional("status.legacyCode")];
+        let mut tx = input_doc();
+        tx["legacyRef"] = json!("A1");
+        assert!(check_message_type(&tx, &errors, &ignore_list, 1.0).is_ok());
+    }
+}
@@ -0,0 +1,21 @@
+pub mod args;
+pub mod check_schema;
+pub mod checkpoint;
+pub mod client;
+pub mod coerce;
+pub mod couchdump;
+pub mod descriptor;
+pub mod diff;
+pub mod dump_descriptor;
+pub mod fetch;
+pub mod guard;
+pub mod lint;
+pub mod pipeline;
+pub mod script;
+pub mod seen;
+pub mod stats;
+pub mod try_doc;
+pub mod unflatten;
+pub mod update;
+pub mod valid_proto;
+pub mod workers;
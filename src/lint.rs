@@ -0,0 +1,105 @@
+use mlua::Lua;
+use protobuf::descriptor::FileDescriptorSet;
+use serde_json::Value;
+
+use crate::script;
+use crate::valid_proto::{self, ValidationError};
+
+/// Runs the transform against a synthetic worst-case document generated from the message
+/// descriptor and validates the output.
+/// Returns the validation errors still present after the transform (empty when it passes).
+pub fn lint_transform(
+    lua: &Lua,
+    file_descriptor_set: &FileDescriptorSet,
+    table_name: &str,
+    ignore_list: Vec<String>,
+    string_transform: bool,
+) -> Result<Vec<ValidationError>, String> {
+    let mut fixture = valid_proto::synthetic_document(file_descriptor_set, table_name)
+        .ok_or(format!("message {} not found in the proto file", table_name))?;
+
+    // Give the transform the CouchDB metadata it would see on a real document
+    fixture["_id"] = Value::from("lint-fixture");
+    fixture["_rev"] = Value::from("1-lint");
+
+    // the synthetic document is invalid by design, the transform gets its errors too
+    let errors = valid_proto::validate_json(file_descriptor_set, table_name, &fixture, ignore_list.clone());
+    let output = script::lua_transform(lua, fixture, &script::errors_json(&errors), string_transform)
+        .map_err(|err| format!("transform failed on the synthetic document - {}", err))?;
+
+    Ok(valid_proto::validate_json(
+        file_descriptor_set,
+        table_name,
+        &output,
+        ignore_list,
+    ))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{
+        descriptor::{field_descriptor_proto::Type, DescriptorProto, FieldDescriptorProto},
+        EnumOrUnknown,
+    };
+
+    fn create_test_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.name = Some("Order.proto".to_string());
+
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        for (name, type_) in [("reference", Type::TYPE_STRING), ("quantity", Type::TYPE_INT32)] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            order.field.push(field);
+        }
+
+        file.message_type.push(order);
+        file_set.file.push(file);
+        file_set
+    }
+
+    fn ignore_list() -> Vec<String> {
+        vec!["_id".to_string(), "_rev".to_string()]
+    }
+
+    #[test]
+    fn test_lint_transform_passes_when_script_repairs_fixture() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc)
+                return '{"_id":"lint-fixture","_rev":"1-lint","reference":"R-1","quantity":1}'
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        let errors = lint_transform(&lua, &create_test_descriptor(), "Order", ignore_list(), true).unwrap();
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_lint_transform_reports_remaining_errors() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc)
+                return doc
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        let errors = lint_transform(&lua, &create_test_descriptor(), "Order", ignore_list(), false).unwrap();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["quantity", "reference"]);
+    }
+}
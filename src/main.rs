@@ -1,15 +1,22 @@
-mod args;
-mod fetch;
-mod valid_proto;
+use std::{cell::RefCell, io::IsTerminal, rc::Rc, sync::Arc, time::Duration};
 
-use std::{fs, path::Path, sync::Arc};
-
-use fetch::Fetch;
-use mlua::{Function, Lua};
-use protobuf::descriptor::FileDescriptorSet;
-use protobuf_parse::Parser;
-use reqwest::{Client, StatusCode};
-use serde_json::Value;
+use bulkmorph::{
+    args::{self, Mode},
+    check_schema,
+    checkpoint::Checkpoint,
+    client, couchdump, descriptor, dump_descriptor,
+    fetch::{self, Fetch},
+    lint,
+    pipeline::{self, Pipeline, RunStamp, Sampler, Sink},
+    script,
+    seen::{self, SeenFile},
+    try_doc, valid_proto,
+    workers::LuaPool,
+};
+use mlua::Lua;
+use protobuf::descriptor::{field_descriptor_proto::Type, FileDescriptorSet};
+use reqwest::Client;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() {
@@ -26,179 +33,579 @@ async fn main() {
     let db_host = args.db_url.clone();
     let table_name = args.table_name.clone();
     let ignore_list = args.ignore_list.clone();
-    let dry_run = args.dry_run;
-    let limit = args.limit;
-    let script_dir = args.script_dir.clone();
 
-    // Prepare Lua
-    let lua = Arc::new(mlua::Lua::new());
-
-    // load all include files
-    for entry in fs::read_dir(script_dir.clone() + "/include").unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-
-        if path.is_file() && path.extension() == Some("lua".as_ref()) {
-            println!("include folder {:?}", path);
+    // One HTTP client for every CouchDB request of the run, carrying the --header values, credentials and user agent
+    let mut headers = args.headers.clone();
+    if let (Some(username), Some(password)) = (&args.username, &args.password) {
+        headers.push(client::basic_auth_header(username, password));
+    }
+    let mut client = match client::build_client(&headers, args.user_agent.as_deref(), args.timeouts) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
+    let session_auth = args.session_auth && args.mode == Mode::Morph;
+    if let (true, Some(username), Some(password)) = (session_auth, &args.username, &args.password) {
+        // The session cookie replaces the basic auth header on every later request
+        let session = client::session_cookie_header(&client, &db_host, username, password).await;
+        headers.pop();
+        client = match session.and_then(|cookie| {
+            headers.push(cookie);
+            client::build_client(&headers, args.user_agent.as_deref(), args.timeouts)
+        }) {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return;
+            }
+        };
+    }
 
-            let result = lua.load(path.clone()).exec();
+    // Prepare protobuf
+    // Parse the .proto file, or load a prebuilt descriptor set once for the whole run
+    let file_descriptor_set = match &args.descriptor_set {
+        Some(source) => match client::build_schema_client(args.user_agent.as_deref(), args.timeouts) {
+            Ok(schema_client) => descriptor::load_descriptor_set(source, &schema_client).await,
+            Err(err) => Err(err),
+        },
+        None => descriptor::parse_proto(&args.proto_path, &args.proto_dir, args.proto_root.as_deref()),
+    };
+    let file_descriptor_set = match file_descriptor_set {
+        Ok(file_descriptor_set) => Arc::new(file_descriptor_set),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
 
-            match result {
-                Ok(()) => println!("Successfully loaded script {:?}", path),
-                Err(err) => eprintln!("problem with {:?} - Error: {}", path, err),
+    if args.mode == Mode::DumpDescriptor {
+        let dump = dump_descriptor::dump(&file_descriptor_set, args.dump_format);
+        let written = match &args.dump_output {
+            Some(path) => std::fs::write(path, dump)
+                .map_err(|e| format!("Unable to write descriptor dump {:?} - {}", path, e)),
+            None => {
+                print!("{}", dump);
+                Ok(())
             }
+        };
+        if let Err(err) = written {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
+        return;
     }
 
-    // Validate that we have a valid lua script to transform the JSON input
-    // A valid transformation requires proto file named with lua name
-    // Example: Transaction.proto and Transaction.lua
-    let lua_script = script_dir.clone() + "/" + &table_name + ".lua";
-    if !fs::metadata(lua_script.clone()).is_ok() {
-        eprintln!("Error: Lua script {:?} not found", lua_script);
-        return;
+    if args.mode == Mode::CheckSchema {
+        let table_name = Some(table_name.as_str()).filter(|table_name| !table_name.is_empty());
+        let pitfalls = check_schema::check_schema(&file_descriptor_set, table_name);
+        if pitfalls.is_empty() {
+            println!("No schema pitfalls found");
+            return;
+        }
+        for pitfall in &pitfalls {
+            println!("Warning: {}", pitfall);
+        }
+        std::process::exit(1);
     }
 
-    println!("loading lua script {:?}", lua_script);
-    let path = Path::new(&lua_script);
-    let result = lua.load(path).exec();
-    match result {
-        Ok(()) => println!("Successfully loaded script {:?}", lua_script),
-        Err(err) => {
-            eprintln!("problem with {:?} - Error: {}", lua_script, err);
+    // a misspelled output message would reject every transform output
+    if let Some(output_message) = &args.output_message {
+        if !valid_proto::build_message_types(&file_descriptor_set).contains_key(&output_message.to_lowercase()) {
+            eprintln!("Error: --output-message {:?} is not a message of the schema", output_message);
             return;
         }
     }
 
-    // ensure that the lua script has a transform function
-    let result: Result<mlua::Function, mlua::Error> = lua.globals().get("transform");
-    match result {
-        Ok(_) => println!(
-            "Successfully loaded transform function from {:?}",
-            lua_script
-        ),
-        Err(err) => {
-            eprintln!("Error: transform function not found - {}", err);
+    // --ignore names, on top of the CouchDB metadata fields
+    let ignore_list = valid_proto::ignore_list(&ignore_list);
+
+    // The subcommands work on a single table
+    if args.mode != Mode::Morph {
+        let (message_name, lua) = match prepare_table(&args, &table_name, &file_descriptor_set) {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return;
+            }
+        };
+        run_subcommand(&args, &table_name, &message_name, &lua, &file_descriptor_set, ignore_list);
+        return;
+    }
+
+    // One run id shared by every table
+    let run_stamp = args
+        .run_id_field
+        .as_ref()
+        .map(|run_id_field| RunStamp::new(run_id_field, args.run_id.clone()));
+    if let Some(run_stamp) = &run_stamp {
+        println!("Run id {}", run_stamp.run_id());
+    }
+
+    // a table without a message fails before any table is scanned
+    for table_name in &args.tables {
+        if let Err(err) = resolve_message(&args, table_name, &file_descriptor_set) {
+            eprintln!("Error: {}", err);
             return;
         }
     }
 
-    // Prepare protobuf
-    // Parse the .proto file into a FileDescriptorSet
-    let file_descriptor_set: FileDescriptorSet = Parser::new()
-        .pure()
-        .inputs(&[args.proto_path])
-        .includes(&[args.proto_dir])
-        .file_descriptor_set()
-        .unwrap();
-    let file_descriptor_set = Arc::new(file_descriptor_set);
-
-    let fetcher = Fetch::new(&db_host, &table_name, limit);
-
-    // fields to ignore because of couchdb metadata
-    // let ignore_list = vec!["_id".to_string(), "_rev".to_string()];
-
-    // convert ignore list to a vector of strings
-    let ignore_list: Vec<String> = ignore_list.split(',').map(|s| s.to_string()).collect();
-
-    fetcher
-        .with_callback(Box::new({
-            let file_descriptor_set: Arc<FileDescriptorSet> = Arc::clone(&file_descriptor_set);
-            move |doc| {
-                let err = valid_proto::validate_json(&file_descriptor_set, &table_name, &doc, ignore_list.clone());
-                if err.len() > 0 {
-                    // println!("{} will be updated because it does not match the schema", doc["_id"]);
-                        let doc_clone = doc.clone();
-                    let result = lua_transform(&lua.clone(), doc_clone);
-                    match result {
-                        Ok(transformed_doc) => {
-                            // validate the transformed document again, if it is still invalid, return
-                            let err = valid_proto::validate_json(&file_descriptor_set, &table_name, &transformed_doc, ignore_list.clone());
-                            if err.len() > 0 {
-                                if !args.stat {
-                                    println!();
-                                    println!("{} will not be updated because it still does not match the schema after transform", doc["_id"]);
-                                    for e in err {
-                                        println!("Error: {} - {:?}", e.field, e.error_type);
-                                    }
-                                    println!("---------------------------------");
-                                } else {
-                                    println!("{}", doc["_id"].as_str().unwrap());
-                                }
-                                return;
-                            } else if !dry_run {
-                                let dbhost_clone = db_host.clone();
-                                let table_name = table_name.clone();
-
-                                tokio::task::block_in_place(|| {
-                                    let rt = tokio::runtime::Runtime::new().unwrap();
-                                    rt.block_on(async move {
-                                        let client = Client::new();
-                                        if let Err(e) = update_document(&client, &dbhost_clone, &table_name, &transformed_doc).await {
-                                            eprintln!("Failed to update document {}: {}", doc["_id"], e);
-                                        } else {
-                                            println!("{} updated successfully", doc["_id"]);
-                                        }
-                                    });
-                                });
-                            } else {
-                                println!("{} will be updated", doc["_id"]);
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!("Error: {}", err);
-                            return;
-                        },
-                    }
-                }
+    // Tables are processed one after the other, sharing the client and the descriptor set
+    for table_name in &args.tables {
+        if args.tables.len() > 1 {
+            println!("Processing table {}", table_name);
+        }
+        match morph_table(&args, table_name, &client, &file_descriptor_set, &ignore_list, run_stamp.clone()).await {
+            Ok(false) => (),
+            Ok(true) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return;
             }
-        })) // closure to be called for each document
-        .execute()
-        .await;
+        }
+    }
 }
 
-// Execute transformation on the JSON input using the Lua script
-fn lua_transform(lua: &Lua, doc: Value) -> Result<Value, Box<dyn std::error::Error>> {
-    // Get the Lua transform method
-    let transform: Function = lua.globals().get("transform")?;
+/// Message the documents of a table are validated against, the table name unless
+/// --auto-message falls back to the only message of a single-message proto.
+fn resolve_message(
+    args: &args::Args,
+    table_name: &str,
+    file_descriptor_set: &FileDescriptorSet,
+) -> Result<String, String> {
+    let mut message_name = table_name.to_string();
+    if args.auto_message {
+        if let Some(auto_message) = valid_proto::auto_message(file_descriptor_set, table_name) {
+            message_name = auto_message;
+        }
+    }
+    valid_proto::check_message(file_descriptor_set, &message_name)?;
+    Ok(message_name)
+}
 
-    let input_json = doc.to_string();
+/// Message and Lua state of a table: the table script is loaded into its own state, as every
+/// script defines the same global functions.
+fn prepare_table(
+    args: &args::Args,
+    table_name: &str,
+    file_descriptor_set: &FileDescriptorSet,
+) -> Result<(String, Rc<Lua>), String> {
+    let message_name = resolve_message(args, table_name, file_descriptor_set)?;
+    if message_name != table_name {
+        eprintln!(
+            "Warning: no message named {:?}, validating against {}, the only message of the proto",
+            table_name, message_name
+        );
+    }
 
-    // Call the Lua function with the JSON input
-    let output_str: String = transform.call(input_json)?;
+    // Prepare Lua
+    let lua = Rc::new(script::load_lua(&args.script_dir, &args.lua_libs, table_name, args.sandbox)?);
 
-    return serde_json::from_str(&output_str).map_err(|e| e.into());
+    // the mask must lead to a nested message, anything else would validate nothing
+    if let Some(mask) = &args.field_mask {
+        let field = valid_proto::resolve_field(file_descriptor_set, &message_name, mask);
+        if !field.is_some_and(|field| field.type_() == Type::TYPE_MESSAGE) {
+            return Err(format!("--field-mask {:?} is not a message field of {}", mask, message_name));
+        }
+    }
+
+    Ok((message_name, lua))
 }
 
-/// Persists changes to a document in CouchDB when the dry-run mode is disabled.
-async fn update_document(
-    client: &Client,
-    db_host: &str,
+/// Runs the lint-transform or try subcommand on the table, exiting with 1 when the
+/// transform output does not match the schema.
+fn run_subcommand(
+    args: &args::Args,
+    table_name: &str,
+    message_name: &str,
+    lua: &Lua,
+    file_descriptor_set: &FileDescriptorSet,
+    ignore_list: Vec<String>,
+) {
+    if args.mode == Mode::LintTransform {
+        match lint::lint_transform(lua, file_descriptor_set, message_name, ignore_list, args.string_transform) {
+            Ok(errors) if errors.is_empty() => {
+                println!("{} transform produces a valid document from the synthetic fixture", table_name);
+            }
+            Ok(errors) => {
+                println!("{} transform output still does not match the schema", table_name);
+                for e in errors {
+                    println!("Error: {}", e);
+                }
+                std::process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.mode == Mode::Try {
+        let doc = match &args.doc {
+            Some(doc) => Ok(doc.clone()),
+            None => std::io::read_to_string(std::io::stdin()).map_err(|e| e.to_string()),
+        };
+        let doc = match doc.and_then(|doc| {
+            serde_json::from_str(&doc).map_err(|e| format!("Invalid document - {}", e))
+        }) {
+            Ok(doc) => doc,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+        let mut stdout = std::io::stdout();
+        let verdict = try_doc::try_document(
+            lua,
+            file_descriptor_set,
+            message_name,
+            ignore_list,
+            doc,
+            args.string_transform,
+            &mut stdout,
+        );
+        match verdict {
+            Ok(try_doc::Verdict::StillInvalid) => std::process::exit(1),
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Fetches, validates, transforms and updates the documents of one table, then writes the
+/// end-of-run outputs. Returns whether the run was aborted, its scan stopped on an error or
+/// its written documents could not be flushed.
+async fn morph_table(
+    args: &args::Args,
     table_name: &str,
-    doc: &Value,
-) -> Result<(), String> {
-    let id = doc["_id"].as_str().ok_or("Document missing '_id' field")?;
-    let rev = doc["_rev"]
-        .as_str()
-        .ok_or("Document missing '_rev' field")?;
-    let idencoded = urlencoding::encode(id);
-    let url = format!("{}/{}/{}", db_host, table_name, idencoded);
-
-    let response = client
-        .put(&url)
-        .json(doc)
-        .header("If-Match", rev)
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
-
-    if response.status() != StatusCode::OK && response.status() != StatusCode::CREATED {
-        return Err(format!(
-            "Failed to update document {}: Status code {}",
-            id,
-            response.status()
-        ));
-    }
-
-    Ok(())
+    client: &Client,
+    file_descriptor_set: &Arc<FileDescriptorSet>,
+    ignore_list: &[String],
+    run_stamp: Option<RunStamp>,
+) -> Result<bool, String> {
+    let db_host = args.db_url.clone();
+    let dry_run = args.dry_run;
+    let limit = args.limit;
+    let script_dir = args.script_dir.clone();
+    let (message_name, lua) = prepare_table(args, table_name, file_descriptor_set)?;
+
+    let options = pipeline::Options {
+        dry_run,
+        stat: args.stat,
+        assert_output_shape: args.assert_output_shape,
+        min_key_overlap: args.min_key_overlap,
+        on_invalid: args.on_invalid,
+        format: args.format,
+        validation: valid_proto::ValidationOptions {
+            strict_strings: args.strict_strings,
+            only_fields: args
+                .only_fields
+                .split(',')
+                .filter(|field| !field.is_empty())
+                .map(|field| field.to_string())
+                .collect(),
+            unknown_enum_ok: args.unknown_enum_ok,
+            consistent_arrays: args.consistent_arrays,
+            ranges: args.ranges.clone(),
+            strict_resolution: args.strict_resolution,
+            enum_case: args.enum_case,
+            field_mask: args.field_mask.clone(),
+            timestamp_format: args.timestamp_format,
+            require_nonempty_messages: args.require_nonempty_messages,
+            strict_integer_format: args.strict_integer_format,
+            max_depth: Some(args.max_depth),
+            strict_required: args.strict_required,
+            field_name_mode: args.field_name_mode,
+        },
+        auto_coerce: args.auto_coerce,
+        fill_defaults: args.fill_defaults,
+        report_fixes: args.report_fixes,
+        transform_valid_if_changed: args.transform_valid_if_changed,
+        max_doc_bytes: args.max_doc_bytes,
+        wrong_type_ratio: args.wrong_type_ratio,
+        verify_updates: args.verify_updates,
+        defer_conflicts: args.defer_conflicts,
+        unflatten: args.unflatten,
+        bulk_size: args.bulk_size,
+        max_concurrency: args.max_concurrency,
+        conflict_retries: args.conflict_retries,
+        output_message: args.output_message.clone(),
+        string_transform: args.string_transform,
+        show_diff: args.show_diff,
+    };
+
+    // Offline mode writes results to a file, a rehearsal to the shadow table,
+    // otherwise documents are updated in CouchDB
+    let sink = match (&args.output_path, &args.shadow_table) {
+        (Some(output_path), _) => match Sink::file(output_path) {
+            Ok(sink) => sink,
+            Err(err) => return Err(err),
+        },
+        (None, Some(shadow_table)) => Sink::Shadow {
+            client: client.clone(),
+            db_host: db_host.clone(),
+            shadow_table: shadow_table.clone(),
+        },
+        (None, None) => Sink::CouchDb {
+            client: client.clone(),
+            db_host: db_host.clone(),
+            table_name: table_name.to_string(),
+            preserve_revs: args.preserve_revs,
+        },
+    };
+    let mut pipeline = Pipeline::new(
+        Rc::clone(&lua),
+        Arc::clone(file_descriptor_set),
+        &message_name,
+        ignore_list.to_vec(),
+        options,
+        sink,
+    );
+    if args.report.is_some() {
+        pipeline = pipeline.with_report();
+    }
+
+    if let Some(seen_path) = &args.seen_file {
+        match SeenFile::load(seen_path) {
+            Ok(seen) => pipeline = pipeline.with_seen(seen),
+            Err(err) => return Err(err),
+        }
+    }
+    if args.reset_lua_per_doc {
+        let script_dir = script_dir.clone();
+        let lua_libs = args.lua_libs.clone();
+        let table_name = table_name.to_string();
+        let sandbox = args.sandbox;
+        pipeline = pipeline.with_fresh_lua_per_doc(Box::new(move || {
+            script::reload_lua(&script_dir, &lua_libs, &table_name, sandbox)
+        }));
+    }
+    if let Some(skip_ids_path) = &args.skip_ids_file {
+        match seen::load_ids(skip_ids_path) {
+            Ok(skip_ids) => pipeline = pipeline.with_skip_ids(skip_ids),
+            Err(err) => return Err(err),
+        }
+    }
+    if let Some(dump_path) = &args.dump_invalid {
+        match pipeline.with_dump_invalid(dump_path) {
+            Ok(with_dump) => pipeline = with_dump,
+            Err(err) => return Err(err),
+        }
+    }
+    if let Some(dead_letter_path) = &args.dead_letter {
+        match pipeline.with_dead_letter(dead_letter_path) {
+            Ok(with_dead_letter) => pipeline = with_dead_letter,
+            Err(err) => return Err(err),
+        }
+    }
+    if let Some(run_stamp) = run_stamp {
+        pipeline = pipeline.with_run_stamp(run_stamp);
+    }
+    if args.input_dump.is_some() {
+        pipeline = pipeline.with_duplicate_check();
+    }
+    if let Some(max_rate) = args.abort_if_invalid_rate {
+        pipeline = pipeline.with_max_invalid_rate(max_rate, args.abort_warmup);
+    }
+    if let Some(sample_rate) = args.sample_rate {
+        pipeline = pipeline.with_sampler(Sampler::new(sample_rate, args.seed));
+    }
+    if let Some(lua_workers) = args.lua_workers {
+        let script_dir = script_dir.clone();
+        let lua_libs = args.lua_libs.clone();
+        let table_name = table_name.to_string();
+        let sandbox = args.sandbox;
+        match LuaPool::new(
+            lua_workers,
+            args.string_transform,
+            Arc::new(move || script::reload_lua(&script_dir, &lua_libs, &table_name, sandbox)),
+        ) {
+            Ok(lua_pool) => pipeline = pipeline.with_lua_pool(lua_pool),
+            Err(err) => return Err(err),
+        }
+    }
+    let pipeline = Rc::new(pipeline);
+
+    // A read error ends the scan early, the documents processed so far are still written and reported
+    let mut scan_failed = false;
+    if let Some(input_dump) = &args.input_dump {
+        // Documents are handed to the pipeline in batches of --limit, for --lua-workers
+        let batch = RefCell::new(Vec::new());
+        let result = couchdump::read_dump(input_dump, |doc| {
+            batch.borrow_mut().push(doc);
+            if batch.borrow().len() >= limit {
+                pipeline.process_batch(batch.take());
+            }
+        });
+        // the documents read before an error are processed as well
+        pipeline.process_batch(batch.take());
+        match result {
+            Ok(count) => println!("Processed {} documents from {:?}", count, input_dump),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                scan_failed = true;
+            }
+        }
+    } else {
+        // Cancelled by --max-docs inside the fetcher or by the --timeout timer
+        let cancel = CancellationToken::new();
+        if let Some(timeout) = args.timeout {
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(timeout)).await;
+                println!("Timeout of {} seconds reached, stopping", timeout);
+                cancel.cancel();
+            });
+        }
+
+        // Counts of the interrupted run are carried over into the next checkpoints
+        let resumed = match (&args.checkpoint, args.resume) {
+            (Some(checkpoint_path), true) => match Checkpoint::load(checkpoint_path) {
+                Ok(resumed) => {
+                    println!(
+                        "Resuming after {} documents ({} updated)",
+                        resumed.processed, resumed.updated
+                    );
+                    resumed
+                }
+                Err(err) => return Err(err),
+            },
+            _ => Checkpoint::default(),
+        };
+
+        // One bar for every shard, plain lines when piped or logged
+        let progress = (!args.no_progress && std::io::stdout().is_terminal()).then(fetch::progress_bar);
+        if let Some(progress) = &progress {
+            progress.set_position(resumed.processed as u64);
+        }
+
+        // --shards splits the ids into disjoint ranges fetched concurrently
+        let id_ranges = match args.shards {
+            Some(shards) => fetch::shard_ranges(shards),
+            None => vec![(args.id_range_start.clone(), args.id_range_end.clone())],
+        };
+
+        let mut fetchers: Vec<Fetch> = id_ranges
+            .into_iter()
+            .map(|(id_range_start, id_range_end)| {
+                let mut fetcher = Fetch::new(&db_host, table_name, limit)
+                    .with_client(client.clone())
+                    .with_cancellation(cancel.clone())
+                    .with_max_docs(args.max_docs)
+                    .with_fetch_rate(args.fetch_rate)
+                    .with_execution_stats(args.exec_stats)
+                    .with_id_range(id_range_start, id_range_end)
+                    .with_retry(args.max_retries, Duration::from_millis(args.retry_base_ms))
+                    .with_selector(args.selector.clone())
+                    .with_progress(progress.clone())
+                    .with_bookmark(resumed.bookmark.clone());
+                if let Some(checkpoint_path) = args.checkpoint.clone() {
+                    fetcher = fetcher.with_bookmark_callback(Box::new({
+                        let pipeline = Rc::clone(&pipeline);
+                        let resumed = resumed.clone();
+                        move |bookmark| {
+                            // buffered updates are written first, the checkpoint must not skip them
+                            pipeline.flush_updates();
+                            let stats = pipeline.stats();
+                            let checkpoint = Checkpoint {
+                                bookmark: Some(bookmark.to_string()),
+                                processed: resumed.processed + stats.scanned,
+                                updated: resumed.updated + stats.updated,
+                            };
+                            if let Err(err) = checkpoint.save(&checkpoint_path) {
+                                eprintln!("Error: {}", err);
+                            }
+                        }
+                    }));
+                }
+
+                // Documents of a page are collected, then processed together before on_batch
+                let batch = Rc::new(RefCell::new(Vec::new()));
+                fetcher
+                    .with_callback(Box::new({
+                        let batch = Rc::clone(&batch);
+                        move |doc| batch.borrow_mut().push(doc)
+                    })) // closure to be called for each document
+                    .with_batch_callback(Box::new({
+                        let pipeline = Rc::clone(&pipeline);
+                        let cancel = cancel.clone();
+                        let lua = Rc::clone(&lua);
+                        move |ids| {
+                            pipeline.process_batch(batch.take());
+                            if pipeline.is_aborted() {
+                                cancel.cancel();
+                            }
+                            if let Err(err) = script::lua_on_batch(&lua, ids) {
+                                eprintln!("Error: on_batch failed - {}", err);
+                            }
+                        }
+                    }))
+            })
+            .collect();
+
+        // Every shard runs to its own end, the pipeline processes the pages as they arrive
+        let results = futures_util::future::join_all(fetchers.iter_mut().map(|fetcher| fetcher.execute())).await;
+        for err in results.into_iter().filter_map(Result::err) {
+            eprintln!("Error: {}", err);
+            scan_failed = true;
+        }
+        if let Some(progress) = &progress {
+            progress.finish();
+        }
+    }
+
+    // the last updates, fewer than --bulk-size
+    pipeline.flush_updates();
+    if !pipeline.is_aborted() {
+        pipeline.retry_conflicts();
+    }
+
+    // an output or seen file left unflushed loses documents, the run fails like a scan error
+    let finish_failed = match pipeline.finish() {
+        Ok(()) => false,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            true
+        }
+    };
+    if let Some(shares) = pipeline.worker_shares() {
+        println!("Documents transformed per Lua worker: {:?}", shares);
+    }
+
+    if let Some(summary_path) = &args.summary_json {
+        if let Err(err) = pipeline.stats().write_json(summary_path) {
+            eprintln!("Error: {}", err);
+        }
+    }
+    // --stat survey: how widespread each error type is
+    if args.stat {
+        println!("Invalid documents by error type:");
+        for (error_type, count) in pipeline.error_histogram().documents_by_type() {
+            println!("  {}: {}", error_type, count);
+        }
+    }
+    if let Some(csv_path) = &args.csv_out {
+        if let Err(err) = pipeline.error_histogram().write_csv(csv_path) {
+            eprintln!("Error: {}", err);
+        }
+    }
+    if let (Some(report_path), Some(report)) = (&args.report, pipeline.report()) {
+        match report.write_json(report_path) {
+            Ok(()) => println!("Report of {} documents written to {}", report.records().len(), report_path),
+            Err(err) => eprintln!("Error: {}", err),
+        }
+    }
+
+    println!("{}", pipeline.stats());
+    if scan_failed {
+        eprintln!("Error: the scan of {} stopped early, not every document was processed", table_name);
+    }
+    Ok(pipeline.is_aborted() || scan_failed || finish_failed)
 }
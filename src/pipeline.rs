@@ -0,0 +1,2317 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
+};
+
+use mlua::Lua;
+use protobuf::descriptor::FileDescriptorSet;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::sync::{
+    mpsc::{error::TrySendError, Sender},
+    Semaphore,
+};
+
+use crate::{
+    coerce, diff, guard, script,
+    seen::SeenFile,
+    stats::{DocumentReport, ErrorHistogram, RunStats},
+    unflatten, update, valid_proto,
+    workers::LuaPool,
+};
+
+/// Destination for documents that validate after the transform.
+pub enum Sink {
+    /// Update the document in place in CouchDB
+    CouchDb {
+        client: Client,
+        db_host: String,
+        table_name: String,
+        preserve_revs: bool, // Write through _bulk_docs with new_edits=false
+    },
+    /// Append the transformed document as a JSON line (offline processing)
+    File(RefCell<BufWriter<File>>),
+    /// Write the transformed document, without `_rev`, to a separate table (rehearsal)
+    Shadow {
+        client: Client,
+        db_host: String,
+        shadow_table: String,
+    },
+}
+
+impl Sink {
+    /// Opens (truncating) an output file for offline processing.
+    pub fn file(path: &str) -> Result<Sink, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Unable to create output file {:?} - {}", path, e))?;
+        Ok(Sink::File(RefCell::new(BufWriter::new(file))))
+    }
+
+    fn write(&self, doc: &Value) -> Result<(), String> {
+        block_on(self.send(doc))
+    }
+
+    /// Writes a document from async code, see `send_concurrently`.
+    async fn send(&self, doc: &Value) -> Result<(), String> {
+        match self {
+            Sink::CouchDb {
+                client,
+                db_host,
+                table_name,
+                preserve_revs: true,
+            } => update::bulk_update(client, db_host, table_name, std::slice::from_ref(doc), true).await,
+            Sink::CouchDb {
+                client,
+                db_host,
+                table_name,
+                ..
+            } => update::update_document(client, db_host, table_name, doc).await,
+            Sink::File(writer) => {
+                let mut writer = writer.borrow_mut();
+                writeln!(writer, "{}", doc).map_err(|e| e.to_string())
+            }
+            Sink::Shadow {
+                client,
+                db_host,
+                shadow_table,
+            } => update::write_shadow_document(client, db_host, shadow_table, doc).await,
+        }
+    }
+
+    /// Whether the updates of a batch can be sent at the same time, see `--max-concurrency`.
+    fn writes_concurrently(&self) -> bool {
+        !matches!(self, Sink::File(_))
+    }
+
+    /// Whether updates can be buffered and written together through `_bulk_docs`.
+    fn writes_in_bulk(&self) -> bool {
+        matches!(self, Sink::CouchDb { .. })
+    }
+
+    /// Writes many documents in one request, see `writes_in_bulk`.
+    fn write_bulk(&self, docs: &[Value]) -> Result<update::BulkResults, String> {
+        match self {
+            Sink::CouchDb {
+                client,
+                db_host,
+                table_name,
+                preserve_revs,
+            } => block_on(update::bulk_update_documents(client, db_host, table_name, docs, *preserve_revs)),
+            _ => Err("bulk writes are only supported for CouchDB updates".to_string()),
+        }
+    }
+
+    /// Reads a written document back, `None` when the sink cannot be read from.
+    fn read(&self, id: &str) -> Option<Result<Value, String>> {
+        match self {
+            Sink::CouchDb {
+                client,
+                db_host,
+                table_name,
+                ..
+            } => Some(block_on(update::get_document(client, db_host, table_name, id))),
+            Sink::File(_) => None,
+            Sink::Shadow {
+                client,
+                db_host,
+                shadow_table,
+            } => Some(block_on(update::get_document(client, db_host, shadow_table, id))),
+        }
+    }
+
+    fn delete(&self, doc: &Value) -> Result<(), String> {
+        match self {
+            Sink::CouchDb {
+                client,
+                db_host,
+                table_name,
+                ..
+            } => block_on(update::delete_document(client, db_host, table_name, doc)),
+            Sink::File(writer) => {
+                // Offline output records the deletion as a CouchDB tombstone
+                let tombstone = json!({"_id": doc["_id"], "_rev": doc["_rev"], "_deleted": true});
+                let mut writer = writer.borrow_mut();
+                writeln!(writer, "{}", tombstone).map_err(|e| e.to_string())
+            }
+            // Rejected at startup, a rehearsal never deletes
+            Sink::Shadow { .. } => Err("deletions are not rehearsed in the shadow table".to_string()),
+        }
+    }
+
+    /// Flushes buffered output, if any.
+    pub fn flush(&self) -> Result<(), String> {
+        match self {
+            Sink::CouchDb { .. } | Sink::Shadow { .. } => Ok(()),
+            Sink::File(writer) => writer.borrow_mut().flush().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Runs an async CouchDB request to completion from the synchronous document callback.
+/// The request runs on the current runtime, so the shared client keeps its connections.
+/// Writes are never left in flight: a document is reported as updated only once CouchDB
+/// answered, and nothing is pending when the fetcher returns.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+/// Policy for documents that still do not match the schema after the transform.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OnInvalid {
+    #[default]
+    Skip, // Print the remaining errors and leave the document untouched
+    Report, // Only write the document id and errors to the errors sink (stderr)
+    Delete, // Mark the document as deleted, for genuinely unsalvageable records
+}
+
+impl std::str::FromStr for OnInvalid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnInvalid::Skip),
+            "report" => Ok(OnInvalid::Report),
+            "delete" => Ok(OnInvalid::Delete),
+            _ => Err(format!("Unknown --on-invalid policy {:?}", s)),
+        }
+    }
+}
+
+/// How validation errors are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text, // Human-readable listing
+    Json, // One JSON object per document
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown --format {:?}", s)),
+        }
+    }
+}
+
+/// Builds the machine-readable error record of a document.
+fn error_record(doc: &Value, err: &[valid_proto::ValidationError]) -> Value {
+    json!({"_id": doc["_id"], "errors": err})
+}
+
+/// What to do with a document that stays invalid after the transform.
+#[derive(Debug, PartialEq)]
+enum InvalidAction {
+    Print,         // Human-readable error listing on stdout
+    Report,        // Single JSON line on stderr
+    PreviewDelete, // Announce the deletion without performing it
+    Delete,        // Delete the document through the sink
+}
+
+fn invalid_action(policy: OnInvalid, dry_run: bool) -> InvalidAction {
+    match policy {
+        OnInvalid::Skip => InvalidAction::Print,
+        OnInvalid::Report => InvalidAction::Report,
+        OnInvalid::Delete if dry_run => InvalidAction::PreviewDelete,
+        OnInvalid::Delete => InvalidAction::Delete,
+    }
+}
+
+/// Switches that shape how each document is handled.
+#[derive(Default)]
+pub struct Options {
+    pub dry_run: bool,             // Preview changes without writing to the sink
+    pub stat: bool,                // Survey mode: print the ids of invalid documents, transform and write nothing
+    pub assert_output_shape: bool, // Refuse transform outputs that no longer resemble the input
+    pub min_key_overlap: f64,      // Minimum share of input keys the transform output must keep
+    pub on_invalid: OnInvalid,     // Policy for documents still invalid after the transform
+    pub format: OutputFormat,      // How remaining validation errors are printed
+    pub validation: valid_proto::ValidationOptions, // Optional validator checks
+    pub auto_coerce: bool,         // Fix simple type mismatches before calling Lua
+    pub fill_defaults: bool,       // Set missing scalar fields to their proto default before calling Lua
+    pub report_fixes: bool,        // Print the fields changed by each repair with a confidence label
+    pub transform_valid_if_changed: bool, // Also transform valid documents, writing only changed outputs
+    pub max_doc_bytes: Option<usize>, // Largest document size that may be written
+    pub wrong_type_ratio: Option<f64>, // Unknown to matching fields ratio above which a document is of another type
+    pub verify_updates: bool,      // Read updated documents back and validate them again
+    pub unflatten: bool,           // Rewrite dotted top-level keys into nested objects before validation
+    pub defer_conflicts: bool,     // Retry conflicting updates once, after the main scan
+    pub bulk_size: Option<usize>,  // Buffer updates and write them through _bulk_docs in batches of this size
+    pub max_concurrency: usize,    // Updates of a batch sent at the same time; 0 or 1 sends them one by one
+    pub conflict_retries: usize,   // Immediate retries of a conflicting update with the current revision
+    pub output_message: Option<String>, // Message written documents must match, when the transform migrates them
+    pub string_transform: bool,    // Hand documents to transform, enrich and approve as JSON text instead of Lua tables
+    pub show_diff: bool,           // In dry-run mode, print the fields each update would add, remove or change
+}
+
+/// What happened to a single document.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Valid,           // Already matches the schema, nothing to do
+    WouldUpdate,     // Transform fixed it, but dry-run prevented the write
+    Updated,         // Transform fixed it and the result was written
+    StillInvalid,    // Transform output still does not match the schema
+    Rejected,        // Transform output failed a safety check or was vetoed by approve
+    TransformFailed, // Lua transform raised an error or returned invalid JSON
+    WriteFailed,     // Writing the transformed document failed
+    WouldDelete,     // Still invalid and would be deleted, but dry-run prevented it
+    Deleted,         // Still invalid and marked as deleted
+    Unchanged,       // Same revision already validated in a previous run
+    Dumped,          // Invalid document exported by --dump-invalid, not transformed
+    Skipped,         // Id listed in --skip-ids-file, not even validated
+    AlreadyStamped,  // Already written by an earlier attempt of the same run
+    Aborted,         // Not processed, the run was aborted by --abort-if-invalid-rate
+    TooLarge,        // Repaired document exceeds --max-doc-bytes, not written
+    Deferred,        // Needs the transform, but left out of this run by --sample-rate
+    LikelyWrongType, // Mostly unknown fields, probably another message type; not transformed
+    VerificationFailed, // Written, but the document read back does not validate
+    ConflictDeferred, // Write conflicted, retried after the main scan by --defer-conflicts
+    DuplicateId,     // Id already processed earlier in the run, e.g. twice in an input dump; skipped
+    Buffered,        // Repaired, written with the next _bulk_docs request (--bulk-size)
+    Listed,          // Invalid, id printed by --stat, not transformed
+}
+
+/// Progress of a single document, sent to an embedding application. Each event carries
+/// the document id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessEvent {
+    Fetched(String),     // Received by the pipeline
+    Validated(String),   // Already matches the schema
+    Transformed(String), // Repaired, whether written or not (dry-run)
+    Updated(String),     // Repaired document written to the sink
+    Failed(String),      // Still invalid, or the transform, a safety check or the write failed
+}
+
+/// Picks a random fraction of the documents, for canary runs of a risky transform.
+pub struct Sampler {
+    rate: f64, // Share of the documents picked, between 0.0 and 1.0
+    rng: RefCell<StdRng>,
+}
+
+impl Sampler {
+    /// A seed makes the selection reproducible from one run to the next.
+    pub fn new(rate: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Sampler {
+            rate,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    /// Whether the next document is part of the sample.
+    fn pick(&self) -> bool {
+        self.rng.borrow_mut().gen::<f64>() < self.rate
+    }
+}
+
+/// Marks every written document with the id of the run, so a retried run can skip the
+/// documents an earlier attempt already updated.
+#[derive(Clone)]
+pub struct RunStamp {
+    field: String,  // Document field holding the run id
+    run_id: String, // Id shared by the attempts of one run
+}
+
+impl RunStamp {
+    /// Without a run id, a random UUID is generated.
+    pub fn new(field: &str, run_id: Option<String>) -> Self {
+        RunStamp {
+            field: field.to_string(),
+            run_id: run_id.unwrap_or_else(random_uuid),
+        }
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Whether the document was written by this run.
+    fn is_stamped(&self, doc: &Value) -> bool {
+        doc[&self.field].as_str() == Some(self.run_id.as_str())
+    }
+
+    fn stamp(&self, doc: &Value) -> Value {
+        let mut stamped = doc.clone();
+        if let Some(fields) = stamped.as_object_mut() {
+            fields.insert(self.field.clone(), Value::String(self.run_id.clone()));
+        }
+        stamped
+    }
+}
+
+/// Random (version 4) UUID, e.g. `9b2e4c1a-6f0d-4d3b-8a57-0c9e2f4b7d61`.
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Where a document stands once the steps before the transform ran.
+enum Step {
+    Done(Outcome), // Settled without the transform
+    Transform {
+        doc: Value,              // Document as validated, after enrich and unflatten
+        lua_input: Value,        // Document handed to the transform, possibly coerced
+        fresh_lua: Option<Lua>,  // State created for this document by --reset-lua-per-doc
+        fixes: Vec<coerce::Fix>, // Fields already fixed by --fill-defaults and --auto-coerce
+        errors_json: String,     // Validation errors of the document, the second argument of transform
+    },
+}
+
+/// Creates a Lua state with the scripts loaded.
+pub type LuaFactory = Box<dyn Fn() -> Result<Lua, String>>;
+
+/// Validates, transforms and writes back documents one at a time.
+pub struct Pipeline {
+    lua: Rc<Lua>,
+    file_descriptor_set: Arc<FileDescriptorSet>,
+    table_name: String,
+    ignore_list: Vec<String>,
+    options: Options,
+    sink: Sink,
+    seen: Option<SeenFile>,
+    dump_invalid: Option<RefCell<BufWriter<File>>>, // Forensic export of invalid documents
+    dead_letter: Option<RefCell<BufWriter<File>>>, // Documents still invalid after the transform, for a later run
+    lua_factory: Option<LuaFactory>, // Builds a fresh Lua state for every document
+    skip_ids: HashSet<String>,       // Ids skipped before validation
+    sampler: Option<Sampler>,        // Only transforms a random fraction of the invalid documents
+    events: Option<Sender<ProcessEvent>>, // Live progress for an embedding application
+    dropped_events: Cell<usize>,     // Events not sent because the channel was full
+    run_stamp: Option<RunStamp>,     // Run id written into every updated document
+    max_invalid_rate: Option<(f64, usize)>, // Invalid rate in percent that aborts the run, after a warmup
+    lua_pool: Option<LuaPool>,       // Worker threads transforming the documents of a batch in parallel
+    aborted: Cell<bool>,
+    conflicts: RefCell<Vec<String>>, // Ids whose update conflicted, retried by `retry_conflicts`
+    retrying: Cell<bool>,            // Conflicts are no longer deferred during the final pass
+    conflict_attempts: Cell<usize>,  // Nested immediate retries of the document being written
+    processed_ids: Option<RefCell<HashSet<String>>>, // Ids seen in this run, a later occurrence is a DuplicateId
+    pending_updates: RefCell<Vec<(Value, Value)>>, // Fetched and repaired documents waiting for --bulk-size or the end of the batch
+    batching: Cell<bool>,            // A batch is being processed, its updates are sent together at its end
+    write_permits: Semaphore,        // One per update in flight, --max-concurrency in total
+    stats: RefCell<RunStats>,
+    errors: RefCell<ErrorHistogram>, // Validation errors of the documents as fetched
+    report: Option<RefCell<DocumentReport>>, // Errors and outcome of every document, for --report
+    started: Instant,
+}
+
+impl Pipeline {
+    pub fn new(
+        lua: Rc<Lua>,
+        file_descriptor_set: Arc<FileDescriptorSet>,
+        table_name: &str,
+        ignore_list: Vec<String>,
+        options: Options,
+        sink: Sink,
+    ) -> Self {
+        let write_permits = Semaphore::new(options.max_concurrency.max(1));
+        Pipeline {
+            lua,
+            file_descriptor_set,
+            table_name: table_name.to_string(),
+            ignore_list,
+            options,
+            sink,
+            seen: None,
+            dump_invalid: None,
+            dead_letter: None,
+            lua_factory: None,
+            skip_ids: HashSet::new(),
+            sampler: None,
+            events: None,
+            dropped_events: Cell::new(0),
+            run_stamp: None,
+            max_invalid_rate: None,
+            lua_pool: None,
+            aborted: Cell::new(false),
+            conflicts: RefCell::new(Vec::new()),
+            retrying: Cell::new(false),
+            conflict_attempts: Cell::new(0),
+            processed_ids: None,
+            pending_updates: RefCell::new(Vec::new()),
+            batching: Cell::new(false),
+            write_permits,
+            stats: RefCell::new(RunStats::default()),
+            errors: RefCell::new(ErrorHistogram::default()),
+            report: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Keeps the errors and outcome of every document, see `report`.
+    pub fn with_report(mut self) -> Self {
+        self.report = Some(RefCell::new(DocumentReport::default()));
+        self
+    }
+
+    /// Skips revisions recorded in the seen file and records the ones that validate.
+    pub fn with_seen(mut self, seen: SeenFile) -> Self {
+        self.seen = Some(seen);
+        self
+    }
+
+    /// Skips the given ids entirely, e.g. the documents updated by an interrupted run.
+    pub fn with_skip_ids(mut self, skip_ids: HashSet<String>) -> Self {
+        self.skip_ids = skip_ids;
+        self
+    }
+
+    /// Exports every invalid document with its errors as a JSON line, instead of
+    /// transforming and updating it.
+    pub fn with_dump_invalid(mut self, path: &str) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Unable to create dump file {:?} - {}", path, e))?;
+        self.dump_invalid = Some(RefCell::new(BufWriter::new(file)));
+        Ok(self)
+    }
+
+    /// Appends every document still invalid after the transform, with its remaining errors,
+    /// as a JSON line, whatever the --on-invalid policy does with it.
+    pub fn with_dead_letter(mut self, path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Unable to open dead-letter file {:?} - {}", path, e))?;
+        self.dead_letter = Some(RefCell::new(BufWriter::new(file)));
+        Ok(self)
+    }
+
+    /// Runs every document in a fresh Lua state, so a document cannot leave global state
+    /// behind for the next one. Slower, as the scripts are loaded again for each document.
+    pub fn with_fresh_lua_per_doc(mut self, lua_factory: LuaFactory) -> Self {
+        self.lua_factory = Some(lua_factory);
+        self
+    }
+
+    /// Transforms and writes only the invalid documents picked by the sampler; the others
+    /// are counted as deferred and left for a later run.
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Stamps every written document with the run id and skips the documents already
+    /// stamped with it. The stamp field is ignored by the validation.
+    pub fn with_run_stamp(mut self, run_stamp: RunStamp) -> Self {
+        self.ignore_list.push(run_stamp.field.clone());
+        self.run_stamp = Some(run_stamp);
+        self
+    }
+
+    /// Skips and reports a document whose id was already processed earlier in the run, e.g.
+    /// twice in a dump. Every id is kept in memory until the end of the run, so it is meant
+    /// for offline inputs: a CouchDB scan returns each id once.
+    pub fn with_duplicate_check(mut self) -> Self {
+        self.processed_ids = Some(RefCell::new(HashSet::new()));
+        self
+    }
+
+    /// Transforms the invalid documents of `process_batch` on the pool's workers. Each worker
+    /// has its own Lua state: globals set by a transform are not shared between workers.
+    pub fn with_lua_pool(mut self, lua_pool: LuaPool) -> Self {
+        self.lua_pool = Some(lua_pool);
+        self
+    }
+
+    /// Aborts the run once `warmup` documents were processed and more than `max_rate`
+    /// percent of them did not validate, a sign of a misconfigured schema or selector.
+    pub fn with_max_invalid_rate(mut self, max_rate: f64, warmup: usize) -> Self {
+        self.max_invalid_rate = Some((max_rate, warmup));
+        self
+    }
+
+    /// Whether the invalid rate aborted the run, later documents are not processed.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.get()
+    }
+
+    /// Events dropped so far because the channel given to `with_events` was full.
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events.get()
+    }
+
+    /// Documents transformed by each Lua worker, when running with a pool.
+    pub fn worker_shares(&self) -> Option<Vec<usize>> {
+        self.lua_pool.as_ref().map(|lua_pool| lua_pool.shares())
+    }
+
+    /// Sends a `ProcessEvent` for every step of every document to the given channel.
+    /// Meant for applications embedding the pipeline, the CLI does not use it. Events are
+    /// sent without waiting: when the channel is full, the event is dropped and counted, see
+    /// `dropped_events`, so a slow receiver never stalls the run and the pipeline works from
+    /// any runtime or none.
+    pub fn with_events(mut self, events: Sender<ProcessEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Flushes the sink and saves the seen file, once all documents are processed.
+    pub fn finish(&self) -> Result<(), String> {
+        self.sink.flush()?;
+        if let Some(dump) = &self.dump_invalid {
+            dump.borrow_mut().flush().map_err(|e| e.to_string())?;
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            dead_letter.borrow_mut().flush().map_err(|e| e.to_string())?;
+        }
+        if let Some(seen) = &self.seen {
+            seen.save()?;
+        }
+        Ok(())
+    }
+
+    /// Counts of the documents processed so far.
+    pub fn stats(&self) -> RunStats {
+        let mut stats = self.stats.borrow().clone();
+        stats.elapsed_secs = self.started.elapsed().as_secs_f64();
+        stats
+    }
+
+    /// Errors and outcome of the documents processed so far, when enabled by `with_report`.
+    pub fn report(&self) -> Option<DocumentReport> {
+        self.report.as_ref().map(|report| report.borrow().clone())
+    }
+
+    /// Validation errors of the documents processed so far, before any repair.
+    pub fn error_histogram(&self) -> ErrorHistogram {
+        self.errors.borrow().clone()
+    }
+
+    /// Runs a document through enrich -> unflatten -> validate -> transform -> validate -> write.
+    pub fn process(&self, doc: Value) -> Outcome {
+        if self.aborted.get() {
+            return Outcome::Aborted;
+        }
+
+        let id = doc["_id"].as_str().unwrap_or_default().to_string();
+        self.emit(ProcessEvent::Fetched(id.clone()));
+
+        let outcome = self.process_document(doc);
+        self.record(id, outcome)
+    }
+
+    /// Runs a batch of documents, transforming the invalid ones in parallel on the Lua
+    /// workers. Without workers, documents are processed one by one. With a --max-concurrency
+    /// above 1, the updates of the batch are sent together once it was processed; they are
+    /// all answered when this returns.
+    pub fn process_batch(&self, docs: Vec<Value>) -> Vec<Outcome> {
+        self.batching.set(true);
+        let mut outcomes = match &self.lua_pool {
+            Some(lua_pool) => self.transform_on_workers(lua_pool, docs),
+            None => docs.into_iter().map(|doc| self.process(doc)).collect(),
+        };
+        self.batching.set(false);
+
+        // held back by `write`, in the order of the batch
+        if !self.writes_in_bulk() {
+            let mut sent = self.flush_updates().into_iter();
+            for outcome in outcomes.iter_mut().filter(|outcome| **outcome == Outcome::Buffered) {
+                if let Some(sent) = sent.next() {
+                    *outcome = sent;
+                }
+            }
+        }
+        outcomes
+    }
+
+    fn transform_on_workers(&self, lua_pool: &LuaPool, docs: Vec<Value>) -> Vec<Outcome> {
+        // Everything up to the transform runs in order, on this thread
+        let mut outcomes: Vec<Option<Outcome>> = Vec::new();
+        let mut pending = Vec::new(); // Position, id, document and fixes waiting for the transform
+        let mut lua_inputs = Vec::new();
+        for doc in docs {
+            if self.aborted.get() {
+                outcomes.push(Some(Outcome::Aborted));
+                continue;
+            }
+
+            let id = doc["_id"].as_str().unwrap_or_default().to_string();
+            self.emit(ProcessEvent::Fetched(id.clone()));
+            match self.prepare(doc) {
+                Step::Done(outcome) => outcomes.push(Some(self.record(id, outcome))),
+                Step::Transform {
+                    doc,
+                    lua_input,
+                    fixes,
+                    errors_json,
+                    ..
+                } => {
+                    pending.push((outcomes.len(), id, doc, fixes));
+                    lua_inputs.push((lua_input, errors_json));
+                    outcomes.push(None);
+                }
+            }
+        }
+
+        let transformed_docs = lua_pool.transform_batch(lua_inputs);
+        for ((position, id, doc, fixes), transformed_doc) in pending.into_iter().zip(transformed_docs) {
+            let outcome = self.complete(&doc, fixes, transformed_doc);
+            outcomes[position] = Some(self.record(id, outcome));
+        }
+
+        outcomes
+            .into_iter()
+            .map(|outcome| outcome.unwrap_or(Outcome::Aborted))
+            .collect()
+    }
+
+    /// Re-fetches every document whose update conflicted during the scan and runs it
+    /// through the pipeline once more. A second conflict is a write failure.
+    pub fn retry_conflicts(&self) -> Vec<Outcome> {
+        let conflicts = self.conflicts.take();
+        if conflicts.is_empty() {
+            return Vec::new();
+        }
+        println!("Retrying {} conflicting updates", conflicts.len());
+
+        self.retrying.set(true);
+        let outcomes = conflicts
+            .into_iter()
+            .map(|id| {
+                let outcome = match self.sink.read(&id) {
+                    Some(Ok(doc)) => self.process_document(doc),
+                    Some(Err(err)) => {
+                        eprintln!("Failed to update document {:?}: {}", id, err);
+                        Outcome::WriteFailed
+                    }
+                    None => Outcome::WriteFailed,
+                };
+                self.stats.borrow_mut().record_retry(&outcome);
+                self.emit_outcome(id, outcome)
+            })
+            .collect();
+        self.retrying.set(false);
+        outcomes
+    }
+
+    /// Counts the outcome of a document, checks the invalid rate and sends the events.
+    fn record(&self, id: String, outcome: Outcome) -> Outcome {
+        self.stats.borrow_mut().record(&outcome);
+
+        // safety valve: nearly every document invalid means the run is misconfigured
+        if let Some((max_rate, warmup)) = self.max_invalid_rate {
+            let stats = self.stats.borrow();
+            if let Some(rate) = stats.invalid_rate_exceeded(max_rate, warmup) {
+                eprintln!(
+                    "Error: {:.1}% of the first {} documents are invalid (maximum {}%), aborting the run. Check the schema and the selector",
+                    rate, stats.scanned, max_rate
+                );
+                self.aborted.set(true);
+            }
+        }
+
+        let outcome = self.emit_outcome(id, outcome);
+        if self
+            .options
+            .bulk_size
+            .is_some_and(|bulk_size| self.pending_updates.borrow().len() >= bulk_size)
+        {
+            self.flush_updates();
+        }
+        outcome
+    }
+
+    /// Writes the buffered updates, in one `_bulk_docs` request or as concurrent updates,
+    /// then counts and reports the outcome of each document. Called when the buffer is full,
+    /// at the end of each batch and once the scan is over.
+    pub fn flush_updates(&self) -> Vec<Outcome> {
+        let pending = self.pending_updates.take();
+        if pending.is_empty() {
+            return Vec::new();
+        }
+
+        let fixed_docs: Vec<Value> = pending.iter().map(|(_, fixed_doc)| fixed_doc.clone()).collect();
+        let results: Vec<Result<(), String>> = if self.writes_in_bulk() {
+            let results = self.sink.write_bulk(&fixed_docs);
+            pending
+                .iter()
+                .map(|(doc, _)| {
+                    let id = doc["_id"].as_str().unwrap_or_default();
+                    match &results {
+                        Ok(results) if results.succeeded.iter().any(|succeeded| succeeded == id) => Ok(()),
+                        Ok(results) if results.conflicted.iter().any(|conflicted| conflicted == id) => {
+                            Err(update::conflict_error(id))
+                        }
+                        Ok(results) => match results.failed.iter().find(|(failed_id, _)| failed_id == id) {
+                            Some((_, reason)) => Err(reason.clone()),
+                            None => Err("no result returned by _bulk_docs".to_string()),
+                        },
+                        Err(e) => Err(e.clone()),
+                    }
+                })
+                .collect()
+        } else {
+            block_on(self.send_concurrently(&fixed_docs))
+        };
+
+        pending
+            .into_iter()
+            .zip(results)
+            .map(|((doc, _), result)| {
+                let id = doc["_id"].as_str().unwrap_or_default().to_string();
+                let outcome = self.written(&doc, result);
+                self.stats.borrow_mut().record_retry(&outcome);
+                self.emit_outcome(id, outcome)
+            })
+            .collect()
+    }
+
+    /// Sends updates at the same time, each one waiting for a write permit: no more than
+    /// --max-concurrency are in flight, the others queue until one is answered.
+    async fn send_concurrently(&self, docs: &[Value]) -> Vec<Result<(), String>> {
+        let sends = docs.iter().map(|doc| async move {
+            let _permit = self.write_permits.acquire().await.map_err(|e| e.to_string())?;
+            self.sink.send(doc).await
+        });
+        futures_util::future::join_all(sends).await
+    }
+
+    /// Whether updates go through the --bulk-size buffer.
+    fn writes_in_bulk(&self) -> bool {
+        self.options.bulk_size.is_some() && self.sink.writes_in_bulk()
+    }
+
+    /// Sends the events of a processed document.
+    fn emit_outcome(&self, id: String, outcome: Outcome) -> Outcome {
+        // a duplicate keeps the outcome of its first occurrence
+        if let Some(report) = &self.report {
+            if outcome != Outcome::DuplicateId && outcome != Outcome::Aborted {
+                report.borrow_mut().outcome(&id, &outcome);
+            }
+        }
+        match outcome {
+            Outcome::Valid | Outcome::Unchanged => self.emit(ProcessEvent::Validated(id)),
+            Outcome::WouldUpdate => self.emit(ProcessEvent::Transformed(id)),
+            Outcome::Updated => {
+                self.emit(ProcessEvent::Transformed(id.clone()));
+                self.emit(ProcessEvent::Updated(id));
+            }
+            Outcome::StillInvalid
+            | Outcome::Rejected
+            | Outcome::TransformFailed
+            | Outcome::WriteFailed
+            | Outcome::WouldDelete
+            | Outcome::Deleted
+            | Outcome::TooLarge
+            | Outcome::LikelyWrongType
+            | Outcome::VerificationFailed
+            | Outcome::DuplicateId => self.emit(ProcessEvent::Failed(id)),
+            Outcome::Dumped
+            | Outcome::Listed
+            | Outcome::Skipped
+            | Outcome::AlreadyStamped
+            | Outcome::Deferred
+            | Outcome::ConflictDeferred
+            | Outcome::Buffered
+            | Outcome::Aborted => (),
+        }
+        outcome
+    }
+
+    /// Sends an event if the channel has room, counting it as dropped otherwise. Events are
+    /// silently dropped once the receiver is gone.
+    fn emit(&self, event: ProcessEvent) {
+        if let Some(events) = &self.events {
+            if let Err(TrySendError::Full(_)) = events.try_send(event) {
+                self.dropped_events.set(self.dropped_events.get() + 1);
+            }
+        }
+    }
+
+    fn process_document(&self, doc: Value) -> Outcome {
+        match self.prepare(doc) {
+            Step::Done(outcome) => outcome,
+            Step::Transform {
+                doc,
+                lua_input,
+                fresh_lua,
+                fixes,
+                errors_json,
+            } => {
+                let lua = fresh_lua.as_ref().unwrap_or(self.lua.as_ref());
+                let transformed_doc =
+                    script::lua_transform(lua, lua_input, &errors_json, self.options.string_transform).map_err(|err| err.to_string());
+                self.complete(&doc, fixes, transformed_doc)
+            }
+        }
+    }
+
+    /// Runs a document through the steps before the transform: enrich, unflatten,
+    /// validation and the checks deciding whether it should be transformed.
+    fn prepare(&self, doc: Value) -> Step {
+        // a second document with the same id would overwrite the first one
+        if let (Some(id), Some(processed_ids)) = (doc["_id"].as_str(), &self.processed_ids) {
+            if !self.is_retry() && !processed_ids.borrow_mut().insert(id.to_string()) {
+                eprintln!("Error: duplicate document id {:?}, skipping this occurrence", id);
+                return Step::Done(Outcome::DuplicateId);
+            }
+        }
+
+        if doc["_id"].as_str().is_some_and(|id| self.skip_ids.contains(id)) {
+            return Step::Done(Outcome::Skipped);
+        }
+
+        if self.run_stamp.as_ref().is_some_and(|run_stamp| run_stamp.is_stamped(&doc)) {
+            return Step::Done(Outcome::AlreadyStamped);
+        }
+
+        if self.seen.as_ref().is_some_and(|seen| seen.contains(&doc)) {
+            return Step::Done(Outcome::Unchanged);
+        }
+
+        let fresh_lua = match &self.lua_factory {
+            Some(lua_factory) => match lua_factory() {
+                Ok(lua) => Some(lua),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    return Step::Done(Outcome::TransformFailed);
+                }
+            },
+            None => None,
+        };
+        let lua = fresh_lua.as_ref().unwrap_or(self.lua.as_ref());
+
+        // let the optional enrich hook merge external data in before validating
+        let enriched = match script::lua_enrich(lua, doc.clone(), self.options.string_transform) {
+            Ok(enriched) => enriched,
+            Err(err) => {
+                eprintln!("Error: enrich failed for {} - {}", doc["_id"], err);
+                return Step::Done(Outcome::TransformFailed);
+            }
+        };
+        let mut prepared = enriched;
+
+        // rewrite dotted keys into nested objects, a conflict leaves the document as is
+        if self.options.unflatten {
+            match unflatten::unflatten(&prepared) {
+                Ok(unflattened) => prepared = unflattened,
+                Err(conflict) => eprintln!("Error: unable to unflatten {} - {}", doc["_id"], conflict),
+            }
+        }
+
+        // a document repaired by enrich or unflatten still goes through every check below
+        let original = doc;
+        let doc = prepared;
+        let repaired = doc != original;
+
+        let err = self.validate(&doc);
+        if !self.is_retry() {
+            self.errors.borrow_mut().record(&err);
+            if let Some(report) = &self.report {
+                report.borrow_mut().validated(doc["_id"].as_str().unwrap_or_default(), &err);
+            }
+        }
+        if err.is_empty() && (!repaired || self.options.stat || self.dump_invalid.is_some()) {
+            if self.options.transform_valid_if_changed && !self.options.stat && self.dump_invalid.is_none() {
+                return Step::Done(self.normalize(lua, &doc));
+            }
+            if let Some(seen) = &self.seen {
+                seen.record(&doc);
+            }
+            return Step::Done(Outcome::Valid);
+        }
+
+        // survey mode: only list the invalid ids, the error types are summed up at the end
+        if self.options.stat {
+            println!("{}", doc["_id"].as_str().unwrap_or_default());
+            return Step::Done(Outcome::Listed);
+        }
+
+        // forensic capture mode: export the document as is and stop there
+        if let Some(dump) = &self.dump_invalid {
+            let record = json!({"doc": doc, "errors": err});
+            return Step::Done(match writeln!(dump.borrow_mut(), "{}", record) {
+                Ok(()) => Outcome::Dumped,
+                Err(e) => {
+                    eprintln!("Failed to dump document {}: {}", doc["_id"], e);
+                    Outcome::WriteFailed
+                }
+            });
+        }
+
+        // multi-type tables: leave documents of another message type alone
+        if let Some(ratio) = self.options.wrong_type_ratio {
+            if let Err(reason) = guard::check_message_type(&doc, &err, &self.ignore_list, ratio) {
+                eprintln!(
+                    "{} is likely not a {} document and will not be transformed: {}",
+                    doc["_id"], self.table_name, reason
+                );
+                return Step::Done(Outcome::LikelyWrongType);
+            }
+        }
+
+        // canary mode: only a random fraction of the documents needing a fix is touched
+        if self.sampler.as_ref().is_some_and(|sampler| !sampler.pick()) {
+            return Step::Done(Outcome::Deferred);
+        }
+
+        // enrich or unflatten already made it valid, the transform only runs to migrate it
+        if err.is_empty() && self.validate_output(&doc).is_empty() {
+            return Step::Done(self.write(&original, &doc));
+        }
+
+        // fill defaults and try safe type coercions first, only documents still invalid reach Lua
+        let mut lua_input = doc.clone();
+        let mut fixes = Vec::new();
+        if self.options.fill_defaults {
+            if let Some((filled, filled_fixes)) =
+                coerce::fill_defaults(&self.file_descriptor_set, &self.table_name, &lua_input, &err)
+            {
+                lua_input = filled;
+                fixes.extend(filled_fixes);
+            }
+        }
+        if self.options.auto_coerce {
+            if let Some((coerced, coerced_fixes)) =
+                coerce::auto_coerce(&self.file_descriptor_set, &self.table_name, &lua_input, &err)
+            {
+                lua_input = coerced;
+                fixes.extend(coerced_fixes);
+            }
+        }
+        if !fixes.is_empty() && self.validate_output(&lua_input).is_empty() {
+            self.report_fixes(&doc, &fixes);
+            return Step::Done(self.write(&doc, &lua_input));
+        }
+
+        Step::Transform {
+            doc,
+            lua_input,
+            fresh_lua,
+            fixes,
+            errors_json: script::errors_json(&err),
+        }
+    }
+
+    /// Checks and writes the transform output of a prepared document.
+    fn complete(&self, doc: &Value, mut fixes: Vec<coerce::Fix>, transformed_doc: Result<Value, String>) -> Outcome {
+        let id = doc["_id"].as_str().unwrap_or_default();
+        if let Some(report) = &self.report {
+            report.borrow_mut().transform_attempted(id);
+        }
+        let transformed_doc = match transformed_doc {
+            Ok(transformed_doc) => transformed_doc,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return Outcome::TransformFailed;
+            }
+        };
+
+        if self.is_suspicious(doc, &transformed_doc) {
+            return Outcome::Rejected;
+        }
+
+        // validate the transformed document again, if it is still invalid, return
+        let err = self.validate_output(&transformed_doc);
+        if let Some(report) = &self.report {
+            report.borrow_mut().transformed(id, &err);
+        }
+        if !err.is_empty() {
+            return self.handle_invalid(doc, err);
+        }
+
+        if self.options.report_fixes {
+            let transform_fixes = coerce::transform_fixes(doc, &transformed_doc, &fixes);
+            fixes.extend(transform_fixes);
+            self.report_fixes(doc, &fixes);
+        }
+        self.write(doc, &transformed_doc)
+    }
+
+    /// Prints the fields changed by the automatic fixes and the transform with their
+    /// confidence (--report-fixes), so reviewers can start with the riskiest ones.
+    fn report_fixes(&self, doc: &Value, fixes: &[coerce::Fix]) {
+        if self.options.report_fixes && !fixes.is_empty() {
+            let fixes: Vec<String> = fixes.iter().map(|fix| fix.to_string()).collect();
+            println!("{} fixes: {}", doc["_id"], fixes.join(", "));
+        }
+    }
+
+    /// Runs the transform on a valid document and writes the output only when it changed
+    /// and still validates. Invalid outputs are reported, never deleted.
+    fn normalize(&self, lua: &Lua, doc: &Value) -> Outcome {
+        let normalized_doc = match script::lua_transform(lua, doc.clone(), "[]", self.options.string_transform) {
+            Ok(normalized_doc) => normalized_doc,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return Outcome::TransformFailed;
+            }
+        };
+
+        if normalized_doc == *doc {
+            if let Some(seen) = &self.seen {
+                seen.record(doc);
+            }
+            return Outcome::Valid;
+        }
+
+        if self.is_suspicious(doc, &normalized_doc) {
+            return Outcome::Rejected;
+        }
+
+        let err = self.validate_output(&normalized_doc);
+        if !err.is_empty() {
+            self.print_invalid(doc, err);
+            return Outcome::StillInvalid;
+        }
+
+        self.write(doc, &normalized_doc)
+    }
+
+    /// Refuses transform outputs that no longer look like the document we fetched
+    /// (--assert-output-shape), reporting why.
+    fn is_suspicious(&self, doc: &Value, output: &Value) -> bool {
+        if !self.options.assert_output_shape {
+            return false;
+        }
+        match guard::check_output_shape(doc, output, self.options.min_key_overlap) {
+            Ok(()) => false,
+            Err(reason) => {
+                eprintln!(
+                    "{} will not be updated because the transform output looks suspicious: {}",
+                    doc["_id"], reason
+                );
+                true
+            }
+        }
+    }
+
+    fn validate(&self, doc: &Value) -> Vec<valid_proto::ValidationError> {
+        valid_proto::validate_json_with(
+            &self.file_descriptor_set,
+            &self.table_name,
+            doc,
+            self.ignore_list.clone(),
+            &self.options.validation,
+        )
+    }
+
+    /// Validates a document about to be written, against --output-message when the
+    /// transform migrates documents to another message.
+    fn validate_output(&self, doc: &Value) -> Vec<valid_proto::ValidationError> {
+        valid_proto::validate_json_with(
+            &self.file_descriptor_set,
+            self.options.output_message.as_deref().unwrap_or(&self.table_name),
+            doc,
+            self.ignore_list.clone(),
+            &self.options.validation,
+        )
+    }
+
+    /// Writes the repaired version of a document, or previews it in dry-run mode.
+    fn write(&self, doc: &Value, fixed_doc: &Value) -> Outcome {
+        // a script rewriting _id or _rev would update the wrong document, or fail obscurely
+        if let Err(reason) = guard::check_identity(doc, fixed_doc) {
+            eprintln!("Error: {} will not be updated because {}", doc["_id"], reason);
+            return Outcome::Rejected;
+        }
+
+        // last word to the script, which may veto the update
+        match script::lua_approve(&self.lua, doc, fixed_doc, self.options.string_transform) {
+            Ok(true) => (),
+            Ok(false) => {
+                eprintln!("{} will not be updated because approve vetoed it", doc["_id"]);
+                return Outcome::Rejected;
+            }
+            Err(err) => {
+                eprintln!("Error: approve failed for {} - {}", doc["_id"], err);
+                return Outcome::Rejected;
+            }
+        }
+
+        let stamped_doc;
+        let fixed_doc = match &self.run_stamp {
+            Some(run_stamp) => {
+                stamped_doc = run_stamp.stamp(fixed_doc);
+                &stamped_doc
+            }
+            None => fixed_doc,
+        };
+
+        // CouchDB would refuse it at write time with a less helpful error
+        if let Some(max_doc_bytes) = self.options.max_doc_bytes {
+            if let Err(reason) = guard::check_size(fixed_doc, max_doc_bytes) {
+                eprintln!("{} will not be updated because it is too large: {}", doc["_id"], reason);
+                return Outcome::TooLarge;
+            }
+        }
+
+        if self.options.dry_run {
+            println!("{} will be updated", doc["_id"]);
+            if self.options.show_diff {
+                for change in diff::diff(doc, fixed_doc) {
+                    println!("  {}", change);
+                }
+            }
+            return Outcome::WouldUpdate;
+        }
+
+        // conflicts retried after the scan are written one by one
+        if self.writes_in_bulk() && !self.is_retry() {
+            self.pending_updates.borrow_mut().push((doc.clone(), fixed_doc.clone()));
+            return Outcome::Buffered;
+        }
+
+        // sent with the other updates of the batch, see `process_batch`
+        if self.options.max_concurrency > 1 && self.batching.get() && self.sink.writes_concurrently() && !self.is_retry() {
+            self.pending_updates.borrow_mut().push((doc.clone(), fixed_doc.clone()));
+            return Outcome::Buffered;
+        }
+
+        let result = self.sink.write(fixed_doc);
+        self.written(doc, result)
+    }
+
+    /// Reports the result of writing a repaired document.
+    fn written(&self, doc: &Value, result: Result<(), String>) -> Outcome {
+        match result {
+            Ok(()) if self.options.verify_updates => self.verify(doc),
+            Ok(()) => {
+                println!("{} updated successfully", doc["_id"]);
+                Outcome::Updated
+            }
+            Err(e) if self.conflict_attempts.get() < self.options.conflict_retries && update::is_conflict(&e) => {
+                println!("{} was updated concurrently, retrying with its current revision", doc["_id"]);
+                self.retry_conflict(doc)
+            }
+            Err(e) if self.options.defer_conflicts && !self.retrying.get() && update::is_conflict(&e) => {
+                println!("{} was updated concurrently, retrying after the scan", doc["_id"]);
+                let id = doc["_id"].as_str().unwrap_or_default().to_string();
+                self.conflicts.borrow_mut().push(id);
+                Outcome::ConflictDeferred
+            }
+            Err(e) => {
+                eprintln!("Failed to update document {}: {}", doc["_id"], e);
+                Outcome::WriteFailed
+            }
+        }
+    }
+
+    /// Fetches the current revision of a document whose update conflicted and runs it through
+    /// the pipeline again, which may conflict and retry once more up to `conflict_retries`.
+    fn retry_conflict(&self, doc: &Value) -> Outcome {
+        let id = doc["_id"].as_str().unwrap_or_default();
+        self.conflict_attempts.set(self.conflict_attempts.get() + 1);
+        let outcome = match self.sink.read(id) {
+            Some(Ok(current_doc)) => self.process_document(current_doc),
+            Some(Err(e)) => {
+                eprintln!("Failed to update document {}: {}", doc["_id"], e);
+                Outcome::WriteFailed
+            }
+            None => Outcome::WriteFailed,
+        };
+        self.conflict_attempts.set(self.conflict_attempts.get() - 1);
+        outcome
+    }
+
+    /// Whether the document being processed was already processed once in this run: a
+    /// conflict retried immediately or after the scan.
+    fn is_retry(&self) -> bool {
+        self.retrying.get() || self.conflict_attempts.get() > 0
+    }
+
+    /// Reads an updated document back and validates it again, to catch a write that
+    /// CouchDB altered or only partially applied.
+    fn verify(&self, doc: &Value) -> Outcome {
+        let id = doc["_id"].as_str().unwrap_or_default();
+        let stored_doc = match self.sink.read(id) {
+            Some(Ok(stored_doc)) => stored_doc,
+            Some(Err(e)) => {
+                eprintln!("Verification failed for {}: {}", doc["_id"], e);
+                return Outcome::VerificationFailed;
+            }
+            None => {
+                println!("{} updated successfully", doc["_id"]);
+                return Outcome::Updated;
+            }
+        };
+
+        let err = self.validate_output(&stored_doc);
+        if !err.is_empty() {
+            eprintln!(
+                "Verification failed for {}: the document read back does not match the schema",
+                doc["_id"]
+            );
+            for e in err {
+                eprintln!("Error: {}", e);
+            }
+            return Outcome::VerificationFailed;
+        }
+
+        println!("{} updated and verified successfully", doc["_id"]);
+        Outcome::Updated
+    }
+
+    /// Prints the errors left after the transform, in the selected format.
+    fn print_invalid(&self, doc: &Value, err: Vec<valid_proto::ValidationError>) {
+        if self.options.format == OutputFormat::Json {
+            println!("{}", error_record(doc, &err));
+        } else {
+            println!();
+            println!(
+                "{} will not be updated because it still does not match the schema after transform",
+                doc["_id"]
+            );
+            for e in err {
+                println!("Error: {}", e);
+            }
+            println!("---------------------------------");
+        }
+    }
+
+    /// Applies the --on-invalid policy to a document the transform could not repair.
+    fn handle_invalid(&self, doc: &Value, err: Vec<valid_proto::ValidationError>) -> Outcome {
+        // kept for a rerun with a fixed script, before a delete policy removes it
+        if let Some(dead_letter) = &self.dead_letter {
+            let record = json!({"doc": doc, "errors": err});
+            if let Err(e) = writeln!(dead_letter.borrow_mut(), "{}", record) {
+                eprintln!("Failed to write document {} to the dead-letter file: {}", doc["_id"], e);
+            }
+        }
+
+        match invalid_action(self.options.on_invalid, self.options.dry_run) {
+            InvalidAction::Print => {
+                self.print_invalid(doc, err);
+                Outcome::StillInvalid
+            }
+            InvalidAction::Report => {
+                eprintln!("{}", error_record(doc, &err));
+                Outcome::StillInvalid
+            }
+            InvalidAction::PreviewDelete => {
+                println!("{} will be deleted because it still does not match the schema after transform", doc["_id"]);
+                Outcome::WouldDelete
+            }
+            InvalidAction::Delete => match self.sink.delete(doc) {
+                Ok(()) => {
+                    println!("{} deleted because it still does not match the schema after transform", doc["_id"]);
+                    Outcome::Deleted
+                }
+                Err(e) => {
+                    eprintln!("Failed to delete document {}: {}", doc["_id"], e);
+                    Outcome::WriteFailed
+                }
+            },
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{
+        descriptor::{field_descriptor_proto::Type, DescriptorProto, FieldDescriptorProto},
+        EnumOrUnknown,
+    };
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, Request, ResponseTemplate,
+    };
+
+    /// Mock CouchDB where order-1 was changed to "2-b" by another writer since it was fetched as "1-a".
+    async fn mock_stale_revision() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(header("if-match", "1-a"))
+            .respond_with(ResponseTemplate::new(409).set_body_json(json!({"error": "conflict"})))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({"ok": true, "id": "order-1", "rev": "3-c"})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"_id": "order-1", "_rev": "2-b", "quantity": "ten"})))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    /// Method and If-Match header of each request received by the server.
+    async fn received_writes(server: &MockServer) -> Vec<(String, Option<String>)> {
+        let requests = server.received_requests().await.unwrap();
+        requests
+            .iter()
+            .map(|request| {
+                let if_match = request.headers.get("if-match").map(|value| value.to_str().unwrap().to_string());
+                (request.method.to_string(), if_match)
+            })
+            .collect()
+    }
+
+    fn create_test_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.name = Some("Order.proto".to_string());
+
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        let mut field = FieldDescriptorProto::new();
+        field.name = Some("quantity".to_string());
+        field.json_name = Some("quantity".to_string());
+        field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        order.field.push(field);
+
+        file.message_type.push(order);
+        file_set.file.push(file);
+        file_set
+    }
+
+    /// Builds a pipeline running `script`, with the test descriptor and `_id`/`_rev` ignored.
+    fn script_pipeline(script: &str, options: Options, sink: Sink) -> Pipeline {
+        let lua = Lua::new();
+        lua.load(script).exec().unwrap();
+        Pipeline::new(
+            Rc::new(lua),
+            Arc::new(create_test_descriptor()),
+            "Order",
+            vec!["_id".to_string(), "_rev".to_string()],
+            options,
+            sink,
+        )
+    }
+
+    /// Transform turning the string quantity "ten" of the fixtures into a number.
+    const FIXING_TRANSFORM: &str = r#"
+        function transform(doc)
+            if doc.quantity == "ten" then doc.quantity = 10 end
+            return doc
+        end
+        "#;
+
+    /// Builds a pipeline whose transform fixes the "ten" quantity of the fixtures.
+    fn fixing_pipeline(options: Options, sink: Sink) -> Pipeline {
+        script_pipeline(FIXING_TRANSFORM, options, sink)
+    }
+
+    /// Builds a pipeline whose transform leaves documents unchanged and writes to a temp file.
+    fn identity_pipeline(options: Options, output: &std::path::Path) -> Pipeline {
+        script_pipeline("function transform(doc) return doc end", options, file_sink(output))
+    }
+
+    /// Path of a test output file, unique per test name and process.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bulkmorph-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    fn file_sink(output: &std::path::Path) -> Sink {
+        Sink::file(output.to_str().unwrap()).unwrap()
+    }
+
+    fn couchdb_sink(server: &MockServer) -> Sink {
+        Sink::CouchDb {
+            client: Client::new(),
+            db_host: server.uri(),
+            table_name: "orders".to_string(),
+            preserve_revs: false,
+        }
+    }
+
+    #[test]
+    fn test_on_invalid_policy_decision() {
+        assert_eq!(invalid_action(OnInvalid::Skip, false), InvalidAction::Print);
+        assert_eq!(invalid_action(OnInvalid::Skip, true), InvalidAction::Print);
+        assert_eq!(invalid_action(OnInvalid::Report, false), InvalidAction::Report);
+        assert_eq!(invalid_action(OnInvalid::Report, true), InvalidAction::Report);
+        assert_eq!(invalid_action(OnInvalid::Delete, false), InvalidAction::Delete);
+        assert_eq!(invalid_action(OnInvalid::Delete, true), InvalidAction::PreviewDelete);
+    }
+
+    #[test]
+    fn test_on_invalid_policy_parsing() {
+        assert_eq!("skip".parse(), Ok(OnInvalid::Skip));
+        assert_eq!("report".parse(), Ok(OnInvalid::Report));
+        assert_eq!("delete".parse(), Ok(OnInvalid::Delete));
+        assert!("purge".parse::<OnInvalid>().is_err());
+        assert_eq!(OnInvalid::default(), OnInvalid::Skip);
+    }
+
+    #[test]
+    fn test_on_invalid_policies_in_pipeline() {
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        let output = temp_path("on-invalid");
+
+        for (policy, dry_run, expected) in [
+            (OnInvalid::Skip, false, Outcome::StillInvalid),
+            (OnInvalid::Report, false, Outcome::StillInvalid),
+            (OnInvalid::Delete, true, Outcome::WouldDelete),
+            (OnInvalid::Delete, false, Outcome::Deleted),
+        ] {
+            let options = Options {
+                on_invalid: policy,
+                dry_run,
+                ..Default::default()
+            };
+            let pipeline = identity_pipeline(options, &output);
+            assert_eq!(pipeline.process(doc.clone()), expected);
+            pipeline.finish().unwrap();
+
+            // Only an actual delete writes a tombstone
+            let written = std::fs::read_to_string(&output).unwrap();
+            if expected == Outcome::Deleted {
+                assert_eq!(written.trim(), r#"{"_deleted":true,"_id":"order-1","_rev":"1-a"}"#);
+            } else {
+                assert!(written.is_empty());
+            }
+        }
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_dump_invalid_writes_only_invalid_documents() {
+        let output = temp_path("dump-output");
+        let dump = temp_path("dump-invalid");
+
+        let pipeline = identity_pipeline(Options::default(), &output)
+            .with_dump_invalid(dump.to_str().unwrap())
+            .unwrap();
+        let valid = json!({"_id": "order-1", "_rev": "1-a", "quantity": 10});
+        let invalid = json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"});
+        assert_eq!(pipeline.process(valid), Outcome::Valid);
+        assert_eq!(pipeline.process(invalid.clone()), Outcome::Dumped);
+        pipeline.finish().unwrap();
+
+        let dumped = std::fs::read_to_string(&dump).unwrap();
+        let lines: Vec<Value> = dumped.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["doc"], invalid);
+        assert_eq!(lines[0]["errors"][0]["field"], "quantity");
+
+        // Nothing is transformed or written
+        assert!(std::fs::read_to_string(&output).unwrap().is_empty());
+
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&dump).unwrap();
+    }
+
+    #[test]
+    fn test_dead_letter_keeps_documents_still_invalid() {
+        let output = temp_path("dead-letter-output");
+        let dead_letter = temp_path("dead-letter");
+        std::fs::write(&dead_letter, "{\"doc\":{\"_id\":\"order-0\"},\"errors\":[]}\n").unwrap();
+
+        // The identity transform cannot repair the quantity
+        let pipeline = identity_pipeline(Options::default(), &output)
+            .with_dead_letter(dead_letter.to_str().unwrap())
+            .unwrap();
+        let valid = json!({"_id": "order-1", "_rev": "1-a", "quantity": 10});
+        let invalid = json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"});
+        assert_eq!(pipeline.process(valid), Outcome::Valid);
+        assert_eq!(pipeline.process(invalid.clone()), Outcome::StillInvalid);
+        pipeline.finish().unwrap();
+
+        // Appended after the records of an earlier run
+        let lines: Vec<Value> = std::fs::read_to_string(&dead_letter)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["doc"], invalid);
+        assert_eq!(lines[1]["errors"][0]["field"], "quantity");
+        assert_eq!(lines[1]["errors"][0]["error_type"], "WrongDataType");
+
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&dead_letter).unwrap();
+    }
+
+    #[test]
+    fn test_enrich_makes_document_valid_without_transform() {
+        let output = temp_path("enrich");
+
+        const SCRIPT: &str = r#"
+            function enrich(doc)
+                if doc.quantity == "ten" then doc.quantity = 10 end
+                return doc
+            end
+            function transform(doc) error("transform should not be called") end
+            "#;
+        let pipeline = script_pipeline(SCRIPT, Options::default(), file_sink(&output));
+
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc), Outcome::Updated);
+        pipeline.finish().unwrap();
+
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(written.trim(), r#"{"_id":"order-1","_rev":"1-a","quantity":10}"#);
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_enriched_document_goes_through_the_gates() {
+        const SCRIPT: &str = r#"
+            function enrich(doc)
+                if doc.quantity == "ten" then doc.quantity = 10 end
+                return doc
+            end
+            function transform(doc) error("transform should not be called") end
+            "#;
+        let output = temp_path("enrich-gates");
+        let dump = temp_path("enrich-gates-dump");
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+
+        // Capture mode writes nothing, the enriched document is valid
+        let pipeline = script_pipeline(SCRIPT, Options::default(), file_sink(&output))
+            .with_dump_invalid(dump.to_str().unwrap())
+            .unwrap();
+        assert_eq!(pipeline.process(doc.clone()), Outcome::Valid);
+        pipeline.finish().unwrap();
+
+        // The canary sample applies, and the document is still reported
+        let pipeline = script_pipeline(SCRIPT, Options::default(), file_sink(&output))
+            .with_sampler(Sampler::new(0.0, Some(7)))
+            .with_report();
+        assert_eq!(pipeline.process(doc), Outcome::Deferred);
+        pipeline.finish().unwrap();
+        assert_eq!(pipeline.report().unwrap().records().len(), 1);
+
+        assert!(std::fs::read_to_string(&output).unwrap().is_empty());
+        assert!(std::fs::read_to_string(&dump).unwrap().is_empty());
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&dump).unwrap();
+    }
+
+    #[test]
+    fn test_fresh_lua_per_doc_isolates_global_state() {
+        // The transform refuses to run twice in the same state
+        const SCRIPT: &str = r#"
+            state = {count = 0}
+            function transform(doc)
+                state.count = state.count + 1
+                if state.count > 1 then error("state left over from a previous document") end
+                doc.quantity = 10
+                return doc
+            end
+            "#;
+        let load = || -> Result<Lua, String> {
+            let lua = Lua::new();
+            lua.load(SCRIPT).exec().map_err(|e| e.to_string())?;
+            Ok(lua)
+        };
+        let output = temp_path("fresh-lua");
+        let first = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        let second = json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"});
+
+        let shared = || script_pipeline(SCRIPT, Options::default(), file_sink(&output));
+
+        // Without isolation the second document sees the first one's state
+        let pipeline = shared();
+        assert_eq!(pipeline.process(first.clone()), Outcome::Updated);
+        assert_eq!(pipeline.process(second.clone()), Outcome::TransformFailed);
+
+        let pipeline = shared().with_fresh_lua_per_doc(Box::new(load));
+        assert_eq!(pipeline.process(first), Outcome::Updated);
+        assert_eq!(pipeline.process(second), Outcome::Updated);
+
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_skip_ids_are_not_validated() {
+        let output = temp_path("skip-ids-out");
+        let pipeline = identity_pipeline(Options::default(), &output)
+            .with_skip_ids(HashSet::from(["order-1".to_string()]));
+
+        // Invalid, but listed: nothing is reported or written
+        let listed = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        let other = json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"});
+        assert_eq!(pipeline.process(listed), Outcome::Skipped);
+        assert_eq!(pipeline.process(other), Outcome::StillInvalid);
+        assert_eq!(pipeline.stats().scanned, 2);
+
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_id_is_skipped() {
+        let output = temp_path("duplicate-ids");
+        let pipeline = identity_pipeline(Options::default(), &output).with_duplicate_check();
+
+        let first = json!({"_id": "order-1", "_rev": "1-a", "quantity": 1});
+        let second = json!({"_id": "order-1", "_rev": "1-b", "quantity": "ten"});
+        assert_eq!(pipeline.process(first.clone()), Outcome::Valid);
+        assert_eq!(pipeline.process(second.clone()), Outcome::DuplicateId);
+        let stats = pipeline.stats();
+        assert_eq!((stats.scanned, stats.valid, stats.failed), (2, 1, 1));
+
+        // Ids are only tracked when asked for
+        let pipeline = identity_pipeline(Options::default(), &output);
+        assert_eq!(pipeline.process(first), Outcome::Valid);
+        assert_eq!(pipeline.process(second), Outcome::StillInvalid);
+
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_transform_valid_if_changed() {
+        let output = temp_path("normalize");
+
+        // 10 is normalized to 11, 20 is left alone, 30 becomes an invalid string
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc)
+                doc = string.gsub(doc, '"quantity":10', '"quantity":11')
+                doc = string.gsub(doc, '"quantity":30', '"quantity":"thirty"')
+                return doc
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let options = Options {
+            string_transform: true,
+            transform_valid_if_changed: true,
+            ..Default::default()
+        };
+        let pipeline = Pipeline::new(
+            Rc::new(lua),
+            Arc::new(create_test_descriptor()),
+            "Order",
+            vec!["_id".to_string(), "_rev".to_string()],
+            options,
+            file_sink(&output),
+        );
+
+        let changed = json!({"_id": "order-1", "_rev": "1-a", "quantity": 10});
+        let unchanged = json!({"_id": "order-2", "_rev": "1-b", "quantity": 20});
+        let broken = json!({"_id": "order-3", "_rev": "1-c", "quantity": 30});
+        assert_eq!(pipeline.process(changed), Outcome::Updated);
+        assert_eq!(pipeline.process(unchanged), Outcome::Valid);
+        assert_eq!(pipeline.process(broken), Outcome::StillInvalid);
+        pipeline.finish().unwrap();
+
+        // Only the changed and valid output is written
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(written.trim(), r#"{"_id":"order-1","_rev":"1-a","quantity":11}"#);
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_normalized_output_shape_is_checked() {
+        let output = temp_path("normalize-shape");
+        let options = Options {
+            transform_valid_if_changed: true,
+            assert_output_shape: true,
+            min_key_overlap: 0.9,
+            ..Default::default()
+        };
+        let script = "function transform(doc) doc.quantity = nil return doc end";
+        let pipeline = script_pipeline(script, options, file_sink(&output));
+
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": 10});
+        assert_eq!(pipeline.process(doc), Outcome::Rejected);
+        pipeline.finish().unwrap();
+
+        assert!(std::fs::read_to_string(&output).unwrap().is_empty());
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_output_is_validated_against_the_output_message() {
+        let output = temp_path("output-message");
+
+        // OrderV1 { int32 quantity } is migrated to OrderV2 { int32 count }
+        let mut file_set = create_test_descriptor();
+        file_set.file[0].message_type[0].name = Some("OrderV1".to_string());
+        let mut order_v2 = DescriptorProto::new();
+        order_v2.name = Some("OrderV2".to_string());
+        let mut field = FieldDescriptorProto::new();
+        field.name = Some("count".to_string());
+        field.json_name = Some("count".to_string());
+        field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        order_v2.field.push(field);
+        file_set.file[0].message_type.push(order_v2);
+
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc)
+                return (string.gsub(doc, '"quantity":"(%d+)"', '"count":%1'))
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let options = Options {
+            string_transform: true,
+            output_message: Some("OrderV2".to_string()),
+            ..Default::default()
+        };
+        let pipeline = Pipeline::new(
+            Rc::new(lua),
+            Arc::new(file_set),
+            "OrderV1",
+            vec!["_id".to_string(), "_rev".to_string()],
+            options,
+            file_sink(&output),
+        );
+
+        // Invalid as OrderV1, the output only matches OrderV2
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "10"});
+        assert_eq!(pipeline.process(doc), Outcome::Updated);
+        let doc = json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc), Outcome::StillInvalid);
+        pipeline.finish().unwrap();
+
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(written.trim(), r#"{"_id":"order-1","_rev":"1-a","count":10}"#);
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_stat_mode_only_lists_invalid_ids() {
+        let output = temp_path("stat");
+        let options = Options {
+            stat: true,
+            ..Default::default()
+        };
+        let pipeline = identity_pipeline(options, &output);
+
+        let valid = json!({"_id": "order-1", "_rev": "1-a", "quantity": 10});
+        let invalid = json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten", "note": "x"});
+        assert_eq!(pipeline.process(valid), Outcome::Valid);
+        assert_eq!(pipeline.process(invalid), Outcome::Listed);
+        pipeline.finish().unwrap();
+
+        // Nothing transformed or written, the error types are counted per document
+        assert!(std::fs::read_to_string(&output).unwrap().is_empty());
+        assert_eq!(pipeline.stats().still_invalid, 1);
+        assert_eq!(
+            pipeline.error_histogram().documents_by_type(),
+            vec![("AdditionalField", 1), ("WrongDataType", 1)]
+        );
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_report_after_fixture_run() {
+        let output = temp_path("report-run");
+
+        let pipeline = fixing_pipeline(Options::default(), file_sink(&output)).with_report();
+
+        pipeline.process_batch(vec![
+            json!({"_id": "order-1", "_rev": "1-a", "quantity": 10}),
+            json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"}),
+            json!({"_id": "order-3", "_rev": "1-c", "quantity": "many"}),
+        ]);
+        pipeline.finish().unwrap();
+
+        let report = serde_json::to_value(pipeline.report().unwrap().records()).unwrap();
+        let summary: Vec<(&str, bool, usize, usize, &str)> = report
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| {
+                (
+                    record["_id"].as_str().unwrap(),
+                    record["transform_attempted"].as_bool().unwrap(),
+                    record["errors"].as_array().unwrap().len(),
+                    record["errors_after_transform"].as_array().unwrap().len(),
+                    record["outcome"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("order-1", false, 0, 0, "skipped"),
+                ("order-2", true, 1, 0, "updated"),
+                ("order-3", true, 1, 1, "failed"),
+            ]
+        );
+        assert_eq!(report[2]["errors"][0], json!({"field": "quantity", "error_type": "WrongDataType"}));
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_inflated_document_is_too_large() {
+        let output = temp_path("too-large");
+
+        let options = Options {
+            max_doc_bytes: Some(200),
+            ..Default::default()
+        };
+        // The fixed document fits, the stamp of the run pushes it over the limit
+        let pipeline = fixing_pipeline(options, file_sink(&output))
+            .with_run_stamp(RunStamp::new("morph_run_id", Some("x".repeat(500))));
+
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc), Outcome::TooLarge);
+        pipeline.finish().unwrap();
+
+        assert!(std::fs::read_to_string(&output).unwrap().is_empty());
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_sampler_picks_expected_fraction() {
+        let sampler = Sampler::new(0.1, Some(42));
+        let picks: Vec<bool> = (0..1000).map(|_| sampler.pick()).collect();
+        let picked = picks.iter().filter(|picked| **picked).count();
+        assert!((70..=130).contains(&picked), "picked {} of 1000", picked);
+
+        // The same seed picks the same documents again
+        let again = Sampler::new(0.1, Some(42));
+        assert_eq!((0..1000).map(|_| again.pick()).collect::<Vec<bool>>(), picks);
+
+        assert!((0..100).all(|_| Sampler::new(1.0, Some(7)).pick()));
+        assert!((0..100).all(|_| !Sampler::new(0.0, Some(7)).pick()));
+    }
+
+    #[test]
+    fn test_events_for_fixture_run() {
+        let output = temp_path("events");
+
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        let pipeline = fixing_pipeline(Options::default(), file_sink(&output)).with_events(sender);
+
+        pipeline.process(json!({"_id": "order-1", "_rev": "1-a", "quantity": 10}));
+        pipeline.process(json!({"_id": "order-2", "_rev": "1-b", "quantity": "ten"}));
+        pipeline.process(json!({"_id": "order-3", "_rev": "1-c", "quantity": "none"}));
+        drop(pipeline);
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                ProcessEvent::Fetched("order-1".to_string()),
+                ProcessEvent::Validated("order-1".to_string()),
+                ProcessEvent::Fetched("order-2".to_string()),
+                ProcessEvent::Transformed("order-2".to_string()),
+                ProcessEvent::Updated("order-2".to_string()),
+                ProcessEvent::Fetched("order-3".to_string()),
+                ProcessEvent::Failed("order-3".to_string()),
+            ]
+        );
+
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_full_event_channel_drops_events() {
+        // No runtime: a full channel must not block nor panic
+        let output = temp_path("dropped-events");
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        let pipeline = identity_pipeline(Options::default(), &output).with_events(sender);
+
+        pipeline.process(json!({"_id": "order-1", "_rev": "1-a", "quantity": 10}));
+        pipeline.process(json!({"_id": "order-2", "_rev": "1-b", "quantity": 10}));
+
+        assert_eq!(pipeline.dropped_events(), 2);
+        assert_eq!(receiver.try_recv(), Ok(ProcessEvent::Fetched("order-1".to_string())));
+        assert_eq!(receiver.try_recv(), Ok(ProcessEvent::Validated("order-1".to_string())));
+        assert!(receiver.try_recv().is_err());
+
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_updates_flags_altered_document() {
+        // CouchDB accepts the update, but the stored document kept the string quantity
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({"ok": true, "id": "order-1", "rev": "2-b"})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"_id": "order-1", "_rev": "2-b", "quantity": "ten"})))
+            .mount(&server)
+            .await;
+
+        let options = Options {
+            verify_updates: true,
+            ..Default::default()
+        };
+        let pipeline = fixing_pipeline(options, couchdb_sink(&server));
+
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc), Outcome::VerificationFailed);
+
+        let methods: Vec<String> = received_writes(&server).await.into_iter().map(|(method, _)| method).collect();
+        assert_eq!(methods, vec!["PUT", "GET"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_updates_are_complete_when_process_returns() {
+        // Slow writes, still in flight if the update was fired and forgotten
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(
+                ResponseTemplate::new(201)
+                    .set_body_json(json!({"ok": true, "id": "order", "rev": "2-b"}))
+                    .set_delay(std::time::Duration::from_millis(200)),
+            )
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let pipeline = fixing_pipeline(Options::default(), couchdb_sink(&server));
+
+        let docs: Vec<Value> = (1..=3)
+            .map(|i| json!({"_id": format!("order-{}", i), "_rev": "1-a", "quantity": "ten"}))
+            .collect();
+        assert_eq!(pipeline.process_batch(docs), vec![Outcome::Updated, Outcome::Updated, Outcome::Updated]);
+        assert_eq!(pipeline.stats().updated, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_one_update_in_flight_at_a_time() {
+        use std::{
+            sync::Mutex,
+            time::{Duration, Instant},
+        };
+
+        // Every PUT takes 50ms to answer: arrivals closer than that overlapped
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with({
+                let arrivals = Arc::clone(&arrivals);
+                move |_: &Request| {
+                    arrivals.lock().unwrap().push(Instant::now());
+                    ResponseTemplate::new(201)
+                        .set_body_json(json!({"ok": true, "id": "order", "rev": "2-b"}))
+                        .set_delay(Duration::from_millis(50))
+                }
+            })
+            .mount(&server)
+            .await;
+
+        let pipeline = fixing_pipeline(Options::default(), couchdb_sink(&server));
+
+        let docs: Vec<Value> = (1..=20)
+            .map(|i| json!({"_id": format!("order-{}", i), "_rev": "1-a", "quantity": "ten"}))
+            .collect();
+        pipeline.process_batch(docs);
+
+        assert_eq!(pipeline.stats().updated, 20);
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 20);
+        assert!(arrivals.windows(2).all(|pair| pair[1] - pair[0] >= Duration::from_millis(50)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_updates_stay_within_the_limit() {
+        use std::{
+            sync::Mutex,
+            time::{Duration, Instant},
+        };
+
+        // Every PUT takes 100ms to answer: more arrivals than the limit within that window overlapped
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with({
+                let arrivals = Arc::clone(&arrivals);
+                move |_: &Request| {
+                    arrivals.lock().unwrap().push(Instant::now());
+                    ResponseTemplate::new(201)
+                        .set_body_json(json!({"ok": true, "id": "order", "rev": "2-b"}))
+                        .set_delay(Duration::from_millis(100))
+                }
+            })
+            .mount(&server)
+            .await;
+
+        let options = Options {
+            max_concurrency: 4,
+            ..Default::default()
+        };
+        let pipeline = fixing_pipeline(options, couchdb_sink(&server));
+
+        let docs: Vec<Value> = (1..=12)
+            .map(|i| json!({"_id": format!("order-{}", i), "_rev": "1-a", "quantity": "ten"}))
+            .collect();
+        let outcomes = pipeline.process_batch(docs);
+
+        // Every update was answered before process_batch returned
+        assert!(outcomes.iter().all(|outcome| *outcome == Outcome::Updated));
+        assert_eq!(pipeline.stats().updated, 12);
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 12);
+        assert!(arrivals.windows(5).all(|window| window[4] - window[0] >= Duration::from_millis(100)));
+        // The limit was reached: the first 4 updates were in flight together
+        assert!(arrivals[3] - arrivals[0] < Duration::from_millis(100));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bulk_updates_are_buffered() {
+        // Every document is written but order-2, changed since it was fetched
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/orders/_bulk_docs"))
+            .respond_with(|request: &Request| {
+                let body: Value = request.body_json().unwrap();
+                let results: Vec<Value> = body["docs"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|doc| match doc["_id"].as_str() {
+                        Some("order-2") => json!({"id": "order-2", "error": "conflict", "reason": "Document update conflict."}),
+                        _ => json!({"ok": true, "id": doc["_id"], "rev": "2-b"}),
+                    })
+                    .collect();
+                ResponseTemplate::new(201).set_body_json(results)
+            })
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let options = Options {
+            bulk_size: Some(2),
+            ..Default::default()
+        };
+        let pipeline = fixing_pipeline(options, couchdb_sink(&server));
+
+        let doc = |i: usize| json!({"_id": format!("order-{}", i), "_rev": "1-a", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc(1)), Outcome::Buffered);
+        assert!(server.received_requests().await.unwrap().is_empty());
+        // The buffer is full, both documents are written in one request
+        assert_eq!(pipeline.process(doc(2)), Outcome::Buffered);
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        assert_eq!(pipeline.process(doc(3)), Outcome::Buffered);
+        assert_eq!(pipeline.flush_updates(), vec![Outcome::Updated]);
+        assert!(pipeline.flush_updates().is_empty());
+        let stats = pipeline.stats();
+        assert_eq!((stats.scanned, stats.transformed, stats.updated, stats.failed), (3, 2, 2, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_conflict_is_retried_with_the_current_revision() {
+        // The first revision was changed by another writer, the fetched one is current
+        let server = mock_stale_revision().await;
+
+        let pipeline_with_retries = |conflict_retries| {
+            let options = Options {
+                conflict_retries,
+                ..Default::default()
+            };
+            fixing_pipeline(options, couchdb_sink(&server))
+        };
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+
+        let pipeline = pipeline_with_retries(1);
+        assert_eq!(pipeline.process(doc.clone()), Outcome::Updated);
+        assert_eq!(
+            received_writes(&server).await,
+            vec![
+                ("PUT".to_string(), Some("1-a".to_string())),
+                ("GET".to_string(), None),
+                ("PUT".to_string(), Some("2-b".to_string())),
+            ]
+        );
+        let stats = pipeline.stats();
+        assert_eq!((stats.scanned, stats.updated, stats.failed), (1, 1, 0));
+
+        // Without retries the conflict is a failure
+        let pipeline = pipeline_with_retries(0);
+        assert_eq!(pipeline.process(doc), Outcome::WriteFailed);
+        assert_eq!(pipeline.stats().failed, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deferred_conflict_is_resolved_after_the_scan() {
+        // The first revision was changed by another writer, the fetched one is current
+        let server = mock_stale_revision().await;
+
+        let options = Options {
+            defer_conflicts: true,
+            ..Default::default()
+        };
+        let pipeline = fixing_pipeline(options, couchdb_sink(&server));
+
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc), Outcome::ConflictDeferred);
+        assert_eq!(pipeline.retry_conflicts(), vec![Outcome::Updated]);
+        assert!(pipeline.retry_conflicts().is_empty());
+
+        assert_eq!(
+            received_writes(&server).await,
+            vec![
+                ("PUT".to_string(), Some("1-a".to_string())),
+                ("GET".to_string(), None),
+                ("PUT".to_string(), Some("2-b".to_string())),
+            ]
+        );
+        let stats = pipeline.stats();
+        assert_eq!((stats.scanned, stats.updated, stats.failed), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_approve_vetoes_an_update() {
+        let output = temp_path("approve");
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc)
+                return (string.gsub(doc, '"quantity":"(%d+)"', '"quantity":%1'))
+            end
+            function approve(original, transformed)
+                -- never touch the orders of the audit customer
+                return not string.find(original, '"customer":"audit"', 1, true)
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let pipeline = Pipeline::new(
+            Rc::new(lua),
+            Arc::new(create_test_descriptor()),
+            "Order",
+            vec!["_id".to_string(), "_rev".to_string(), "customer".to_string()],
+            Options {
+                string_transform: true,
+                ..Default::default()
+            },
+            file_sink(&output),
+        );
+
+        let vetoed = json!({"_id": "order-1", "_rev": "1-a", "customer": "audit", "quantity": "10"});
+        let approved = json!({"_id": "order-2", "_rev": "1-b", "customer": "acme", "quantity": "20"});
+        assert_eq!(pipeline.process(vetoed), Outcome::Rejected);
+        assert_eq!(pipeline.process(approved), Outcome::Updated);
+        pipeline.finish().unwrap();
+
+        let written = std::fs::read_to_string(&output).unwrap();
+        let ids: Vec<Value> = written
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["_id"].clone())
+            .collect();
+        assert_eq!(ids, vec![json!("order-2")]);
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_work_on_tables_by_default() {
+        let output = temp_path("table-hooks");
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function enrich(doc)
+                doc.customer = doc.customer or "unknown"
+                return doc
+            end
+            function transform(doc, errors)
+                assert(errors[1].field == "quantity")
+                doc.quantity = tonumber(doc.quantity)
+                return doc
+            end
+            function approve(original, transformed)
+                -- never touch the orders of the audit customer
+                return original.customer ~= "audit" and transformed.quantity ~= nil
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let pipeline = Pipeline::new(
+            Rc::new(lua),
+            Arc::new(create_test_descriptor()),
+            "Order",
+            vec!["_id".to_string(), "_rev".to_string(), "customer".to_string()],
+            Options::default(),
+            file_sink(&output),
+        );
+
+        let vetoed = json!({"_id": "order-1", "_rev": "1-a", "customer": "audit", "quantity": "10"});
+        let approved = json!({"_id": "order-2", "_rev": "1-b", "quantity": "20"});
+        assert_eq!(pipeline.process(vetoed), Outcome::Rejected);
+        assert_eq!(pipeline.process(approved), Outcome::Updated);
+        pipeline.finish().unwrap();
+
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(
+            written.trim(),
+            r#"{"_id":"order-2","_rev":"1-b","customer":"unknown","quantity":20}"#
+        );
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_run_stamp() {
+        let run_stamp = RunStamp::new("morph_run_id", Some("run-1".to_string()));
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": 10});
+        assert!(!run_stamp.is_stamped(&doc));
+
+        let stamped = run_stamp.stamp(&doc);
+        assert_eq!(stamped["morph_run_id"], "run-1");
+        assert!(run_stamp.is_stamped(&stamped));
+        // Another run updates it again
+        assert!(!RunStamp::new("morph_run_id", Some("run-2".to_string())).is_stamped(&stamped));
+
+        let generated = RunStamp::new("morph_run_id", None);
+        let run_id = generated.run_id();
+        assert_eq!(run_id.len(), 36);
+        assert_eq!(&run_id[14..15], "4");
+        assert_ne!(run_id, RunStamp::new("morph_run_id", None).run_id());
+    }
+
+    #[test]
+    fn test_rerun_skips_stamped_documents() {
+        let output = temp_path("run-stamp");
+        let run = || {
+            fixing_pipeline(Options::default(), file_sink(&output))
+                .with_run_stamp(RunStamp::new("morph_run_id", Some("run-1".to_string())))
+        };
+
+        let pipeline = run();
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "quantity": "ten"});
+        assert_eq!(pipeline.process(doc), Outcome::Updated);
+        pipeline.finish().unwrap();
+        let written: Value = serde_json::from_str(std::fs::read_to_string(&output).unwrap().trim()).unwrap();
+        assert_eq!(written["morph_run_id"], "run-1");
+        assert_eq!(written["quantity"], 10);
+
+        // The retried run finds the document already stamped
+        let pipeline = run();
+        assert_eq!(pipeline.process(written), Outcome::AlreadyStamped);
+
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_high_invalid_rate_aborts_after_warmup() {
+        let output = temp_path("invalid-rate");
+        let pipeline = identity_pipeline(Options::default(), &output).with_max_invalid_rate(50.0, 4);
+
+        let invalid = |i: usize| json!({"_id": format!("order-{}", i), "_rev": "1-a", "quantity": "ten"});
+        for i in 0..3 {
+            assert_eq!(pipeline.process(invalid(i)), Outcome::StillInvalid);
+            assert!(!pipeline.is_aborted());
+        }
+        // The warmup ends with the fourth document, 100% invalid
+        assert_eq!(pipeline.process(invalid(3)), Outcome::StillInvalid);
+        assert!(pipeline.is_aborted());
+
+        assert_eq!(pipeline.process(invalid(4)), Outcome::Aborted);
+        assert_eq!(pipeline.stats().scanned, 4);
+
+        std::fs::remove_file(&output).unwrap();
+    }
+}
@@ -0,0 +1,453 @@
+use std::{fs, path::Path};
+
+use mlua::{Function, Lua, LuaOptions, LuaSerdeExt, StdLib, Table};
+use serde_json::{json, Value};
+
+use crate::valid_proto::ValidationError;
+
+/// Prepares a Lua state for transforming documents of the given table.
+/// - Loads every `.lua` file found in each of the `lua_libs` directories, in the given order.
+/// - Loads every `.lua` file found in `{script_dir}/include`.
+/// - Loads `{script_dir}/{table_name}.lua` and ensures it defines a `transform` function.
+///
+/// With `sandbox`, the scripts run in the restricted state of `sandboxed_lua`.
+pub fn load_lua(script_dir: &str, lua_libs: &[String], table_name: &str, sandbox: bool) -> Result<Lua, String> {
+    init_lua(script_dir, lua_libs, table_name, sandbox, true)
+}
+
+/// Same as `load_lua` without progress messages, for states created once per document.
+pub fn reload_lua(script_dir: &str, lua_libs: &[String], table_name: &str, sandbox: bool) -> Result<Lua, String> {
+    init_lua(script_dir, lua_libs, table_name, sandbox, false)
+}
+
+fn init_lua(
+    script_dir: &str,
+    lua_libs: &[String],
+    table_name: &str,
+    sandbox: bool,
+    verbose: bool,
+) -> Result<Lua, String> {
+    let lua = if sandbox { sandboxed_lua()? } else { Lua::new() };
+    register_json(&lua).map_err(|e| format!("Unable to register the json helpers - {}", e))?;
+
+    // shared libraries first, so local includes can build on them
+    for lua_lib in lua_libs {
+        load_folder(&lua, lua_lib, "Lua library", verbose)?;
+    }
+
+    // load all include files
+    let include_dir = script_dir.to_string() + "/include";
+    load_folder(&lua, &include_dir, "include", verbose)?;
+
+    // Validate that we have a valid lua script to transform the JSON input
+    // A valid transformation requires proto file named with lua name
+    // Example: Transaction.proto and Transaction.lua
+    let lua_script = script_dir.to_string() + "/" + table_name + ".lua";
+    if fs::metadata(&lua_script).is_err() {
+        return Err(format!("Lua script {:?} not found", lua_script));
+    }
+
+    if verbose {
+        println!("loading lua script {:?}", lua_script);
+    }
+    lua.load(Path::new(&lua_script))
+        .exec()
+        .map_err(|err| format!("problem with {:?} - Error: {}", lua_script, err))?;
+    if verbose {
+        println!("Successfully loaded script {:?}", lua_script);
+    }
+
+    // ensure that the lua script has a transform function
+    match lua.globals().get::<mlua::Value>("transform") {
+        Ok(mlua::Value::Function(_)) => (),
+        Ok(mlua::Value::Nil) => return Err("transform function not found".to_string()),
+        // Lua's type() reports integers as numbers too
+        Ok(mlua::Value::Integer(_)) | Ok(mlua::Value::Number(_)) => {
+            return Err("global `transform` exists but is a number, expected a function".to_string())
+        }
+        Ok(other) => {
+            return Err(format!(
+                "global `transform` exists but is a {}, expected a function",
+                other.type_name()
+            ))
+        }
+        Err(err) => return Err(format!("transform function not found - {}", err)),
+    }
+    if verbose {
+        println!(
+            "Successfully loaded transform function from {:?}",
+            lua_script
+        );
+    }
+
+    Ok(lua)
+}
+
+/// Functions of the `os` library kept in the sandbox, none of them touches the system.
+const SANDBOX_OS_FUNCTIONS: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+/// Creates a Lua state for untrusted scripts: only the base, string, table, math, utf8 and
+/// coroutine libraries are opened, without `io`, `package`/`require` or `debug`. The base
+/// functions reading files (`dofile`, `loadfile`) are removed, and `os` is reduced to
+/// `SANDBOX_OS_FUNCTIONS`.
+fn sandboxed_lua() -> Result<Lua, String> {
+    let libs = StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8 | StdLib::COROUTINE | StdLib::OS;
+    let lua = Lua::new_with(libs, LuaOptions::new()).map_err(|e| format!("Unable to create the Lua sandbox - {}", e))?;
+
+    let restrict = || -> mlua::Result<()> {
+        let globals = lua.globals();
+        globals.set("dofile", mlua::Value::Nil)?;
+        globals.set("loadfile", mlua::Value::Nil)?;
+
+        let os: Table = globals.get("os")?;
+        let safe_os = lua.create_table()?;
+        for name in SANDBOX_OS_FUNCTIONS {
+            safe_os.set(name, os.get::<Function>(name)?)?;
+        }
+        globals.set("os", safe_os)
+    };
+    restrict().map_err(|e| format!("Unable to create the Lua sandbox - {}", e))?;
+    Ok(lua)
+}
+
+/// Loads every `.lua` file of a folder, sorted by file name. A file that fails to load is
+/// reported and skipped.
+fn load_folder(lua: &Lua, folder: &str, kind: &str, verbose: bool) -> Result<(), String> {
+    let entries = fs::read_dir(folder)
+        .map_err(|e| format!("Unable to read {} folder {:?} - {}", kind, folder, e))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        paths.push(entry.path());
+    }
+    paths.sort();
+
+    for path in paths {
+        if path.is_file() && path.extension() == Some("lua".as_ref()) {
+            if verbose {
+                println!("{} folder {:?}", kind, path);
+            }
+
+            let result = lua.load(path.clone()).exec();
+
+            match result {
+                Ok(()) if verbose => println!("Successfully loaded script {:?}", path),
+                Ok(()) => (),
+                Err(err) => eprintln!("problem with {:?} - Error: {}", path, err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// JSON array of the `{field, error_type}` of each validation error, as handed to `transform`.
+pub fn errors_json(errors: &[ValidationError]) -> String {
+    let errors: Vec<Value> = errors
+        .iter()
+        .map(|error| json!({"field": error.field, "error_type": format!("{:?}", error.error_type)}))
+        .collect();
+    Value::from(errors).to_string()
+}
+
+/// Registers the global `json` table: `json.decode(text)` returns the Lua value of a JSON
+/// text and `json.encode(value)` the JSON text of a Lua value, both backed by serde_json.
+/// JSON null is decoded to `json.null` rather than nil, so it survives a round trip.
+pub fn register_json(lua: &Lua) -> mlua::Result<()> {
+    let json = lua.create_table()?;
+    json.set(
+        "decode",
+        lua.create_function(|lua, text: String| {
+            let value: Value = serde_json::from_str(&text).map_err(mlua::Error::external)?;
+            lua.to_value(&value)
+        })?,
+    )?;
+    json.set(
+        "encode",
+        lua.create_function(|lua, value: mlua::Value| {
+            let value: Value = lua.from_value(value)?;
+            Ok(value.to_string())
+        })?,
+    )?;
+    json.set("null", lua.null())?;
+    lua.globals().set("json", json)
+}
+
+/// Execute transformation on the JSON input using the Lua script. The document and its
+/// validation errors are passed as Lua tables and a table is expected back; with
+/// `string_transform`, as JSON texts for scripts returning a JSON text.
+pub fn lua_transform(
+    lua: &Lua,
+    doc: Value,
+    errors_json: &str,
+    string_transform: bool,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    // Get the Lua transform method
+    let transform: Function = lua.globals().get("transform")?;
+
+    if string_transform {
+        let input_json = doc.to_string();
+
+        // Call the Lua function with the JSON input; Lua drops the extra argument of
+        // a `transform(json)` written for a single argument
+        let output_str: String = transform.call((input_json, errors_json))?;
+
+        return serde_json::from_str(&output_str).map_err(|e| e.into());
+    }
+
+    let errors: Value = serde_json::from_str(errors_json)?;
+    let output: mlua::Value = transform.call((lua.to_value(&doc)?, lua.to_value(&errors)?))?;
+    table_output(lua, "transform", output)
+}
+
+/// Converts the table returned by a hook back to JSON, refusing the JSON text returned by
+/// a script written for --string-transform.
+fn table_output(lua: &Lua, hook: &str, output: mlua::Value) -> Result<Value, Box<dyn std::error::Error>> {
+    if output.is_string() {
+        return Err(format!("{} returned a string, scripts working on JSON text need --string-transform", hook).into());
+    }
+    Ok(lua.from_value(output)?)
+}
+
+/// Runs the optional `enrich(doc)` hook, which merges external data into the document
+/// before validation. Like `transform`, it receives and returns a Lua table, or JSON text
+/// with `string_transform`. Scripts that do not define `enrich` get the document back unchanged.
+pub fn lua_enrich(lua: &Lua, doc: Value, string_transform: bool) -> Result<Value, Box<dyn std::error::Error>> {
+    let enrich: Option<Function> = lua.globals().get("enrich")?;
+    match enrich {
+        Some(enrich) if string_transform => {
+            let output_str: String = enrich.call(doc.to_string())?;
+            serde_json::from_str(&output_str).map_err(|e| e.into())
+        }
+        Some(enrich) => {
+            let output: mlua::Value = enrich.call(lua.to_value(&doc)?)?;
+            table_output(lua, "enrich", output)
+        }
+        None => Ok(doc),
+    }
+}
+
+/// Runs the optional `approve(original, transformed)` hook right before an update, with both
+/// documents as Lua tables, or JSON text with `string_transform`. The update goes ahead unless
+/// it returns false (or nil); scripts that do not define `approve` approve everything.
+pub fn lua_approve(lua: &Lua, original: &Value, transformed: &Value, string_transform: bool) -> Result<bool, mlua::Error> {
+    let approve: Option<Function> = lua.globals().get("approve")?;
+    match approve {
+        Some(approve) if string_transform => approve.call((original.to_string(), transformed.to_string())),
+        Some(approve) => approve.call((lua.to_value(original)?, lua.to_value(transformed)?)),
+        None => Ok(true),
+    }
+}
+
+/// Calls the optional `on_batch(ids)` hook with the ids of a processed page.
+/// Scripts that do not define `on_batch` are left alone.
+pub fn lua_on_batch(lua: &Lua, ids: Vec<String>) -> Result<(), mlua::Error> {
+    let on_batch: Option<Function> = lua.globals().get("on_batch")?;
+    if let Some(on_batch) = on_batch {
+        let ids = lua.create_sequence_from(ids)?;
+        on_batch.call::<()>(ids)?;
+    }
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_on_batch_receives_page_ids() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            batches = {}
+            function on_batch(ids)
+                table.insert(batches, table.concat(ids, ","))
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        lua_on_batch(&lua, vec!["a".to_string(), "b".to_string()]).unwrap();
+        lua_on_batch(&lua, vec!["c".to_string()]).unwrap();
+
+        let batches: Vec<String> = lua.load("return batches").eval().unwrap();
+        assert_eq!(batches, vec!["a,b", "c"]);
+    }
+
+    #[test]
+    fn test_transform_that_is_not_a_function() {
+        let script_dir = std::env::temp_dir().join(format!("bulkmorph-script-{}", std::process::id()));
+        fs::create_dir_all(script_dir.join("include")).unwrap();
+        fs::write(script_dir.join("order.lua"), "transform = 42").unwrap();
+
+        let err = load_lua(script_dir.to_str().unwrap(), &[], "order", false).unwrap_err();
+        assert_eq!(err, "global `transform` exists but is a number, expected a function");
+
+        fs::remove_dir_all(script_dir).unwrap();
+    }
+
+    #[test]
+    fn test_lua_lib_helpers_are_available_to_the_transform() {
+        let root = std::env::temp_dir().join(format!("bulkmorph-lua-lib-{}", std::process::id()));
+        let lua_lib = root.join("lib");
+        let script_dir = root.join("scripts");
+        fs::create_dir_all(&lua_lib).unwrap();
+        fs::create_dir_all(script_dir.join("include")).unwrap();
+        fs::write(lua_lib.join("numbers.lua"), "function to_number(s) return tonumber(s) end").unwrap();
+        // Local includes are loaded after the libraries and can use them
+        fs::write(script_dir.join("include").join("quantity.lua"), "function fix_quantity(s) return to_number(s) end").unwrap();
+        fs::write(
+            script_dir.join("order.lua"),
+            r#"function transform(doc)
+                return (string.gsub(doc, '"quantity":"(%d+)"', function(q) return '"quantity":' .. fix_quantity(q) end))
+            end"#,
+        )
+        .unwrap();
+
+        let lua_libs = vec![lua_lib.to_str().unwrap().to_string()];
+        let lua = load_lua(script_dir.to_str().unwrap(), &lua_libs, "order", false).unwrap();
+        let doc = serde_json::json!({"_id": "order-1", "quantity": "10"});
+        assert_eq!(
+            lua_transform(&lua, doc, "[]", true).unwrap(),
+            serde_json::json!({"_id": "order-1", "quantity": 10})
+        );
+
+        let missing = vec![root.join("missing").to_str().unwrap().to_string()];
+        let err = load_lua(script_dir.to_str().unwrap(), &missing, "order", false).unwrap_err();
+        assert!(err.starts_with("Unable to read Lua library folder"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_transform_receives_the_validation_errors() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc, errors)
+                local count = select(2, string.gsub(errors, '"error_type"', ''))
+                return (string.gsub(doc, '}$', ',"error_count":' .. count .. '}'))
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        let errors = vec![
+            ValidationError {
+                field: "quantity".to_string(),
+                error_type: crate::valid_proto::ErrorType::WrongDataType,
+                found: None,
+                expected: None,
+            },
+            ValidationError {
+                field: "customer".to_string(),
+                error_type: crate::valid_proto::ErrorType::MissingField,
+                found: None,
+                expected: None,
+            },
+        ];
+        assert_eq!(
+            errors_json(&errors),
+            r#"[{"error_type":"WrongDataType","field":"quantity"},{"error_type":"MissingField","field":"customer"}]"#
+        );
+        let doc = serde_json::json!({"_id": "order-1"});
+        assert_eq!(
+            lua_transform(&lua, doc, &errors_json(&errors), true).unwrap(),
+            serde_json::json!({"_id": "order-1", "error_count": 2})
+        );
+    }
+
+    #[test]
+    fn test_transform_edits_the_document_as_a_table() {
+        let lua = Lua::new();
+        register_json(&lua).unwrap();
+        lua.load(
+            r#"
+            function transform(doc, errors)
+                doc.customer.address.zip = tonumber(doc.customer.address.zip)
+                doc.fixed = errors[1].field
+                doc.notes = json.decode(json.encode({"checked"}))
+                return doc
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        let doc = serde_json::json!({
+            "_id": "order-1",
+            "customer": {"name": "Ada", "address": {"zip": "12345", "line2": null}},
+            "tags": []
+        });
+        let errors = r#"[{"field":"customer.address.zip","error_type":"WrongDataType"}]"#;
+        assert_eq!(
+            lua_transform(&lua, doc.clone(), errors, false).unwrap(),
+            serde_json::json!({
+                "_id": "order-1",
+                "customer": {"name": "Ada", "address": {"zip": 12345, "line2": null}},
+                "tags": [],
+                "fixed": "customer.address.zip",
+                "notes": ["checked"]
+            })
+        );
+
+        // A script still returning JSON text is pointed to --string-transform
+        lua.load("function transform(doc) return '{}' end").exec().unwrap();
+        let err = lua_transform(&lua, doc, "[]", false).unwrap_err();
+        assert!(err.to_string().contains("--string-transform"));
+    }
+
+    #[test]
+    fn test_sandbox_removes_system_access() {
+        let script_dir = std::env::temp_dir().join(format!("bulkmorph-sandbox-{}", std::process::id()));
+        fs::create_dir_all(script_dir.join("include")).unwrap();
+        fs::write(
+            script_dir.join("order.lua"),
+            r#"function transform(doc)
+                return (string.gsub(doc, '"quantity":"(%d+)"', function(q) return '"quantity":' .. math.floor(tonumber(q)) end))
+            end"#,
+        )
+        .unwrap();
+        let script_dir = script_dir.to_str().unwrap();
+
+        let lua = load_lua(script_dir, &[], "order", true).unwrap();
+        let missing: Vec<bool> = lua
+            .load("return { os.execute == nil, os.getenv == nil, io == nil, require == nil, package == nil, dofile == nil, loadfile == nil }")
+            .eval()
+            .unwrap();
+        assert!(missing.iter().all(|missing| *missing), "{:?}", missing);
+        // string, table and math helpers and the clock are still there
+        let available: bool = lua
+            .load("return type(string.format) == 'function' and type(table.concat) == 'function' and type(os.time) == 'function'")
+            .eval()
+            .unwrap();
+        assert!(available);
+        let doc = serde_json::json!({"_id": "order-1", "quantity": "10"});
+        assert_eq!(
+            lua_transform(&lua, doc, "[]", true).unwrap(),
+            serde_json::json!({"_id": "order-1", "quantity": 10})
+        );
+
+        // Without the sandbox, scripts keep the full standard library
+        let lua = load_lua(script_dir, &[], "order", false).unwrap();
+        let has_execute: bool = lua.load("return os.execute ~= nil").eval().unwrap();
+        assert!(has_execute);
+
+        fs::remove_dir_all(script_dir).unwrap();
+    }
+
+    #[test]
+    fn test_approve_is_optional() {
+        let lua = Lua::new();
+        let doc = serde_json::json!({"_id": "order-1"});
+        assert!(lua_approve(&lua, &doc, &doc, false).unwrap());
+    }
+
+    #[test]
+    fn test_on_batch_is_optional() {
+        let lua = Lua::new();
+        assert!(lua_on_batch(&lua, vec!["a".to_string()]).is_ok());
+    }
+}
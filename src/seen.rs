@@ -0,0 +1,132 @@
+use std::{cell::RefCell, collections::HashSet, fs};
+
+use serde_json::Value;
+
+/// Remembers which document revisions already validated, so incremental runs can skip them.
+/// The file holds one hex-encoded hash of `_id` + `_rev` per line.
+pub struct SeenFile {
+    path: String,
+    previous: HashSet<u64>,         // Revisions recorded by earlier runs
+    current: RefCell<HashSet<u64>>, // Revisions validated during this run
+}
+
+impl SeenFile {
+    /// Loads the hashes recorded by a previous run. A missing file starts empty.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let previous = match fs::read_to_string(path) {
+            Ok(content) => content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    u64::from_str_radix(line.trim(), 16)
+                        .map_err(|e| format!("Invalid entry {:?} in {:?} - {}", line, path, e))
+                })
+                .collect::<Result<HashSet<u64>, String>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(format!("Unable to read seen file {:?} - {}", path, e)),
+        };
+
+        Ok(SeenFile {
+            path: path.to_string(),
+            previous,
+            current: RefCell::new(HashSet::new()),
+        })
+    }
+
+    /// Whether this exact revision validated in a previous run.
+    pub fn contains(&self, doc: &Value) -> bool {
+        self.previous.contains(&revision_hash(doc))
+    }
+
+    /// Records a revision that validated during this run.
+    pub fn record(&self, doc: &Value) {
+        self.current.borrow_mut().insert(revision_hash(doc));
+    }
+
+    /// Writes the revisions recorded so far, including those of previous runs.
+    pub fn save(&self) -> Result<(), String> {
+        let current = self.current.borrow();
+        let content: String = self
+            .previous
+            .union(&current)
+            .map(|hash| format!("{:016x}\n", hash))
+            .collect();
+        fs::write(&self.path, content)
+            .map_err(|e| format!("Unable to write seen file {:?} - {}", self.path, e))
+    }
+}
+
+/// Loads a list of document ids, one per line. Blank lines are ignored.
+pub fn load_ids(path: &str) -> Result<HashSet<String>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read ids file {:?} - {}", path, e))?;
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// FNV-1a hash of `_id` and `_rev`, stable across runs and Rust versions.
+fn revision_hash(doc: &Value) -> u64 {
+    let id = doc["_id"].as_str().unwrap_or_default();
+    let rev = doc["_rev"].as_str().unwrap_or_default();
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in id.bytes().chain([0u8]).chain(rev.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_unchanged_revision_is_skipped_on_second_run() {
+        let path = std::env::temp_dir().join(format!("bulkmorph-seen-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let doc = json!({"_id": "order-1", "_rev": "1-a", "amount": 10});
+        let other = json!({"_id": "order-2", "_rev": "1-b", "amount": 12});
+
+        // First run: nothing seen yet, both documents validate
+        let first = SeenFile::load(path).unwrap();
+        assert!(!first.contains(&doc));
+        first.record(&doc);
+        first.record(&other);
+        first.save().unwrap();
+
+        // Second run: same revision is skipped, a new revision is validated again
+        let second = SeenFile::load(path).unwrap();
+        assert!(second.contains(&doc));
+        let updated = json!({"_id": "order-2", "_rev": "2-c", "amount": "12"});
+        assert!(!second.contains(&updated));
+        second.save().unwrap();
+
+        // Earlier runs are kept when the file is saved again
+        let third = SeenFile::load(path).unwrap();
+        assert!(third.contains(&doc));
+        assert!(third.contains(&other));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_ids_ignores_blank_lines() {
+        let path = std::env::temp_dir().join(format!("bulkmorph-skip-ids-{}.txt", std::process::id()));
+        fs::write(&path, "order-1\n\n  order-2  \n").unwrap();
+
+        let ids = load_ids(path.to_str().unwrap()).unwrap();
+        assert_eq!(ids, HashSet::from(["order-1".to_string(), "order-2".to_string()]));
+
+        fs::remove_file(&path).unwrap();
+        assert!(load_ids(path.to_str().unwrap()).is_err());
+    }
+}
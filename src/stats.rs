@@ -0,0 +1,488 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use serde::Serialize;
+
+use crate::{pipeline::Outcome, valid_proto::ValidationError};
+
+/// Document counts for a whole run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunStats {
+    pub scanned: usize,       // Documents passed to the pipeline
+    pub valid: usize,         // Already valid, unchanged since a previous run, skipped by id or already stamped
+    pub transformed: usize,   // Repaired by coercion or the transform
+    pub still_invalid: usize, // Still invalid after the transform (skipped, reported, deleted or dumped) or of another type
+    pub updated: usize,       // Repaired documents actually written
+    pub failed: usize,        // Transform, safety check, size, write or verification failures, duplicate ids
+    pub deferred: usize,      // Left for a later run by --sample-rate
+    pub elapsed_secs: f64,    // Wall-clock duration of the run
+}
+
+impl RunStats {
+    /// Counts the outcome of one document.
+    pub fn record(&mut self, outcome: &Outcome) {
+        self.scanned += 1;
+        match outcome {
+            Outcome::Valid | Outcome::Unchanged | Outcome::Skipped | Outcome::AlreadyStamped => {
+                self.valid += 1
+            }
+            Outcome::WouldUpdate => self.transformed += 1,
+            Outcome::Updated => {
+                self.transformed += 1;
+                self.updated += 1;
+            }
+            Outcome::StillInvalid
+            | Outcome::WouldDelete
+            | Outcome::Deleted
+            | Outcome::Dumped
+            | Outcome::Listed
+            | Outcome::LikelyWrongType => self.still_invalid += 1,
+            Outcome::Deferred => self.deferred += 1,
+            Outcome::Aborted => (), // Not processed, never recorded
+            Outcome::ConflictDeferred => (), // Counted by the retry after the scan
+            Outcome::Buffered => (),         // Counted once the buffered updates are written
+            Outcome::Rejected
+            | Outcome::TransformFailed
+            | Outcome::WriteFailed
+            | Outcome::TooLarge
+            | Outcome::VerificationFailed
+            | Outcome::DuplicateId => self.failed += 1,
+        }
+    }
+
+    /// Counts the final outcome of a document already counted as scanned, retried after the
+    /// scan or written in bulk.
+    pub fn record_retry(&mut self, outcome: &Outcome) {
+        self.record(outcome);
+        self.scanned -= 1;
+    }
+
+    /// Share of the documents, in percent, that did not validate as fetched.
+    pub fn invalid_rate(&self) -> f64 {
+        if self.scanned == 0 {
+            return 0.0;
+        }
+        (self.scanned - self.valid) as f64 * 100.0 / self.scanned as f64
+    }
+
+    /// The invalid rate, once at least `warmup` documents were scanned and the rate is
+    /// above `max_rate` percent.
+    pub fn invalid_rate_exceeded(&self, max_rate: f64, warmup: usize) -> Option<f64> {
+        let rate = self.invalid_rate();
+        (self.scanned >= warmup && rate > max_rate).then_some(rate)
+    }
+
+    /// Writes the counts as a single JSON object.
+    pub fn write_json(&self, path: &str) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, content)
+            .map_err(|e| format!("Unable to write summary {:?} - {}", path, e))
+    }
+
+    /// Documents scanned per second of wall-clock time.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed_secs <= 0.0 {
+            return 0.0;
+        }
+        self.scanned as f64 / self.elapsed_secs
+    }
+}
+
+/// The summary block printed at the end of a run.
+impl std::fmt::Display for RunStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Summary: {} documents scanned in {:.1}s ({:.1} documents/s)",
+            self.scanned,
+            self.elapsed_secs,
+            self.throughput()
+        )?;
+        writeln!(f, "  valid:         {}", self.valid)?;
+        writeln!(f, "  invalid:       {}", self.scanned - self.valid)?;
+        writeln!(f, "  transformed:   {}", self.transformed)?;
+        writeln!(f, "  still invalid: {}", self.still_invalid)?;
+        writeln!(f, "  updated:       {}", self.updated)?;
+        writeln!(f, "  failed:        {}", self.failed)?;
+        write!(f, "  deferred:      {}", self.deferred)
+    }
+}
+
+/// Validation errors of a whole run, counted by field path and error type.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorHistogram {
+    counts: HashMap<(String, String), usize>, // (field path, error type) -> occurrences
+    documents: HashMap<String, usize>,        // Error type -> documents with at least one such error
+}
+
+impl ErrorHistogram {
+    /// Counts the errors of one document. Array indices are dropped from the paths, so
+    /// `items[0].id` and `items[7].id` are both counted as `items[].id`.
+    pub fn record(&mut self, errors: &[ValidationError]) {
+        for error in errors {
+            let key = (field_pattern(&error.field), format!("{:?}", error.error_type));
+            *self.counts.entry(key).or_insert(0) += 1;
+        }
+
+        let error_types: HashSet<String> = errors.iter().map(|error| format!("{:?}", error.error_type)).collect();
+        for error_type in error_types {
+            *self.documents.entry(error_type).or_insert(0) += 1;
+        }
+    }
+
+    /// `(error type, documents)` rows, the most widespread type first, then by type.
+    pub fn documents_by_type(&self) -> Vec<(&str, usize)> {
+        let mut rows: Vec<(&str, usize)> = self
+            .documents
+            .iter()
+            .map(|(error_type, count)| (error_type.as_str(), *count))
+            .collect();
+        rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        rows
+    }
+
+    /// `(field path, error type, count)` rows, most frequent first, then by path and type.
+    pub fn rows(&self) -> Vec<(&str, &str, usize)> {
+        let mut rows: Vec<(&str, &str, usize)> = self
+            .counts
+            .iter()
+            .map(|((field, error_type), count)| (field.as_str(), error_type.as_str(), *count))
+            .collect();
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)).then(a.1.cmp(b.1)));
+        rows
+    }
+
+    /// Writes the rows as CSV with a `field_path,error_type,count` header.
+    pub fn write_csv(&self, path: &str) -> Result<(), String> {
+        let mut content = String::from("field_path,error_type,count\n");
+        for (field, error_type, count) in self.rows() {
+            content.push_str(&format!("{},{},{}\n", csv_field(field), error_type, count));
+        }
+        fs::write(path, content)
+            .map_err(|e| format!("Unable to write error summary {:?} - {}", path, e))
+    }
+}
+
+/// One document of the `--report` file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReportRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub errors: Vec<ReportedError>,         // Validation errors of the document as fetched
+    pub transform_attempted: bool,          // Whether the Lua transform was called
+    pub errors_after_transform: Vec<ReportedError>, // Errors of the transform output
+    pub outcome: &'static str,              // updated, skipped or failed
+    pub detail: String,                     // Precise outcome, e.g. WouldUpdate or TooLarge
+}
+
+/// Field path and type of a validation error, as written to the report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportedError {
+    pub field: String,
+    pub error_type: String,
+}
+
+impl ReportedError {
+    fn from_errors(errors: &[ValidationError]) -> Vec<ReportedError> {
+        errors
+            .iter()
+            .map(|error| ReportedError {
+                field: error.field.clone(),
+                error_type: format!("{:?}", error.error_type),
+            })
+            .collect()
+    }
+}
+
+/// What happened to each document of the run, in processing order, for `--report`.
+#[derive(Debug, Clone, Default)]
+pub struct DocumentReport {
+    records: Vec<ReportRecord>,
+    positions: HashMap<String, usize>, // Id -> index of its record
+}
+
+impl DocumentReport {
+    fn entry(&mut self, id: &str) -> &mut ReportRecord {
+        let position = *self.positions.entry(id.to_string()).or_insert_with(|| {
+            self.records.push(ReportRecord {
+                id: id.to_string(),
+                outcome: "skipped",
+                ..Default::default()
+            });
+            self.records.len() - 1
+        });
+        &mut self.records[position]
+    }
+
+    /// Records the validation errors of a document as fetched.
+    pub fn validated(&mut self, id: &str, errors: &[ValidationError]) {
+        self.entry(id).errors = ReportedError::from_errors(errors);
+    }
+
+    /// Records that the transform was called for a document.
+    pub fn transform_attempted(&mut self, id: &str) {
+        self.entry(id).transform_attempted = true;
+    }
+
+    /// Records the validation errors of the transform output.
+    pub fn transformed(&mut self, id: &str, errors: &[ValidationError]) {
+        self.entry(id).errors_after_transform = ReportedError::from_errors(errors);
+    }
+
+    /// Records the final outcome of a document, replacing an earlier one such as Buffered.
+    pub fn outcome(&mut self, id: &str, outcome: &Outcome) {
+        let record = self.entry(id);
+        record.outcome = match outcome {
+            Outcome::Updated | Outcome::WouldUpdate | Outcome::Buffered => "updated",
+            Outcome::Rejected
+            | Outcome::TransformFailed
+            | Outcome::WriteFailed
+            | Outcome::TooLarge
+            | Outcome::VerificationFailed
+            | Outcome::StillInvalid
+            | Outcome::WouldDelete
+            | Outcome::Deleted
+            | Outcome::LikelyWrongType
+            | Outcome::DuplicateId => "failed",
+            Outcome::Valid
+            | Outcome::Unchanged
+            | Outcome::Skipped
+            | Outcome::AlreadyStamped
+            | Outcome::Dumped
+            | Outcome::Listed
+            | Outcome::Deferred
+            | Outcome::ConflictDeferred
+            | Outcome::Aborted => "skipped",
+        };
+        record.detail = format!("{:?}", outcome);
+    }
+
+    pub fn records(&self) -> &[ReportRecord] {
+        &self.records
+    }
+
+    /// Writes the records as a JSON array.
+    pub fn write_json(&self, path: &str) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(&self.records).map_err(|e| e.to_string())?;
+        fs::write(path, content).map_err(|e| format!("Unable to write report {:?} - {}", path, e))
+    }
+}
+
+/// The field path with the array indices removed, map keys are kept.
+fn field_pattern(field: &str) -> String {
+    let mut pattern = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(start) = rest.find('[') {
+        pattern.push_str(&rest[..=start]);
+        rest = &rest[start + 1..];
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits > 0 && rest[digits..].starts_with(']') {
+            rest = &rest[digits..];
+        }
+    }
+    pattern.push_str(rest);
+    pattern
+}
+
+/// Quotes a CSV value containing a comma, a quote or a line break, e.g. a map key.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_summary_json_after_fixture_run() {
+        let mut stats = RunStats::default();
+        for outcome in [
+            Outcome::Valid,
+            Outcome::Valid,
+            Outcome::Unchanged,
+            Outcome::Updated,
+            Outcome::WouldUpdate,
+            Outcome::StillInvalid,
+            Outcome::Deleted,
+            Outcome::TransformFailed,
+            Outcome::WriteFailed,
+            Outcome::Deferred,
+        ] {
+            stats.record(&outcome);
+        }
+        stats.elapsed_secs = 1.5;
+
+        let path = std::env::temp_dir().join(format!("bulkmorph-summary-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        stats.write_json(path).unwrap();
+
+        let summary: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(summary["scanned"], 10);
+        assert_eq!(summary["valid"], 3);
+        assert_eq!(summary["transformed"], 2);
+        assert_eq!(summary["still_invalid"], 2);
+        assert_eq!(summary["updated"], 1);
+        assert_eq!(summary["failed"], 2);
+        assert_eq!(summary["deferred"], 1);
+        assert_eq!(summary["elapsed_secs"], 1.5);
+        assert_eq!(summary.as_object().unwrap().len(), 8);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_summary_block_after_fixture_run() {
+        let mut stats = RunStats::default();
+        for outcome in [
+            Outcome::Valid,
+            Outcome::Valid,
+            Outcome::Updated,
+            Outcome::Updated,
+            Outcome::WouldUpdate,
+            Outcome::StillInvalid,
+            Outcome::TransformFailed,
+            Outcome::Deferred,
+        ] {
+            stats.record(&outcome);
+        }
+        stats.elapsed_secs = 2.0;
+
+        assert_eq!(stats.throughput(), 4.0);
+        assert_eq!(
+            stats.to_string(),
+            "Summary: 8 documents scanned in 2.0s (4.0 documents/s)
+  valid:         2
+  invalid:       6
+  transformed:   3
+  still invalid: 1
+  updated:       2
+  failed:        1
+  deferred:      1"
+        );
+        assert_eq!(RunStats::default().throughput(), 0.0);
+    }
+
+    #[test]
+    fn test_invalid_rate_exceeded_after_warmup() {
+        let mut stats = RunStats::default();
+        for _ in 0..8 {
+            stats.record(&Outcome::StillInvalid);
+        }
+        stats.record(&Outcome::Valid);
+        // High rate, but the warmup is not over yet
+        assert_eq!(stats.invalid_rate_exceeded(50.0, 10), None);
+
+        stats.record(&Outcome::Updated);
+        assert_eq!(stats.invalid_rate(), 90.0);
+        assert_eq!(stats.invalid_rate_exceeded(50.0, 10), Some(90.0));
+        assert_eq!(stats.invalid_rate_exceeded(95.0, 10), None);
+    }
+
+    #[test]
+    fn test_error_csv_after_fixture_run() {
+        use crate::valid_proto::ErrorType;
+
+        let error = |field: &str, error_type: ErrorType| ValidationError {
+            field: field.to_string(),
+            error_type,
+            found: None,
+            expected: None,
+        };
+        let mut histogram = ErrorHistogram::default();
+        histogram.record(&[
+            error("quantity", ErrorType::WrongDataType),
+            error("items[0].id", ErrorType::WrongDataType),
+            error("items[1].id", ErrorType::WrongDataType),
+        ]);
+        histogram.record(&[error("quantity", ErrorType::WrongDataType), error("name", ErrorType::MissingField)]);
+        histogram.record(&[
+            error("items[12].id", ErrorType::WrongDataType),
+            error("stock[\"a,b\"]", ErrorType::NullValue),
+        ]);
+        histogram.record(&[]);
+        // Documents per error type, whatever the number of errors in each document
+        assert_eq!(
+            histogram.documents_by_type(),
+            vec![("WrongDataType", 3), ("MissingField", 1), ("NullValue", 1)]
+        );
+
+        let path = std::env::temp_dir().join(format!("bulkmorph-errors-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        histogram.write_csv(path).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "field_path,error_type,count
+items[].id,WrongDataType,3
+quantity,WrongDataType,2
+name,MissingField,1
+\"stock[\"\"a,b\"\"]\",NullValue,1
+"
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_report_records_each_document() {
+        use crate::valid_proto::ErrorType;
+
+        let error = |field: &str, error_type: ErrorType| ValidationError {
+            field: field.to_string(),
+            error_type,
+            found: None,
+            expected: None,
+        };
+        let mut report = DocumentReport::default();
+        report.validated("order-1", &[]);
+        report.outcome("order-1", &Outcome::Valid);
+        report.validated("order-2", &[error("quantity", ErrorType::WrongDataType)]);
+        report.transform_attempted("order-2");
+        report.transformed("order-2", &[]);
+        report.outcome("order-2", &Outcome::Buffered);
+        report.validated("order-3", &[error("quantity", ErrorType::WrongDataType)]);
+        report.transform_attempted("order-3");
+        report.transformed("order-3", &[error("name", ErrorType::MissingField)]);
+        report.outcome("order-3", &Outcome::StillInvalid);
+        // The buffered update is written later
+        report.outcome("order-2", &Outcome::Updated);
+
+        let path = std::env::temp_dir().join(format!("bulkmorph-report-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        report.write_json(path).unwrap();
+
+        let written: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!([
+                {"_id": "order-1", "errors": [], "transform_attempted": false, "errors_after_transform": [], "outcome": "skipped", "detail": "Valid"},
+                {
+                    "_id": "order-2",
+                    "errors": [{"field": "quantity", "error_type": "WrongDataType"}],
+                    "transform_attempted": true,
+                    "errors_after_transform": [],
+                    "outcome": "updated",
+                    "detail": "Updated"
+                },
+                {
+                    "_id": "order-3",
+                    "errors": [{"field": "quantity", "error_type": "WrongDataType"}],
+                    "transform_attempted": true,
+                    "errors_after_transform": [{"field": "name", "error_type": "MissingField"}],
+                    "outcome": "failed",
+                    "detail": "StillInvalid"
+                }
+            ])
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
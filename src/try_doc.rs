@@ -0,0 +1,137 @@
+use std::io::Write;
+
+use mlua::Lua;
+use protobuf::descriptor::FileDescriptorSet;
+use serde_json::Value;
+
+use crate::script;
+use crate::valid_proto::{self, ValidationError};
+
+/// What the pipeline would do with the document.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Valid,        // Matches the schema, the transform is not called
+    Fixed,        // Invalid, but the transform output matches the schema
+    StillInvalid, // The transform output still does not match the schema
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Verdict::Valid => write!(f, "valid, no transform needed"),
+            Verdict::Fixed => write!(f, "fixed by the transform"),
+            Verdict::StillInvalid => write!(f, "still invalid after the transform"),
+        }
+    }
+}
+
+/// Runs a single document through validate -> transform -> validate, printing the errors,
+/// the transform output and the verdict to `out`.
+pub fn try_document(
+    lua: &Lua,
+    file_descriptor_set: &FileDescriptorSet,
+    table_name: &str,
+    ignore_list: Vec<String>,
+    doc: Value,
+    string_transform: bool,
+    out: &mut impl Write,
+) -> Result<Verdict, String> {
+    let validate = |doc: &Value| {
+        valid_proto::validate_json(file_descriptor_set, table_name, doc, ignore_list.clone())
+    };
+
+    let errors = validate(&doc);
+    print_errors(out, "Validation errors", &errors)?;
+    if errors.is_empty() {
+        return print_verdict(out, Verdict::Valid);
+    }
+
+    let output = script::lua_transform(lua, doc, &script::errors_json(&errors), string_transform)
+        .map_err(|err| format!("transform failed - {}", err))?;
+    let pretty = serde_json::to_string_pretty(&output).map_err(|e| e.to_string())?;
+    writeln!(out, "Transform output:\n{}", pretty).map_err(|e| e.to_string())?;
+
+    let errors = validate(&output);
+    print_errors(out, "Validation errors after transform", &errors)?;
+    if errors.is_empty() {
+        print_verdict(out, Verdict::Fixed)
+    } else {
+        print_verdict(out, Verdict::StillInvalid)
+    }
+}
+
+fn print_errors(out: &mut impl Write, title: &str, errors: &[ValidationError]) -> Result<(), String> {
+    if errors.is_empty() {
+        return writeln!(out, "{}: none", title).map_err(|e| e.to_string());
+    }
+    writeln!(out, "{}:", title).map_err(|e| e.to_string())?;
+    for e in errors {
+        writeln!(out, "Error: {}", e).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn print_verdict(out: &mut impl Write, verdict: Verdict) -> Result<Verdict, String> {
+    writeln!(out, "Verdict: {}", verdict).map_err(|e| e.to_string())?;
+    Ok(verdict)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{
+        descriptor::{field_descriptor_proto::Type, DescriptorProto, FieldDescriptorProto},
+        EnumOrUnknown,
+    };
+    use serde_json::json;
+
+    fn create_test_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.name = Some("Order.proto".to_string());
+
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        let mut field = FieldDescriptorProto::new();
+        field.name = Some("quantity".to_string());
+        field.json_name = Some("quantity".to_string());
+        field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        order.field.push(field);
+
+        file.message_type.push(order);
+        file_set.file.push(file);
+        file_set
+    }
+
+    #[test]
+    fn test_try_invalid_document_inline() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            function transform(doc)
+                return (string.gsub(doc, '"quantity":"ten"', '"quantity":10'))
+            end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let ignore_list = vec!["_id".to_string(), "_rev".to_string()];
+
+        let mut out = Vec::new();
+        let doc = json!({"_id": "order-1", "quantity": "ten"});
+        let verdict = try_document(&lua, &create_test_descriptor(), "Order", ignore_list.clone(), doc, true, &mut out);
+        assert_eq!(verdict, Ok(Verdict::Fixed));
+        let printed = String::from_utf8(out).unwrap();
+        assert!(printed.contains("Error: quantity - WrongDataType"));
+        assert!(printed.contains("\"quantity\": 10"));
+        assert!(printed.ends_with("Verdict: fixed by the transform\n"));
+
+        let mut out = Vec::new();
+        let doc = json!({"_id": "order-2", "quantity": "many"});
+        let verdict = try_document(&lua, &create_test_descriptor(), "Order", ignore_list, doc, true, &mut out);
+        assert_eq!(verdict, Ok(Verdict::StillInvalid));
+        let printed = String::from_utf8(out).unwrap();
+        assert!(printed.ends_with("Verdict: still invalid after the transform\n"));
+    }
+}
@@ -0,0 +1,91 @@
+use serde_json::{Map, Value};
+
+/// Rewrites dotted top-level keys into nested objects, `{"a.b": 1}` becomes `{"a": {"b": 1}}`.
+/// A dotted key is merged into an existing object, but never replaces a value: a key
+/// whose path is already taken, or goes through a non-object, is reported as a conflict.
+pub fn unflatten(doc: &Value) -> Result<Value, String> {
+    let Some(doc_obj) = doc.as_object() else {
+        return Ok(doc.clone());
+    };
+
+    // Plain keys first, so dotted keys are checked against the whole document
+    let mut unflattened: Map<String, Value> = doc_obj
+        .iter()
+        .filter(|(key, _)| !is_dotted(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    for (key, value) in doc_obj.iter().filter(|(key, _)| is_dotted(key)) {
+        let segments: Vec<&str> = key.split('.').collect();
+        let (leaf, parents) = segments.split_last().unwrap_or((&"", &[]));
+
+        let mut target = &mut unflattened;
+        for segment in parents {
+            let entry = target
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            target = entry.as_object_mut().ok_or_else(|| {
+                format!("key {:?} conflicts with non-object field {:?}", key, segment)
+            })?;
+        }
+        if target.contains_key(*leaf) {
+            return Err(format!("key {:?} conflicts with an existing nested field", key));
+        }
+        target.insert(leaf.to_string(), value.clone());
+    }
+
+    Ok(Value::Object(unflattened))
+}
+
+/// Whether a key is a dotted path made of non-empty segments, e.g. `address.city`.
+fn is_dotted(key: &str) -> bool {
+    key.contains('.') && key.split('.').all(|segment| !segment.is_empty())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_dotted_keys_become_nested_objects() {
+        let doc = json!({
+            "_id": "customer-1",
+            "address.city": "Kuala Lumpur",
+            "address.geo.lat": 3.139,
+            "contact": {"email": "a@b.c"},
+            "contact.phone": "012",
+            "version..2": true
+        });
+
+        assert_eq!(
+            unflatten(&doc).unwrap(),
+            json!({
+                "_id": "customer-1",
+                "address": {"city": "Kuala Lumpur", "geo": {"lat": 3.139}},
+                "contact": {"email": "a@b.c", "phone": "012"},
+                "version..2": true
+            })
+        );
+
+        // Nothing to do without dotted keys
+        let nested = json!({"a": {"b": 1}});
+        assert_eq!(unflatten(&nested).unwrap(), nested);
+    }
+
+    #[test]
+    fn test_conflicting_keys_are_reported() {
+        let doc = json!({"a.b": 1, "a": {"b": 2}});
+        assert_eq!(
+            unflatten(&doc),
+            Err(r#"key "a.b" conflicts with an existing nested field"#.to_string())
+        );
+
+        let doc = json!({"a.b": 1, "a": "text"});
+        assert_eq!(
+            unflatten(&doc),
+            Err(r#"key "a.b" conflicts with non-object field "a""#.to_string())
+        );
+    }
+}
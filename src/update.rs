@@ -0,0 +1,451 @@
+use md5::{Digest, Md5};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+use crate::client::send_with_retry;
+
+/// Persists changes to a document in CouchDB when the dry-run mode is disabled.
+pub async fn update_document(
+    client: &Client,
+    db_host: &str,
+    table_name: &str,
+    doc: &Value,
+) -> Result<(), String> {
+    let id = doc["_id"].as_str().ok_or("Document missing '_id' field")?;
+    let rev = doc["_rev"]
+        .as_str()
+        .ok_or("Document missing '_rev' field")?;
+    let idencoded = urlencoding::encode(id);
+    let url = format!("{}/{}/{}", db_host, table_name, idencoded);
+
+    let response = send_with_retry(client.put(&url).json(doc).header("If-Match", rev)).await?;
+
+    if response.status() != StatusCode::OK && response.status() != StatusCode::CREATED {
+        return Err(format!(
+            "Failed to update document {}: Status code {}",
+            id,
+            response.status()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether an update failed because the document was changed since it was fetched.
+pub fn is_conflict(err: &str) -> bool {
+    err.contains(&format!("Status code {}", StatusCode::CONFLICT))
+}
+
+/// Reads the current revision of a document back from CouchDB.
+pub async fn get_document(
+    client: &Client,
+    db_host: &str,
+    table_name: &str,
+    id: &str,
+) -> Result<Value, String> {
+    let url = format!("{}/{}/{}", db_host, table_name, urlencoding::encode(id));
+
+    let response = send_with_retry(client.get(&url)).await?;
+    if response.status() != StatusCode::OK {
+        return Err(format!(
+            "Failed to read document {}: Status code {}",
+            id,
+            response.status()
+        ));
+    }
+
+    response.json().await.map_err(|e| e.to_string())
+}
+
+/// Marks a document as deleted in CouchDB, used for records that cannot be repaired.
+pub async fn delete_document(
+    client: &Client,
+    db_host: &str,
+    table_name: &str,
+    doc: &Value,
+) -> Result<(), String> {
+    let id = doc["_id"].as_str().ok_or("Document missing '_id' field")?;
+    let rev = doc["_rev"]
+        .as_str()
+        .ok_or("Document missing '_rev' field")?;
+    let idencoded = urlencoding::encode(id);
+    let url = format!("{}/{}/{}", db_host, table_name, idencoded);
+
+    let response = send_with_retry(client.delete(&url).query(&[("rev", rev)])).await?;
+
+    if response.status() != StatusCode::OK && response.status() != StatusCode::ACCEPTED {
+        return Err(format!(
+            "Failed to delete document {}: Status code {}",
+            id,
+            response.status()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Writes a transformed document to a shadow table for rehearsal. `_rev` is dropped so the
+/// document is created fresh; one left by a previous rehearsal is overwritten.
+pub async fn write_shadow_document(
+    client: &Client,
+    db_host: &str,
+    shadow_table: &str,
+    doc: &Value,
+) -> Result<(), String> {
+    let id = doc["_id"].as_str().ok_or("Document missing '_id' field")?;
+    let url = format!("{}/{}/{}", db_host, shadow_table, urlencoding::encode(id));
+
+    let mut shadow_doc = doc.clone();
+    if let Some(fields) = shadow_doc.as_object_mut() {
+        fields.remove("_rev");
+        fields.remove("_revisions");
+    }
+
+    let mut response = send_with_retry(client.put(&url).json(&shadow_doc)).await?;
+
+    // Already written by a previous rehearsal, overwrite its current revision
+    if response.status() == StatusCode::CONFLICT {
+        let existing = send_with_retry(client.head(&url)).await?;
+        let rev = existing
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| etag.trim_matches('"').to_string())
+            .ok_or_else(|| format!("No revision found for shadow document {}", id))?;
+        response = send_with_retry(client.put(&url).query(&[("rev", rev)]).json(&shadow_doc)).await?;
+    }
+
+    if response.status() != StatusCode::OK && response.status() != StatusCode::CREATED {
+        return Err(format!(
+            "Failed to write shadow document {}: Status code {}",
+            id,
+            response.status()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Writes documents through `_bulk_docs`, failing when any of them is refused. See
+/// `bulk_update_documents` for `preserve_revs`.
+pub async fn bulk_update(
+    client: &Client,
+    db_host: &str,
+    table_name: &str,
+    docs: &[Value],
+    preserve_revs: bool,
+) -> Result<(), String> {
+    let results = bulk_update_documents(client, db_host, table_name, docs, preserve_revs).await?;
+
+    let failures: Vec<String> = results
+        .conflicted
+        .iter()
+        .map(|id| format!("{} (conflict)", id))
+        .chain(results.failed.iter().map(|(id, reason)| format!("{} ({})", id, reason)))
+        .collect();
+    if !failures.is_empty() {
+        return Err(format!("Failed to update documents: {}", failures.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Per-document results of a `_bulk_docs` update.
+#[derive(Debug, Default, PartialEq)]
+pub struct BulkResults {
+    pub succeeded: Vec<String>,        // Ids written
+    pub conflicted: Vec<String>,       // Ids changed since they were fetched
+    pub failed: Vec<(String, String)>, // Ids refused for another reason, with the error and reason
+}
+
+/// Updates many documents in one `_bulk_docs` request. Each document succeeds or fails on
+/// its own: the request only fails as a whole when CouchDB cannot be reached or refuses it.
+/// With `preserve_revs`, each document is sent as a child revision of its `_rev` with its
+/// revision history, and CouchDB is told to store the revisions as given (`new_edits=false`).
+pub async fn bulk_update_documents(
+    client: &Client,
+    db_host: &str,
+    table_name: &str,
+    docs: &[Value],
+    preserve_revs: bool,
+) -> Result<BulkResults, String> {
+    let results = post_bulk_docs(client, db_host, table_name, &bulk_docs_body(docs, preserve_revs)).await?;
+
+    // Entries are {"ok": true, "id", "rev"} or {"id", "error", "reason"}
+    let mut bulk_results = BulkResults::default();
+    for result in results {
+        let id = result["id"].as_str().unwrap_or_default().to_string();
+        match result["error"].as_str() {
+            None => bulk_results.succeeded.push(id),
+            Some("conflict") => bulk_results.conflicted.push(id),
+            Some(error) => {
+                let reason = format!("{} - {}", error, result["reason"].as_str().unwrap_or_default());
+                bulk_results.failed.push((id, reason));
+            }
+        }
+    }
+
+    // With new_edits=false, CouchDB only lists the documents it refused
+    if preserve_revs {
+        for doc in docs {
+            let id = doc["_id"].as_str().unwrap_or_default().to_string();
+            let reported = bulk_results.succeeded.contains(&id)
+                || bulk_results.conflicted.contains(&id)
+                || bulk_results.failed.iter().any(|(failed_id, _)| *failed_id == id);
+            if !reported {
+                bulk_results.succeeded.push(id);
+            }
+        }
+    }
+    Ok(bulk_results)
+}
+
+/// The error of an update refused because the document changed since it was fetched, as
+/// returned by `update_document`.
+pub fn conflict_error(id: &str) -> String {
+    format!("Failed to update document {}: Status code {}", id, StatusCode::CONFLICT)
+}
+
+/// Posts a `_bulk_docs` request and returns the per-document results.
+async fn post_bulk_docs(
+    client: &Client,
+    db_host: &str,
+    table_name: &str,
+    body: &Value,
+) -> Result<Vec<Value>, String> {
+    let url = format!("{}/{}/_bulk_docs", db_host, table_name);
+
+    let response = send_with_retry(client.post(&url).json(body)).await?;
+
+    if response.status() != StatusCode::CREATED {
+        return Err(format!(
+            "Failed to update documents in bulk: Status code {}",
+            response.status()
+        ));
+    }
+
+    let results: Value = response.json().await.map_err(|e| e.to_string())?;
+    match results {
+        Value::Array(results) => Ok(results),
+        _ => Err("Unexpected _bulk_docs response, expected an array".to_string()),
+    }
+}
+
+/// Builds the `_bulk_docs` request body.
+fn bulk_docs_body(docs: &[Value], preserve_revs: bool) -> Value {
+    if !preserve_revs {
+        return json!({ "docs": docs });
+    }
+
+    let docs: Vec<Value> = docs.iter().map(child_revision).collect();
+    json!({ "docs": docs, "new_edits": false })
+}
+
+/// Gives a document a new revision `N+1-<hash>` following its `_rev`, at the front of its
+/// `_revisions` history, as CouchDB does on an update. With `new_edits=false` CouchDB stores
+/// the revisions it is given: a changed body sent under the current `_rev` would be taken
+/// for that revision, which it already has, and dropped while reporting success.
+/// Documents fetched without history get the history of their current `_rev`. The hash is
+/// the MD5 of the previous revision and the body, so the same repair gives the same revision.
+fn child_revision(doc: &Value) -> Value {
+    let mut doc = doc.clone();
+    let rev = doc["_rev"].as_str().unwrap_or_default().to_string();
+    let Some((start, hash)) = rev.split_once('-') else {
+        return doc;
+    };
+    let Ok(start) = start.parse::<u64>() else {
+        return doc;
+    };
+
+    let mut ids = match doc["_revisions"]["ids"].as_array() {
+        Some(ids) if doc["_revisions"]["start"] == start => ids.clone(),
+        _ => vec![Value::from(hash)],
+    };
+    if let Some(fields) = doc.as_object_mut() {
+        fields.remove("_rev");
+        fields.remove("_revisions");
+    }
+
+    let mut md5 = Md5::new();
+    md5.update(rev.as_bytes());
+    md5.update(doc.to_string().as_bytes());
+    let new_hash: String = md5.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    ids.insert(0, Value::from(new_hash.clone()));
+    doc["_rev"] = Value::from(format!("{}-{}", start + 1, new_hash));
+    doc["_revisions"] = json!({ "start": start + 1, "ids": ids });
+    doc
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{any, body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_update_document_puts_with_revision() {
+        let doc = json!({"_id": "order-1", "_rev": "3-abc", "amount": 10});
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/orders/order-1"))
+            .and(header("if-match", "3-abc"))
+            .and(body_json(&doc))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({"ok": true, "id": "order-1", "rev": "4-new"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        update_document(&Client::new(), &server.uri(), "orders", &doc)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bulk_update_documents_reports_each_document() {
+        let docs: Vec<Value> = (1..=3)
+            .map(|i| json!({"_id": format!("order-{}", i), "_rev": "1-a"}))
+            .collect();
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/orders/_bulk_docs"))
+            .and(body_json(json!({ "docs": docs })))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!([
+                {"ok": true, "id": "order-1", "rev": "2-a"},
+                {"id": "order-2", "error": "conflict", "reason": "Document update conflict."},
+                {"id": "order-3", "error": "forbidden", "reason": "Only admins may edit."}
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let results = bulk_update_documents(&Client::new(), &server.uri(), "orders", &docs, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            results,
+            BulkResults {
+                succeeded: vec!["order-1".to_string()],
+                conflicted: vec!["order-2".to_string()],
+                failed: vec![("order-3".to_string(), "forbidden - Only admins may edit.".to_string())],
+            }
+        );
+        assert!(is_conflict(&conflict_error("order-2")));
+    }
+
+    #[tokio::test]
+    async fn test_update_document_conflict_is_an_error() {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(409).set_body_json(json!({"error": "conflict"})))
+            .mount(&server)
+            .await;
+        let doc = json!({"_id": "order-1", "_rev": "3-abc"});
+
+        let err = update_document(&Client::new(), &server.uri(), "orders", &doc)
+            .await
+            .unwrap_err();
+        assert!(err.contains("409"));
+        assert!(is_conflict(&err));
+    }
+
+    #[tokio::test]
+    async fn test_get_document_reads_back_by_id() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/orders/order%2F1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"_id": "order/1", "_rev": "4-new", "amount": 10})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let doc = get_document(&Client::new(), &server.uri(), "orders", "order/1")
+            .await
+            .unwrap();
+        assert_eq!(doc, json!({"_id": "order/1", "_rev": "4-new", "amount": 10}));
+
+        // Unmatched requests get a 404
+        let err = get_document(&Client::new(), &server.uri(), "orders", "order-2")
+            .await
+            .unwrap_err();
+        assert!(err.contains("404"));
+    }
+
+    #[tokio::test]
+    async fn test_shadow_document_written_without_rev() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/orders_shadow/order%2F1"))
+            .and(body_json(json!({"_id": "order/1", "amount": 10})))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({"ok": true})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let doc = json!({"_id": "order/1", "_rev": "3-abc", "amount": 10});
+
+        write_shadow_document(&Client::new(), &server.uri(), "orders_shadow", &doc)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_bulk_body_sends_a_child_revision() {
+        let docs = vec![
+            json!({"_id": "order-1", "_rev": "3-abc", "amount": 10}),
+            json!({"_id": "order-2", "_rev": "2-def", "_revisions": {"start": 2, "ids": ["def", "xyz"]}}),
+        ];
+
+        let body = bulk_docs_body(&docs, true);
+        assert_eq!(body["new_edits"], json!(false));
+
+        // A new revision of the repaired body, following the fetched one
+        let rev = body["docs"][0]["_rev"].as_str().unwrap();
+        let (start, hash) = rev.split_once('-').unwrap();
+        assert_eq!(start, "4");
+        assert_eq!(hash.len(), 32);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(body["docs"][0]["_revisions"], json!({"start": 4, "ids": [hash, "abc"]}));
+        assert_eq!(body["docs"][0]["amount"], 10);
+        assert_eq!(body["docs"][1]["_rev"].as_str().unwrap().split_once('-').unwrap().0, "3");
+        assert_eq!(body["docs"][1]["_revisions"]["ids"].as_array().unwrap()[1..], [json!("def"), json!("xyz")]);
+
+        // Same repair, same revision; another body, another revision
+        assert_eq!(bulk_docs_body(&docs, true), body);
+        let changed = vec![json!({"_id": "order-1", "_rev": "3-abc", "amount": 11})];
+        assert_ne!(bulk_docs_body(&changed, true)["docs"][0]["_rev"], rev);
+
+        let body = bulk_docs_body(&docs, false);
+        assert_eq!(body, json!({ "docs": docs }));
+    }
+
+    #[tokio::test]
+    async fn test_preserved_revisions_succeed_unless_listed() {
+        // With new_edits=false, CouchDB only reports the refused documents
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/orders/_bulk_docs"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!([
+                {"id": "order-2", "error": "forbidden", "reason": "Only admins may edit."}
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let docs: Vec<Value> = (1..=2)
+            .map(|i| json!({"_id": format!("order-{}", i), "_rev": "1-a"}))
+            .collect();
+
+        let results = bulk_update_documents(&Client::new(), &server.uri(), "orders", &docs, true)
+            .await
+            .unwrap();
+        assert_eq!(results.succeeded, vec!["order-1".to_string()]);
+        assert_eq!(results.failed.len(), 1);
+
+        let requests = server.received_requests().await.unwrap();
+        let body: Value = requests[0].body_json().unwrap();
+        assert_eq!(body["new_edits"], json!(false));
+        assert!(body["docs"][0]["_rev"].as_str().unwrap().starts_with("2-"));
+    }
+}
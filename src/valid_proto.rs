@@ -1,22 +1,273 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr};
 
-use protobuf::descriptor::{FieldDescriptorProto, FileDescriptorSet};
+use protobuf::descriptor::{
+    field_descriptor_proto::Type, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorSet,
+};
+use serde::Serialize;
 use serde_json::Value;
 
-#[derive(Debug, PartialEq)] // PartialEq for unit testing
+#[derive(Debug, PartialEq, Serialize)] // PartialEq for unit testing
 pub struct ValidationError {
     pub field: String, // Full path, e.g., "parent.child.field"
     pub error_type: ErrorType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub found: Option<String>, // Observed JSON type, e.g., "string" for an invalid array element, the differing indices of an inconsistent array, the value out of range or the unresolved type name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>, // Protobuf type of a field holding a value of the wrong type, e.g. "TYPE_INT32"
 }
 
-#[derive(Debug, PartialEq)] // PartialEq for unit testing
+impl std::fmt::Display for ValidationError {
+    /// `items[1].id - WrongDataType (expected TYPE_INT32, found string)`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} - {:?}", self.field, self.error_type)?;
+        match (&self.expected, &self.found) {
+            (Some(expected), Some(found)) => write!(f, " (expected {}, found {})", expected, found),
+            (Some(expected), None) => write!(f, " (expected {})", expected),
+            (None, Some(found)) => write!(f, " (found {})", found),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)] // PartialEq for unit testing
 pub enum ErrorType {
     AdditionalField,       // Field present in JSON but not in Protobuf
     MissingField,          // Required field missing in JSON
     WrongDataType,         // Field type mismatch
     MissingArrayField,     // Empty array for a repeated field that should have data
     InvalidArrayElement,   // Array element doesn’t match expected type
-    NestedValidationError, // Error in a nested message
+    InvalidStringContent,  // String contains characters protobuf rejects (e.g. NUL)
+    InvalidEnumValue,      // Enum name or number not declared by the enum
+    InconsistentArrayShape, // Elements of a repeated message field have differing key sets
+    RangeViolation,        // Number outside the business-rule range given with --range
+    UnresolvedType,        // Field type not found in the schema (--strict-resolution)
+    EmptyRequiredMessage,  // Required message field given as {} (--require-nonempty-messages)
+    FloatInteger,          // Integer field stored as a float such as 1e9 (--strict-integer-format)
+    MaxDepthExceeded,      // Messages nested deeper than the maximum depth, not validated further
+    NullValue,             // Field present but null, as opposed to a MissingField
+    InvalidMapKey,         // Map key not valid for the declared key type, e.g. "x" for map<int32, V>
+    MultipleOneofSet,      // More than one member of a oneof group is set
+    MissingOneof,          // No member of a oneof group is set, where the presence rules expect one
+}
+
+/// Optional checks applied on top of the schema validation.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationOptions {
+    pub strict_strings: bool, // Flag string values containing NUL characters
+    pub only_fields: Vec<String>, // When not empty, only these field paths (and their nested fields) are checked
+    pub unknown_enum_ok: bool, // Accept undeclared enum numbers with a warning (proto3 open enums)
+    pub consistent_arrays: bool, // Flag repeated message fields whose elements have differing key sets
+    pub ranges: Vec<FieldRange>, // Business-rule bounds for numeric fields
+    pub strict_resolution: bool, // Report field types missing from the schema instead of skipping them
+    pub enum_case: EnumCase, // How JSON enum names are normalized before matching declared names
+    pub field_mask: Option<String>, // Only validate the subtree under this nested message path
+    pub timestamp_format: TimestampFormat, // Expected JSON encoding of google.protobuf.Timestamp fields
+    pub require_nonempty_messages: bool, // Report required message fields given as {} as a whole
+    pub strict_integer_format: bool, // Report integer fields stored in float or exponential form
+    pub max_depth: Option<usize>, // Deepest message nesting validated, DEFAULT_MAX_DEPTH when not set
+    pub strict_required: bool, // Require every non-repeated field, as if the file declared no syntax
+    pub field_name_mode: FieldNameMode, // Whether JSON keys may use the json_name, the field name or both
+}
+
+/// Message nesting validated before reporting MaxDepthExceeded, e.g. for a self-referencing
+/// tree node message.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// JSON encoding of `google.protobuf.Timestamp` fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339, // String such as "2025-01-31T08:00:00Z", the proto3 JSON mapping
+    EpochMillis, // Integer milliseconds since the Unix epoch
+    EpochSecs,   // Integer seconds since the Unix epoch
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch_millis" => Ok(TimestampFormat::EpochMillis),
+            "epoch_secs" => Ok(TimestampFormat::EpochSecs),
+            _ => Err(format!("Unknown --timestamp-format {:?}", s)),
+        }
+    }
+}
+
+/// Well-known types validated as a single JSON value instead of a message.
+pub const TIMESTAMP_TYPE: &str = ".google.protobuf.Timestamp";
+pub const DURATION_TYPE: &str = ".google.protobuf.Duration";
+
+/// Largest `google.protobuf.Duration`, in seconds, about 10,000 years.
+const MAX_DURATION_SECS: u64 = 315_576_000_000;
+
+/// Which name of a proto field a JSON key may use. Documents written by hand or by other
+/// tools sometimes use the snake_case field name instead of the camelCase `json_name`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FieldNameMode {
+    Json,  // Only the `json_name`, e.g. `orderId`
+    Proto, // Only the field name as declared, e.g. `order_id`
+    #[default]
+    Both, // Either of them, errors name the `json_name`
+}
+
+impl FromStr for FieldNameMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(FieldNameMode::Json),
+            "proto" => Ok(FieldNameMode::Proto),
+            "both" => Ok(FieldNameMode::Both),
+            _ => Err(format!("Unknown --field-name-mode {:?}", s)),
+        }
+    }
+}
+
+/// Naming convention JSON enum names are converted to before they are matched against
+/// the declared enum values.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EnumCase {
+    #[default]
+    AsIs, // Exact match
+    ScreamingSnake, // `activeStatus` matches `ACTIVE_STATUS`
+    Camel,          // `ACTIVE_STATUS` matches `activeStatus`
+}
+
+impl FromStr for EnumCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "as_is" => Ok(EnumCase::AsIs),
+            "screaming_snake" => Ok(EnumCase::ScreamingSnake),
+            "camel" => Ok(EnumCase::Camel),
+            _ => Err(format!("Unknown --enum-case {:?}", s)),
+        }
+    }
+}
+
+impl EnumCase {
+    /// Converts a JSON enum name to this convention.
+    fn normalize(self, name: &str) -> String {
+        // Words are separated by `_`, `-` or a lowercase to uppercase change
+        let mut words: Vec<String> = Vec::new();
+        let mut previous: Option<char> = None;
+        for c in name.chars() {
+            if c == '_' || c == '-' {
+                words.push(String::new());
+            } else {
+                let boundary = c.is_uppercase()
+                    && previous.is_some_and(|p| p.is_lowercase() || p.is_ascii_digit());
+                if words.is_empty() || boundary {
+                    words.push(String::new());
+                }
+                if let Some(word) = words.last_mut() {
+                    word.extend(c.to_lowercase());
+                }
+            }
+            previous = Some(c);
+        }
+        let words = words.into_iter().filter(|word| !word.is_empty());
+
+        match self {
+            EnumCase::AsIs => name.to_string(),
+            EnumCase::ScreamingSnake => words
+                .map(|word| word.to_uppercase())
+                .collect::<Vec<String>>()
+                .join("_"),
+            EnumCase::Camel => words
+                .enumerate()
+                .map(|(i, word)| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) if i > 0 => first.to_uppercase().chain(chars).collect(),
+                        _ => word,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Allowed bounds for a numeric field, parsed from `path=min:max`. Either bound may be
+/// left open, e.g. `amount=0:` for a non-negative amount.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldRange {
+    pub path: String,     // Field path without array indexes, e.g. "items.price"
+    pub min: Option<f64>, // Inclusive lower bound
+    pub max: Option<f64>, // Inclusive upper bound
+}
+
+impl FromStr for FieldRange {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid range {:?}, expected path=min:max (Example: age=0:150)", range);
+        let (path, bounds) = range.split_once('=').ok_or_else(invalid)?;
+        let (min, max) = bounds.split_once(':').ok_or_else(invalid)?;
+        let bound = |bound: &str| -> Result<Option<f64>, String> {
+            match bound.trim() {
+                "" => Ok(None),
+                bound => bound.parse().map(Some).map_err(|_| invalid()),
+            }
+        };
+
+        let field_range = FieldRange {
+            path: path.trim().to_string(),
+            min: bound(min)?,
+            max: bound(max)?,
+        };
+        if field_range.path.is_empty() {
+            return Err(invalid());
+        }
+        if let (Some(min), Some(max)) = (field_range.min, field_range.max) {
+            if min > max {
+                return Err(format!("Invalid range {:?}, min is greater than max", range));
+            }
+        }
+        Ok(field_range)
+    }
+}
+
+/// Syntax of the file declaring a message, which decides field presence rules.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Syntax {
+    Unspecified, // No syntax declared: every singular field is expected
+    Proto2,      // Only `required` fields are expected
+    Proto3,      // Implicit presence, absent fields take their default value
+    Editions,    // Only fields with the LEGACY_REQUIRED presence feature are expected
+}
+
+/// FieldOptions.features and FileOptions.features, FeatureSet.field_presence
+const FIELD_OPTIONS_FEATURES: u32 = 21;
+const FILE_OPTIONS_FEATURES: u32 = 50;
+const FEATURE_FIELD_PRESENCE: u32 = 1;
+const FIELD_PRESENCE_LEGACY_REQUIRED: u64 = 3;
+
+/// State shared by the recursive validation functions.
+struct Context<'a> {
+    message_types: &'a HashMap<String, protobuf::descriptor::DescriptorProto>,
+    enum_types: &'a HashMap<String, EnumDescriptorProto>,
+    message_syntax: &'a HashMap<String, Syntax>,
+    ignore_list: &'a [String],
+    options: &'a ValidationOptions,
+}
+
+/// CouchDB metadata fields, never part of the schema.
+pub const COUCHDB_METADATA_FIELDS: [&str; 2] = ["_id", "_rev"];
+
+/// Fields to ignore: the CouchDB metadata fields, then the comma-separated `--ignore` names.
+/// Names are trimmed, empty entries and repeated names are dropped.
+pub fn ignore_list(ignore: &str) -> Vec<String> {
+    let mut ignore_list: Vec<String> = COUCHDB_METADATA_FIELDS.iter().map(|field| field.to_string()).collect();
+    for field in ignore.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+        if !ignore_list.iter().any(|ignored| ignored == field) {
+            ignore_list.push(field.to_string());
+        }
+    }
+    ignore_list
 }
 
 /// Validates JSON against a Protobuf schema, including nested and repeated fields.
@@ -25,65 +276,460 @@ pub fn validate_json(
     table_name: &str,
     json_value: &Value,
     ignore_list: Vec<String>,
+) -> Vec<ValidationError> {
+    validate_json_with(
+        file_descriptor_set,
+        table_name,
+        json_value,
+        ignore_list,
+        &ValidationOptions::default(),
+    )
+}
+
+/// Validates JSON against a Protobuf schema with additional checks enabled by `options`.
+pub fn validate_json_with(
+    file_descriptor_set: &FileDescriptorSet,
+    table_name: &str,
+    json_value: &Value,
+    ignore_list: Vec<String>,
+    options: &ValidationOptions,
 ) -> Vec<ValidationError> {
     let mut errors = Vec::new();
 
     // Build a map of message types for quick lookup by name
-    let mut message_types = HashMap::new();
-    for file in &file_descriptor_set.file {
-        for message in &file.message_type {
-            if let Some(name) = message.name.clone() {
-                // Store lowercase name to make lookup case-insensitive
-                message_types.insert(name.to_lowercase(), message.clone());
+    let message_types = build_message_types(file_descriptor_set);
+    let enum_types = build_enum_types(file_descriptor_set);
+    let message_syntax = build_message_syntax(file_descriptor_set);
+    let ctx = Context {
+        message_types: &message_types,
+        enum_types: &enum_types,
+        message_syntax: &message_syntax,
+        ignore_list: &ignore_list,
+        options,
+    };
+
+    // Targeted audit: validate only the masked subtree, if present in the document
+    if let Some(mask) = &options.field_mask {
+        match find_field(&message_types, table_name, mask) {
+            Some(field) => {
+                let subtree = mask
+                    .split('.')
+                    .try_fold(json_value, |value, segment| value.get(segment));
+                if let Some(subtree) = subtree {
+                    validate_field(field, subtree, &ctx, mask, 0, &mut errors);
+                }
             }
+            None => errors.push(ValidationError {
+                field: mask.to_string(),
+                error_type: ErrorType::MissingField,
+                found: None,
+                expected: None,
+            }),
         }
+        return errors;
     }
 
     // Find the target message type and start validation
     if let Some(message) = message_types.get(&table_name.to_lowercase()) {
         // Validate the top-level message, starting with an empty path
-        validate_message(
-            message,
-            json_value,
-            &message_types,
-            &ignore_list,
-            "".to_string(),
-            &mut errors,
-        );
+        let type_key = table_name.to_lowercase();
+        validate_message(message, &type_key, json_value, &ctx, "".to_string(), 0, &mut errors);
     } else {
         // If the table_name doesn’t match any message, report an error
         errors.push(ValidationError {
             field: table_name.to_string(),
             error_type: ErrorType::MissingField,
+            found: None,
+            expected: None,
         });
     }
 
     errors
 }
 
+/// The message to validate against when `table_name` matches no message, but the
+/// descriptor set defines exactly one top-level message (--auto-message).
+pub fn auto_message(file_descriptor_set: &FileDescriptorSet, table_name: &str) -> Option<String> {
+    if build_message_types(file_descriptor_set).contains_key(&table_name.to_lowercase()) {
+        return None;
+    }
+    let mut messages = file_descriptor_set.file.iter().flat_map(|file| &file.message_type);
+    match (messages.next(), messages.next()) {
+        (Some(message), None) => message.name.clone(),
+        _ => None,
+    }
+}
+
+/// Checks, case-insensitively, that the schema defines `message_name`, so a misspelled table
+/// fails before the scan rather than marking every document invalid. The error lists the
+/// messages of the schema.
+pub fn check_message(file_descriptor_set: &FileDescriptorSet, message_name: &str) -> Result<(), String> {
+    if build_message_types(file_descriptor_set).contains_key(&message_name.to_lowercase()) {
+        return Ok(());
+    }
+    let mut names: Vec<&str> = file_descriptor_set
+        .file
+        .iter()
+        .filter(|file| file.package() != "google.protobuf")
+        .flat_map(|file| &file.message_type)
+        .map(|message| message.name())
+        .collect();
+    names.sort_unstable();
+    if names.is_empty() {
+        return Err(format!("no message named {:?}, the schema defines no message", message_name));
+    }
+    Err(format!(
+        "no message named {:?} in the schema, available messages: {}",
+        message_name,
+        names.join(", ")
+    ))
+}
+
+/// Lowercase key of a type declared in a file: its fully-qualified name without the leading
+/// dot, e.g. `shop.order.item` for `Order.Item` in package `shop`, as field type names refer to it.
+pub fn qualified_key(package: &str, name: &str) -> String {
+    if package.is_empty() {
+        name.to_lowercase()
+    } else {
+        format!("{}.{}", package, name).to_lowercase()
+    }
+}
+
+/// The fully-qualified key a table name is looked up as: a key of a top-level message as
+/// is, otherwise the first top-level message of that name in any package.
+pub fn table_key(file_descriptor_set: &FileDescriptorSet, table_name: &str) -> String {
+    let key = table_name.to_lowercase();
+    let top_level: Vec<(String, String)> = file_descriptor_set
+        .file
+        .iter()
+        .flat_map(|file| {
+            file.message_type
+                .iter()
+                .map(|message| (qualified_key(file.package(), message.name()), message.name().to_lowercase()))
+        })
+        .collect();
+    if top_level.iter().any(|(qualified, _)| *qualified == key) {
+        return key;
+    }
+    top_level
+        .into_iter()
+        .find(|(_, name)| *name == key)
+        .map_or(key, |(qualified, _)| qualified)
+}
+
+/// Builds a map of message types keyed by lowercase name for case-insensitive lookup.
+/// Every message is keyed by its fully-qualified name, nested messages and generated map
+/// entries as `package.outer.inner`, matching their type name. Top-level messages of a
+/// package are also found by their own name, as given to --table, unless that is the
+/// qualified name of another message.
+pub fn build_message_types(
+    file_descriptor_set: &FileDescriptorSet,
+) -> HashMap<String, protobuf::descriptor::DescriptorProto> {
+    let mut message_types = HashMap::new();
+    for file in &file_descriptor_set.file {
+        for message in &file.message_type {
+            if let Some(name) = message.name.as_deref() {
+                // Store lowercase name to make lookup case-insensitive
+                let key = qualified_key(file.package(), name);
+                message_types.insert(key.clone(), message.clone());
+                add_nested_messages(&mut message_types, message, &key);
+            }
+        }
+    }
+    for file in &file_descriptor_set.file {
+        for message in &file.message_type {
+            if let Some(name) = message.name.as_deref() {
+                message_types.entry(name.to_lowercase()).or_insert_with(|| message.clone());
+            }
+        }
+    }
+    message_types
+}
+
+/// Adds the messages declared in a message and, recursively, in its nested messages, keyed
+/// by their dotted path below the key of the message.
+fn add_nested_messages(
+    message_types: &mut HashMap<String, protobuf::descriptor::DescriptorProto>,
+    message: &protobuf::descriptor::DescriptorProto,
+    path: &str,
+) {
+    for nested in &message.nested_type {
+        let name = format!("{}.{}", path, nested.name());
+        message_types.insert(name.to_lowercase(), nested.clone());
+        add_nested_messages(message_types, nested, &name);
+    }
+}
+
+/// Maps each message (lowercase key, as in `build_message_types`) to the syntax of its file.
+/// Files of editions that default every field to LEGACY_REQUIRED keep the strict rules.
+fn build_message_syntax(file_descriptor_set: &FileDescriptorSet) -> HashMap<String, Syntax> {
+    let mut message_syntax = HashMap::new();
+    for file in &file_descriptor_set.file {
+        let syntax = match file.syntax.as_deref() {
+            Some("proto2") => Syntax::Proto2,
+            Some("proto3") => Syntax::Proto3,
+            Some("editions") => {
+                let file_presence = file.options.as_ref().and_then(|options| {
+                    feature_presence(options.special_fields.unknown_fields(), FILE_OPTIONS_FEATURES)
+                });
+                if file_presence == Some(FIELD_PRESENCE_LEGACY_REQUIRED) {
+                    Syntax::Unspecified
+                } else {
+                    Syntax::Editions
+                }
+            }
+            _ => Syntax::Unspecified,
+        };
+        let mut messages: Vec<(String, &protobuf::descriptor::DescriptorProto)> = file
+            .message_type
+            .iter()
+            .map(|message| (qualified_key(file.package(), message.name()), message))
+            .collect();
+        while let Some((key, message)) = messages.pop() {
+            for nested in &message.nested_type {
+                messages.push((format!("{}.{}", key, nested.name().to_lowercase()), nested));
+            }
+            message_syntax.insert(key, syntax);
+        }
+    }
+    // Top-level messages found by their own name, as in `build_message_types`
+    for file in &file_descriptor_set.file {
+        for message in &file.message_type {
+            let syntax = message_syntax[&qualified_key(file.package(), message.name())];
+            message_syntax.entry(message.name().to_lowercase()).or_insert(syntax);
+        }
+    }
+    message_syntax
+}
+
+/// Reads `features.field_presence` from options, kept as unknown fields because the
+/// descriptor types predate editions.
+fn feature_presence(unknown_fields: &protobuf::UnknownFields, features_field: u32) -> Option<u64> {
+    let protobuf::UnknownValueRef::LengthDelimited(features) = unknown_fields.get(features_field)? else {
+        return None;
+    };
+
+    let mut input = protobuf::CodedInputStream::from_bytes(features);
+    let mut presence = None;
+    while let Ok(Some(tag)) = input.read_raw_tag_or_eof() {
+        if tag == FEATURE_FIELD_PRESENCE << 3 {
+            presence = input.read_uint64().ok();
+        } else if protobuf::rt::skip_field_for_tag(tag, &mut input).is_err() {
+            break;
+        }
+    }
+    presence
+}
+
+/// Whether an absent field should be reported as MissingField under the file's presence rules.
+fn is_required(field: &FieldDescriptorProto, syntax: Syntax) -> bool {
+    use protobuf::descriptor::field_descriptor_proto::Label;
+
+    if field.label() == Label::LABEL_REPEATED {
+        return false; // repeated fields can be empty
+    }
+    match syntax {
+        Syntax::Unspecified => true,
+        Syntax::Proto2 => field.label() == Label::LABEL_REQUIRED,
+        Syntax::Proto3 => false,
+        Syntax::Editions => {
+            let presence = field.options.as_ref().and_then(|options| {
+                feature_presence(options.special_fields.unknown_fields(), FIELD_OPTIONS_FEATURES)
+            });
+            presence == Some(FIELD_PRESENCE_LEGACY_REQUIRED)
+        }
+    }
+}
+
+/// Whether a required, singular message field is given as an empty JSON object.
+fn is_empty_required_message(field: &FieldDescriptorProto, value: &Value, syntax: Syntax) -> bool {
+    field.type_() == Type::TYPE_MESSAGE
+        && field.type_name() != TIMESTAMP_TYPE
+        && field.type_name() != DURATION_TYPE
+        && is_required(field, syntax)
+        && value.as_object().is_some_and(|obj| obj.is_empty())
+}
+
+/// Builds a map of enum types keyed by lowercase fully-qualified name. Enums declared inside
+/// a message are keyed as `package.message.enum` (or `package.message.nested.enum`), matching
+/// their type name.
+pub fn build_enum_types(file_descriptor_set: &FileDescriptorSet) -> HashMap<String, EnumDescriptorProto> {
+    let mut enum_types = HashMap::new();
+    for file in &file_descriptor_set.file {
+        for enum_type in &file.enum_type {
+            enum_types.insert(qualified_key(file.package(), enum_type.name()), enum_type.clone());
+        }
+        for message in &file.message_type {
+            add_nested_enums(&mut enum_types, message, &qualified_key(file.package(), message.name()));
+        }
+    }
+    enum_types
+}
+
+/// Adds the enums declared in a message and, recursively, in its nested messages, keyed by
+/// their dotted path, e.g. `shipment.tracking.carrier`.
+fn add_nested_enums(
+    enum_types: &mut HashMap<String, EnumDescriptorProto>,
+    message: &protobuf::descriptor::DescriptorProto,
+    path: &str,
+) {
+    for enum_type in &message.enum_type {
+        let name = format!("{}.{}", path, enum_type.name());
+        enum_types.insert(name.to_lowercase(), enum_type.clone());
+    }
+    for nested in &message.nested_type {
+        add_nested_enums(enum_types, nested, &format!("{}.{}", path, nested.name()));
+    }
+}
+
+/// Generates a worst-case document for the given message: every field is present
+/// but carries a value of the wrong JSON type. Returns None when the message is unknown.
+pub fn synthetic_document(file_descriptor_set: &FileDescriptorSet, table_name: &str) -> Option<Value> {
+    let message_types = build_message_types(file_descriptor_set);
+    let message = message_types.get(&table_name.to_lowercase())?;
+
+    let mut doc = serde_json::Map::new();
+    for field in &message.field {
+        if let Some(name) = field_json_name(field) {
+            doc.insert(name, wrong_value(field));
+        }
+    }
+    Some(Value::Object(doc))
+}
+
+/// Finds the field descriptor addressed by an error path such as `items[1].id`.
+/// Returns None when a segment does not resolve to a field of the expected message.
+pub fn resolve_field(
+    file_descriptor_set: &FileDescriptorSet,
+    table_name: &str,
+    path: &str,
+) -> Option<FieldDescriptorProto> {
+    let message_types = build_message_types(file_descriptor_set);
+    find_field(&message_types, table_name, path).cloned()
+}
+
+/// `resolve_field` over an already built map of message types.
+fn find_field<'a>(
+    message_types: &'a HashMap<String, protobuf::descriptor::DescriptorProto>,
+    table_name: &str,
+    path: &str,
+) -> Option<&'a FieldDescriptorProto> {
+    let mut message = message_types.get(&table_name.to_lowercase())?;
+
+    let segments: Vec<&str> = path.split('.').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        // Array indexes address elements of the same field
+        let name = segment.split('[').next()?;
+        let field = message
+            .field
+            .iter()
+            .find(|field| field_json_name(field).as_deref() == Some(name))?;
+        if i == segments.len() - 1 {
+            return Some(field);
+        }
+
+        let type_name = field.type_name.as_ref()?;
+        message = message_types.get(&type_name.trim_start_matches('.').to_lowercase())?;
+    }
+    None
+}
+
+/// Picks a JSON value that can never satisfy the given field.
+fn wrong_value(field: &FieldDescriptorProto) -> Value {
+    use protobuf::descriptor::field_descriptor_proto::{Label, Type};
+
+    if field.label() == Label::LABEL_REPEATED {
+        // Repeated fields expect an array
+        return Value::Object(serde_json::Map::new());
+    }
+    match field.type_() {
+        Type::TYPE_STRING => Value::from(0),
+        Type::TYPE_MESSAGE => Value::from("synthetic"),
+        Type::TYPE_BOOL => Value::from("true"),
+        Type::TYPE_INT32 | Type::TYPE_FLOAT => Value::from("0"),
+        _ => Value::Null,
+    }
+}
+
+/// Returns the JSON key of a field.
+/// An explicit `json_name` option left in the field options takes precedence over
+/// the `json_name` derived by the parser, which may still hold the camelCase default.
+pub fn field_json_name(field: &FieldDescriptorProto) -> Option<String> {
+    let explicit = field.options.uninterpreted_option.iter().find_map(|option| {
+        let is_json_name = option.name.len() == 1
+            && option.name[0].name_part.as_deref() == Some("json_name");
+        if !is_json_name {
+            return None;
+        }
+        option
+            .string_value
+            .as_ref()
+            .and_then(|value| String::from_utf8(value.clone()).ok())
+    });
+
+    explicit.or_else(|| field.json_name.clone())
+}
+
+/// The JSON keys a field accepts under the given mode, the preferred one first.
+fn field_keys(field: &FieldDescriptorProto, mode: FieldNameMode) -> Vec<String> {
+    let json_name = field_json_name(field);
+    let mut keys: Vec<String> = match mode {
+        FieldNameMode::Json => json_name.into_iter().collect(),
+        FieldNameMode::Proto => field.name.clone().into_iter().collect(),
+        FieldNameMode::Both => json_name.into_iter().chain(field.name.clone()).collect(),
+    };
+    keys.dedup();
+    keys
+}
+
 /// Recursively validates a message against a JSON value.
 fn validate_message(
     message: &protobuf::descriptor::DescriptorProto,
+    type_key: &str, // Key of the message in the lookup maps
     json_value: &Value,
-    message_types: &HashMap<String, protobuf::descriptor::DescriptorProto>,
-    ignore_list: &[String],
+    ctx: &Context,
     parent_path: String, // Tracks the current field path (e.g., "parent.child")
+    depth: usize,        // Number of messages above this one
     errors: &mut Vec<ValidationError>,
 ) {
+    if depth > ctx.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH) {
+        // Self-referencing message nested too deep, stop before the stack runs out
+        errors.push(ValidationError {
+            field: parent_path,
+            error_type: ErrorType::MaxDepthExceeded,
+            found: None,
+            expected: None,
+        });
+        return;
+    }
+
     if let Value::Object(json_obj) = json_value {
-        // Map Protobuf fields for this message by their JSON names
+        // Map Protobuf fields for this message by the JSON keys they accept
         let mut proto_fields = HashMap::new();
+        let mut declared = Vec::new();
         for field in &message.field {
-            if let Some(name) = field.json_name.clone() {
-                if !ignore_list.contains(&name) {
-                    proto_fields.insert(name, field.clone());
-                }
+            let keys = field_keys(field, ctx.options.field_name_mode);
+            if keys.is_empty() || keys.iter().any(|key| ctx.ignore_list.contains(key)) {
+                continue;
             }
+            for key in &keys {
+                proto_fields.insert(key.clone(), field);
+            }
+            declared.push((keys, field));
         }
 
+        let syntax = if ctx.options.strict_required {
+            Syntax::Unspecified
+        } else {
+            ctx.message_syntax
+                .get(type_key)
+                .copied()
+                .unwrap_or(Syntax::Unspecified)
+        };
+
         // Check JSON fields against Protobuf schema
         for (key, value) in json_obj {
-            if ignore_list.contains(key) {
+            if ctx.ignore_list.contains(key) {
                 continue; // Skip ignored fields
             }
             // Construct the full path for error reporting
@@ -92,65 +738,167 @@ fn validate_message(
             } else {
                 format!("{}.{}", parent_path, key)
             };
+            if !is_selected(&field_path, &ctx.options.only_fields) {
+                continue; // Outside of --only-fields, treated as ignored
+            }
 
             if let Some(field) = proto_fields.get(key) {
+                if ctx.options.require_nonempty_messages
+                    && is_empty_required_message(field, value, syntax)
+                {
+                    // One error for the object instead of each of its missing fields
+                    errors.push(ValidationError {
+                        field: field_path,
+                        error_type: ErrorType::EmptyRequiredMessage,
+                        found: None,
+                        expected: None,
+                    });
+                    continue;
+                }
                 // Field exists in schema; validate its value
-                validate_field(
-                    field,
-                    value,
-                    message_types,
-                    ignore_list,
-                    &field_path,
-                    errors,
-                );
+                validate_field(field, value, ctx, &field_path, depth, errors);
             } else {
                 // Field isn’t in schema; report as additional
                 errors.push(ValidationError {
                     field: field_path,
                     error_type: ErrorType::AdditionalField,
+                    found: None,
+                    expected: None,
                 });
             }
         }
 
-        // Check for missing required fields in JSON
-        for (name, field) in &proto_fields {
+        // Check for missing required fields in JSON, named by their preferred key
+        for (keys, field) in &declared {
             let field_path = if parent_path.is_empty() {
-                name.clone()
+                keys[0].clone()
             } else {
-                format!("{}.{}", parent_path, name)
+                format!("{}.{}", parent_path, keys[0])
             };
-            if !json_obj.contains_key(name)
-                && field.label()
-                    != protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED
+            if !keys.iter().any(|key| json_obj.contains_key(key))
+                && is_selected(&field_path, &ctx.options.only_fields)
+                && is_required(field, syntax)
+                && !is_oneof_member(field)
             {
-                // Report missing non-repeated fields (repeated fields can be empty)
+                // Report missing fields the presence rules expect
                 errors.push(ValidationError {
                     field: field_path,
                     error_type: ErrorType::MissingField,
+                    found: None,
+                    expected: None,
                 });
             }
         }
+
+        validate_oneofs(message, json_obj, &declared, ctx, &parent_path, syntax, errors);
     } else {
         // JSON should be an object for a message; report type mismatch
         errors.push(ValidationError {
             field: parent_path,
             error_type: ErrorType::WrongDataType,
+            found: None,
+            expected: None,
         });
     }
 }
 
+/// Whether a field belongs to a oneof group. proto3 `optional` fields are wrapped in a
+/// synthetic oneof of their own, which is not a group.
+fn is_oneof_member(field: &FieldDescriptorProto) -> bool {
+    field.oneof_index.is_some() && !field.proto3_optional()
+}
+
+/// Checks that at most one member of each oneof group is set. A group with no member set
+/// is reported as a whole, when the presence rules expect every singular field. The value
+/// of the set member is validated by `validate_field` like any other field.
+fn validate_oneofs(
+    message: &protobuf::descriptor::DescriptorProto,
+    json_obj: &serde_json::Map<String, Value>,
+    declared: &[(Vec<String>, &FieldDescriptorProto)], // Fields not ignored, with their JSON keys
+    ctx: &Context,
+    parent_path: &str,
+    syntax: Syntax,
+    errors: &mut Vec<ValidationError>,
+) {
+    let join_path = |name: &str| {
+        if parent_path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", parent_path, name)
+        }
+    };
+
+    for (index, oneof) in message.oneof_decl.iter().enumerate() {
+        // Members in declaration order, a null member counts as not set
+        let members: Vec<&[String]> = declared
+            .iter()
+            .filter(|(keys, field)| {
+                is_oneof_member(field)
+                    && field.oneof_index == Some(index as i32)
+                    && is_selected(&join_path(&keys[0]), &ctx.options.only_fields)
+            })
+            .map(|(keys, _)| keys.as_slice())
+            .collect();
+        if members.is_empty() {
+            continue; // Synthetic oneof, or every member is ignored
+        }
+        let set: Vec<&str> = members
+            .iter()
+            .filter_map(|keys| {
+                keys.iter()
+                    .find(|key| json_obj.get(*key).is_some_and(|value| !value.is_null()))
+            })
+            .map(String::as_str)
+            .collect();
+
+        if set.len() > 1 {
+            errors.push(ValidationError {
+                field: join_path(oneof.name()),
+                error_type: ErrorType::MultipleOneofSet,
+                found: Some(set.join(",")),
+                expected: None,
+            });
+        } else if set.is_empty() && syntax == Syntax::Unspecified {
+            errors.push(ValidationError {
+                field: join_path(oneof.name()),
+                error_type: ErrorType::MissingOneof,
+                found: None,
+                expected: Some(members.iter().map(|keys| keys[0].as_str()).collect::<Vec<_>>().join(",")),
+            });
+        }
+    }
+}
+
 /// Validates a single Protobuf field against its JSON value.
 fn validate_field(
     field: &FieldDescriptorProto,
     value: &Value,
-    message_types: &HashMap<String, protobuf::descriptor::DescriptorProto>,
-    ignore_list: &[String],
+    ctx: &Context,
     field_path: &str,
+    depth: usize, // Depth of the message declaring the field
     errors: &mut Vec<ValidationError>,
 ) {
+    // An explicit null (common in CouchDB for unset fields) is not an absent field
+    if value.is_null() {
+        errors.push(ValidationError {
+            field: field_path.to_string(),
+            error_type: ErrorType::NullValue,
+            found: None,
+            expected: Some(format!("{:?}", field.type_())),
+        });
+        return;
+    }
+
+    // Map fields are repeated entry messages on the wire, but JSON objects
+    if let Some(entry) = map_entry(field, ctx) {
+        validate_map(entry, value, ctx, field_path, depth, errors);
+        return;
+    }
+
     match field.label() {
         protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED => {
             // Handle repeated fields, which map to JSON arrays
+            // ([packed=true] only changes the wire format, so it is not looked at here)
             if let Value::Array(arr) = value {
                 if arr.is_empty()
                     && field.type_()
@@ -160,28 +908,53 @@ fn validate_field(
                     errors.push(ValidationError {
                         field: field_path.to_string(),
                         error_type: ErrorType::MissingArrayField,
+                        found: None,
+                        expected: None,
                     });
                 }
                 // Validate each array element
                 for (i, item) in arr.iter().enumerate() {
                     let item_path = format!("{}[{}]", field_path, i);
+                    if item.is_null() {
+                        // Same error whatever the element type, messages included
+                        errors.push(ValidationError {
+                            field: item_path,
+                            error_type: ErrorType::InvalidArrayElement,
+                            found: Some(json_type_name(item).to_string()),
+                            expected: Some(format!("{:?}", field.type_())),
+                        });
+                        continue;
+                    }
                     if field.type_()
                         == protobuf::descriptor::field_descriptor_proto::Type::TYPE_MESSAGE
                     {
                         // Nested message in a repeated field
-                        if let Some(type_name) = field.type_name.clone() {
-                            let clean_type_name = type_name.trim_start_matches('.').to_lowercase();
-                            if let Some(nested_message) = message_types.get(&clean_type_name) {
-                                // Recursively validate the nested message
-                                validate_message(
-                                    nested_message,
-                                    item,
-                                    message_types,
-                                    ignore_list,
-                                    item_path,
-                                    errors,
-                                );
-                            }
+                        if field.type_name() == TIMESTAMP_TYPE {
+                            validate_timestamp(item, ctx, &item_path, errors);
+                        } else if field.type_name() == DURATION_TYPE {
+                            validate_duration(item, &item_path, errors);
+                        } else if let Some((type_key, nested_message)) =
+                            nested_message(field, ctx, &item_path, errors)
+                        {
+                            // Recursively validate the nested message
+                            validate_message(nested_message, &type_key, item, ctx, item_path, depth + 1, errors);
+                        }
+                    } else if field.type_() == Type::TYPE_ENUM {
+                        // Enum in repeated field
+                        match validate_enum(field, item, ctx, &item_path) {
+                            Ok(()) => (),
+                            Err(ErrorType::WrongDataType) => errors.push(ValidationError {
+                                field: item_path,
+                                error_type: ErrorType::InvalidArrayElement,
+                                found: Some(json_type_name(item).to_string()),
+                                expected: None,
+                            }),
+                            Err(error_type) => errors.push(ValidationError {
+                                field: item_path,
+                                error_type,
+                                found: None,
+                                expected: None,
+                            }),
                         }
                     } else {
                         // Primitive type in repeated field
@@ -189,15 +962,48 @@ fn validate_field(
                             errors.push(ValidationError {
                                 field: item_path,
                                 error_type: ErrorType::InvalidArrayElement,
+                                found: Some(json_type_name(item).to_string()),
+                                expected: Some(format!("{:?}", field.type_())),
+                            });
+                        } else if ctx.options.strict_strings && has_nul(item) {
+                            errors.push(ValidationError {
+                                field: item_path,
+                                error_type: ErrorType::InvalidStringContent,
+                                found: None,
+                                expected: None,
+                            });
+                        } else if ctx.options.strict_integer_format && is_float_integer(field.type_(), item) {
+                            errors.push(ValidationError {
+                                field: item_path,
+                                error_type: ErrorType::FloatInteger,
+                                found: Some(item.to_string()),
+                                expected: None,
                             });
+                        } else {
+                            check_range(item, &item_path, ctx, errors);
                         }
                     }
                 }
+                if ctx.options.consistent_arrays
+                    && field.type_()
+                        == protobuf::descriptor::field_descriptor_proto::Type::TYPE_MESSAGE
+                {
+                    if let Some(indices) = inconsistent_elements(arr) {
+                        errors.push(ValidationError {
+                            field: field_path.to_string(),
+                            error_type: ErrorType::InconsistentArrayShape,
+                            found: Some(indices),
+                            expected: None,
+                        });
+                    }
+                }
             } else {
                 // Repeated field should be an array; report type mismatch
                 errors.push(ValidationError {
                     field: field_path.to_string(),
                     error_type: ErrorType::WrongDataType,
+                    found: None,
+                    expected: None,
                 });
             }
         }
@@ -205,19 +1011,23 @@ fn validate_field(
             // Handle non-repeated fields
             if field.type_() == protobuf::descriptor::field_descriptor_proto::Type::TYPE_MESSAGE {
                 // Nested message field
-                if let Some(type_name) = field.type_name.clone() {
-                    let clean_type_name = type_name.trim_start_matches('.').to_lowercase();
-                    if let Some(nested_message) = message_types.get(&clean_type_name) {
-                        // Recursively validate the nested message
-                        validate_message(
-                            nested_message,
-                            value,
-                            message_types,
-                            ignore_list,
-                            field_path.to_string(),
-                            errors,
-                        );
-                    }
+                if field.type_name() == TIMESTAMP_TYPE {
+                    validate_timestamp(value, ctx, field_path, errors);
+                } else if field.type_name() == DURATION_TYPE {
+                    validate_duration(value, field_path, errors);
+                } else if let Some((type_key, nested_message)) = nested_message(field, ctx, field_path, errors) {
+                    // Recursively validate the nested message
+                    validate_message(nested_message, &type_key, value, ctx, field_path.to_string(), depth + 1, errors);
+                }
+            } else if field.type_() == Type::TYPE_ENUM {
+                // Enum field, given by name or by number
+                if let Err(error_type) = validate_enum(field, value, ctx, field_path) {
+                    errors.push(ValidationError {
+                        field: field_path.to_string(),
+                        error_type,
+                        found: None,
+                        expected: None,
+                    });
                 }
             } else {
                 // Primitive type field
@@ -225,29 +1035,401 @@ fn validate_field(
                     errors.push(ValidationError {
                         field: field_path.to_string(),
                         error_type: ErrorType::WrongDataType,
+                        found: Some(json_type_name(value).to_string()),
+                        expected: Some(format!("{:?}", field.type_())),
+                    });
+                } else if ctx.options.strict_strings && has_nul(value) {
+                    errors.push(ValidationError {
+                        field: field_path.to_string(),
+                        error_type: ErrorType::InvalidStringContent,
+                        found: None,
+                        expected: None,
+                    });
+                } else if ctx.options.strict_integer_format && is_float_integer(field.type_(), value) {
+                    errors.push(ValidationError {
+                        field: field_path.to_string(),
+                        error_type: ErrorType::FloatInteger,
+                        found: Some(value.to_string()),
+                        expected: None,
                     });
+                } else {
+                    check_range(value, field_path, ctx, errors);
                 }
             }
         }
     }
 }
 
-/// Checks if a JSON value matches a Protobuf primitive type.
-fn is_valid_primitive(
-    field_type: protobuf::descriptor::field_descriptor_proto::Type,
-    value: &Value,
-) -> bool {
-    match (field_type, value) {
-        // String field should be a JSON string
-        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_STRING, Value::String(_)) => true,
-        // Int32 field should be a JSON number that fits in i64
-        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_INT32, Value::Number(n)) => {
-            n.is_i64()
-        }
-        // Float field can be any JSON number
-        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_FLOAT, Value::Number(_)) => true,
-        // Bool field should be a JSON boolean
-        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_BOOL, Value::Bool(_)) => true,
+/// Checks a `google.protobuf.Timestamp` value against the configured encoding.
+fn validate_timestamp(value: &Value, ctx: &Context, field_path: &str, errors: &mut Vec<ValidationError>) {
+    let valid = match ctx.options.timestamp_format {
+        TimestampFormat::Rfc3339 => value
+            .as_str()
+            .is_some_and(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).is_ok()),
+        TimestampFormat::EpochMillis | TimestampFormat::EpochSecs => value.is_i64(),
+    };
+    if !valid {
+        errors.push(ValidationError {
+            field: field_path.to_string(),
+            error_type: ErrorType::WrongDataType,
+            found: Some(json_type_name(value).to_string()),
+            expected: Some("google.protobuf.Timestamp".to_string()),
+        });
+    }
+}
+
+/// Checks a `google.protobuf.Duration` value: a string of seconds with up to nine
+/// fractional digits and an `s` suffix, e.g. "3.5s" or "-0.001s".
+fn validate_duration(value: &Value, field_path: &str, errors: &mut Vec<ValidationError>) {
+    if !value.as_str().is_some_and(is_duration) {
+        errors.push(ValidationError {
+            field: field_path.to_string(),
+            error_type: ErrorType::WrongDataType,
+            found: Some(json_type_name(value).to_string()),
+            expected: Some("google.protobuf.Duration".to_string()),
+        });
+    }
+}
+
+/// Whether a string is a duration in the proto3 JSON form, within the range of the type.
+fn is_duration(duration: &str) -> bool {
+    let Some(number) = duration.strip_suffix('s') else {
+        return false;
+    };
+    let number = number.strip_prefix('-').unwrap_or(number);
+    let (seconds, nanos) = number.split_once('.').unwrap_or((number, "0"));
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    is_digits(seconds)
+        && is_digits(nanos)
+        && nanos.len() <= 9
+        && seconds.parse::<u64>().is_ok_and(|seconds| {
+            seconds < MAX_DURATION_SECS || (seconds == MAX_DURATION_SECS && nanos.bytes().all(|b| b == b'0'))
+        })
+}
+
+/// Looks up the message type of a nested message field, with its key. A type missing from
+/// the schema is skipped, or reported as `UnresolvedType` with `strict_resolution`.
+fn nested_message<'a>(
+    field: &FieldDescriptorProto,
+    ctx: &'a Context,
+    field_path: &str,
+    errors: &mut Vec<ValidationError>,
+) -> Option<(String, &'a protobuf::descriptor::DescriptorProto)> {
+    let type_name = field.type_name.as_ref()?;
+    let type_key = type_name.trim_start_matches('.').to_lowercase();
+    let nested_message = ctx.message_types.get(&type_key).map(|message| (type_key, message));
+    if nested_message.is_none() && ctx.options.strict_resolution {
+        errors.push(ValidationError {
+            field: field_path.to_string(),
+            error_type: ErrorType::UnresolvedType,
+            found: Some(type_name.clone()),
+            expected: None,
+        });
+    }
+    nested_message
+}
+
+/// Reports a number outside the `--range` bounds configured for its field path.
+fn check_range(value: &Value, field_path: &str, ctx: &Context, errors: &mut Vec<ValidationError>) {
+    let Some(number) = value.as_f64() else {
+        return;
+    };
+    if ctx.options.ranges.is_empty() {
+        return;
+    }
+
+    let path = strip_indexes(field_path);
+    for range in ctx.options.ranges.iter().filter(|range| range.path == path) {
+        let below = range.min.is_some_and(|min| number < min);
+        let above = range.max.is_some_and(|max| number > max);
+        if below || above {
+            errors.push(ValidationError {
+                field: field_path.to_string(),
+                error_type: ErrorType::RangeViolation,
+                found: Some(value.to_string()),
+                expected: None,
+            });
+        }
+    }
+}
+
+/// Returns the generated entry message when the field is a `map<K, V>`.
+fn map_entry<'a>(
+    field: &FieldDescriptorProto,
+    ctx: &'a Context,
+) -> Option<&'a protobuf::descriptor::DescriptorProto> {
+    if field.label() != protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED
+        || field.type_() != Type::TYPE_MESSAGE
+    {
+        return None;
+    }
+    let type_name = field.type_name.as_ref()?.trim_start_matches('.').to_lowercase();
+    ctx.message_types
+        .get(&type_name)
+        .filter(|entry| entry.options.map_entry())
+}
+
+/// Validates a map given as a JSON object. Each value is checked against the entry's
+/// `value` field, message values recursively, with paths like `field["key"].subfield`.
+fn validate_map(
+    entry: &protobuf::descriptor::DescriptorProto,
+    value: &Value,
+    ctx: &Context,
+    field_path: &str,
+    depth: usize,
+    errors: &mut Vec<ValidationError>,
+) {
+    let Value::Object(entries) = value else {
+        errors.push(ValidationError {
+            field: field_path.to_string(),
+            error_type: ErrorType::WrongDataType,
+            found: None,
+            expected: None,
+        });
+        return;
+    };
+
+    // The entry message declares key = 1 and value = 2
+    let key_field = entry.field.iter().find(|field| field.number() == 1);
+    let Some(value_field) = entry.field.iter().find(|field| field.number() == 2) else {
+        return;
+    };
+    for (key, item) in entries {
+        let item_path = format!("{}[\"{}\"]", field_path, key);
+        if let Some(key_field) = key_field {
+            if !is_valid_map_key(key, key_field.type_()) {
+                errors.push(ValidationError {
+                    field: item_path.clone(),
+                    error_type: ErrorType::InvalidMapKey,
+                    found: Some(key.clone()),
+                    expected: Some(format!("{:?}", key_field.type_())),
+                });
+            }
+        }
+        validate_field(value_field, item, ctx, &item_path, depth, errors);
+    }
+}
+
+/// Whether a JSON object key, always a string, encodes a value of the map key type.
+/// Integer keys are written in decimal and booleans as "true" or "false".
+fn is_valid_map_key(key: &str, key_type: Type) -> bool {
+    match key_type {
+        Type::TYPE_INT32 | Type::TYPE_SINT32 | Type::TYPE_SFIXED32 => key.parse::<i32>().is_ok(),
+        Type::TYPE_UINT32 | Type::TYPE_FIXED32 => key.parse::<u32>().is_ok(),
+        Type::TYPE_INT64 | Type::TYPE_SINT64 | Type::TYPE_SFIXED64 => key.parse::<i64>().is_ok(),
+        Type::TYPE_UINT64 | Type::TYPE_FIXED64 => key.parse::<u64>().is_ok(),
+        Type::TYPE_BOOL => key == "true" || key == "false",
+        _ => true, // string keys, other types are not allowed as map keys by protoc
+    }
+}
+
+/// Compares the key set of every object element with the first one. Returns the indices
+/// of the elements that differ, e.g. "1,3", or None when all elements share one shape.
+fn inconsistent_elements(arr: &[Value]) -> Option<String> {
+    let key_set = |value: &Value| -> Option<Vec<String>> {
+        let mut keys: Vec<String> = value.as_object()?.keys().cloned().collect();
+        keys.sort();
+        Some(keys)
+    };
+
+    let first = key_set(arr.first()?)?;
+    let differing: Vec<String> = arr
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, item)| key_set(item).is_some_and(|keys| keys != first))
+        .map(|(i, _)| i.to_string())
+        .collect();
+
+    if differing.is_empty() {
+        None
+    } else {
+        Some(differing.join(","))
+    }
+}
+
+/// Checks an enum value, given either by name or by number, against the declared values.
+/// Undeclared numbers only produce a warning when `unknown_enum_ok` is set.
+fn validate_enum(
+    field: &FieldDescriptorProto,
+    value: &Value,
+    ctx: &Context,
+    field_path: &str,
+) -> Result<(), ErrorType> {
+    let enum_type = field
+        .type_name
+        .as_ref()
+        .and_then(|type_name| ctx.enum_types.get(&type_name.trim_start_matches('.').to_lowercase()));
+
+    if enum_type.is_none() && ctx.options.strict_resolution {
+        return Err(ErrorType::UnresolvedType);
+    }
+
+    match value {
+        Value::String(name) => match enum_type {
+            Some(enum_type)
+                if !enum_type
+                    .value
+                    .iter()
+                    .any(|v| v.name() == ctx.options.enum_case.normalize(name)) =>
+            {
+                Err(ErrorType::InvalidEnumValue)
+            }
+            _ => Ok(()),
+        },
+        Value::Number(n) if n.is_i64() => match enum_type {
+            Some(enum_type)
+                if !enum_type
+                    .value
+                    .iter()
+                    .any(|v| Some(v.number() as i64) == n.as_i64()) =>
+            {
+                if ctx.options.unknown_enum_ok {
+                    eprintln!("Warning: {} - unknown enum value {} accepted", field_path, n);
+                    Ok(())
+                } else {
+                    Err(ErrorType::InvalidEnumValue)
+                }
+            }
+            _ => Ok(()),
+        },
+        _ => Err(ErrorType::WrongDataType),
+    }
+}
+
+/// Whether a field path is covered by the `only_fields` selection: the path is listed,
+/// nested below a listed path, or on the way to one. An empty selection covers everything.
+fn is_selected(field_path: &str, only_fields: &[String]) -> bool {
+    if only_fields.is_empty() {
+        return true;
+    }
+
+    // Array indexes do not take part in the selection, `items[2].id` matches `items.id`
+    let path = strip_indexes(field_path);
+
+    only_fields.iter().any(|selected| {
+        path == *selected
+            || path.starts_with(&format!("{}.", selected))
+            || selected.starts_with(&format!("{}.", path))
+    })
+}
+
+/// Removes array indexes and map keys from a path, `items[2].id` becomes `items.id`.
+fn strip_indexes(field_path: &str) -> String {
+    let mut path = String::new();
+    let mut in_index = false;
+    for c in field_path.chars() {
+        match c {
+            '[' => in_index = true,
+            ']' => in_index = false,
+            _ if !in_index => path.push(c),
+            _ => (),
+        }
+    }
+    path
+}
+
+/// Whether a string value contains a NUL character, which protobuf storage rejects.
+fn has_nul(value: &Value) -> bool {
+    matches!(value, Value::String(s) if s.contains('\0'))
+}
+
+/// Names the JSON type of a value for error reporting.
+pub fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Whether a string is standard base64 with padding, as the proto3 JSON mapping encodes bytes.
+fn is_base64(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return false;
+    }
+    let padding = bytes.iter().rev().take_while(|b| **b == b'=').count();
+    padding <= 2
+        && bytes[..bytes.len() - padding]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+}
+
+/// Integer held by a JSON number. Floats such as `1e9` or `2.0` count when they have no
+/// fractional part, as proto3 JSON parsers accept them for integer fields.
+fn integer_value(n: &serde_json::Number) -> Option<i128> {
+    if let Some(n) = n.as_i64() {
+        return Some(n.into());
+    }
+    if let Some(n) = n.as_u64() {
+        return Some(n.into());
+    }
+    n.as_f64()
+        .filter(|n| n.is_finite() && n.fract() == 0.0)
+        .map(|n| n as i128)
+}
+
+/// Whether an integer field value was stored as a float, e.g. `1e9` or `1.0e9`.
+fn is_float_integer(field_type: Type, value: &Value) -> bool {
+    let is_integer_type = matches!(
+        field_type,
+        Type::TYPE_INT32
+            | Type::TYPE_INT64
+            | Type::TYPE_UINT32
+            | Type::TYPE_UINT64
+            | Type::TYPE_SINT32
+            | Type::TYPE_SINT64
+            | Type::TYPE_FIXED32
+            | Type::TYPE_FIXED64
+            | Type::TYPE_SFIXED32
+            | Type::TYPE_SFIXED64
+    );
+    is_integer_type && value.as_number().is_some_and(|n| n.is_f64())
+}
+
+/// Checks if a JSON value matches a Protobuf primitive type.
+fn is_valid_primitive(
+    field_type: protobuf::descriptor::field_descriptor_proto::Type,
+    value: &Value,
+) -> bool {
+    match (field_type, value) {
+        // String field should be a JSON string
+        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_STRING, Value::String(_)) => true,
+        // Int32 field should be a JSON number that fits in i64
+        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_INT32, Value::Number(n)) => {
+            integer_value(n).is_some_and(|n| i64::try_from(n).is_ok())
+        }
+        // Signed 32-bit fields should be a JSON number that fits in i32
+        (Type::TYPE_SINT32 | Type::TYPE_SFIXED32, Value::Number(n)) => {
+            integer_value(n).is_some_and(|n| i32::try_from(n).is_ok())
+        }
+        // Unsigned 32-bit fields should be a JSON number that fits in u32
+        (Type::TYPE_UINT32 | Type::TYPE_FIXED32, Value::Number(n)) => {
+            integer_value(n).is_some_and(|n| u32::try_from(n).is_ok())
+        }
+        // 64-bit fields are JSON numbers, or strings in the proto3 JSON mapping
+        (Type::TYPE_INT64 | Type::TYPE_SINT64 | Type::TYPE_SFIXED64, Value::Number(n)) => {
+            integer_value(n).is_some_and(|n| i64::try_from(n).is_ok())
+        }
+        (Type::TYPE_INT64 | Type::TYPE_SINT64 | Type::TYPE_SFIXED64, Value::String(s)) => {
+            s.parse::<i64>().is_ok()
+        }
+        (Type::TYPE_UINT64 | Type::TYPE_FIXED64, Value::Number(n)) => {
+            integer_value(n).is_some_and(|n| u64::try_from(n).is_ok())
+        }
+        (Type::TYPE_UINT64 | Type::TYPE_FIXED64, Value::String(s)) => s.parse::<u64>().is_ok(),
+        // Float field can be any JSON number
+        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_FLOAT, Value::Number(_)) => true,
+        // Double field can be any JSON number, like float
+        (Type::TYPE_DOUBLE, Value::Number(_)) => true,
+        // Bytes field should be a base64 string
+        (Type::TYPE_BYTES, Value::String(s)) => is_base64(s),
+        // Bool field should be a JSON boolean
+        (protobuf::descriptor::field_descriptor_proto::Type::TYPE_BOOL, Value::Bool(_)) => true,
         _ => false, // Any other combination is invalid
     }
 }
@@ -338,6 +1520,85 @@ mod tests {
         file_set
     }
 
+    #[test]
+    fn test_ignored_fields_are_not_additional() {
+        assert_eq!(ignore_list(""), vec!["_id", "_rev"]);
+        assert_eq!(ignore_list(" audit , ,_rev,legacy "), vec!["_id", "_rev", "audit", "legacy"]);
+
+        let file_set = create_test_descriptor();
+        let json_value = json!({"_id": "doc-1", "_rev": "1-a", "name": "order", "audit": {"by": "ops"}});
+        assert_eq!(
+            validate_json(&file_set, "TopLevel", &json_value, ignore_list("")),
+            vec![ValidationError {
+                field: "audit".to_string(),
+                error_type: ErrorType::AdditionalField,
+                found: None,
+                expected: None,
+            }]
+        );
+        assert_eq!(validate_json(&file_set, "TopLevel", &json_value, ignore_list(" audit ")), vec![]);
+    }
+
+    #[test]
+    fn test_field_mask_only_reports_masked_subtree() {
+        let file_set = create_test_descriptor();
+        let json_value = json!({
+            "name": 42,
+            "extra": true,
+            "items": [
+                {"id": "one", "description": "first", "details": [{"value": 7}]}
+            ]
+        });
+
+        // Without a mask every error is reported
+        let errors = validate_json(&file_set, "TopLevel", &json_value, vec![]);
+        assert_eq!(errors.len(), 4);
+
+        let options = ValidationOptions {
+            field_mask: Some("items".to_string()),
+            ..Default::default()
+        };
+        let mut fields: Vec<String> = validate_json_with(&file_set, "TopLevel", &json_value, vec![], &options)
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
+        fields.sort();
+        assert_eq!(fields, vec!["items[0].details[0].value", "items[0].id"]);
+
+        // Nothing to validate when the subtree is absent
+        let errors = validate_json_with(&file_set, "TopLevel", &json!({"name": 42}), vec![], &options);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_synthetic_document_fails_every_field() {
+        let file_set = create_test_descriptor();
+
+        let doc = synthetic_document(&file_set, "toplevel").unwrap();
+        assert_eq!(doc, json!({"name": 0, "items": {}}));
+
+        let errors = validate_json(&file_set, "TopLevel", &doc, vec![]);
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    field: "items".to_string(),
+                    error_type: ErrorType::WrongDataType,
+                    found: None,
+                    expected: None,
+                },
+                ValidationError {
+                    field: "name".to_string(),
+                    error_type: ErrorType::WrongDataType,
+                    found: Some("number".to_string()),
+                    expected: Some("TYPE_STRING".to_string()),
+                },
+            ]
+        );
+
+        assert_eq!(synthetic_document(&file_set, "Unknown"), None);
+    }
+
     #[test]
     fn test_nested_and_repeated_validation_with_sub_descriptor() {
         let file_set = create_test_descriptor();
@@ -375,6 +1636,8 @@ mod tests {
             ValidationError {
                 field: "extra".to_string(),
                 error_type: ErrorType::AdditionalField,
+                found: None,
+                expected: None,
             }
         );
         assert_eq!(
@@ -382,21 +1645,1565 @@ mod tests {
             ValidationError {
                 field: "items[0].details[1].value".to_string(),
                 error_type: ErrorType::WrongDataType,
+                found: Some("number".to_string()),
+                expected: Some("TYPE_STRING".to_string()),
             }
         );
         assert_eq!(
             errors[2],
             ValidationError {
-                field: "items[1].id".to_string(),
-                error_type: ErrorType::InvalidArrayElement,
+                field: "items[1].details[1].extra".to_string(),
+                error_type: ErrorType::AdditionalField,
+                found: None,
+                expected: None,
             }
         );
         assert_eq!(
             errors[3],
             ValidationError {
-                field: "items[1].details[1].extra".to_string(),
-                error_type: ErrorType::AdditionalField,
+                field: "items[1].details[1].value".to_string(),
+                error_type: ErrorType::MissingField,
+                found: None,
+                expected: None,
+            }
+        );
+        assert_eq!(
+            errors[4],
+            ValidationError {
+                field: "items[1].id".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: Some("string".to_string()),
+                expected: Some("TYPE_INT32".to_string()),
             }
         );
     }
+
+    #[test]
+    fn test_invalid_array_elements_serialize_with_index_and_type() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        let mut message = DescriptorProto::new();
+        message.name = Some("Tagged".to_string());
+        let mut tags_field = FieldDescriptorProto::new();
+        tags_field.name = Some("tags".to_string());
+        tags_field.json_name = Some("tags".to_string());
+        tags_field.label = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+        ));
+        tags_field.type_ = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Type::TYPE_STRING,
+        ));
+        message.field.push(tags_field);
+        file.message_type.push(message);
+        file_set.file.push(file);
+
+        let json_value = json!({"tags": ["a", 1, "b", true, null]});
+        let errors = validate_json(&file_set, "Tagged", &json_value, vec![]);
+
+        assert_eq!(
+            serde_json::to_value(&errors).unwrap(),
+            json!([
+                {"field": "tags[1]", "error_type": "InvalidArrayElement", "found": "number", "expected": "TYPE_STRING"},
+                {"field": "tags[3]", "error_type": "InvalidArrayElement", "found": "bool", "expected": "TYPE_STRING"},
+                {"field": "tags[4]", "error_type": "InvalidArrayElement", "found": "null", "expected": "TYPE_STRING"}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_json_name_option_overrides_derived_name() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        let mut message = DescriptorProto::new();
+        message.name = Some("Order".to_string());
+        let mut ref_field = FieldDescriptorProto::new();
+        ref_field.name = Some("order_ref".to_string());
+        ref_field.json_name = Some("orderRef".to_string()); // Default camelCase derivation
+        ref_field.type_ = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Type::TYPE_STRING,
+        ));
+        let mut option = protobuf::descriptor::UninterpretedOption::new();
+        let mut name_part = protobuf::descriptor::uninterpreted_option::NamePart::new();
+        name_part.name_part = Some("json_name".to_string());
+        name_part.is_extension = Some(true);
+        option.name.push(name_part);
+        option.string_value = Some(b"ref".to_vec());
+        ref_field.options.mut_or_insert_default().uninterpreted_option.push(option);
+        message.field.push(ref_field);
+        file.message_type.push(message);
+        file_set.file.push(file);
+
+        // Stored documents use the override
+        let errors = validate_json(&file_set, "Order", &json!({"ref": "R-1"}), vec![]);
+        assert!(errors.is_empty());
+
+        // The derived name is no longer expected
+        let errors = validate_json(&file_set, "Order", &json!({"orderRef": "R-1"}), vec![]);
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    field: "orderRef".to_string(),
+                    error_type: ErrorType::AdditionalField,
+                    found: None,
+                    expected: None,
+                },
+                ValidationError {
+                    field: "ref".to_string(),
+                    error_type: ErrorType::MissingField,
+                    found: None,
+                    expected: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snake_case_keys_follow_field_name_mode() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        let mut message = DescriptorProto::new();
+        message.name = Some("Order".to_string());
+        for (name, json_name, type_) in [
+            ("order_id", "orderId", Type::TYPE_STRING),
+            ("line_count", "lineCount", Type::TYPE_INT32),
+        ] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(json_name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            message.field.push(field);
+        }
+        file.message_type.push(message);
+        file_set.file.push(file);
+        let validate = |doc: &Value, field_name_mode: FieldNameMode| {
+            let options = ValidationOptions {
+                field_name_mode,
+                ..Default::default()
+            };
+            validate_json_with(&file_set, "Order", doc, vec![], &options)
+        };
+        let error = |field: &str, error_type: ErrorType| ValidationError {
+            field: field.to_string(),
+            error_type,
+            found: None,
+            expected: None,
+        };
+
+        let snake = json!({"order_id": "O-1", "line_count": 2});
+        let mixed = json!({"orderId": "O-1", "line_count": "two"});
+
+        // Either name is accepted by default, and the value is still validated
+        assert!(validate(&snake, FieldNameMode::Both).is_empty());
+        assert_eq!(
+            validate(&mixed, FieldNameMode::Both),
+            vec![ValidationError {
+                field: "line_count".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: Some("string".to_string()),
+                expected: Some("TYPE_INT32".to_string()),
+            }]
+        );
+
+        assert!(validate(&snake, FieldNameMode::Proto).is_empty());
+        assert_eq!(
+            validate(&json!({"orderId": "O-1", "line_count": 2}), FieldNameMode::Proto),
+            vec![error("orderId", ErrorType::AdditionalField), error("order_id", ErrorType::MissingField)]
+        );
+
+        assert_eq!(
+            validate(&snake, FieldNameMode::Json),
+            vec![
+                error("line_count", ErrorType::AdditionalField),
+                error("order_id", ErrorType::AdditionalField),
+                error("orderId", ErrorType::MissingField),
+                error("lineCount", ErrorType::MissingField),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strict_strings_flags_embedded_nul() {
+        let file_set = create_test_descriptor();
+        let options = ValidationOptions {
+            strict_strings: true,
+            ..Default::default()
+        };
+
+        let clean = json!({"name": "clean"});
+        let errors = validate_json_with(&file_set, "TopLevel", &clean, vec![], &options);
+        assert!(errors.is_empty());
+
+        let with_nul: Value = serde_json::from_str(r#"{"name": "bad\u0000name"}"#).unwrap();
+        let errors = validate_json_with(&file_set, "TopLevel", &with_nul, vec![], &options);
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                field: "name".to_string(),
+                error_type: ErrorType::InvalidStringContent,
+                found: None,
+                expected: None,
+            }]
+        );
+
+        // Without the option the NUL character is accepted
+        let errors = validate_json(&file_set, "TopLevel", &with_nul, vec![]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_only_fields_reports_selected_field_errors() {
+        let file_set = create_test_descriptor();
+        let options = ValidationOptions {
+            only_fields: vec!["items.details.value".to_string()],
+            ..Default::default()
+        };
+
+        let json_value = json!({
+            "name": 1, // Wrong type, but not selected
+            "items": [
+                {"id": "one", "details": [{"value": "a"}, {"value": 42}]},
+                {"details": [{"extra": "field"}]}
+            ],
+            "extra": "field"
+        });
+
+        let errors = validate_json_with(&file_set, "TopLevel", &json_value, vec![], &options);
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    field: "items[0].details[1].value".to_string(),
+                    error_type: ErrorType::WrongDataType,
+                    found: Some("number".to_string()),
+                    expected: Some("TYPE_STRING".to_string()),
+                },
+                ValidationError {
+                    field: "items[1].details[0].value".to_string(),
+                    error_type: ErrorType::MissingField,
+                    found: None,
+                    expected: None,
+                },
+            ]
+        );
+    }
+
+    fn create_enum_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+
+        let mut status = EnumDescriptorProto::new();
+        status.name = Some("Status".to_string());
+        for (number, name) in [(0, "PENDING"), (1, "SHIPPED"), (2, "IN_TRANSIT")] {
+            let mut value = protobuf::descriptor::EnumValueDescriptorProto::new();
+            value.name = Some(name.to_string());
+            value.number = Some(number);
+            status.value.push(value);
+        }
+
+        let mut shipment = DescriptorProto::new();
+        shipment.name = Some("Shipment".to_string());
+        let mut status_field = FieldDescriptorProto::new();
+        status_field.name = Some("status".to_string());
+        status_field.json_name = Some("status".to_string());
+        status_field.type_name = Some(".Status".to_string());
+        status_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_ENUM));
+        shipment.field.push(status_field);
+
+        file.enum_type.push(status);
+        file.message_type.push(shipment);
+        file_set.file.push(file);
+        file_set
+    }
+
+    #[test]
+    fn test_unknown_enum_number_is_accepted_with_flag() {
+        let file_set = create_enum_descriptor();
+        let out_of_range = json!({"status": 7});
+        let invalid_enum = vec![ValidationError {
+            field: "status".to_string(),
+            error_type: ErrorType::InvalidEnumValue,
+            found: None,
+            expected: None,
+        }];
+
+        // Strict by default
+        let errors = validate_json(&file_set, "Shipment", &out_of_range, vec![]);
+        assert_eq!(errors, invalid_enum);
+
+        // Open enum semantics: an unknown number is only a warning
+        let options = ValidationOptions {
+            unknown_enum_ok: true,
+            ..Default::default()
+        };
+        let errors = validate_json_with(&file_set, "Shipment", &out_of_range, vec![], &options);
+        assert!(errors.is_empty());
+
+        // Unknown names still fail, declared names and numbers pass
+        let errors = validate_json_with(&file_set, "Shipment", &json!({"status": "LOST"}), vec![], &options);
+        assert_eq!(errors, invalid_enum);
+        assert!(validate_json(&file_set, "Shipment", &json!({"status": "SHIPPED"}), vec![]).is_empty());
+        assert!(validate_json(&file_set, "Shipment", &json!({"status": 1}), vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_enum_nested_in_nested_message() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+
+        // Shipment.Tracking.Carrier, used by a repeated Parcel field
+        let mut carrier = EnumDescriptorProto::new();
+        carrier.name = Some("Carrier".to_string());
+        for (number, name) in [(0, "POS"), (1, "DHL")] {
+            let mut value = protobuf::descriptor::EnumValueDescriptorProto::new();
+            value.name = Some(name.to_string());
+            value.number = Some(number);
+            carrier.value.push(value);
+        }
+        let mut tracking = DescriptorProto::new();
+        tracking.name = Some("Tracking".to_string());
+        tracking.enum_type.push(carrier);
+
+        let mut shipment = DescriptorProto::new();
+        shipment.name = Some("Shipment".to_string());
+        shipment.nested_type.push(tracking);
+        let mut parcels_field = FieldDescriptorProto::new();
+        parcels_field.name = Some("parcels".to_string());
+        parcels_field.json_name = Some("parcels".to_string());
+        parcels_field.label = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+        ));
+        parcels_field.type_name = Some(".Parcel".to_string());
+        parcels_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+        shipment.field.push(parcels_field);
+
+        let mut parcel = DescriptorProto::new();
+        parcel.name = Some("Parcel".to_string());
+        let mut carrier_field = FieldDescriptorProto::new();
+        carrier_field.name = Some("carrier".to_string());
+        carrier_field.json_name = Some("carrier".to_string());
+        carrier_field.type_name = Some(".Shipment.Tracking.Carrier".to_string());
+        carrier_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_ENUM));
+        parcel.field.push(carrier_field);
+
+        file.message_type.push(shipment);
+        file.message_type.push(parcel);
+        file_set.file.push(file);
+
+        let json_value = json!({"parcels": [{"carrier": "DHL"}, {"carrier": 0}, {"carrier": "FEDEX"}]});
+        let options = ValidationOptions {
+            strict_resolution: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_json_with(&file_set, "Shipment", &json_value, vec![], &options),
+            vec![ValidationError {
+                field: "parcels[2].carrier".to_string(),
+                error_type: ErrorType::InvalidEnumValue,
+                found: None,
+                expected: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_enum_case_matches_camel_case_names() {
+        let file_set = create_enum_descriptor();
+        let camel_case = json!({"status": "inTransit"});
+
+        // Exact match by default
+        let errors = validate_json(&file_set, "Shipment", &camel_case, vec![]);
+        assert_eq!(errors[0].error_type, ErrorType::InvalidEnumValue);
+
+        let options = ValidationOptions {
+            enum_case: EnumCase::ScreamingSnake,
+            ..Default::default()
+        };
+        assert!(validate_json_with(&file_set, "Shipment", &camel_case, vec![], &options).is_empty());
+        let kebab_case = json!({"status": "in-transit"});
+        assert!(validate_json_with(&file_set, "Shipment", &kebab_case, vec![], &options).is_empty());
+        let unknown = json!({"status": "lostInTransit"});
+        let errors = validate_json_with(&file_set, "Shipment", &unknown, vec![], &options);
+        assert_eq!(errors[0].error_type, ErrorType::InvalidEnumValue);
+    }
+
+    #[test]
+    fn test_enum_case_conversions() {
+        assert_eq!(EnumCase::ScreamingSnake.normalize("activeStatus"), "ACTIVE_STATUS");
+        assert_eq!(EnumCase::ScreamingSnake.normalize("ACTIVE_STATUS"), "ACTIVE_STATUS");
+        assert_eq!(EnumCase::ScreamingSnake.normalize("level2Check"), "LEVEL2_CHECK");
+        assert_eq!(EnumCase::Camel.normalize("ACTIVE_STATUS"), "activeStatus");
+        assert_eq!(EnumCase::Camel.normalize("activeStatus"), "activeStatus");
+        assert_eq!(EnumCase::AsIs.normalize("activeStatus"), "activeStatus");
+        assert_eq!("camel".parse(), Ok(EnumCase::Camel));
+        assert!("kebab".parse::<EnumCase>().is_err());
+    }
+
+    #[test]
+    fn test_packed_repeated_field_accepts_json_array() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        let mut samples = DescriptorProto::new();
+        samples.name = Some("Samples".to_string());
+        let mut values_field = FieldDescriptorProto::new();
+        values_field.name = Some("values".to_string());
+        values_field.json_name = Some("values".to_string());
+        values_field.label = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+        ));
+        values_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        let mut options = protobuf::descriptor::FieldOptions::new();
+        options.packed = Some(true);
+        values_field.options = protobuf::MessageField::some(options);
+        samples.field.push(values_field);
+        file.message_type.push(samples);
+        file_set.file.push(file);
+
+        let errors = validate_json(&file_set, "Samples", &json!({"values": [1, 2, 3]}), vec![]);
+        assert!(errors.is_empty());
+
+        let errors = validate_json(&file_set, "Samples", &json!({"values": 1}), vec![]);
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                field: "values".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: None,
+                expected: None,
+            }]
+        );
+
+        let errors = validate_json(&file_set, "Samples", &json!({"values": [1, "2"]}), vec![]);
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                field: "values[1]".to_string(),
+                error_type: ErrorType::InvalidArrayElement,
+                found: Some("string".to_string()),
+                expected: Some("TYPE_INT32".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_consistent_arrays_flags_differing_elements() {
+        let file_set = create_test_descriptor();
+        let options = ValidationOptions {
+            consistent_arrays: true,
+            ..Default::default()
+        };
+
+        let json_value = json!({
+            "name": "test",
+            "items": [
+                {"id": 1, "description": "first", "details": [{"value": "a"}]},
+                {"id": 2, "details": [{"value": "b"}]}
+            ]
+        });
+
+        let errors = validate_json_with(&file_set, "TopLevel", &json_value, vec![], &options);
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    field: "items[1].description".to_string(),
+                    error_type: ErrorType::MissingField,
+                    found: None,
+                    expected: None,
+                },
+                ValidationError {
+                    field: "items".to_string(),
+                    error_type: ErrorType::InconsistentArrayShape,
+                    found: Some("1".to_string()),
+                    expected: None,
+                },
+            ]
+        );
+
+        // Off by default
+        let errors = validate_json(&file_set, "TopLevel", &json_value, vec![]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    /// A `Reading` message with a singular `value` and `note`, declared with the given syntax.
+    fn create_presence_descriptor(
+        syntax: &str,
+        configure: impl Fn(&mut FieldDescriptorProto, &mut FieldDescriptorProto),
+    ) -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some(syntax.to_string());
+
+        let mut reading = DescriptorProto::new();
+        reading.name = Some("Reading".to_string());
+        let mut value_field = FieldDescriptorProto::new();
+        value_field.name = Some("value".to_string());
+        value_field.json_name = Some("value".to_string());
+        value_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        let mut note_field = FieldDescriptorProto::new();
+        note_field.name = Some("note".to_string());
+        note_field.json_name = Some("note".to_string());
+        note_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_STRING));
+        configure(&mut value_field, &mut note_field);
+        reading.field.push(value_field);
+        reading.field.push(note_field);
+
+        file.message_type.push(reading);
+        file_set.file.push(file);
+        file_set
+    }
+
+    fn missing_value() -> Vec<ValidationError> {
+        vec![ValidationError {
+            field: "value".to_string(),
+            error_type: ErrorType::MissingField,
+            found: None,
+            expected: None,
+        }]
+    }
+
+    #[test]
+    fn test_proto2_presence_only_requires_required_fields() {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        let file_set = create_presence_descriptor("proto2", |value, note| {
+            value.label = Some(EnumOrUnknown::new(Label::LABEL_REQUIRED));
+            note.label = Some(EnumOrUnknown::new(Label::LABEL_OPTIONAL));
+        });
+
+        assert_eq!(validate_json(&file_set, "Reading", &json!({}), vec![]), missing_value());
+        assert!(validate_json(&file_set, "Reading", &json!({"value": 1}), vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_proto3_presence_allows_absent_fields() {
+        let file_set = create_presence_descriptor("proto3", |_, note| {
+            note.proto3_optional = Some(true);
+        });
+
+        assert!(validate_json(&file_set, "Reading", &json!({}), vec![]).is_empty());
+        // Types are still checked for fields that are present
+        let errors = validate_json(&file_set, "Reading", &json!({"value": "1"}), vec![]);
+        assert_eq!(errors[0].error_type, ErrorType::WrongDataType);
+    }
+
+    #[test]
+    fn test_strict_required_reports_every_absent_field() {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        let options = ValidationOptions {
+            strict_required: true,
+            ..Default::default()
+        };
+        let mut missing_both = missing_value();
+        missing_both.insert(
+            0,
+            ValidationError {
+                field: "note".to_string(),
+                ..missing_value().remove(0)
+            },
+        );
+
+        let file_set = create_presence_descriptor("proto3", |_, _| {});
+        let mut errors = validate_json_with(&file_set, "Reading", &json!({}), vec![], &options);
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        assert_eq!(errors, missing_both);
+
+        let file_set = create_presence_descriptor("proto2", |value, note| {
+            value.label = Some(EnumOrUnknown::new(Label::LABEL_REQUIRED));
+            note.label = Some(EnumOrUnknown::new(Label::LABEL_OPTIONAL));
+        });
+        let mut errors = validate_json_with(&file_set, "Reading", &json!({}), vec![], &options);
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        assert_eq!(errors, missing_both);
+        assert!(validate_json_with(&file_set, "Reading", &json!({"value": 1, "note": "a"}), vec![], &options).is_empty());
+    }
+
+    #[test]
+    fn test_editions_presence_follows_field_features() {
+        let file_set = create_presence_descriptor("editions", |value, _| {
+            // features.field_presence = LEGACY_REQUIRED
+            let mut options = protobuf::descriptor::FieldOptions::new();
+            options
+                .special_fields
+                .mut_unknown_fields()
+                .add_length_delimited(FIELD_OPTIONS_FEATURES, vec![0x08, 0x03]);
+            value.options = protobuf::MessageField::some(options);
+        });
+
+        assert_eq!(validate_json(&file_set, "Reading", &json!({}), vec![]), missing_value());
+        assert!(validate_json(&file_set, "Reading", &json!({"value": 1}), vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_map_with_message_values() {
+        // message Inventory { map<string, Item> stock = 1; }  message Item { int32 count = 1; }
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+
+        let mut item = DescriptorProto::new();
+        item.name = Some("Item".to_string());
+        let mut count_field = FieldDescriptorProto::new();
+        count_field.name = Some("count".to_string());
+        count_field.json_name = Some("count".to_string());
+        count_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        item.field.push(count_field);
+
+        let mut entry = DescriptorProto::new();
+        entry.name = Some("StockEntry".to_string());
+        let mut entry_options = protobuf::descriptor::MessageOptions::new();
+        entry_options.map_entry = Some(true);
+        entry.options = protobuf::MessageField::some(entry_options);
+        let mut key_field = FieldDescriptorProto::new();
+        key_field.name = Some("key".to_string());
+        key_field.number = Some(1);
+        key_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_STRING));
+        let mut value_field = FieldDescriptorProto::new();
+        value_field.name = Some("value".to_string());
+        value_field.number = Some(2);
+        value_field.type_name = Some(".Item".to_string());
+        value_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+        entry.field.push(key_field);
+        entry.field.push(value_field);
+
+        let mut inventory = DescriptorProto::new();
+        inventory.name = Some("Inventory".to_string());
+        let mut stock_field = FieldDescriptorProto::new();
+        stock_field.name = Some("stock".to_string());
+        stock_field.json_name = Some("stock".to_string());
+        stock_field.label = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+        ));
+        stock_field.type_name = Some(".Inventory.StockEntry".to_string());
+        stock_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+        inventory.field.push(stock_field);
+        inventory.nested_type.push(entry);
+
+        file.message_type.push(inventory);
+        file.message_type.push(item);
+        file_set.file.push(file);
+
+        let json_value = json!({
+            "stock": {
+                "apple": {"count": 3},
+                "pear": {"count": "three"}
+            }
+        });
+        let errors = validate_json(&file_set, "Inventory", &json_value, vec![]);
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                field: "stock[\"pear\"].count".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: Some("string".to_string()),
+                expected: Some("TYPE_INT32".to_string()),
+            }]
+        );
+
+        let errors = validate_json(&file_set, "Inventory", &json!({"stock": []}), vec![]);
+        assert_eq!(errors[0].error_type, ErrorType::WrongDataType);
+    }
+
+    #[test]
+    fn test_nested_message_type_is_validated() {
+        // message Outer { message Inner { int32 count = 1; } Inner inner = 1; }
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto3".to_string());
+
+        let mut inner = DescriptorProto::new();
+        inner.name = Some("Inner".to_string());
+        let mut count_field = FieldDescriptorProto::new();
+        count_field.name = Some("count".to_string());
+        count_field.json_name = Some("count".to_string());
+        count_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT32));
+        inner.field.push(count_field);
+
+        let mut outer = DescriptorProto::new();
+        outer.name = Some("Outer".to_string());
+        let mut inner_field = FieldDescriptorProto::new();
+        inner_field.name = Some("inner".to_string());
+        inner_field.json_name = Some("inner".to_string());
+        inner_field.type_name = Some(".Outer.Inner".to_string());
+        inner_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+        outer.field.push(inner_field);
+        outer.nested_type.push(inner);
+
+        file.message_type.push(outer);
+        file_set.file.push(file);
+
+        assert!(validate_json(&file_set, "Outer", &json!({"inner": {"count": 3}}), vec![]).is_empty());
+        // proto3 presence applies to the nested message too
+        assert!(validate_json(&file_set, "Outer", &json!({"inner": {}}), vec![]).is_empty());
+
+        let errors = validate_json(&file_set, "Outer", &json!({"inner": {"count": "three"}}), vec![]);
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                field: "inner.count".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: Some("string".to_string()),
+                expected: Some("TYPE_INT32".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_packaged_types_resolve_by_qualified_name() {
+        let dir = std::env::temp_dir().join(format!("bulkmorph-package-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let proto = dir.join("shop.proto");
+        std::fs::write(
+            &proto,
+            r#"syntax = "proto2";
+package shop.v1;
+message Order {
+  message Item {
+    enum Unit { PIECE = 0; BOX = 1; }
+    required int32 count = 1;
+    optional Unit unit = 2;
+  }
+  required Item item = 1;
+}
+"#,
+        )
+        .unwrap();
+        let file_set = crate::descriptor::parse_proto(proto.to_str().unwrap(), dir.to_str().unwrap(), None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(file_set.file[0].message_type[0].field[0].type_name(), ".shop.v1.Order.Item");
+
+        // The table is found by its own name and by its qualified name
+        let valid = json!({"item": {"count": 3, "unit": "BOX"}});
+        assert!(validate_json(&file_set, "Order", &valid, vec![]).is_empty());
+        assert!(validate_json(&file_set, "shop.v1.Order", &valid, vec![]).is_empty());
+
+        // Nested message and enum resolve, with the proto2 presence of their file
+        let options = ValidationOptions {
+            strict_resolution: true,
+            ..Default::default()
+        };
+        let invalid = json!({"item": {"unit": "CRATE"}});
+        let errors = validate_json_with(&file_set, "Order", &invalid, vec![], &options);
+        let errors: Vec<(&str, &ErrorType)> = errors.iter().map(|e| (e.field.as_str(), &e.error_type)).collect();
+        assert_eq!(
+            errors,
+            vec![("item.unit", &ErrorType::InvalidEnumValue), ("item.count", &ErrorType::MissingField)]
+        );
+        assert_eq!(resolve_field(&file_set, "Order", "item.count").unwrap().name(), "count");
+    }
+
+    #[test]
+    fn test_range_parsing() {
+        assert_eq!(
+            "age=0:150".parse(),
+            Ok(FieldRange {
+                path: "age".to_string(),
+                min: Some(0.0),
+                max: Some(150.0),
+            })
+        );
+        let open: FieldRange = "items.price=0:".parse().unwrap();
+        assert_eq!((open.min, open.max), (Some(0.0), None));
+        assert!("age".parse::<FieldRange>().is_err());
+        assert!("age=0-150".parse::<FieldRange>().is_err());
+        assert!("age=150:0".parse::<FieldRange>().is_err());
+    }
+
+    #[test]
+    fn test_range_violations() {
+        let file_set = create_presence_descriptor("proto3", |_, _| ());
+        let options = ValidationOptions {
+            ranges: vec!["value=0:150".parse().unwrap()],
+            ..Default::default()
+        };
+        let validate = |value: i64| {
+            validate_json_with(&file_set, "Reading", &json!({"value": value}), vec![], &options)
+        };
+
+        assert!(validate(42).is_empty());
+        assert!(validate(0).is_empty());
+        assert!(validate(150).is_empty());
+        assert_eq!(
+            validate(-1),
+            vec![ValidationError {
+                field: "value".to_string(),
+                error_type: ErrorType::RangeViolation,
+                found: Some("-1".to_string()),
+                expected: None,
+            }]
+        );
+        assert_eq!(validate(151)[0].found, Some("151".to_string()));
+
+        // Open upper bound: only non-negative values are required
+        let options = ValidationOptions {
+            ranges: vec!["value=0:".parse().unwrap()],
+            ..Default::default()
+        };
+        let errors = validate_json_with(&file_set, "Reading", &json!({"value": 1000000}), vec![], &options);
+        assert!(errors.is_empty());
+        let errors = validate_json_with(&file_set, "Reading", &json!({"value": -5}), vec![], &options);
+        assert_eq!(errors[0].error_type, ErrorType::RangeViolation);
+    }
+
+    #[test]
+    fn test_unresolved_nested_type() {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        // Descriptor set missing the file that declares `.billing.Money`
+        let file_set = create_presence_descriptor("proto3", |value, note| {
+            value.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+            value.type_name = Some(".billing.Money".to_string());
+            note.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+            note.type_name = Some(".billing.Note".to_string());
+            note.label = Some(EnumOrUnknown::new(Label::LABEL_REPEATED));
+        });
+        let json_value = json!({"value": {"units": 10}, "note": [{"text": "a"}, {"text": "b"}]});
+
+        // Lenient by default, the fields are skipped
+        assert!(validate_json(&file_set, "Reading", &json_value, vec![]).is_empty());
+
+        let options = ValidationOptions {
+            strict_resolution: true,
+            ..Default::default()
+        };
+        let errors = validate_json_with(&file_set, "Reading", &json_value, vec![], &options);
+        let mut unresolved: Vec<(String, Option<String>)> = errors
+            .into_iter()
+            .map(|error| {
+                assert_eq!(error.error_type, ErrorType::UnresolvedType);
+                (error.field, error.found)
+            })
+            .collect();
+        unresolved.sort();
+        assert_eq!(
+            unresolved,
+            vec![
+                ("note[0]".to_string(), Some(".billing.Note".to_string())),
+                ("note[1]".to_string(), Some(".billing.Note".to_string())),
+                ("value".to_string(), Some(".billing.Money".to_string())),
+            ]
+        );
+    }
+
+    /// An `Event` message with a singular `createdAt` and a repeated `seenAt` Timestamp.
+    fn create_timestamp_descriptor() -> FileDescriptorSet {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto3".to_string());
+        file.dependency.push("google/protobuf/timestamp.proto".to_string());
+
+        let mut event = DescriptorProto::new();
+        event.name = Some("Event".to_string());
+        for (name, label) in [("createdAt", Label::LABEL_OPTIONAL), ("seenAt", Label::LABEL_REPEATED)] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.label = Some(EnumOrUnknown::new(label));
+            field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+            field.type_name = Some(TIMESTAMP_TYPE.to_string());
+            event.field.push(field);
+        }
+
+        file.message_type.push(event);
+        file_set.file.push(file);
+        file_set
+    }
+
+    #[test]
+    fn test_rfc3339_timestamps_by_default() {
+        let file_set = create_timestamp_descriptor();
+
+        let valid = json!({"createdAt": "2025-01-31T08:00:00Z", "seenAt": ["2025-02-01T10:30:00.5+08:00"]});
+        assert!(validate_json(&file_set, "Event", &valid, vec![]).is_empty());
+
+        let invalid = json!({"createdAt": 1738310400000i64, "seenAt": ["yesterday"]});
+        assert_eq!(
+            validate_json(&file_set, "Event", &invalid, vec![]),
+            vec![
+                ValidationError {
+                    field: "createdAt".to_string(),
+                    error_type: ErrorType::WrongDataType,
+                    found: Some("number".to_string()),
+                    expected: Some("google.protobuf.Timestamp".to_string()),
+                },
+                ValidationError {
+                    field: "seenAt[0]".to_string(),
+                    error_type: ErrorType::WrongDataType,
+                    found: Some("string".to_string()),
+                    expected: Some("google.protobuf.Timestamp".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duration_strings() {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        let mut job = DescriptorProto::new();
+        job.name = Some("Job".to_string());
+        for (name, label) in [("timeout", Label::LABEL_OPTIONAL), ("backoff", Label::LABEL_REPEATED)] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.label = Some(EnumOrUnknown::new(label));
+            field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+            field.type_name = Some(DURATION_TYPE.to_string());
+            job.field.push(field);
+        }
+        file.message_type.push(job);
+        file_set.file.push(file);
+
+        let valid = json!({"timeout": "3.5s", "backoff": ["1s", "-0.000000001s", "315576000000s"]});
+        assert!(validate_json(&file_set, "Job", &valid, vec![]).is_empty());
+
+        let invalid = json!({"timeout": 3.5, "backoff": ["1m", "1.5", "1.0000000001s", "315576000001s"]});
+        let errors = validate_json(&file_set, "Job", &invalid, vec![]);
+        assert_eq!(
+            errors[4],
+            ValidationError {
+                field: "timeout".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: Some("number".to_string()),
+                expected: Some("google.protobuf.Duration".to_string()),
+            }
+        );
+        let fields: Vec<&str> = errors.iter().map(|error| error.field.as_str()).collect();
+        assert_eq!(fields, ["backoff[0]", "backoff[1]", "backoff[2]", "backoff[3]", "timeout"]);
+    }
+
+    #[test]
+    fn test_epoch_millis_timestamps() {
+        let file_set = create_timestamp_descriptor();
+        let options = ValidationOptions {
+            timestamp_format: TimestampFormat::EpochMillis,
+            ..Default::default()
+        };
+
+        let epoch = json!({"createdAt": 1738310400000i64, "seenAt": [1738405800500i64]});
+        assert!(validate_json_with(&file_set, "Event", &epoch, vec![], &options).is_empty());
+
+        // RFC3339 strings and fractional numbers are not the configured encoding
+        let rfc3339 = json!({"createdAt": "2025-01-31T08:00:00Z", "seenAt": [1.5]});
+        let errors = validate_json_with(&file_set, "Event", &rfc3339, vec![], &options);
+        let fields: Vec<(&str, &ErrorType)> = errors
+            .iter()
+            .map(|error| (error.field.as_str(), &error.error_type))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("createdAt", &ErrorType::WrongDataType),
+                ("seenAt[0]", &ErrorType::WrongDataType),
+            ]
+        );
+        assert_eq!("epoch_secs".parse(), Ok(TimestampFormat::EpochSecs));
+    }
+
+    fn create_required_message_descriptor() -> FileDescriptorSet {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto2".to_string());
+
+        let field = |name: &str, label: Label, type_: Type, type_name: Option<&str>| {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.label = Some(EnumOrUnknown::new(label));
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            field.type_name = type_name.map(str::to_string);
+            field
+        };
+
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        order.field.push(field("shipping", Label::LABEL_REQUIRED, Type::TYPE_MESSAGE, Some(".Address")));
+        order.field.push(field("billing", Label::LABEL_OPTIONAL, Type::TYPE_MESSAGE, Some(".Address")));
+
+        let mut address = DescriptorProto::new();
+        address.name = Some("Address".to_string());
+        address.field.push(field("city", Label::LABEL_REQUIRED, Type::TYPE_STRING, None));
+        address.field.push(field("zip", Label::LABEL_REQUIRED, Type::TYPE_STRING, None));
+
+        file.message_type.push(order);
+        file.message_type.push(address);
+        file_set.file.push(file);
+        file_set
+    }
+
+    #[test]
+    fn test_empty_required_message() {
+        let file_set = create_required_message_descriptor();
+        let json_value = json!({"shipping": {}, "billing": {}});
+        let fields = |errors: Vec<ValidationError>| {
+            let mut fields: Vec<(String, ErrorType)> = errors
+                .into_iter()
+                .map(|error| (error.field, error.error_type))
+                .collect();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            fields
+        };
+
+        // By default the empty object cascades into its missing fields
+        assert_eq!(
+            fields(validate_json(&file_set, "Order", &json_value, vec![])),
+            vec![
+                ("billing.city".to_string(), ErrorType::MissingField),
+                ("billing.zip".to_string(), ErrorType::MissingField),
+                ("shipping.city".to_string(), ErrorType::MissingField),
+                ("shipping.zip".to_string(), ErrorType::MissingField),
+            ]
+        );
+
+        // Only the required field is reported as a whole, the optional one still cascades
+        let options = ValidationOptions {
+            require_nonempty_messages: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(validate_json_with(&file_set, "Order", &json_value, vec![], &options)),
+            vec![
+                ("billing.city".to_string(), ErrorType::MissingField),
+                ("billing.zip".to_string(), ErrorType::MissingField),
+                ("shipping".to_string(), ErrorType::EmptyRequiredMessage),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_integer_types() {
+        use protobuf::descriptor::field_descriptor_proto::Label;
+        let integer_types = [
+            ("int64", Type::TYPE_INT64),
+            ("uint32", Type::TYPE_UINT32),
+            ("uint64", Type::TYPE_UINT64),
+            ("sint32", Type::TYPE_SINT32),
+            ("sint64", Type::TYPE_SINT64),
+            ("fixed32", Type::TYPE_FIXED32),
+            ("fixed64", Type::TYPE_FIXED64),
+            ("sfixed32", Type::TYPE_SFIXED32),
+            ("sfixed64", Type::TYPE_SFIXED64),
+        ];
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto3".to_string());
+        let mut counters = DescriptorProto::new();
+        counters.name = Some("Counters".to_string());
+        for (name, type_) in integer_types {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.label = Some(EnumOrUnknown::new(Label::LABEL_OPTIONAL));
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            counters.field.push(field);
+        }
+        file.message_type.push(counters);
+        file_set.file.push(file);
+
+        let valid = json!({
+            "int64": -9223372036854775808i64,
+            "uint32": 4294967295u32,
+            "uint64": 18446744073709551615u64,
+            "sint32": -2147483648,
+            "sint64": "-9223372036854775808",
+            "fixed32": 0,
+            "fixed64": "18446744073709551615",
+            "sfixed32": 2147483647,
+            "sfixed64": "42"
+        });
+        assert!(validate_json(&file_set, "Counters", &valid, vec![]).is_empty());
+
+        let invalid = json!({
+            "int64": "9223372036854775808",
+            "uint32": 4294967296u64,
+            "uint64": -1,
+            "sint32": 2147483648u32,
+            "sint64": 1.5,
+            "fixed32": "1",
+            "fixed64": "-1",
+            "sfixed32": -2147483649i64,
+            "sfixed64": "forty-two"
+        });
+        let mut fields: Vec<String> = validate_json(&file_set, "Counters", &invalid, vec![])
+            .into_iter()
+            .filter(|error| error.error_type == ErrorType::WrongDataType)
+            .map(|error| error.field)
+            .collect();
+        fields.sort();
+        let mut expected: Vec<String> = integer_types.iter().map(|(name, _)| name.to_string()).collect();
+        expected.sort();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn test_double_and_bytes_types() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto3".to_string());
+        let mut attachment = DescriptorProto::new();
+        attachment.name = Some("Attachment".to_string());
+        for (name, type_) in [("weight", Type::TYPE_DOUBLE), ("content", Type::TYPE_BYTES)] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            attachment.field.push(field);
+        }
+        file.message_type.push(attachment);
+        file_set.file.push(file);
+
+        let valid = json!({"weight": 2.5, "content": "aGVsbG8gd29ybGQ="});
+        assert!(validate_json(&file_set, "Attachment", &valid, vec![]).is_empty());
+        let valid = json!({"weight": 3, "content": ""});
+        assert!(validate_json(&file_set, "Attachment", &valid, vec![]).is_empty());
+
+        for content in [json!("aGVsbG8*d29ybGQ="), json!("aGVsbG8"), json!("a==="), json!(42)] {
+            let invalid = json!({"weight": "2.5", "content": content});
+            let mut fields: Vec<String> = validate_json(&file_set, "Attachment", &invalid, vec![])
+                .into_iter()
+                .filter(|error| error.error_type == ErrorType::WrongDataType)
+                .map(|error| error.field)
+                .collect();
+            fields.sort();
+            assert_eq!(fields, vec!["content", "weight"], "content {}", content);
+        }
+    }
+
+    #[test]
+    fn test_strict_integer_format() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        let mut account = DescriptorProto::new();
+        account.name = Some("Account".to_string());
+        let mut balance_field = FieldDescriptorProto::new();
+        balance_field.name = Some("balance".to_string());
+        balance_field.json_name = Some("balance".to_string());
+        balance_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_INT64));
+        account.field.push(balance_field);
+        file.message_type.push(account);
+        file_set.file.push(file);
+
+        let exponential: Value = serde_json::from_str(r#"{"balance": 1e9}"#).unwrap();
+        let plain: Value = serde_json::from_str(r#"{"balance": 1000000000}"#).unwrap();
+        let fractional: Value = serde_json::from_str(r#"{"balance": 1.5e0}"#).unwrap();
+
+        // An integral float is an integer to proto3 JSON parsers
+        assert!(validate_json(&file_set, "Account", &exponential, vec![]).is_empty());
+        assert!(validate_json(&file_set, "Account", &plain, vec![]).is_empty());
+
+        let options = ValidationOptions {
+            strict_integer_format: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_json_with(&file_set, "Account", &exponential, vec![], &options),
+            vec![ValidationError {
+                field: "balance".to_string(),
+                error_type: ErrorType::FloatInteger,
+                found: Some("1000000000.0".to_string()),
+                expected: None,
+            }]
+        );
+        assert!(validate_json_with(&file_set, "Account", &plain, vec![], &options).is_empty());
+        assert_eq!(
+            validate_json_with(&file_set, "Account", &fractional, vec![], &options)[0].error_type,
+            ErrorType::WrongDataType
+        );
+    }
+
+    fn create_tree_descriptor() -> FileDescriptorSet {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto3".to_string());
+        let mut tree_node = DescriptorProto::new();
+        tree_node.name = Some("TreeNode".to_string());
+        let mut name_field = FieldDescriptorProto::new();
+        name_field.name = Some("name".to_string());
+        name_field.json_name = Some("name".to_string());
+        name_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_STRING));
+        let mut children_field = FieldDescriptorProto::new();
+        children_field.name = Some("children".to_string());
+        children_field.json_name = Some("children".to_string());
+        children_field.label = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+        ));
+        children_field.type_name = Some(".TreeNode".to_string());
+        children_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+        tree_node.field.push(name_field);
+        tree_node.field.push(children_field);
+        file.message_type.push(tree_node);
+        file_set.file.push(file);
+        file_set
+    }
+
+    /// A chain of `depth` nodes below the root, the deepest one without children.
+    fn nested_tree(depth: usize) -> Value {
+        let mut node = json!({"name": "leaf"});
+        for level in (0..depth).rev() {
+            node = json!({"name": format!("level-{}", level), "children": [node]});
+        }
+        node
+    }
+
+    #[test]
+    fn test_self_referencing_message_depth_limit() {
+        let file_set = create_tree_descriptor();
+
+        // Within the default limit
+        let tree = nested_tree(DEFAULT_MAX_DEPTH);
+        assert!(validate_json(&file_set, "TreeNode", &tree, vec![]).is_empty());
+
+        // One level too deep is reported once, at the node that is not validated
+        let tree = nested_tree(DEFAULT_MAX_DEPTH + 1);
+        let errors = validate_json(&file_set, "TreeNode", &tree, vec![]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error_type, ErrorType::MaxDepthExceeded);
+        assert_eq!(errors[0].field, vec!["children[0]"; DEFAULT_MAX_DEPTH + 1].join("."));
+
+        let options = ValidationOptions {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let tree = json!({"children": [{"children": [{"children": [{"name": 42}]}]}]});
+        assert_eq!(
+            validate_json_with(&file_set, "TreeNode", &tree, vec![], &options),
+            vec![ValidationError {
+                field: "children[0].children[0].children[0]".to_string(),
+                error_type: ErrorType::MaxDepthExceeded,
+                found: None,
+                expected: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_wrong_data_type_reports_expected_type() {
+        let file_set = create_test_descriptor();
+        let json_value = json!({"items": [{"id": 1}, {"id": "two"}]});
+
+        let errors: Vec<ValidationError> = validate_json(&file_set, "TopLevel", &json_value, vec![])
+            .into_iter()
+            .filter(|error| error.error_type == ErrorType::WrongDataType)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                field: "items[1].id".to_string(),
+                error_type: ErrorType::WrongDataType,
+                found: Some("string".to_string()),
+                expected: Some("TYPE_INT32".to_string()),
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "items[1].id - WrongDataType (expected TYPE_INT32, found string)"
+        );
+    }
+
+    #[test]
+    fn test_null_elements_in_repeated_fields() {
+        let file_set = create_test_descriptor();
+        let null_element = |field: &str, expected: &str| ValidationError {
+            field: field.to_string(),
+            error_type: ErrorType::InvalidArrayElement,
+            found: Some("null".to_string()),
+            expected: Some(expected.to_string()),
+        };
+
+        // Repeated message field
+        let json_value = json!({
+            "name": "test",
+            "items": [{"id": 1, "description": "first", "details": [{"value": "a"}]}, null]
+        });
+        assert_eq!(
+            validate_json(&file_set, "TopLevel", &json_value, vec![]),
+            vec![null_element("items[1]", "TYPE_MESSAGE")]
+        );
+
+        // Repeated scalar field
+        let mut tagged = DescriptorProto::new();
+        tagged.name = Some("Tagged".to_string());
+        let mut tags_field = FieldDescriptorProto::new();
+        tags_field.name = Some("tags".to_string());
+        tags_field.json_name = Some("tags".to_string());
+        tags_field.label = Some(EnumOrUnknown::new(
+            protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+        ));
+        tags_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_STRING));
+        tagged.field.push(tags_field);
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.message_type.push(tagged);
+        file_set.file.push(file);
+
+        assert_eq!(
+            validate_json(&file_set, "Tagged", &json!({"tags": ["a", null, "b"]}), vec![]),
+            vec![null_element("tags[1]", "TYPE_STRING")]
+        );
+    }
+
+    #[test]
+    fn test_null_values_are_not_missing_fields() {
+        let file_set = create_test_descriptor();
+        let null_value = |field: &str, expected: &str| ValidationError {
+            field: field.to_string(),
+            error_type: ErrorType::NullValue,
+            found: None,
+            expected: Some(expected.to_string()),
+        };
+
+        // Scalar and repeated message fields
+        let json_value = json!({"name": null, "items": null});
+        let mut errors = validate_json(&file_set, "TopLevel", &json_value, vec![]);
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        assert_eq!(
+            errors,
+            vec![null_value("items", "TYPE_MESSAGE"), null_value("name", "TYPE_STRING")]
+        );
+
+        // Singular message field
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        let mut item_field = FieldDescriptorProto::new();
+        item_field.name = Some("item".to_string());
+        item_field.json_name = Some("item".to_string());
+        item_field.type_name = Some(".SubMessage".to_string());
+        item_field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+        order.field.push(item_field);
+        let mut file_set = create_test_descriptor();
+        file_set.file[0].message_type.push(order);
+
+        assert_eq!(
+            validate_json(&file_set, "Order", &json!({"item": null}), vec![]),
+            vec![null_value("item", "TYPE_MESSAGE")]
+        );
+        // An absent field is still reported as missing
+        assert_eq!(
+            validate_json(&file_set, "Order", &json!({}), vec![])[0].error_type,
+            ErrorType::MissingField
+        );
+    }
+
+    #[test]
+    fn test_map_keys_match_the_key_type() {
+        // message Counters { map<string, int32> by_name = 1; map<int32, int32> by_id = 2; }
+        let map_entry = |name: &str, key_type: Type| {
+            let mut entry = DescriptorProto::new();
+            entry.name = Some(name.to_string());
+            let mut entry_options = protobuf::descriptor::MessageOptions::new();
+            entry_options.map_entry = Some(true);
+            entry.options = protobuf::MessageField::some(entry_options);
+            for (number, field_name, type_) in [(1, "key", key_type), (2, "value", Type::TYPE_INT32)] {
+                let mut field = FieldDescriptorProto::new();
+                field.name = Some(field_name.to_string());
+                field.number = Some(number);
+                field.type_ = Some(EnumOrUnknown::new(type_));
+                entry.field.push(field);
+            }
+            entry
+        };
+        let map_field = |name: &str, entry_name: &str| {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.label = Some(EnumOrUnknown::new(
+                protobuf::descriptor::field_descriptor_proto::Label::LABEL_REPEATED,
+            ));
+            field.type_name = Some(format!(".Counters.{}", entry_name));
+            field.type_ = Some(EnumOrUnknown::new(Type::TYPE_MESSAGE));
+            field
+        };
+
+        let mut counters = DescriptorProto::new();
+        counters.name = Some("Counters".to_string());
+        counters.field.push(map_field("by_name", "ByNameEntry"));
+        counters.field.push(map_field("by_id", "ByIdEntry"));
+        counters.nested_type.push(map_entry("ByNameEntry", Type::TYPE_STRING));
+        counters.nested_type.push(map_entry("ByIdEntry", Type::TYPE_INT32));
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.syntax = Some("proto3".to_string());
+        file.message_type.push(counters);
+        file_set.file.push(file);
+
+        let valid = json!({"by_name": {"apples": 3, "pears": 0}, "by_id": {"7": 1, "-2": 5}});
+        assert_eq!(validate_json(&file_set, "Counters", &valid, vec![]), vec![]);
+
+        let invalid = json!({"by_name": {"apples": "three"}, "by_id": {"x7": 1, "4294967296": 2}});
+        let mut errors = validate_json(&file_set, "Counters", &invalid, vec![]);
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    field: "by_id[\"4294967296\"]".to_string(),
+                    error_type: ErrorType::InvalidMapKey,
+                    found: Some("4294967296".to_string()),
+                    expected: Some("TYPE_INT32".to_string()),
+                },
+                ValidationError {
+                    field: "by_id[\"x7\"]".to_string(),
+                    error_type: ErrorType::InvalidMapKey,
+                    found: Some("x7".to_string()),
+                    expected: Some("TYPE_INT32".to_string()),
+                },
+                ValidationError {
+                    field: "by_name[\"apples\"]".to_string(),
+                    error_type: ErrorType::WrongDataType,
+                    found: Some("string".to_string()),
+                    expected: Some("TYPE_INT32".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oneof_allows_a_single_member() {
+        // message Payment { oneof method { string card = 1; string cash = 2; } int32 amount = 3; }
+        let mut payment = DescriptorProto::new();
+        payment.name = Some("Payment".to_string());
+        let mut method = protobuf::descriptor::OneofDescriptorProto::new();
+        method.name = Some("method".to_string());
+        payment.oneof_decl.push(method);
+        for (name, type_, oneof_index) in [
+            ("card", Type::TYPE_STRING, Some(0)),
+            ("cash", Type::TYPE_STRING, Some(0)),
+            ("amount", Type::TYPE_INT32, None),
+        ] {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            field.oneof_index = oneof_index;
+            payment.field.push(field);
+        }
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.message_type.push(payment);
+        file_set.file.push(file);
+
+        let validate = |doc: Value| validate_json(&file_set, "Payment", &doc, vec![]);
+        assert_eq!(validate(json!({"card": "4242", "amount": 10})), vec![]);
+        assert_eq!(validate(json!({"cash": "EUR", "amount": 10})), vec![]);
+
+        assert_eq!(
+            validate(json!({"card": "4242", "cash": "EUR", "amount": 10})),
+            vec![ValidationError {
+                field: "method".to_string(),
+                error_type: ErrorType::MultipleOneofSet,
+                found: Some("card,cash".to_string()),
+                expected: None,
+            }]
+        );
+        // No syntax declared, so one member is expected instead of every member
+        assert_eq!(
+            validate(json!({"amount": 10})),
+            vec![ValidationError {
+                field: "method".to_string(),
+                error_type: ErrorType::MissingOneof,
+                found: None,
+                expected: Some("card,cash".to_string()),
+            }]
+        );
+
+        // Oneof groups are optional with proto3 presence
+        let mut file_set = file_set.clone();
+        file_set.file[0].syntax = Some("proto3".to_string());
+        assert_eq!(validate_json(&file_set, "Payment", &json!({}), vec![]), vec![]);
+    }
+
+    #[test]
+    fn test_oneof_member_values_are_validated() {
+        // message Payment { oneof method { Card card = 1; Kind kind = 2; } }
+        // message Card { int32 number = 1; }  enum Kind { CASH = 0; }
+        let field = |name: &str, type_: Type, type_name: Option<&str>, oneof_index: Option<i32>| {
+            let mut field = FieldDescriptorProto::new();
+            field.name = Some(name.to_string());
+            field.json_name = Some(name.to_string());
+            field.type_ = Some(EnumOrUnknown::new(type_));
+            field.type_name = type_name.map(str::to_string);
+            field.oneof_index = oneof_index;
+            field
+        };
+        let mut payment = DescriptorProto::new();
+        payment.name = Some("Payment".to_string());
+        let mut method = protobuf::descriptor::OneofDescriptorProto::new();
+        method.name = Some("method".to_string());
+        payment.oneof_decl.push(method);
+        payment.field.push(field("card", Type::TYPE_MESSAGE, Some(".Card"), Some(0)));
+        payment.field.push(field("kind", Type::TYPE_ENUM, Some(".Kind"), Some(0)));
+        let mut card = DescriptorProto::new();
+        card.name = Some("Card".to_string());
+        card.field.push(field("number", Type::TYPE_INT32, None, None));
+        let mut kind = protobuf::descriptor::EnumDescriptorProto::new();
+        kind.name = Some("Kind".to_string());
+        let mut cash = protobuf::descriptor::EnumValueDescriptorProto::new();
+        cash.name = Some("CASH".to_string());
+        cash.number = Some(0);
+        kind.value.push(cash);
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.message_type.push(payment);
+        file.message_type.push(card);
+        file.enum_type.push(kind);
+        file_set.file.push(file);
+
+        let validate = |doc: Value| validate_json(&file_set, "Payment", &doc, vec![]);
+        assert_eq!(validate(json!({"card": {"number": 4242}})), vec![]);
+        assert_eq!(validate(json!({"kind": "CASH"})), vec![]);
+
+        // The single set member is still checked against its own type
+        let errors = validate(json!({"card": {"number": "4242"}}));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "card.number");
+        assert_eq!(errors[0].error_type, ErrorType::WrongDataType);
+        let errors = validate(json!({"kind": "CHEQUE"}));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "kind");
+        assert_eq!(errors[0].error_type, ErrorType::InvalidEnumValue);
+    }
+
+    #[test]
+    fn test_auto_message_falls_back_to_the_sole_message() {
+        let mut order = DescriptorProto::new();
+        order.name = Some("Order".to_string());
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        file.message_type.push(order);
+        file_set.file.push(file);
+
+        assert_eq!(auto_message(&file_set, "orders_v2"), Some("Order".to_string()));
+        // A matching table name is used as is
+        assert_eq!(auto_message(&file_set, "order"), None);
+
+        // No fallback when the choice would be a guess
+        let mut invoice = DescriptorProto::new();
+        invoice.name = Some("Invoice".to_string());
+        file_set.file[0].message_type.push(invoice);
+        assert_eq!(auto_message(&file_set, "orders_v2"), None);
+        assert_eq!(auto_message(&create_test_descriptor(), "orders_v2"), None);
+    }
+
+    #[test]
+    fn test_missing_message_lists_the_messages() {
+        let mut file_set = FileDescriptorSet::new();
+        let mut file = protobuf::descriptor::FileDescriptorProto::new();
+        for name in ["Order", "Invoice"] {
+            let mut message = DescriptorProto::new();
+            message.name = Some(name.to_string());
+            file.message_type.push(message);
+        }
+        file_set.file.push(file);
+
+        assert_eq!(check_message(&file_set, "order"), Ok(()));
+        assert_eq!(check_message(&file_set, "INVOICE"), Ok(()));
+        assert_eq!(
+            check_message(&file_set, "orders"),
+            Err("no message named \"orders\" in the schema, available messages: Invoice, Order".to_string())
+        );
+        assert_eq!(
+            check_message(&FileDescriptorSet::new(), "orders"),
+            Err("no message named \"orders\", the schema defines no message".to_string())
+        );
+    }
 }
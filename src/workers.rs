@@ -0,0 +1,189 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use mlua::Lua;
+use serde_json::Value;
+
+use crate::script;
+
+/// Creates a Lua state with the scripts loaded, callable from any worker thread.
+pub type SharedLuaFactory = Arc<dyn Fn() -> Result<Lua, String> + Send + Sync>;
+
+/// A document to transform and its position in the batch.
+struct Job {
+    index: usize,
+    doc: Value,
+    errors_json: String, // Validation errors handed to the transform
+}
+
+/// Pool of OS threads, each owning its own Lua state, running the `transform` function of
+/// many documents in parallel. `Lua` is not `Send`, so every state stays on its thread.
+pub struct LuaPool {
+    jobs: Option<mpsc::Sender<Job>>,
+    results: mpsc::Receiver<(usize, Result<Value, String>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    processed: Vec<Arc<AtomicUsize>>, // Documents transformed by each worker
+}
+
+impl LuaPool {
+    /// Starts `size` workers, each loading the scripts with `lua_factory`. Fails when one of
+    /// them cannot load the scripts. With `string_transform`, documents are handed to the
+    /// transform as JSON text.
+    pub fn new(size: usize, string_transform: bool, lua_factory: SharedLuaFactory) -> Result<Self, String> {
+        let (job_sender, job_receiver) = mpsc::channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = mpsc::channel();
+        let (ready_sender, ready) = mpsc::channel::<Result<(), String>>();
+
+        let mut workers = Vec::new();
+        let mut processed = Vec::new();
+        for _ in 0..size {
+            let job_receiver = Arc::clone(&job_receiver);
+            let result_sender = result_sender.clone();
+            let ready_sender = ready_sender.clone();
+            let lua_factory = Arc::clone(&lua_factory);
+            let count = Arc::new(AtomicUsize::new(0));
+            processed.push(Arc::clone(&count));
+
+            workers.push(thread::spawn(move || {
+                let lua = match lua_factory() {
+                    Ok(lua) => lua,
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = ready_sender.send(Ok(()));
+
+                // Take one job at a time, the lock is released before transforming
+                loop {
+                    let job = job_receiver.lock().map_err(|e| e.to_string()).and_then(|receiver| {
+                        receiver.recv().map_err(|e| e.to_string())
+                    });
+                    let Ok(job) = job else {
+                        break; // The pool was dropped
+                    };
+
+                    let result = script::lua_transform(&lua, job.doc, &job.errors_json, string_transform).map_err(|err| err.to_string());
+                    count.fetch_add(1, Ordering::Relaxed);
+                    if result_sender.send((job.index, result)).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(ready_sender);
+
+        let pool = LuaPool {
+            jobs: Some(job_sender),
+            results,
+            workers,
+            processed,
+        };
+        for _ in 0..size {
+            ready
+                .recv()
+                .map_err(|_| "Lua worker stopped during startup".to_string())??;
+        }
+        Ok(pool)
+    }
+
+    /// Transforms a batch of documents, each with its validation errors as JSON, across the
+    /// workers. Results are in the order of the documents.
+    pub fn transform_batch(&self, docs: Vec<(Value, String)>) -> Vec<Result<Value, String>> {
+        let count = docs.len();
+        let mut results: Vec<Option<Result<Value, String>>> = (0..count).map(|_| None).collect();
+
+        if let Some(jobs) = &self.jobs {
+            for (index, (doc, errors_json)) in docs.into_iter().enumerate() {
+                if jobs.send(Job { index, doc, errors_json }).is_err() {
+                    break;
+                }
+            }
+        }
+        for _ in 0..count {
+            match self.results.recv() {
+                Ok((index, result)) => results[index] = Some(result),
+                Err(_) => break,
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| Err("Lua worker stopped".to_string())))
+            .collect()
+    }
+
+    /// Number of documents transformed by each worker so far.
+    pub fn shares(&self) -> Vec<usize> {
+        self.processed
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+impl Drop for LuaPool {
+    /// Closes the job queue and waits for the workers to finish.
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_workers_share_the_batch() {
+        // Slow enough for every worker to pick up documents
+        let lua_factory: SharedLuaFactory = Arc::new(|| {
+            let lua = Lua::new();
+            lua.load(
+                r#"
+                function transform(doc)
+                    local x = 0
+                    for i = 1, 2000000 do x = x + i end
+                    return (string.gsub(doc, '"quantity":"(%d+)"', '"quantity":%1'))
+                end
+                "#,
+            )
+            .exec()
+            .map_err(|e| e.to_string())?;
+            Ok(lua)
+        });
+        let pool = LuaPool::new(3, true, lua_factory).unwrap();
+
+        let docs: Vec<(Value, String)> = (0..30)
+            .map(|i| (json!({"_id": format!("order-{}", i), "quantity": i.to_string()}), "[]".to_string()))
+            .collect();
+        let results = pool.transform_batch(docs);
+
+        assert_eq!(results.len(), 30);
+        for (i, result) in results.into_iter().enumerate() {
+            assert_eq!(result, Ok(json!({"_id": format!("order-{}", i), "quantity": i})));
+        }
+        let shares = pool.shares();
+        assert_eq!(shares.iter().sum::<usize>(), 30);
+        assert!(shares.iter().all(|share| *share > 0), "shares {:?}", shares);
+    }
+
+    #[test]
+    fn test_worker_startup_failure() {
+        let lua_factory: SharedLuaFactory = Arc::new(|| Err("transform function not found".to_string()));
+        assert_eq!(
+            LuaPool::new(2, false, lua_factory).err(),
+            Some("transform function not found".to_string())
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// Integration tests: a whole morph against a mock CouchDB, from the `_find` scan to the updates
+
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc, sync::Arc};
+
+use bulkmorph::{
+    descriptor,
+    fetch::Fetch,
+    pipeline::{Options, Pipeline, Sink},
+};
+use mlua::Lua;
+use reqwest::Client;
+use serde_json::{json, Value};
+use wiremock::{
+    matchers::{body_partial_json, method, path, path_regex},
+    Mock, MockServer, Request, ResponseTemplate,
+};
+
+/// Mock CouchDB serving the orders table in two `_find` pages linked by the "page-2" bookmark.
+/// order-1 and order-3 have a string quantity, order-2 is already valid.
+async fn mock_couchdb() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/orders"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"db_name": "orders", "doc_count": 3})))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/orders/_find"))
+        .and(body_partial_json(json!({"bookmark": "page-2"})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "docs": [{"_id": "order-3", "_rev": "1-c", "quantity": "ten"}],
+            "bookmark": "end"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/orders/_find"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "docs": [
+                {"_id": "order-1", "_rev": "1-a", "quantity": "ten"},
+                {"_id": "order-2", "_rev": "1-b", "quantity": 5}
+            ],
+            "bookmark": "page-2"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    server
+}
+
+/// Runs the morph over the whole table, page by page as the CLI does, and returns the pipeline.
+/// The schema is written to a temp directory named after the test.
+async fn morph(name: &str, server: &MockServer, options: Options) -> Rc<Pipeline> {
+    let dir = std::env::temp_dir().join(format!("bulkmorph-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let proto = dir.join("order.proto");
+    std::fs::write(&proto, "syntax = \"proto3\";\nmessage Order { int32 quantity = 1; }\n").unwrap();
+    let file_descriptor_set = descriptor::parse_proto(proto.to_str().unwrap(), dir.to_str().unwrap(), None).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let lua = Lua::new();
+    lua.load(
+        r#"
+        function transform(doc)
+            if doc.quantity == "ten" then doc.quantity = 10 end
+            return doc
+        end
+        "#,
+    )
+    .exec()
+    .unwrap();
+    let sink = Sink::CouchDb {
+        client: Client::new(),
+        db_host: server.uri(),
+        table_name: "orders".to_string(),
+        preserve_revs: false,
+    };
+    let pipeline = Rc::new(Pipeline::new(
+        Rc::new(lua),
+        Arc::new(file_descriptor_set),
+        "Order",
+        vec!["_id".to_string(), "_rev".to_string()],
+        options,
+        sink,
+    ));
+
+    let batch = Rc::new(RefCell::new(Vec::new()));
+    Fetch::new(&server.uri(), "orders", 2)
+        .with_callback(Box::new({
+            let batch = Rc::clone(&batch);
+            move |doc| batch.borrow_mut().push(doc)
+        }))
+        .with_batch_callback(Box::new({
+            let pipeline = Rc::clone(&pipeline);
+            move |_| {
+                pipeline.process_batch(batch.take());
+            }
+        }))
+        .execute()
+        .await
+        .unwrap();
+    pipeline.flush_updates();
+    pipeline.finish().unwrap();
+    pipeline
+}
+
+/// The bookmark of each `_find` request, in order.
+async fn find_bookmarks(server: &MockServer) -> Vec<Value> {
+    let requests = server.received_requests().await.unwrap();
+    requests
+        .iter()
+        .filter(|request| request.url.path() == "/orders/_find")
+        .map(|request| request.body_json::<Value>().unwrap()["bookmark"].clone())
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_morph_updates_every_invalid_document() {
+    let server = mock_couchdb().await;
+    Mock::given(method("PUT"))
+        .and(path_regex("^/orders/order-[0-9]+$"))
+        .respond_with(|request: &Request| {
+            let id = request.url.path().trim_start_matches("/orders/").to_string();
+            ResponseTemplate::new(201).set_body_json(json!({"ok": true, "id": id, "rev": "2-z"}))
+        })
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let pipeline = morph("morph-updates", &server, Options::default()).await;
+
+    assert_eq!(find_bookmarks(&server).await, vec![Value::Null, json!("page-2")]);
+    let requests = server.received_requests().await.unwrap();
+    let updates: BTreeMap<String, (Option<String>, Value)> = requests
+        .iter()
+        .filter(|request| request.method.as_str() == "PUT")
+        .map(|request| {
+            let if_match = request.headers.get("if-match").map(|rev| rev.to_str().unwrap().to_string());
+            (request.url.path().to_string(), (if_match, request.body_json().unwrap()))
+        })
+        .collect();
+    assert_eq!(
+        updates,
+        BTreeMap::from([
+            (
+                "/orders/order-1".to_string(),
+                (Some("1-a".to_string()), json!({"_id": "order-1", "_rev": "1-a", "quantity": 10}))
+            ),
+            (
+                "/orders/order-3".to_string(),
+                (Some("1-c".to_string()), json!({"_id": "order-3", "_rev": "1-c", "quantity": 10}))
+            ),
+        ])
+    );
+
+    let stats = pipeline.stats();
+    assert_eq!((stats.scanned, stats.valid, stats.updated, stats.failed), (3, 1, 2, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_morph_sends_bulk_updates() {
+    let server = mock_couchdb().await;
+    Mock::given(method("POST"))
+        .and(path("/orders/_bulk_docs"))
+        .respond_with(|request: &Request| {
+            let body: Value = request.body_json().unwrap();
+            let results: Vec<Value> = body["docs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|doc| json!({"ok": true, "id": doc["_id"], "rev": "2-z"}))
+                .collect();
+            ResponseTemplate::new(201).set_body_json(results)
+        })
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // The buffer holds both repaired documents, across the two pages
+    let options = Options {
+        bulk_size: Some(10),
+        ..Default::default()
+    };
+    let pipeline = morph("morph-bulk", &server, options).await;
+
+    assert_eq!(find_bookmarks(&server).await, vec![Value::Null, json!("page-2")]);
+    let requests = server.received_requests().await.unwrap();
+    let bodies: Vec<Value> = requests
+        .iter()
+        .filter(|request| request.url.path() == "/orders/_bulk_docs")
+        .map(|request| request.body_json().unwrap())
+        .collect();
+    assert_eq!(
+        bodies,
+        vec![json!({"docs": [
+            {"_id": "order-1", "_rev": "1-a", "quantity": 10},
+            {"_id": "order-3", "_rev": "1-c", "quantity": 10}
+        ]})]
+    );
+
+    let stats = pipeline.stats();
+    assert_eq!((stats.scanned, stats.valid, stats.updated, stats.failed), (3, 1, 2, 0));
+}